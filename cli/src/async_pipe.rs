@@ -15,16 +15,33 @@ cfg_if::cfg_if! {
 		pub type AsyncPipeWriteHalf = tokio::net::unix::OwnedWriteHalf;
 		pub type AsyncPipeReadHalf = tokio::net::unix::OwnedReadHalf;
 
+		use crate::util::{permissions::restrict_dir, runtime_dir::denial_hint};
+
+		/// Maps permission errors, which may come from SELinux or AppArmor, to
+		/// an error that says how to resolve them.
+		fn map_denied(path: &Path, e: std::io::Error, other: fn(std::io::Error) -> CodeError) -> CodeError {
+			if e.kind() == std::io::ErrorKind::PermissionDenied {
+				CodeError::SocketAccessDenied {
+					path: path.display().to_string(),
+					hint: denial_hint(path),
+				}
+			} else {
+				other(e)
+			}
+		}
+
 		pub async fn get_socket_rw_stream(path: &Path) -> Result<AsyncPipe, CodeError> {
 			tokio::net::UnixStream::connect(path)
 				.await
-				.map_err(CodeError::AsyncPipeFailed)
+				.map_err(|e| map_denied(path, e, CodeError::AsyncPipeFailed))
 		}
 
 		pub async fn listen_socket_rw_stream(path: &Path) -> Result<AsyncPipeListener, CodeError> {
-			tokio::net::UnixListener::bind(path)
-				.map(AsyncPipeListener)
-				.map_err(CodeError::AsyncPipeListenerFailed)
+			let listener = tokio::net::UnixListener::bind(path)
+				.map_err(|e| map_denied(path, e, CodeError::AsyncPipeListenerFailed))?;
+			// connecting to a unix socket requires write permission on it
+			restrict_dir(path).map_err(CodeError::AsyncPipeListenerFailed)?;
+			Ok(AsyncPipeListener(listener))
 		}
 
 		pub struct AsyncPipeListener(tokio::net::UnixListener);
@@ -38,11 +55,65 @@ cfg_if::cfg_if! {
 		pub fn socket_stream_split(pipe: AsyncPipe) -> (AsyncPipeReadHalf, AsyncPipeWriteHalf) {
 			pipe.into_split()
 		}
+
+		/// Checks that the process on the other end of the socket is run by the
+		/// current user, so other users on the host can't pose as its servers.
+		pub fn check_peer_is_current_user(pipe: &AsyncPipe, path: &Path) -> Result<(), CodeError> {
+			let uid = pipe.peer_cred().map_err(CodeError::AsyncPipeFailed)?.uid();
+			if uid != unsafe { libc::geteuid() } {
+				return Err(CodeError::SocketPeerNotTrusted {
+					path: path.display().to_string(),
+					uid,
+				});
+			}
+
+			Ok(())
+		}
+
+		/// Gets a directory for sockets that only the current user can access.
+		/// It's checked to be owned by the user, since the runtime dir may be
+		/// shared, such as when it's the temp dir.
+		fn private_socket_dir() -> std::io::Result<PathBuf> {
+			use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+			let uid = unsafe { libc::geteuid() };
+			let dir = crate::util::runtime_dir::runtime_dir()
+				.join(format!("{}-sockets-{}", APPLICATION_NAME, uid));
+			match std::fs::create_dir(&dir) {
+				Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => return Err(e),
+				_ => {}
+			}
+
+			let meta = std::fs::symlink_metadata(&dir)?;
+			if !meta.is_dir() || meta.uid() != uid {
+				return Err(std::io::Error::new(
+					std::io::ErrorKind::PermissionDenied,
+					format!("{} isn't a directory owned by the current user", dir.display()),
+				));
+			}
+			std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700))?;
+			Ok(dir)
+		}
 	} else {
 		use tokio::{time::sleep, io::{AsyncRead, AsyncWrite, ReadBuf}};
 		use tokio::net::windows::named_pipe::{ClientOptions, ServerOptions, NamedPipeClient, NamedPipeServer};
-		use std::{time::Duration, pin::Pin, task::{Context, Poll}, io};
+		use std::{time::Duration, pin::Pin, task::{Context, Poll}, io, ptr};
 		use pin_project::pin_project;
+		use winapi::{
+			ctypes::c_void,
+			shared::{
+				minwindef::{DWORD, FALSE},
+				sddl::{ConvertSidToStringSidW, ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1},
+			},
+			um::{
+				handleapi::CloseHandle,
+				minwinbase::SECURITY_ATTRIBUTES,
+				processthreadsapi::{GetCurrentProcess, OpenProcessToken},
+				securitybaseapi::GetTokenInformation,
+				winbase::LocalFree,
+				winnt::{TokenUser, HANDLE, LPWSTR, TOKEN_QUERY, TOKEN_USER},
+			},
+		};
 
 		#[pin_project(project = AsyncPipeProj)]
 		pub enum AsyncPipe {
@@ -126,9 +197,89 @@ cfg_if::cfg_if! {
 			Ok(AsyncPipe::PipeClient(client))
 		}
 
+		/// Security attributes that only let the current user connect to a
+		/// pipe. The default DACL of pipes also lets LocalSystem and
+		/// administrators connect, and gives everyone read access.
+		struct OwnerOnlySecurity(SECURITY_ATTRIBUTES);
+
+		// the descriptor is only read after it's created
+		unsafe impl Send for OwnerOnlySecurity {}
+		unsafe impl Sync for OwnerOnlySecurity {}
+
+		impl OwnerOnlySecurity {
+			fn new() -> io::Result<Self> {
+				let sddl: Vec<u16> = format!("D:P(A;;GA;;;{})", current_user_sid()?)
+					.encode_utf16()
+					.chain(Some(0))
+					.collect();
+				let mut descriptor = ptr::null_mut();
+				let ok = unsafe {
+					ConvertStringSecurityDescriptorToSecurityDescriptorW(
+						sddl.as_ptr(),
+						SDDL_REVISION_1 as DWORD,
+						&mut descriptor,
+						ptr::null_mut(),
+					)
+				};
+				if ok == FALSE {
+					return Err(io::Error::last_os_error());
+				}
+
+				Ok(OwnerOnlySecurity(SECURITY_ATTRIBUTES {
+					nLength: std::mem::size_of::<SECURITY_ATTRIBUTES>() as DWORD,
+					lpSecurityDescriptor: descriptor,
+					bInheritHandle: FALSE,
+				}))
+			}
+
+			fn create(&mut self, options: &ServerOptions, path: &Path) -> io::Result<NamedPipeServer> {
+				unsafe {
+					options.create_with_security_attributes_raw(path, &mut self.0 as *mut _ as *mut c_void)
+				}
+			}
+		}
+
+		impl Drop for OwnerOnlySecurity {
+			fn drop(&mut self) {
+				unsafe { LocalFree(self.0.lpSecurityDescriptor) };
+			}
+		}
+
+		/// Gets the SID of the user the CLI runs as, like `S-1-5-21-...`.
+		fn current_user_sid() -> io::Result<String> {
+			unsafe {
+				let mut token: HANDLE = ptr::null_mut();
+				if OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) == FALSE {
+					return Err(io::Error::last_os_error());
+				}
+
+				// u64s, so the buffer is aligned for the TOKEN_USER
+				let mut len: DWORD = 0;
+				GetTokenInformation(token, TokenUser, ptr::null_mut(), 0, &mut len);
+				let mut buf = vec![0u64; (len as usize + 7) / 8];
+				let ok = GetTokenInformation(token, TokenUser, buf.as_mut_ptr() as *mut c_void, len, &mut len);
+				let err = io::Error::last_os_error();
+				CloseHandle(token);
+				if ok == FALSE {
+					return Err(err);
+				}
+
+				let user = &*(buf.as_ptr() as *const TOKEN_USER);
+				let mut sid: LPWSTR = ptr::null_mut();
+				if ConvertSidToStringSidW(user.User.Sid, &mut sid) == FALSE {
+					return Err(io::Error::last_os_error());
+				}
+				let sid_len = (0..).take_while(|&i| *sid.add(i) != 0).count();
+				let s = String::from_utf16_lossy(std::slice::from_raw_parts(sid, sid_len));
+				LocalFree(sid as *mut c_void);
+				Ok(s)
+			}
+		}
+
 		pub struct AsyncPipeListener {
 			path: PathBuf,
-			server: NamedPipeServer
+			server: NamedPipeServer,
+			security: OwnerOnlySecurity,
 		}
 
 		impl AsyncPipeListener {
@@ -147,8 +298,9 @@ cfg_if::cfg_if! {
 				// isn't closed (after it's done in the task) before a new one is
 				// available. Otherwise the client might error with
 				// `io::ErrorKind::NotFound`.
-				let next_server = ServerOptions::new()
-					.create(&self.path)
+				let next_server = self
+					.security
+					.create(&ServerOptions::new(), &self.path)
 					.map_err(CodeError::AsyncPipeListenerFailed)?;
 
 
@@ -157,17 +309,38 @@ cfg_if::cfg_if! {
 		}
 
 		pub async fn listen_socket_rw_stream(path: &Path) -> Result<AsyncPipeListener, CodeError> {
-			let server = ServerOptions::new()
-					.first_pipe_instance(true)
-					.create(path)
-					.map_err(CodeError::AsyncPipeListenerFailed)?;
+			let mut security = OwnerOnlySecurity::new().map_err(CodeError::AsyncPipeListenerFailed)?;
+			let server = security
+				.create(ServerOptions::new().first_pipe_instance(true), path)
+				.map_err(CodeError::AsyncPipeListenerFailed)?;
 
-			Ok(AsyncPipeListener { path: path.to_owned(), server })
+			Ok(AsyncPipeListener { path: path.to_owned(), server, security })
 		}
 
 		pub fn socket_stream_split(pipe: AsyncPipe) -> (AsyncPipeReadHalf, AsyncPipeWriteHalf) {
 			tokio::io::split(pipe)
 		}
+
+		/// Not checked on Windows. Pipes are named randomly, so other users
+		/// can't create the ones servers are started on ahead of them, and the
+		/// ones the CLI listens on only let the current user connect, see
+		/// `OwnerOnlySecurity`.
+		pub fn check_peer_is_current_user(_pipe: &AsyncPipe, _path: &Path) -> Result<(), CodeError> {
+			Ok(())
+		}
+	}
+}
+
+/// Gets a random name for a pipe/socket that only the current user can
+/// connect to, for servers the CLI starts.
+pub fn get_private_socket_name() -> Result<PathBuf, CodeError> {
+	cfg_if::cfg_if! {
+		if #[cfg(unix)] {
+			let dir = private_socket_dir().map_err(CodeError::AsyncPipeListenerFailed)?;
+			Ok(dir.join(format!("{}-{}", APPLICATION_NAME, Uuid::new_v4())))
+		} else {
+			Ok(get_socket_name())
+		}
 	}
 }
 
@@ -175,7 +348,7 @@ cfg_if::cfg_if! {
 pub fn get_socket_name() -> PathBuf {
 	cfg_if::cfg_if! {
 		if #[cfg(unix)] {
-			std::env::temp_dir().join(format!("{}-{}", APPLICATION_NAME, Uuid::new_v4()))
+			crate::util::runtime_dir::runtime_dir().join(format!("{}-{}", APPLICATION_NAME, Uuid::new_v4()))
 		} else {
 			PathBuf::from(format!(r"\\.\pipe\{}-{}", APPLICATION_NAME, Uuid::new_v4()))
 		}
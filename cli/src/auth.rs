@@ -3,15 +3,15 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
+#[cfg(feature = "keyring")]
+use crate::util::errors::wrap;
 use crate::{
 	constants::{get_default_user_agent, PRODUCT_NAME_LONG},
 	debug, info, log,
 	state::{LauncherPaths, PersistedState},
 	trace,
 	util::{
-		errors::{
-			wrap, AnyError, OAuthError, RefreshTokenNotAvailableError, StatusError, WrappedError,
-		},
+		errors::{AnyError, OAuthError, RefreshTokenNotAvailableError, StatusError, WrappedError},
 		input::prompt_options,
 	},
 	warning,
@@ -203,13 +203,15 @@ where
 	serde_json::from_str::<T>(&dec).ok()
 }
 
-#[cfg(target_os = "windows")]
+#[cfg(all(feature = "keyring", target_os = "windows"))]
 const KEYCHAIN_ENTRY_LIMIT: usize = 1024;
-#[cfg(not(target_os = "windows"))]
+#[cfg(all(feature = "keyring", not(target_os = "windows")))]
 const KEYCHAIN_ENTRY_LIMIT: usize = 128 * 1024;
 
+#[cfg(feature = "keyring")]
 const CONTINUE_MARKER: &str = "<MORE>";
 
+#[cfg(feature = "keyring")]
 #[derive(Default)]
 struct KeyringStorage {
 	// keywring storage can be split into multiple entries due to entry length limits
@@ -217,6 +219,7 @@ struct KeyringStorage {
 	entries: Vec<keyring::Entry>,
 }
 
+#[cfg(feature = "keyring")]
 macro_rules! get_next_entry {
 	($self: expr, $i: expr) => {
 		match $self.entries.get($i) {
@@ -230,6 +233,7 @@ macro_rules! get_next_entry {
 	};
 }
 
+#[cfg(feature = "keyring")]
 impl StorageImplementation for KeyringStorage {
 	fn read(&mut self) -> Result<Option<StoredCredential>, WrappedError> {
 		let mut str = String::new();
@@ -290,6 +294,29 @@ impl StorageImplementation for KeyringStorage {
 	}
 }
 
+/// Opens the OS keyring, unless it can't be read or the user prefers file
+/// storage.
+#[cfg(feature = "keyring")]
+fn open_keyring_storage() -> Option<StorageWithLastRead> {
+	if std::env::var("VSCODE_CLI_USE_FILE_KEYCHAIN").is_ok() {
+		return None;
+	}
+
+	let mut keyring_storage = KeyringStorage::default();
+	match keyring_storage.read() {
+		Ok(v) => Some(StorageWithLastRead {
+			last_read: Cell::new(Ok(v)),
+			storage: Box::new(keyring_storage),
+		}),
+		Err(_) => None,
+	}
+}
+
+#[cfg(not(feature = "keyring"))]
+fn open_keyring_storage() -> Option<StorageWithLastRead> {
+	None
+}
+
 struct FileStorage(PersistedState<Option<String>>);
 
 impl StorageImplementation for FileStorage {
@@ -325,23 +352,16 @@ impl Auth {
 			return op(s);
 		}
 
-		let mut keyring_storage = KeyringStorage::default();
-		let mut file_storage = FileStorage(PersistedState::new(self.file_storage_path.clone()));
-
-		let keyring_storage_result = match std::env::var("VSCODE_CLI_USE_FILE_KEYCHAIN") {
-			Ok(_) => Err(wrap("", "user prefers file storage")),
-			_ => keyring_storage.read(),
-		};
-
-		let mut storage = match keyring_storage_result {
-			Ok(v) => StorageWithLastRead {
-				last_read: Cell::new(Ok(v)),
-				storage: Box::new(keyring_storage),
-			},
-			Err(_) => StorageWithLastRead {
-				last_read: Cell::new(file_storage.read()),
-				storage: Box::new(file_storage),
-			},
+		let mut storage = match open_keyring_storage() {
+			Some(s) => s,
+			None => {
+				let mut file_storage =
+					FileStorage(PersistedState::new(self.file_storage_path.clone()));
+				StorageWithLastRead {
+					last_read: Cell::new(file_storage.read()),
+					storage: Box::new(file_storage),
+				}
+			}
 		};
 
 		let out = op(&mut storage);
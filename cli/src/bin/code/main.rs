@@ -10,17 +10,20 @@ use clap::Parser;
 use cli::{
 	commands::{args, internal_wsl, tunnels, update, version, CommandContext},
 	constants::get_default_user_agent,
-	desktop, log,
-	state::LauncherPaths,
+	desktop, log, product, self_update,
+	state::{config::CliConfig, LauncherPaths},
+	tunnels::editor_hook::EDITOR_HOOK_ENV,
+	update_service,
 	util::{
 		errors::{wrap, AnyError},
-		is_integrated_cli,
-		prereqs::PreReqChecker,
+		is_integrated_cli, permissions,
+		prereqs::{self, PreReqChecker},
+		runtime_dir, tags,
 	},
 };
 use legacy_args::try_parse_legacy;
-use opentelemetry::sdk::trace::TracerProvider as SdkTracerProvider;
-use opentelemetry::trace::TracerProvider;
+use opentelemetry::sdk::{self, trace::TracerProvider as SdkTracerProvider, Resource};
+use opentelemetry::{trace::TracerProvider, KeyValue};
 
 #[tokio::main]
 async fn main() -> Result<(), std::convert::Infallible> {
@@ -36,18 +39,67 @@ async fn main() -> Result<(), std::convert::Infallible> {
 		});
 
 	let core = parsed.core();
-	let context_paths = LauncherPaths::new(&core.global_options.cli_data_dir).unwrap();
+	if let Some(path) = &core.global_options.product_config {
+		product::install_product_config(
+			product::load_product_config(path).unwrap_or_else(|e| print_and_exit(e)),
+		);
+	}
+	permissions::install_policy(core.global_options.permission_policy());
+	runtime_dir::install_runtime_dir(core.global_options.runtime_dir.clone());
+	let context_paths = LauncherPaths::new(
+		&core.global_options.cli_data_dir,
+		&core.global_options.writable_data_dir,
+	)
+	.unwrap_or_else(|e| print_and_exit(e));
+	let (config, config_error) = match CliConfig::load(&context_paths) {
+		Ok(c) => (c, None),
+		Err(e) => (CliConfig::default(), Some(e)),
+	};
+	let (context_tags, invalid_tags) = tags::load_tags(&config, &core.global_options.tags);
+	tags::install_tags(context_tags);
+	update_service::install_custom_qualities(update_service::load_custom_qualities(&config));
+	update_service::install_download_mirror(update_service::load_download_mirror(
+		&config,
+		core.global_options.download_mirror.as_deref(),
+	));
+	update_service::install_download_connections(
+		core.global_options.download_connections.unwrap_or(1),
+	);
+	prereqs::install_platform_override(core.global_options.platform_override);
+	self_update::install_allow_unsigned_updates(core.global_options.allow_unsigned_updates);
 	let context_args = core.clone();
 
 	// gets a command context without installing the global logger
-	let context_no_logger = || CommandContext {
-		http: reqwest::ClientBuilder::new()
-			.user_agent(get_default_user_agent())
-			.build()
-			.unwrap(),
-		paths: context_paths,
-		log: make_logger(&context_args),
-		args: context_args,
+	let context_no_logger = || {
+		let log = make_logger(&context_args, &invalid_tags);
+		if let Some(e) = &config_error {
+			log.emit(
+				log::Level::Warn,
+				&format!(
+					"Ignoring the config file, servers won't be started until it's fixed: {}",
+					e
+				),
+			);
+		}
+		if let (Some(dir), None) = (
+			context_paths.read_only_root(),
+			&context_args.global_options.writable_data_dir,
+		) {
+			log.emit(
+				log::Level::Warn,
+				&format!("Data directory {} is read-only, keeping state in {} until the machine restarts. Use --writable-data-dir to keep it elsewhere.", dir.display(), context_paths.root().display()),
+			);
+		}
+
+		CommandContext {
+			http: reqwest::ClientBuilder::new()
+				.user_agent(get_default_user_agent())
+				.build()
+				.unwrap(),
+			paths: context_paths,
+			log,
+			args: context_args,
+		}
 	};
 
 	// gets a command context with the global logger installer. Usually what most commands want.
@@ -71,6 +123,11 @@ async fn main() -> Result<(), std::convert::Infallible> {
 		},
 		args::AnyCli::Standalone(args::StandaloneCli { core: c, .. })
 		| args::AnyCli::Integrated(args::IntegratedCli { core: c, .. }) => match c.subcommand {
+			// in a terminal on a tunnel host, open files in the connected editor
+			None if std::env::var_os(EDITOR_HOOK_ENV).is_some() => {
+				let hook = std::env::var_os(EDITOR_HOOK_ENV).unwrap();
+				tunnels::open_in_editor(context!(), hook.into()).await
+			}
 			None => {
 				let context = context!();
 				let ca = context.args.get_base_code_args();
@@ -100,6 +157,10 @@ async fn main() -> Result<(), std::convert::Infallible> {
 
 			Some(args::Commands::Tunnel(tunnel_args)) => match tunnel_args.subcommand {
 				Some(args::TunnelSubcommand::Prune) => tunnels::prune(context!()).await,
+				Some(args::TunnelSubcommand::Servers) => tunnels::servers(context!()).await,
+				Some(args::TunnelSubcommand::Releases(releases_args)) => {
+					tunnels::releases(context!(), releases_args).await
+				}
 				Some(args::TunnelSubcommand::Unregister) => tunnels::unregister(context!()).await,
 				Some(args::TunnelSubcommand::Kill) => tunnels::kill(context!()).await,
 				Some(args::TunnelSubcommand::Restart) => tunnels::restart(context!()).await,
@@ -113,6 +174,18 @@ async fn main() -> Result<(), std::convert::Infallible> {
 				Some(args::TunnelSubcommand::Service(service_args)) => {
 					tunnels::service(context_no_logger(), service_args).await
 				}
+				Some(args::TunnelSubcommand::SelfTest(self_test_args)) => {
+					tunnels::self_test(context!(), self_test_args).await
+				}
+				Some(args::TunnelSubcommand::SupportBundle(bundle_args)) => {
+					tunnels::support_bundle(context!(), bundle_args).await
+				}
+				Some(args::TunnelSubcommand::Askpass(askpass_args)) => {
+					tunnels::askpass(context!(), askpass_args).await
+				}
+				Some(args::TunnelSubcommand::OpenExternal(open_args)) => {
+					tunnels::open_external(context!(), open_args).await
+				}
 				None => tunnels::serve(context_no_logger(), tunnel_args.serve_args).await,
 			},
 		},
@@ -124,17 +197,35 @@ async fn main() -> Result<(), std::convert::Infallible> {
 	}
 }
 
-fn make_logger(core: &args::CliCore) -> log::Logger {
+fn make_logger(core: &args::CliCore, invalid_tags: &[String]) -> log::Logger {
 	let log_level = if core.global_options.verbose {
 		log::Level::Trace
 	} else {
 		core.global_options.log.unwrap_or(log::Level::Info)
 	};
 
-	let tracer = SdkTracerProvider::builder().build().tracer("codecli");
-	let mut log = log::Logger::new(tracer, log_level);
-	if let Some(f) = &core.global_options.log_to_file {
-		log = log.tee(log::FileLogSink::new(log_level, f).expect("expected to make file logger"))
+	let resource = Resource::new(
+		tags::tags()
+			.into_iter()
+			.map(|(key, value)| KeyValue::new(key, value)),
+	);
+	let tracer = SdkTracerProvider::builder()
+		.with_config(sdk::trace::config().with_resource(Resource::default().merge(&resource)))
+		.build()
+		.tracer("codecli");
+	let log = log::Logger::for_target(
+		tracer,
+		log_level,
+		core.global_options.log_target.unwrap_or_default(),
+		core.global_options.log_to_file.as_deref(),
+	)
+	.unwrap_or_else(|e| print_and_exit(format!("error setting up logging: {}", e)));
+
+	for key in invalid_tags {
+		log.emit(
+			log::Level::Warn,
+			&format!("Ignoring tag '{}' in config.json, tag names may only contain letters, digits, and underscores", key),
+		);
 	}
 
 	log
@@ -5,7 +5,20 @@
 
 use std::{fmt, path::PathBuf};
 
-use crate::{constants, log, options, tunnels::code_server::CodeServerArgs};
+use crate::{
+	constants, log, options,
+	tunnels::{
+		auto_update::{parse_maintenance_window, MaintenanceWindow},
+		code_server::CodeServerArgs,
+	},
+	update_service::{parse_download_connections, parse_platform, Platform},
+	util::{
+		permissions::{parse_mode, PermissionPolicy},
+		rate_limit::{parse_method_rate_limit, RateLimit},
+		redact::RedactionRule,
+		tags::parse_tag,
+	},
+};
 use clap::{ArgEnum, Args, Parser, Subcommand};
 use const_format::concatcp;
 
@@ -169,6 +182,17 @@ pub struct StandaloneUpdateArgs {
 	/// Only check for updates, without actually updating the CLI.
 	#[clap(long)]
 	pub check: bool,
+
+	/// Reinstall the version of the CLI that the last update replaced. Only
+	/// that one version is kept, so rolling back again undoes the rollback
+	/// rather than going further back.
+	#[clap(long, conflicts_with_all = &["check", "channel"])]
+	pub rollback: bool,
+
+	/// Quality of builds to update to from now on, including in tunnels.
+	/// Defaults to the quality the CLI was built with.
+	#[clap(long, arg_enum)]
+	pub channel: Option<options::Quality>,
 }
 
 #[derive(Subcommand, Debug, Clone)]
@@ -432,11 +456,23 @@ pub struct GlobalOptions {
 	#[clap(long, env = "VSCODE_CLI_DATA_DIR", global = true)]
 	pub cli_data_dir: Option<String>,
 
+	/// Directory where logs, caches, and credentials are kept if the data dir
+	/// is read-only, such as when running from an immutable image. Defaults
+	/// to a private directory of the user in the runtime dir.
+	#[clap(
+		long,
+		env = "VSCODE_CLI_WRITABLE_DATA_DIR",
+		global = true,
+		value_name = "dir"
+	)]
+	pub writable_data_dir: Option<String>,
+
 	/// Print verbose output (implies --wait).
 	#[clap(long, global = true)]
 	pub verbose: bool,
 
-	/// Log to a file in addition to stdout. Used when running as a service.
+	/// Log to a file in addition to the log target. Used when running as a
+	/// service.
 	#[clap(long, global = true, hide = true)]
 	pub log_to_file: Option<PathBuf>,
 
@@ -444,6 +480,17 @@ pub struct GlobalOptions {
 	#[clap(long, arg_enum, value_name = "level", global = true)]
 	pub log: Option<log::Level>,
 
+	/// Where to write logs. 'auto' uses the systemd journal when running as
+	/// a systemd service, and the terminal otherwise.
+	#[clap(
+		long,
+		arg_enum,
+		env = "VSCODE_CLI_LOG_TARGET",
+		value_name = "target",
+		global = true
+	)]
+	pub log_target: Option<log::LogTarget>,
+
 	/// Disable telemetry for the current command, even if it was previously
 	/// accepted as part of the license prompt or specified in '--telemetry-level'
 	#[clap(long, global = true, hide = true)]
@@ -452,9 +499,105 @@ pub struct GlobalOptions {
 	/// Sets the initial telemetry level
 	#[clap(arg_enum, long, global = true, hide = true)]
 	pub telemetry_level: Option<options::TelemetryLevel>,
+
+	/// Octal mode for files the CLI creates, such as credentials and logs.
+	/// Defaults to 600.
+	#[clap(long, env = "VSCODE_CLI_FILE_MODE", global = true, value_name = "mode", parse(try_from_str = parse_mode))]
+	pub file_mode: Option<u32>,
+
+	/// Octal mode for directories and sockets the CLI creates. Defaults to 700.
+	#[clap(long, env = "VSCODE_CLI_DIR_MODE", global = true, value_name = "mode", parse(try_from_str = parse_mode))]
+	pub dir_mode: Option<u32>,
+
+	/// Directory where sockets and other runtime files are created. Defaults
+	/// to $XDG_RUNTIME_DIR, or the temp dir if it's not set.
+	#[clap(
+		long,
+		env = "VSCODE_CLI_RUNTIME_DIR",
+		global = true,
+		value_name = "dir"
+	)]
+	pub runtime_dir: Option<PathBuf>,
+
+	/// Base URL of a mirror of the update service that VS Code Server and CLI
+	/// builds are downloaded from, such as an internal artifact server for
+	/// air-gapped hosts. Defaults to the download_mirror in the data dir's
+	/// config.json, if any.
+	#[clap(
+		long,
+		visible_alias = "update-endpoint",
+		env = "VSCODE_CLI_DOWNLOAD_MIRROR",
+		global = true,
+		value_name = "url"
+	)]
+	pub download_mirror: Option<String>,
+
+	/// Number of concurrent connections that VS Code Server and CLI builds
+	/// are downloaded over, in chunks, when the server supports it. Defaults
+	/// to 1.
+	#[clap(
+		long,
+		env = "VSCODE_CLI_DOWNLOAD_CONNECTIONS",
+		global = true,
+		value_name = "count",
+		parse(try_from_str = parse_download_connections)
+	)]
+	pub download_connections: Option<usize>,
+
+	/// Platform to use builds for, such as LinuxAlpineX64, instead of the one
+	/// detected. For containers whose userland the detection gets wrong, or
+	/// to run x64 servers under Rosetta or qemu-user with DarwinX64 or
+	/// LinuxX64.
+	#[clap(
+		long,
+		env = "VSCODE_CLI_PLATFORM_OVERRIDE",
+		global = true,
+		value_name = "platform",
+		parse(try_from_str = parse_platform)
+	)]
+	pub platform_override: Option<Platform>,
+
+	/// Product config file, in the format of the editor's product.json, whose
+	/// names and update service are used instead of the built-in ones. For
+	/// driving servers of other builds of the editor, such as VSCodium.
+	#[clap(
+		long,
+		env = "VSCODE_CLI_PRODUCT_CONFIG",
+		global = true,
+		value_name = "file"
+	)]
+	pub product_config: Option<PathBuf>,
+
+	/// Install CLI updates even if their signatures can't be verified, such
+	/// as builds from a mirror that re-signs or doesn't sign them.
+	#[clap(long, env = "VSCODE_CLI_ALLOW_UNSIGNED_UPDATES", global = true)]
+	pub allow_unsigned_updates: bool,
+
+	/// Tag to add to spans, metrics, logs, and support bundles, in the form
+	/// key=value. Can be given multiple times, and is added to tags in the
+	/// data dir's config.json.
+	#[clap(
+		long = "tag",
+		env = "VSCODE_CLI_TAGS",
+		global = true,
+		value_name = "key=value",
+		use_value_delimiter = true,
+		parse(try_from_str = parse_tag)
+	)]
+	pub tags: Vec<(String, String)>,
 }
 
 impl GlobalOptions {
+	/// Gets the policy for files the CLI creates, applying any modes given
+	/// over the defaults.
+	pub fn permission_policy(&self) -> PermissionPolicy {
+		let default = PermissionPolicy::default();
+		PermissionPolicy {
+			file_mode: self.file_mode.unwrap_or(default.file_mode),
+			dir_mode: self.dir_mode.unwrap_or(default.dir_mode),
+		}
+	}
+
 	pub fn add_code_args(&self, target: &mut Vec<String>) {
 		if self.verbose {
 			target.push("--verbose".to_string());
@@ -601,6 +744,90 @@ pub struct TunnelServeArgs {
 	/// If set, the user accepts the server license terms and the server will be started without a user prompt.
 	#[clap(long)]
 	pub accept_server_license_terms: bool,
+
+	/// Allows clients to forward tunnel ports to unix sockets (or named pipes) at this path. If a directory, any socket within it may be used. Can be given multiple times.
+	#[clap(long = "allow-forward-socket", value_name = "PATH")]
+	pub allow_forward_sockets: Vec<PathBuf>,
+
+	/// Allows clients to forward tunnel ports to this host, in addition to loopback addresses. Can be given multiple times.
+	#[clap(long = "allow-forward-host", value_name = "HOST")]
+	pub allow_forward_hosts: Vec<String>,
+
+	/// Secret that clients must give to shut down the tunnel remotely. Remote shutdown is disabled if not set.
+	#[clap(long, env = "VSCODE_CLI_ADMIN_TOKEN", value_name = "token")]
+	pub admin_token: Option<String>,
+
+	/// Limits how often each client can call an RPC method, as method=calls/seconds. Overrides the default limits on update, acquire_cli, serve, and spawn. Can be given multiple times.
+	#[clap(long = "rpc-rate-limit", value_name = "LIMIT", parse(try_from_str = parse_method_rate_limit))]
+	pub rpc_rate_limits: Vec<(String, RateLimit)>,
+
+	/// Serves the read-only health and metrics methods over JSON-RPC on a unix socket (or named pipe) at this path, for collectors that shouldn't have access to the tunnel.
+	#[clap(long, value_name = "PATH")]
+	pub metrics_socket: Option<PathBuf>,
+
+	/// Octal mode for the metrics socket, such as 770 to let a collector in the same group connect. Defaults to the --dir-mode. Unix only.
+	#[clap(long, value_name = "mode", requires = "metrics_socket", parse(try_from_str = parse_mode))]
+	pub metrics_socket_mode: Option<u32>,
+
+	/// Serves the same methods as the tunnel on a unix socket (or named pipe) at this path, for tools on this machine. Clients that start by sending `{` are spoken to in newline-delimited JSON, and others in msgpack.
+	#[clap(long, value_name = "PATH")]
+	pub control_socket: Option<PathBuf>,
+
+	/// Largest message, in bytes, that clients may send. Larger messages are discarded and the client is sent an error. Defaults to 64 MiB.
+	#[clap(long, value_name = "BYTES")]
+	pub max_frame_size: Option<usize>,
+
+	/// Logs RPC calls that take longer than this many milliseconds, with their params. Defaults to 10 seconds.
+	#[clap(long, value_name = "MS")]
+	pub slow_rpc_threshold: Option<u64>,
+
+	/// Closes connections from clients that break the protocol, such as by calling unknown methods or reusing request IDs, after telling them why. For developing clients.
+	#[clap(long, hide = true)]
+	pub strict_protocol: bool,
+
+	/// Runs servers from this install, such as one extracted from a server archive on an air-gapped host, instead of downloading them. Clients can only connect with the install's commit and quality.
+	#[clap(long, value_name = "DIR")]
+	pub use_server_path: Option<PathBuf>,
+
+	/// Allows clients to request servers installed in this directory instead of downloading them. Can be given multiple times.
+	#[clap(long = "allow-server-path", value_name = "DIR")]
+	pub allow_server_paths: Vec<PathBuf>,
+
+	/// Caps the memory each server can use, in megabytes. Uses cgroups v2 on Linux, which needs the CLI to run in a leaf of a delegated cgroup, such as with systemd's Delegate=yes and DelegateSubgroup=, and job objects on Windows.
+	#[clap(long, value_name = "MB")]
+	pub server_memory_limit: Option<u64>,
+
+	/// Caps the CPU each server can use, as a percent of one core, so 200 allows two cores. Uses cgroups v2 on Linux and job objects on Windows.
+	#[clap(long, value_name = "PERCENT")]
+	pub server_cpu_limit: Option<u32>,
+
+	/// Allows clients to connect to a server that's already running on this socket (or named pipe), such as one managed by another supervisor, instead of starting one. Can be given multiple times.
+	#[clap(long = "allow-server-socket", value_name = "PATH")]
+	pub allow_server_sockets: Vec<PathBuf>,
+
+	/// Caches extensions downloaded by servers on this machine, so they can be reused by other servers.
+	#[clap(long)]
+	pub cache_extension_downloads: bool,
+
+	/// Starts servers with a connection token kept in a file in the data dir, instead of one derived from the tunnel. Clients with the admin token can get and rotate it with the getconnectiontoken and rotateconnectiontoken methods.
+	#[clap(long)]
+	pub use_connection_token_file: bool,
+
+	/// Sets up the latest server when the tunnel starts, and whenever a new one is published, so clients don't wait for it to be installed when they connect.
+	#[clap(long)]
+	pub prefetch_server: bool,
+
+	/// Checks for CLI updates this often, installs them, and restarts the tunnel into them.
+	#[clap(long, value_name = "MINUTES")]
+	pub auto_update_interval: Option<u64>,
+
+	/// Checks for CLI updates, hourly unless --auto-update-interval is given, and tells connected clients when there's one.
+	#[clap(long)]
+	pub notify_updates: bool,
+
+	/// Only installs updates found with --auto-update-interval in this window of local time, such as 02:00-05:00.
+	#[clap(long, value_name = "HH:MM-HH:MM", requires = "auto_update_interval", parse(try_from_str = parse_maintenance_window))]
+	pub maintenance_window: Option<MaintenanceWindow>,
 }
 
 #[derive(Args, Debug, Clone)]
@@ -617,6 +844,13 @@ pub enum TunnelSubcommand {
 	/// Delete all servers which are currently not running.
 	Prune,
 
+	/// Lists servers that are installed and running, as JSON.
+	Servers,
+
+	/// Lists recent releases of the CLI or server, as JSON, to choose one to
+	/// pin or roll back to.
+	Releases(TunnelReleasesArgs),
+
 	/// Stops any running tunnel on the system.
 	Kill,
 
@@ -638,6 +872,90 @@ pub enum TunnelSubcommand {
 	/// (Preview) Manages the tunnel when installed as a system service,
 	#[clap(subcommand)]
 	Service(TunnelServiceSubCommands),
+
+	/// Runs the tunnel's control server locally, without connecting to the
+	/// port forwarding service, and checks that each of its features works.
+	SelfTest(TunnelSelfTestArgs),
+
+	/// Collects logs and diagnostics into an archive to attach to issues.
+	/// Personal information and secrets are redacted.
+	SupportBundle(TunnelSupportBundleArgs),
+
+	/// Prompts for a credential in the connected client. Run by git and ssh.
+	#[clap(hide = true)]
+	Askpass(TunnelAskpassArgs),
+
+	/// Opens URLs in the connected client's browser. Run through $BROWSER.
+	#[clap(hide = true)]
+	OpenExternal(TunnelOpenExternalArgs),
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelOpenExternalArgs {
+	/// The URLs to open.
+	pub urls: Vec<String>,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelAskpassArgs {
+	/// The prompt to show.
+	#[clap(allow_hyphen_values = true)]
+	pub prompt: Vec<String>,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelReleasesArgs {
+	/// Lists releases of the server instead of the CLI.
+	#[clap(long)]
+	pub server: bool,
+
+	/// Quality of the releases. Defaults to the quality of the CLI.
+	#[clap(long, arg_enum)]
+	pub quality: Option<options::Quality>,
+
+	/// Most releases to list. Defaults to 10.
+	#[clap(long)]
+	pub limit: Option<usize>,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelSupportBundleArgs {
+	/// Path of the archive to write. Defaults to a timestamped file in the
+	/// current directory.
+	#[clap(long, short)]
+	pub output: Option<PathBuf>,
+
+	/// Kinds of information to redact, separated by commas. Defaults to all.
+	#[clap(long, arg_enum, value_name = "kinds", use_value_delimiter = true)]
+	pub redact: Vec<RedactionRule>,
+
+	/// Disables redaction. Review the archive before sharing it.
+	#[clap(long, conflicts_with = "redact")]
+	pub no_redact: bool,
+
+	/// Additional text to redact, such as project or organization names. May
+	/// be given more than once.
+	#[clap(long, value_name = "text")]
+	pub redact_text: Vec<String>,
+
+	/// Skips running the self-test, which starts a control server in-process.
+	#[clap(long)]
+	pub skip_self_test: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelSelfTestArgs {
+	/// Skips starting a VS Code server, which may need to be downloaded.
+	#[clap(long)]
+	pub skip_serve: bool,
+
+	/// Quality of the VS Code server to start. Defaults to the quality of the CLI.
+	#[clap(long, arg_enum)]
+	pub quality: Option<options::Quality>,
+
+	/// Commit of the VS Code server to start. Defaults to the latest release.
+	#[clap(long)]
+	pub commit_id: Option<String>,
 }
 
 #[derive(Subcommand, Debug, Clone)]
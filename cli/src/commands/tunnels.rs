@@ -4,51 +4,82 @@
  *--------------------------------------------------------------------------------------------*/
 
 use async_trait::async_trait;
+use futures::future::join_all;
+use serde::Serialize;
 use sha2::{Digest, Sha256};
-use std::{str::FromStr, time::Duration};
+use std::{
+	fs::File,
+	io::{Read, Seek, SeekFrom},
+	path::{Path, PathBuf},
+	str::FromStr,
+	sync::Arc,
+	time::Duration,
+};
 use sysinfo::Pid;
-use tokio::sync::mpsc;
+use tokio::{io::AsyncReadExt, sync::mpsc};
 
 use super::{
 	args::{
-		AuthProvider, CliCore, ExistingTunnelArgs, TunnelRenameArgs, TunnelServeArgs,
-		TunnelServiceSubCommands, TunnelUserSubCommands,
+		AuthProvider, CliCore, ExistingTunnelArgs, TunnelAskpassArgs, TunnelOpenExternalArgs,
+		TunnelReleasesArgs, TunnelRenameArgs, TunnelSelfTestArgs, TunnelServeArgs,
+		TunnelServiceSubCommands, TunnelSupportBundleArgs, TunnelUserSubCommands,
 	},
 	CommandContext,
 };
 
 use crate::{
-	async_pipe::socket_stream_split,
+	async_pipe::{get_socket_rw_stream, socket_stream_split},
 	auth::Auth,
-	constants::{APPLICATION_NAME, TUNNEL_CLI_LOCK_NAME, TUNNEL_SERVICE_LOCK_NAME},
+	constants::{
+		APPLICATION_NAME, TUNNEL_CLI_LOCK_NAME, TUNNEL_SERVICE_LOCK_NAME, VSCODE_CLI_QUALITY,
+	},
 	json_rpc::{new_json_rpc, start_json_rpc},
 	log,
+	msgpack_rpc::DEFAULT_MAX_FRAME_SIZE,
 	singleton::connect_as_client,
-	state::LauncherPaths,
+	state::{config::CliConfig, LauncherPaths},
 	tunnels::{
 		code_server::CodeServerArgs,
-		create_service_manager, dev_tunnels, legal,
+		connection_token::ConnectionTokenFile,
+		create_service_manager, dev_tunnels,
+		editor_hook::{parse_goto, EDITOR_HOOK_ENV},
+		extension_cache::ExtensionCacheProxy,
+		legal,
 		paths::get_all_servers,
+		prebuilt_server::{PrebuiltServer, ServerPathPolicy},
 		protocol,
+		resource_limits::ResourceLimits,
 		shutdown_signal::ShutdownRequest,
 		singleton_server::{
 			make_singleton_server, start_singleton_server, BroadcastLogSink, SingletonServerArgs,
 		},
-		Next, ServiceContainer, ServiceManager,
+		support_bundle::SupportBundle,
+		Next, ServiceContainer, ServiceManager, DEFAULT_SLOW_RPC_THRESHOLD,
 	},
 	util::{
 		app_lock::AppMutex,
-		errors::{wrap, AnyError, CodeError},
+		errors::{wrap, AnyError, CodeError, UpdatesNotConfigured},
+		http::{BoxedHttp, ReqwestSimpleHttp},
+		is_integrated_cli,
+		permissions::fix_permissions,
 		prereqs::PreReqChecker,
+		redact::{RedactionRule, Redactor},
+		runtime_dir::{active_mac_policy, runtime_dir, selinux_context},
 	},
 };
 use crate::{
+	options::Quality,
 	singleton::{acquire_singleton, SingletonConnection},
 	tunnels::{
+		auto_update::{AutoUpdateSchedule, DEFAULT_UPDATE_CHECK_INTERVAL},
+		client::ControlClient,
 		dev_tunnels::ActiveTunnel,
+		protocol::{OpenInEditorParams, ServeParams},
+		serve_stream,
 		singleton_client::{start_singleton_client, SingletonClientArgs},
 		SleepInhibitor,
 	},
+	update_service::{Platform, TargetKind, UpdateService, DEFAULT_LISTED_RELEASES},
 };
 
 impl From<AuthProvider> for crate::auth::AuthProvider {
@@ -98,6 +129,7 @@ impl ServiceContainer for TunnelServiceContainer {
 		serve_with_csa(
 			launcher_paths,
 			log,
+			Arc::new(ReqwestSimpleHttp::new()),
 			TunnelServeArgs {
 				random_name: true, // avoid prompting
 				..Default::default()
@@ -127,19 +159,38 @@ pub async fn service(
 			let current_exe =
 				std::env::current_exe().map_err(|e| wrap(e, "could not get current exe"))?;
 
-			manager
-				.register(
-					current_exe,
-					&[
-						"--verbose",
-						"--cli-data-dir",
-						ctx.paths.root().as_os_str().to_string_lossy().as_ref(),
-						"tunnel",
-						"service",
-						"internal-run",
-					],
-				)
-				.await?;
+			let mut run_args = vec![
+				"--verbose".to_string(),
+				"--cli-data-dir".to_string(),
+				ctx.paths
+					.read_only_root()
+					.unwrap_or_else(|| ctx.paths.root())
+					.to_string_lossy()
+					.to_string(),
+			];
+			if ctx.paths.read_only_root().is_some() {
+				run_args.push(format!(
+					"--writable-data-dir={}",
+					ctx.paths.root().display()
+				));
+			}
+			// the service doesn't see the user's environment, so pass modes explicitly
+			if let Some(mode) = ctx.args.global_options.file_mode {
+				run_args.push(format!("--file-mode={:o}", mode));
+			}
+			if let Some(mode) = ctx.args.global_options.dir_mode {
+				run_args.push(format!("--dir-mode={:o}", mode));
+			}
+			if let Some(dir) = &ctx.args.global_options.runtime_dir {
+				run_args.push(format!("--runtime-dir={}", dir.display()));
+			}
+			for (key, value) in &ctx.args.global_options.tags {
+				run_args.push(format!("--tag={}={}", key, value));
+			}
+			run_args.extend(["tunnel", "service", "internal-run"].map(String::from));
+
+			let run_args: Vec<&str> = run_args.iter().map(|s| s.as_str()).collect();
+			manager.register(current_exe, &run_args).await?;
 			ctx.log.result(format!("Service successfully installed! You can use `{} tunnel service log` to monitor it, and `{} tunnel service uninstall` to remove it.", APPLICATION_NAME, APPLICATION_NAME));
 		}
 		TunnelServiceSubCommands::Uninstall => {
@@ -206,7 +257,7 @@ pub async fn unregister(ctx: CommandContext) -> Result<i32, AnyError> {
 	Ok(0)
 }
 
-async fn do_single_rpc_call<
+pub(super) async fn do_single_rpc_call<
 	P: serde::Serialize,
 	R: serde::de::DeserializeOwned + Send + 'static,
 >(
@@ -279,6 +330,82 @@ pub async fn status(ctx: CommandContext) -> Result<i32, AnyError> {
 	Ok(0)
 }
 
+/// Prompts for a credential in the client that spawned this process, and
+/// prints what the user entered. Run by git and ssh through the askpass shim.
+pub async fn askpass(ctx: CommandContext, args: TunnelAskpassArgs) -> Result<i32, AnyError> {
+	let hook = std::env::var_os(EDITOR_HOOK_ENV).ok_or(CodeError::EditorHookUnavailable)?;
+	let client = connect_to_editor_hook(&ctx.log, Path::new(&hook)).await?;
+
+	match client.askpass(args.prompt.join(" ")).await? {
+		Some(response) => {
+			ctx.log.result(response);
+			Ok(0)
+		}
+		None => Ok(1), // cancelled
+	}
+}
+
+/// Opens URLs in the browser of the client that spawned this process. Run by
+/// programs that open `$BROWSER`, such as xdg-open.
+pub async fn open_external(
+	ctx: CommandContext,
+	args: TunnelOpenExternalArgs,
+) -> Result<i32, AnyError> {
+	let hook = std::env::var_os(EDITOR_HOOK_ENV).ok_or(CodeError::EditorHookUnavailable)?;
+	let client = connect_to_editor_hook(&ctx.log, Path::new(&hook)).await?;
+
+	for url in args.urls {
+		client.open_external(url).await?;
+	}
+
+	Ok(0)
+}
+
+async fn connect_to_editor_hook(log: &log::Logger, hook: &Path) -> Result<ControlClient, AnyError> {
+	let stream = get_socket_rw_stream(hook).await?;
+	let (read, write) = socket_stream_split(stream);
+	ControlClient::connect(log.clone(), read, write).await
+}
+
+/// Opens files in the editor of the client that spawned this process. Used
+/// when `code` is run in a terminal on the tunnel host.
+pub async fn open_in_editor(ctx: CommandContext, hook: PathBuf) -> Result<i32, AnyError> {
+	let options = &ctx.args.editor_options;
+	let mut files: Vec<(&str, Option<u32>)> = ctx
+		.args
+		.open_paths
+		.iter()
+		.map(|p| (p.as_str(), None))
+		.collect();
+	if let Some(goto) = &options.goto {
+		files.push(parse_goto(goto));
+	}
+
+	if files.is_empty() {
+		ctx.log.result(format!(
+			"Pass the files to open, such as `{} file.txt`",
+			APPLICATION_NAME
+		));
+		return Ok(1);
+	}
+
+	let cwd = std::env::current_dir().map_err(|e| wrap(e, "could not get current dir"))?;
+	let client = connect_to_editor_hook(&ctx.log, &hook).await?;
+
+	join_all(files.into_iter().map(|(path, line)| {
+		client.open_in_editor(OpenInEditorParams {
+			path: cwd.join(path).to_string_lossy().to_string(),
+			line,
+			wait: options.wait,
+		})
+	}))
+	.await
+	.into_iter()
+	.collect::<Result<Vec<_>, _>>()?;
+
+	Ok(0)
+}
+
 /// Removes unused servers.
 pub async fn prune(ctx: CommandContext) -> Result<i32, AnyError> {
 	get_all_servers(&ctx.paths)
@@ -297,10 +424,398 @@ pub async fn prune(ctx: CommandContext) -> Result<i32, AnyError> {
 	Ok(0)
 }
 
+pub async fn servers(ctx: CommandContext) -> Result<i32, AnyError> {
+	let servers = ctx.paths.server_inventory.list().await;
+	ctx.log.result(serde_json::to_string(&servers).unwrap());
+	Ok(0)
+}
+
+pub async fn releases(ctx: CommandContext, args: TunnelReleasesArgs) -> Result<i32, AnyError> {
+	let quality = match args.quality {
+		Some(q) => q,
+		None => VSCODE_CLI_QUALITY
+			.ok_or_else(|| UpdatesNotConfigured("no configured quality".to_string()))
+			.and_then(|q| Quality::try_from(q).map_err(UpdatesNotConfigured))?,
+	};
+	let (target, platform) = if args.server {
+		(TargetKind::Server, PreReqChecker::new().verify().await?)
+	} else {
+		let platform = Platform::env_default()
+			.ok_or_else(|| CodeError::UnsupportedPlatform(std::env::consts::ARCH.to_string()))?;
+		(TargetKind::Cli, platform)
+	};
+
+	let update_service = UpdateService::new(
+		ctx.log.clone(),
+		Arc::new(ReqwestSimpleHttp::with_client(ctx.http.clone())),
+	);
+	let releases: Vec<_> = update_service
+		.list_releases(
+			platform,
+			target,
+			quality,
+			None,
+			args.limit.unwrap_or(DEFAULT_LISTED_RELEASES),
+		)
+		.await?
+		.into_iter()
+		.map(protocol::ReleaseInfo::from)
+		.collect();
+
+	ctx.log.result(serde_json::to_string(&releases).unwrap());
+	Ok(0)
+}
+
+/// How long the self-test waits to start a server, which may need to be
+/// downloaded first.
+const SELF_TEST_SERVE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Outcome of one of the self-test's checks.
+#[derive(Serialize)]
+struct SelfTestCheck {
+	name: &'static str,
+	/// One of "pass", "fail", or "skip".
+	status: &'static str,
+	detail: String,
+}
+
+/// Runs the control server over an in-memory connection and calls each of
+/// its subsystems, reporting which ones work.
+pub async fn self_test(ctx: CommandContext, args: TunnelSelfTestArgs) -> Result<i32, AnyError> {
+	let checks = run_self_test(&ctx, args).await?;
+	for check in &checks {
+		ctx.log.result(format!(
+			"[{}] {}: {}",
+			check.status, check.name, check.detail
+		));
+	}
+
+	Ok(if checks.iter().any(|c| c.status == "fail") {
+		1
+	} else {
+		0
+	})
+}
+
+async fn run_self_test(
+	ctx: &CommandContext,
+	args: TunnelSelfTestArgs,
+) -> Result<Vec<SelfTestCheck>, AnyError> {
+	let platform = PreReqChecker::new().verify().await?;
+	// the server won't start with a config it can't read, report why up front
+	CliConfig::load(&ctx.paths)?;
+	let (client_stream, server_stream) = tokio::io::duplex(64 * 1024);
+	let (server_read, server_write) = tokio::io::split(server_stream);
+	let server = tokio::spawn(serve_stream(
+		ctx.log.clone(),
+		server_read,
+		server_write,
+		ctx.paths.clone(),
+		(&ctx.args).into(),
+		platform,
+	));
+
+	let mut checks = vec![];
+	let mut report = |name: &'static str, result: Result<String, AnyError>| {
+		let (status, detail) = match result {
+			Ok(detail) => ("pass", detail),
+			Err(e) => ("fail", e.to_string()),
+		};
+		checks.push(SelfTestCheck {
+			name,
+			status,
+			detail,
+		});
+	};
+	let skip = |name: &'static str, detail: &str| SelfTestCheck {
+		name,
+		status: "skip",
+		detail: detail.to_string(),
+	};
+
+	let (client_read, client_write) = tokio::io::split(client_stream);
+	let client = match ControlClient::connect(ctx.log.clone(), client_read, client_write).await {
+		Ok(c) => c,
+		Err(e) => {
+			report("control", Err(e));
+			server.abort();
+			return Ok(checks);
+		}
+	};
+
+	report(
+		"control",
+		client.ping().await.map(|_| {
+			format!(
+				"connected to CLI {} (protocol {})",
+				client.version().version,
+				client.version().protocol_version
+			)
+		}),
+	);
+	report(
+		"sysinfo",
+		client.sysinfo().await.map(|i| {
+			format!(
+				"{}, {} CPUs, {} TLS",
+				i.os_version.or(i.os_name).unwrap_or_default(),
+				i.cpu_count,
+				i.tls_backend
+			)
+		}),
+	);
+	report("forward", self_test_forward(&client).await);
+	report("spawn", self_test_spawn(&client).await);
+
+	let quality = args
+		.quality
+		.or_else(|| VSCODE_CLI_QUALITY.and_then(|q| Quality::try_from(q).ok()));
+	match quality {
+		_ if args.skip_serve => checks.push(skip("serve", "--skip-serve was given")),
+		None => checks.push(skip(
+			"serve",
+			"no quality configured, pass --quality to test it",
+		)),
+		Some(quality) => {
+			let serve = client.serve(ServeParams {
+				socket_id: 0,
+				commit_id: args.commit_id,
+				version: None,
+				quality,
+				custom_quality: None,
+				extensions: vec![],
+				connection_token: None,
+				use_local_download: false,
+				compress: false,
+				server_path: None,
+				server_archive: None,
+				instance: None,
+				server_socket: None,
+				restart_on_crash: false,
+				telemetry_level: None,
+				server_args: vec![],
+			});
+			let result = match tokio::time::timeout(SELF_TEST_SERVE_TIMEOUT, serve).await {
+				Ok(r) => r.map(|_| format!("started the {} server", quality)),
+				Err(_) => Err(wrap("timed out", "error starting server").into()),
+			};
+			report("serve", result);
+		}
+	}
+
+	drop(client);
+	server.abort();
+
+	Ok(checks)
+}
+
+async fn self_test_forward(client: &ControlClient) -> Result<String, AnyError> {
+	let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0))
+		.await
+		.map_err(|e| wrap(e, "error listening on a port to forward"))?;
+	let port = listener
+		.local_addr()
+		.map_err(|e| wrap(e, "error getting listener address"))?
+		.port();
+
+	let uri = client
+		.forward(protocol::ForwardParams { port, target: None })
+		.await?;
+	client.unforward(port).await?;
+	Ok(format!("forwarded port {} to {}", port, uri))
+}
+
+async fn self_test_spawn(client: &ControlClient) -> Result<String, AnyError> {
+	let exe = std::env::current_exe().map_err(|e| wrap(e, "error getting current exe"))?;
+	let mut process = client
+		.spawn(protocol::SpawnParams {
+			command: exe.to_string_lossy().to_string(),
+			args: vec!["--version".to_string()],
+			env: Default::default(),
+			pty: None,
+			cwd: None,
+			shell: false,
+			kill_on_disconnect: true,
+			usage_interval_ms: None,
+		})
+		.await?;
+
+	let mut output = String::new();
+	process
+		.stdout
+		.read_to_string(&mut output)
+		.await
+		.map_err(|e| wrap(e, "error reading process output"))?;
+	let result = process.result.wait().await?;
+	if result.exit_code != 0 {
+		return Err(wrap(result.message, "spawned process failed").into());
+	}
+
+	Ok(format!(
+		"ran {} --version: {}",
+		exe.display(),
+		output.lines().next().unwrap_or_default()
+	))
+}
+
+/// Only the end of each log is included in support bundles.
+const SUPPORT_BUNDLE_LOG_BYTES: usize = 1024 * 1024;
+/// Maximum number of error lines gathered from the logs.
+const SUPPORT_BUNDLE_ERROR_LINES: usize = 200;
+
+/// Host checks included in support bundles.
+#[derive(Serialize)]
+struct DoctorReport {
+	mac_policy: Option<String>,
+	runtime_dir: String,
+	runtime_dir_context: Option<String>,
+	tunnel_quota: Option<protocol::singleton::TunnelQuota>,
+	self_test: Vec<SelfTestCheck>,
+}
+
+/// Gathers logs, the running tunnel's status, and self-test results into a
+/// redacted archive, with a manifest of what was included.
+pub async fn support_bundle(
+	ctx: CommandContext,
+	args: TunnelSupportBundleArgs,
+) -> Result<i32, AnyError> {
+	let rules = match args.redact {
+		_ if args.no_redact => vec![],
+		r if r.is_empty() => RedactionRule::all(),
+		r => r,
+	};
+	let mut bundle = SupportBundle::new(Redactor::new(&rules, &args.redact_text));
+
+	let mut logs = vec![(
+		"logs/tunnel-service.log".to_string(),
+		ctx.paths.service_log_file(),
+	)];
+	for server in get_all_servers(&ctx.paths) {
+		let paths = server.server_paths(&ctx.paths);
+		let name = paths.server_dir.file_name().unwrap_or_default();
+		logs.push((
+			format!("logs/servers/{}.log", name.to_string_lossy()),
+			paths.logfile,
+		));
+	}
+
+	let mut errors = vec![];
+	for (name, path) in logs {
+		match read_log_tail(&path) {
+			Ok(text) => {
+				errors.extend(
+					text.lines()
+						.filter(|l| is_error_line(l))
+						.map(|l| format!("{}: {}", name, l)),
+				);
+				bundle.add_text(name, &text);
+			}
+			Err(e) => bundle.omit(name, e),
+		}
+	}
+
+	let skip = errors.len().saturating_sub(SUPPORT_BUNDLE_ERROR_LINES);
+	bundle.add_text("errors.log", &errors[skip..].join("\n"));
+
+	let tunnel_quota = match do_single_rpc_call::<_, protocol::singleton::Status>(
+		&ctx,
+		protocol::singleton::METHOD_STATUS,
+		protocol::EmptyObject {},
+	)
+	.await
+	{
+		Ok(status) => {
+			bundle.add_json("status.json", &status);
+			status.quota
+		}
+		Err(e) => {
+			bundle.omit("status.json", e);
+			None
+		}
+	};
+
+	let self_test = if args.skip_self_test {
+		vec![]
+	} else {
+		let args = TunnelSelfTestArgs {
+			skip_serve: true,
+			quality: None,
+			commit_id: None,
+		};
+		match run_self_test(&ctx, args).await {
+			Ok(checks) => checks,
+			Err(e) => vec![SelfTestCheck {
+				name: "prereqs",
+				status: "fail",
+				detail: e.to_string(),
+			}],
+		}
+	};
+
+	let runtime_dir = runtime_dir();
+	bundle.add_json(
+		"doctor.json",
+		&DoctorReport {
+			mac_policy: active_mac_policy().map(|p| format!("{:?}", p)),
+			runtime_dir_context: selinux_context(&runtime_dir),
+			runtime_dir: runtime_dir.display().to_string(),
+			tunnel_quota,
+			self_test,
+		},
+	);
+
+	let output = args.output.unwrap_or_else(|| {
+		PathBuf::from(format!(
+			"{}-support-{}.tar.gz",
+			APPLICATION_NAME,
+			chrono::Local::now().format("%Y%m%d-%H%M%S")
+		))
+	});
+	bundle.write(&output)?;
+
+	ctx.log.result(format!(
+		"Wrote support bundle to {}. Review its contents before sharing it.",
+		output.display()
+	));
+
+	Ok(0)
+}
+
+/// Reads the end of the log, starting at a line boundary.
+fn read_log_tail(path: &Path) -> std::io::Result<String> {
+	let mut file = File::open(path)?;
+	let offset = file
+		.metadata()?
+		.len()
+		.saturating_sub(SUPPORT_BUNDLE_LOG_BYTES as u64);
+	file.seek(SeekFrom::Start(offset))?;
+
+	let mut tail = vec![];
+	file.take(SUPPORT_BUNDLE_LOG_BYTES as u64)
+		.read_to_end(&mut tail)?;
+	let start = match offset {
+		0 => 0,
+		_ => tail.iter().position(|b| *b == b'\n').map_or(0, |i| i + 1),
+	};
+	Ok(String::from_utf8_lossy(&tail[start..]).to_string())
+}
+
+/// Gets whether the log line, formatted like `[timestamp] level message`,
+/// is an error.
+fn is_error_line(line: &str) -> bool {
+	match line.split_once("] ") {
+		Some((_, rest)) => rest.starts_with("error ") || rest.starts_with("critical "),
+		None => false,
+	}
+}
+
 /// Starts the gateway server.
 pub async fn serve(ctx: CommandContext, gateway_args: TunnelServeArgs) -> Result<i32, AnyError> {
 	let CommandContext {
-		log, paths, args, ..
+		log,
+		paths,
+		args,
+		http,
 	} = ctx;
 
 	let no_sleep = match gateway_args.no_sleep.then(SleepInhibitor::new) {
@@ -317,7 +832,8 @@ pub async fn serve(ctx: CommandContext, gateway_args: TunnelServeArgs) -> Result
 	legal::require_consent(&paths, gateway_args.accept_server_license_terms)?;
 
 	let csa = (&args).into();
-	let result = serve_with_csa(paths, log, gateway_args, csa).await;
+	let http = Arc::new(ReqwestSimpleHttp::with_client(http));
+	let result = serve_with_csa(paths, log, http, gateway_args, csa).await;
 	drop(no_sleep);
 
 	result
@@ -333,6 +849,7 @@ fn get_connection_token(tunnel: &ActiveTunnel) -> String {
 async fn serve_with_csa(
 	paths: LauncherPaths,
 	mut log: log::Logger,
+	http: BoxedHttp,
 	gateway_args: TunnelServeArgs,
 	mut csa: CodeServerArgs,
 ) -> Result<i32, AnyError> {
@@ -340,6 +857,30 @@ async fn serve_with_csa(
 	log = log.tee(log_broadcast.clone());
 	log::install_global_logger(log.clone()); // re-install so that library logs are captured
 
+	// data created by older versions, or under a looser umask, may be readable by others
+	match fix_permissions(paths.root()) {
+		Ok(fixed) => {
+			for path in fixed {
+				warning!(log, "Restricted permissions on {}", path.display());
+			}
+		}
+		Err(e) => warning!(log, "Error checking permissions of the data dir: {}", e),
+	}
+
+	let runtime_dir = runtime_dir();
+	match active_mac_policy() {
+		Some(policy) => info!(
+			log,
+			"{:?} is enforcing, runtime files are in {} (context: {})",
+			policy,
+			runtime_dir.display(),
+			selinux_context(&runtime_dir)
+				.as_deref()
+				.unwrap_or("unknown")
+		),
+		None => debug!(log, "Runtime files are in {}", runtime_dir.display()),
+	}
+
 	let shutdown = match gateway_args
 		.parent_process_id
 		.and_then(|p| Pid::from_str(&p).ok())
@@ -383,9 +924,92 @@ async fn serve_with_csa(
 
 	debug!(log, "starting as new singleton");
 
-	let mut server =
-		make_singleton_server(log_broadcast.clone(), log.clone(), server, shutdown.clone());
+	let _extension_cache = if gateway_args.cache_extension_downloads {
+		let proxy = ExtensionCacheProxy::start(log.clone(), paths.extension_cache_dir()).await?;
+		let (key, value) = proxy.server_env();
+		csa = csa.to_builder().server_env(key, value).build()?;
+		Some(proxy)
+	} else {
+		None
+	};
+	if gateway_args.use_connection_token_file {
+		let file = ConnectionTokenFile::new(&paths);
+		file.get_or_create()
+			.map_err(|e| wrap(e, "error creating connection token file"))?;
+		csa = csa
+			.to_builder()
+			.connection_token_file(file.path().to_string_lossy())
+			.build()?;
+	}
+	let memory_bytes = match gateway_args.server_memory_limit {
+		Some(mb) => Some(mb.checked_mul(1024 * 1024).ok_or_else(|| {
+			CodeError::InvalidServerArgs(format!("a memory limit of {} MB is too large", mb))
+		})?),
+		None => None,
+	};
+	csa = csa
+		.to_builder()
+		.resource_limits(ResourceLimits {
+			memory_bytes,
+			cpu_percent: gateway_args.server_cpu_limit,
+		})
+		.build()?;
+
+	let mut server = make_singleton_server(
+		log_broadcast.clone(),
+		log.clone(),
+		&paths,
+		server,
+		shutdown.clone(),
+	);
 	let platform = spanf!(log, log.span("prereq"), PreReqChecker::new().verify())?;
+	let server_path_policy = ServerPathPolicy {
+		default: gateway_args
+			.use_server_path
+			.as_deref()
+			.map(|p| PrebuiltServer::load(p, platform))
+			.transpose()?,
+		allowed: gateway_args.allow_server_paths.clone(),
+		sockets: gateway_args.allow_server_sockets.clone(),
+	};
+	let prefetch_quality = match VSCODE_CLI_QUALITY.and_then(|q| Quality::try_from(q).ok()) {
+		_ if !gateway_args.prefetch_server => None,
+		_ if server_path_policy.default.is_some() => {
+			warning!(
+				log,
+				"Servers aren't prefetched when --use-server-path is given"
+			);
+			None
+		}
+		None => {
+			warning!(log, "Servers can't be prefetched, this CLI has no quality");
+			None
+		}
+		q => q,
+	};
+	let auto_update = match (
+		gateway_args.auto_update_interval,
+		gateway_args.notify_updates,
+	) {
+		(None, false) => None,
+		_ if matches!(is_integrated_cli(), Ok(true)) => {
+			warning!(
+				log,
+				"The CLI isn't checked for updates when it's installed with the editor"
+			);
+			None
+		}
+		(Some(minutes), _) => Some(AutoUpdateSchedule {
+			interval: Duration::from_secs(minutes.max(1).saturating_mul(60)),
+			window: gateway_args.maintenance_window,
+			install: true,
+		}),
+		(None, true) => Some(AutoUpdateSchedule {
+			interval: DEFAULT_UPDATE_CHECK_INTERVAL,
+			window: None,
+			install: false,
+		}),
+	};
 	let _lock = TUNNEL_CLI_LOCK_NAME.map(AppMutex::new);
 
 	let auth = Auth::new(&paths, log.clone());
@@ -398,7 +1022,23 @@ async fn serve_with_csa(
 				.await
 		}?;
 
-		csa.connection_token = Some(get_connection_token(&tunnel));
+		if csa.connection_token_file.is_none() {
+			csa = csa
+				.to_builder()
+				.connection_token(get_connection_token(&tunnel))
+				.build()?;
+		}
+
+		let limits = CliConfig::load(&paths)
+			.map(|c| c.tunnel_limits)
+			.unwrap_or_default();
+		let quota = match dt.get_quota(&limits).await {
+			Ok(q) => Some(q),
+			Err(e) => {
+				debug!(log, "Error getting the tunnel quota: {}", e);
+				None
+			}
+		};
 
 		let mut r = start_singleton_server(SingletonServerArgs {
 			log: log.clone(),
@@ -406,9 +1046,29 @@ async fn serve_with_csa(
 			paths: &paths,
 			code_server_args: &csa,
 			platform,
+			http: http.clone(),
 			log_broadcast: &log_broadcast,
 			shutdown: shutdown.clone(),
 			server: &mut server,
+			forward_socket_allowlist: &gateway_args.allow_forward_sockets,
+			forward_host_allowlist: &gateway_args.allow_forward_hosts,
+			admin_token: gateway_args.admin_token.as_deref(),
+			rate_limits: &gateway_args.rpc_rate_limits,
+			metrics_socket: gateway_args.metrics_socket.as_deref(),
+			metrics_socket_mode: gateway_args.metrics_socket_mode,
+			control_socket: gateway_args.control_socket.as_deref(),
+			server_path_policy: &server_path_policy,
+			max_frame_size: gateway_args
+				.max_frame_size
+				.unwrap_or(DEFAULT_MAX_FRAME_SIZE),
+			slow_rpc_threshold: gateway_args
+				.slow_rpc_threshold
+				.map(Duration::from_millis)
+				.unwrap_or(DEFAULT_SLOW_RPC_THRESHOLD),
+			strict_protocol: gateway_args.strict_protocol,
+			prefetch_quality,
+			auto_update,
+			quota,
 		})
 		.await?;
 		r.tunnel.close().await.ok();
@@ -3,27 +3,51 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
-use std::sync::Arc;
+use std::{path::PathBuf, sync::Arc};
 
 use indicatif::ProgressBar;
 
 use crate::{
 	constants::PRODUCT_NAME_LONG,
-	self_update::SelfUpdate,
-	update_service::UpdateService,
+	self_update::{can_install_bare_binaries, set_update_channel, SelfUpdate},
+	tunnels::protocol::singleton,
+	update_service::{Release, TargetKind, UpdateService},
 	util::{errors::AnyError, http::ReqwestSimpleHttp, input::ProgressBarReporter},
 };
 
-use super::{args::StandaloneUpdateArgs, CommandContext};
+use super::{args::StandaloneUpdateArgs, tunnels::do_single_rpc_call, CommandContext};
 
 pub async fn update(ctx: CommandContext, args: StandaloneUpdateArgs) -> Result<i32, AnyError> {
 	let update_service = UpdateService::new(
 		ctx.log.clone(),
 		Arc::new(ReqwestSimpleHttp::with_client(ctx.http.clone())),
 	);
-	let update_service = SelfUpdate::new(&update_service)?;
+	if let Some(channel) = args.channel {
+		set_update_channel(&ctx.paths, channel)?;
+		info!(
+			ctx.log,
+			"Now updating to {} builds",
+			channel.get_capitalized_name()
+		);
+	}
+
+	let update_service = SelfUpdate::new(&update_service, &ctx.paths)?;
 
-	let current_version = update_service.get_current_release().await?;
+	if args.rollback {
+		update_service.rollback()?;
+		ctx.log.result(format!(
+			"Rolled {} back to its previous version",
+			PRODUCT_NAME_LONG
+		));
+		return Ok(0);
+	}
+
+	// a running tunnel caches lookups and downloads, so use it if there is one
+	let from_tunnel = get_release_from_tunnel(&ctx, &update_service).await;
+	let current_version = match &from_tunnel {
+		Some(r) => r.clone(),
+		None => update_service.get_current_release().await?,
+	};
 	if update_service.is_up_to_date_with(&current_version) {
 		ctx.log.result(format!(
 			"{} is already to to date ({})",
@@ -38,13 +62,92 @@ pub async fn update(ctx: CommandContext, args: StandaloneUpdateArgs) -> Result<i
 		return Ok(0);
 	}
 
-	let pb = ProgressBar::new(1);
-	pb.set_message("Downloading...");
-	update_service
-		.do_update(&current_version, ProgressBarReporter::from(pb))
-		.await?;
+	// the tunnel keeps only the binary, so its signature must be embedded
+	let downloaded = match from_tunnel {
+		Some(_) if can_install_bare_binaries() => {
+			download_from_tunnel(&ctx, &current_version).await
+		}
+		_ => None,
+	};
+
+	let installed = match downloaded {
+		Some(binary) => match update_service.install_bare_binary(&binary).await {
+			Ok(()) => true,
+			Err(e) => {
+				warning!(
+					ctx.log,
+					"Error installing the update downloaded through the tunnel, downloading it directly: {}",
+					e
+				);
+				false
+			}
+		},
+		None => false,
+	};
+	if !installed {
+		let pb = ProgressBar::new(1);
+		pb.set_message("Downloading...");
+		update_service
+			.do_update(&current_version, ProgressBarReporter::from(pb))
+			.await?;
+	}
 	ctx.log
 		.result(format!("Successfully updated to {}", current_version));
 
 	Ok(0)
 }
+
+async fn get_release_from_tunnel(
+	ctx: &CommandContext,
+	update_service: &SelfUpdate<'_>,
+) -> Option<Release> {
+	let quality = update_service.quality();
+	let r = do_single_rpc_call::<_, singleton::CliRelease>(
+		ctx,
+		singleton::METHOD_LATEST_CLI,
+		singleton::LatestCliParams { quality },
+	)
+	.await;
+
+	match r {
+		Ok(r) => Some(Release {
+			name: r.name,
+			commit: r.commit,
+			platform: update_service.platform(),
+			quality,
+			target: TargetKind::Cli,
+			custom_quality: None,
+		}),
+		Err(e) => {
+			debug!(
+				ctx.log,
+				"Not checking for updates through the tunnel: {}", e
+			);
+			None
+		}
+	}
+}
+
+async fn download_from_tunnel(ctx: &CommandContext, release: &Release) -> Option<PathBuf> {
+	let r = do_single_rpc_call::<_, singleton::DownloadCliResult>(
+		ctx,
+		singleton::METHOD_DOWNLOAD_CLI,
+		singleton::DownloadCliParams {
+			quality: release.quality,
+			commit: release.commit.clone(),
+		},
+	)
+	.await;
+
+	match r {
+		Ok(r) => Some(r.path),
+		Err(e) => {
+			warning!(
+				ctx.log,
+				"Error downloading the update through the tunnel, downloading it directly: {}",
+				e
+			);
+			None
+		}
+	}
+}
@@ -18,6 +18,9 @@ pub const CONTROL_PORT: u16 = 31545;
 ///  2 - Addition of `serve.compressed` property to control whether servermsg's
 ///      are compressed bidirectionally.
 ///  3 - The server's connection token is set to a SHA256 hash of the tunnel ID
+///
+/// Optional features are negotiated with the capabilities sent alongside the
+/// version, rather than by incrementing it.
 pub const PROTOCOL_VERSION: u32 = 3;
 
 /// Prefix for the tunnel tag that includes the version.
@@ -33,6 +36,20 @@ pub const DOCUMENTATION_URL: Option<&'static str> = option_env!("VSCODE_CLI_DOCU
 pub const VSCODE_CLI_COMMIT: Option<&'static str> = option_env!("VSCODE_CLI_COMMIT");
 pub const VSCODE_CLI_UPDATE_ENDPOINT: Option<&'static str> =
 	option_env!("VSCODE_CLI_UPDATE_ENDPOINT");
+/// Base64 ed25519 public key that detached signatures of CLI updates are
+/// checked with, on platforms without embedded signatures.
+pub const VSCODE_CLI_UPDATE_SIGNING_KEY: Option<&'static str> =
+	option_env!("VSCODE_CLI_UPDATE_SIGNING_KEY");
+/// Subject of the certificate CLI updates are Authenticode signed with on
+/// Windows, such as "CN=Microsoft Corporation, O=Microsoft Corporation, ...".
+pub const VSCODE_CLI_WIN32_SIGNER_SUBJECT: Option<&'static str> =
+	option_env!("VSCODE_CLI_WIN32_SIGNER_SUBJECT");
+/// Comma-separated SHA-1 thumbprints of the certificates CLI updates may be
+/// Authenticode signed with on Windows.
+pub const VSCODE_CLI_WIN32_SIGNER_THUMBPRINTS: Option<&'static str> =
+	option_env!("VSCODE_CLI_WIN32_SIGNER_THUMBPRINTS");
+/// Team ID of the Developer ID CLI updates are signed with on macOS.
+pub const VSCODE_CLI_MACOS_TEAM_ID: Option<&'static str> = option_env!("VSCODE_CLI_MACOS_TEAM_ID");
 
 /// Windows lock name for the running tunnel service. Used by the setup script
 /// to detect a tunnel process. See #179265.
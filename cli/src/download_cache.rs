@@ -4,8 +4,8 @@
  *--------------------------------------------------------------------------------------------*/
 
 use std::{
-	fs::create_dir_all,
 	path::{Path, PathBuf},
+	time::{Duration, SystemTime},
 };
 
 use futures::Future;
@@ -13,11 +13,17 @@ use tokio::fs::remove_dir_all;
 
 use crate::{
 	state::PersistedState,
-	util::errors::{wrap, AnyError, WrappedError},
+	util::{
+		errors::{wrap, AnyError, WrappedError},
+		permissions::create_private_dir_all,
+	},
 };
 
 const KEEP_LRU: usize = 5;
 const STAGING_SUFFIX: &str = ".staging";
+/// Staging folders untouched for this long are assumed to be left over from
+/// an interrupted download.
+const STALE_STAGING_AGE: Duration = Duration::from_secs(60 * 60);
 
 #[derive(Clone)]
 pub struct DownloadCache {
@@ -85,8 +91,13 @@ impl DownloadCache {
 		let temp_dir = self.path.join(format!("{}{}", name, STAGING_SUFFIX));
 		let _ = remove_dir_all(&temp_dir).await; // cleanup any existing
 
-		create_dir_all(&temp_dir).map_err(|e| wrap(e, "error creating server directory"))?;
-		do_create(temp_dir.clone()).await?;
+		create_private_dir_all(&temp_dir)
+			.map_err(|e| wrap(e, "error creating server directory"))?;
+		if let Err(e) = do_create(temp_dir.clone()).await {
+			// free the space now, such as if the disk filled while unpacking
+			let _ = remove_dir_all(&temp_dir).await;
+			return Err(e);
+		}
 
 		let _ = self.touch(name.to_string());
 		std::fs::rename(&temp_dir, &target_dir)
@@ -95,6 +106,82 @@ impl DownloadCache {
 		Ok(target_dir)
 	}
 
+	/// Removes entries beyond the ones kept in the LRU, whose removal may have
+	/// failed earlier, and staging folders from interrupted downloads. Entries
+	/// that are `in_use` are kept. Returns the removed folders and how many
+	/// bytes each used.
+	pub fn trim(
+		&self,
+		in_use: impl Fn(&Path) -> bool,
+	) -> Result<Vec<(PathBuf, u64)>, WrappedError> {
+		let mut removed = vec![];
+		let mut remove = |path: PathBuf| {
+			let size = dir_size(&path);
+			if std::fs::remove_dir_all(&path).is_ok() {
+				removed.push((path, size));
+				true
+			} else {
+				false
+			}
+		};
+
+		self.state.update(|l| {
+			let evicted: Vec<String> = l.iter().skip(KEEP_LRU).rev().cloned().collect();
+			for name in evicted {
+				let f = self.path.join(&name);
+				if f.exists() && (in_use(&f) || !remove(f)) {
+					continue;
+				}
+				l.retain(|n| n != &name);
+			}
+		})?;
+
+		for path in self.stale_staging_dirs() {
+			remove(path);
+		}
+
+		Ok(removed)
+	}
+
+	/// Gets the folders that `trim` would remove, and how many bytes each
+	/// uses, without removing them.
+	pub fn trim_preview(&self, in_use: impl Fn(&Path) -> bool) -> Vec<(PathBuf, u64)> {
+		self.state
+			.load()
+			.iter()
+			.skip(KEEP_LRU)
+			.rev()
+			.map(|name| self.path.join(name))
+			.filter(|p| p.exists() && !in_use(p))
+			.chain(self.stale_staging_dirs())
+			.map(|p| {
+				let size = dir_size(&p);
+				(p, size)
+			})
+			.collect()
+	}
+
+	fn stale_staging_dirs(&self) -> Vec<PathBuf> {
+		let entries = match std::fs::read_dir(&self.path) {
+			Ok(e) => e,
+			Err(_) => return vec![], // nothing was cached yet
+		};
+
+		entries
+			.flatten()
+			.filter(|entry| {
+				entry
+					.metadata()
+					.and_then(|m| m.modified())
+					.ok()
+					.and_then(|m| SystemTime::now().duration_since(m).ok())
+					.map_or(false, |age| age > STALE_STAGING_AGE)
+			})
+			.map(|entry| entry.path())
+			.filter(|path| path.is_dir() && path.to_string_lossy().ends_with(STAGING_SUFFIX))
+			.collect()
+	}
+
 	fn touch(&self, name: String) -> Result<(), AnyError> {
 		self.state.update(|l| {
 			if let Some(index) = l.iter().position(|s| s == &name) {
@@ -117,3 +204,92 @@ impl DownloadCache {
 		Ok(())
 	}
 }
+
+/// Gets the total size of files in the directory, best-effort.
+fn dir_size(path: &Path) -> u64 {
+	let entries = match std::fs::read_dir(path) {
+		Ok(e) => e,
+		Err(_) => return 0,
+	};
+
+	entries
+		.flatten()
+		.map(|e| match e.file_type() {
+			Ok(t) if t.is_dir() => dir_size(&e.path()),
+			Ok(t) if t.is_file() => e.metadata().map_or(0, |m| m.len()),
+			_ => 0,
+		})
+		.sum()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn test_create_cleans_up_failures() {
+		let dir = tempfile::tempdir().unwrap();
+		let cache = DownloadCache::new(dir.path().to_path_buf());
+
+		let staging = dir.path().join(format!("entry{}", STAGING_SUFFIX));
+		let result = cache
+			.create("entry", |target_dir| async move {
+				std::fs::write(target_dir.join("partial"), "hello").unwrap();
+				Err(AnyError::from(wrap(
+					std::io::Error::new(std::io::ErrorKind::Other, "disk full"),
+					"error unpacking",
+				)))
+			})
+			.await;
+		assert!(result.is_err());
+		assert!(!staging.exists());
+		assert!(cache.exists("entry").is_none());
+
+		let path = cache
+			.create("entry", |target_dir| async move {
+				std::fs::write(target_dir.join("file"), "hello").unwrap();
+				Ok(())
+			})
+			.await
+			.unwrap();
+		assert!(path.join("file").exists());
+	}
+
+	#[test]
+	fn test_trim() {
+		let dir = tempfile::tempdir().unwrap();
+		let cache = DownloadCache::new(dir.path().to_path_buf());
+
+		let names: Vec<String> = (0..KEEP_LRU + 2).map(|i| format!("entry-{}", i)).collect();
+		for name in &names {
+			std::fs::create_dir_all(dir.path().join(name)).unwrap();
+			std::fs::write(dir.path().join(name).join("file"), "hello").unwrap();
+		}
+		// as if earlier evictions failed
+		cache.state.save(names.clone()).unwrap();
+
+		let in_progress = dir.path().join(format!("new{}", STAGING_SUFFIX));
+		std::fs::create_dir_all(&in_progress).unwrap();
+
+		let expected = vec![
+			(dir.path().join(&names[KEEP_LRU + 1]), 5),
+			(dir.path().join(&names[KEEP_LRU]), 5),
+		];
+		assert_eq!(cache.trim_preview(|_| false), expected);
+		assert!(dir.path().join(&names[KEEP_LRU]).exists());
+
+		// entries in use are kept
+		let used = dir.path().join(&names[KEEP_LRU]);
+		let in_use = |p: &Path| p == used;
+		assert_eq!(cache.trim_preview(in_use), expected[..1].to_vec());
+		let removed = cache.trim(in_use).unwrap();
+		assert_eq!(removed, expected[..1].to_vec());
+		assert!(used.exists());
+		assert_eq!(cache.state.load(), names[..KEEP_LRU + 1].to_vec());
+
+		let removed = cache.trim(|_| false).unwrap();
+		assert_eq!(removed, expected[1..].to_vec());
+		assert_eq!(cache.state.load(), names[..KEEP_LRU].to_vec());
+		assert!(in_progress.exists());
+	}
+}
@@ -3,20 +3,28 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
+use futures::StreamExt;
+use serde::{
+	de::{MapAccess, SeqAccess, Visitor},
+	Deserialize, Deserializer,
+};
+use serde_json::Value;
 use tokio::{
 	io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
 	pin,
 	sync::mpsc,
 };
+use tokio_util::codec::FramedRead;
 
 use crate::{
+	msgpack_rpc::MsgPackCodec,
 	rpc::{self, MaybeSync, Serialization},
 	util::{
 		errors::InvalidRpcDataError,
 		sync::{Barrier, Receivable},
 	},
 };
-use std::io;
+use std::{fmt, io};
 
 #[derive(Clone)]
 pub struct JsonRpcSerializer {}
@@ -104,3 +112,167 @@ pub async fn start_json_rpc<C: Send + Sync + 'static, S: Clone>(
 		}
 	}
 }
+
+/// Carries messages between a client that speaks newline-delimited JSON and
+/// an RPC server that speaks msgpack, reading length-prefixed messages and
+/// writing them back-to-back. Returns when either side closes, or with an
+/// error if a message can't be converted.
+pub async fn bridge_json_to_msgpack(
+	json_read: impl AsyncRead + Unpin,
+	mut json_write: impl AsyncWrite + Unpin,
+	msgpack_read: impl AsyncRead + Unpin,
+	mut msgpack_write: impl AsyncWrite + Unpin,
+) -> io::Result<()> {
+	let mut json_read = BufReader::new(json_read);
+	let mut msgpack_read = FramedRead::new(msgpack_read, MsgPackCodec {});
+	let mut line = Vec::new();
+
+	loop {
+		tokio::select! {
+			n = json_read.read_until(b'\n', &mut line) => {
+				if n? == 0 {
+					return Ok(());
+				}
+
+				if !line.iter().all(u8::is_ascii_whitespace) {
+					let msg = json_to_msgpack(&line)?;
+					msgpack_write.write_all(&(msg.len() as u32).to_be_bytes()).await?;
+					msgpack_write.write_all(&msg).await?;
+				}
+				line.clear();
+			},
+			m = msgpack_read.next() => match m {
+				Some(m) => {
+					let mut json = msgpack_to_json(&m?)?;
+					json.push(b'\n');
+					json_write.write_all(&json).await?;
+					json_write.flush().await?;
+				}
+				None => return Ok(()),
+			},
+		}
+	}
+}
+
+fn json_to_msgpack(json: &[u8]) -> io::Result<Vec<u8>> {
+	let value: Value =
+		serde_json::from_slice(json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+	rmp_serde::to_vec_named(&value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn msgpack_to_json(msg: &[u8]) -> io::Result<Vec<u8>> {
+	let value: MsgPackValue =
+		rmp_serde::from_slice(msg).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+	Ok(serde_json::to_vec(&value.0).unwrap())
+}
+
+/// Any value read from msgpack. Unlike `serde_json::Value`, this accepts
+/// binary data, which is kept as an array of bytes. That's what `serde_bytes`
+/// fields are serialized to in JSON, and they can be read back from it.
+pub(crate) struct MsgPackValue(pub Value);
+
+impl<'de> Deserialize<'de> for MsgPackValue {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		deserializer
+			.deserialize_any(MsgPackValueVisitor)
+			.map(MsgPackValue)
+	}
+}
+
+struct MsgPackValueVisitor;
+
+impl<'de> Visitor<'de> for MsgPackValueVisitor {
+	type Value = Value;
+
+	fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str("any msgpack value")
+	}
+
+	fn visit_bool<E>(self, v: bool) -> Result<Value, E> {
+		Ok(Value::Bool(v))
+	}
+
+	fn visit_i64<E>(self, v: i64) -> Result<Value, E> {
+		Ok(Value::from(v))
+	}
+
+	fn visit_u64<E>(self, v: u64) -> Result<Value, E> {
+		Ok(Value::from(v))
+	}
+
+	fn visit_f64<E>(self, v: f64) -> Result<Value, E> {
+		Ok(Value::from(v))
+	}
+
+	fn visit_str<E>(self, v: &str) -> Result<Value, E> {
+		Ok(Value::from(v))
+	}
+
+	fn visit_bytes<E>(self, v: &[u8]) -> Result<Value, E> {
+		Ok(Value::Array(v.iter().map(|b| Value::from(*b)).collect()))
+	}
+
+	fn visit_none<E>(self) -> Result<Value, E> {
+		Ok(Value::Null)
+	}
+
+	fn visit_unit<E>(self) -> Result<Value, E> {
+		Ok(Value::Null)
+	}
+
+	fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+		MsgPackValue::deserialize(deserializer).map(|v| v.0)
+	}
+
+	fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+		let mut values = Vec::new();
+		while let Some(MsgPackValue(v)) = seq.next_element()? {
+			values.push(v);
+		}
+		Ok(Value::Array(values))
+	}
+
+	fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+		let mut values = serde_json::Map::new();
+		while let Some((MsgPackValue(k), MsgPackValue(v))) = map.next_entry()? {
+			let key = match k {
+				Value::String(s) => s,
+				other => other.to_string(),
+			};
+			values.insert(key, v);
+		}
+		Ok(Value::Object(values))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde::Serialize;
+
+	#[derive(Serialize, Deserialize, Debug, PartialEq)]
+	struct Message {
+		id: Option<u32>,
+		method: String,
+		#[serde(with = "serde_bytes")]
+		body: Vec<u8>,
+	}
+
+	#[test]
+	fn test_transcode_round_trip() {
+		let message = Message {
+			id: Some(1),
+			method: "servermsg".to_string(),
+			body: vec![0, 1, 255],
+		};
+
+		let json = msgpack_to_json(&rmp_serde::to_vec_named(&message).unwrap()).unwrap();
+		assert_eq!(
+			String::from_utf8(json.clone()).unwrap(),
+			r#"{"body":[0,1,255],"id":1,"method":"servermsg"}"#
+		);
+
+		let msgpack = json_to_msgpack(&json).unwrap();
+		assert_eq!(rmp_serde::from_slice::<Message>(&msgpack).unwrap(), message);
+	}
+}
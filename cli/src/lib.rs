@@ -12,6 +12,7 @@ pub mod log;
 pub mod commands;
 pub mod desktop;
 pub mod options;
+pub mod product;
 pub mod self_update;
 pub mod state;
 pub mod tunnels;
@@ -16,7 +16,7 @@ use std::{
 };
 use std::{path::Path, sync::Arc};
 
-use crate::constants::COLORS_ENABLED;
+use crate::constants::{APPLICATION_NAME, COLORS_ENABLED};
 
 static INSTANCE_COUNTER: AtomicU32 = AtomicU32::new(0);
 
@@ -26,21 +26,20 @@ pub fn next_counter() -> u32 {
 }
 
 // Log level
-#[derive(clap::ArgEnum, PartialEq, Eq, PartialOrd, Clone, Copy, Debug, Serialize, Deserialize)]
-#[derive(Default)]
+#[derive(
+	clap::ArgEnum, PartialEq, Eq, PartialOrd, Clone, Copy, Debug, Serialize, Deserialize, Default,
+)]
 pub enum Level {
 	Trace = 0,
 	Debug,
 	#[default]
- Info,
+	Info,
 	Warn,
 	Error,
 	Critical,
 	Off,
 }
 
-
-
 impl fmt::Display for Level {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		match self {
@@ -89,6 +88,18 @@ impl Level {
 	}
 }
 
+/// Where logs are written.
+#[derive(clap::ArgEnum, PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum LogTarget {
+	/// The journal when running as a systemd service, otherwise the terminal.
+	#[default]
+	Auto,
+	Stderr,
+	Journald,
+	/// Only the file given in `--log-to-file`.
+	File,
+}
+
 pub fn new_tunnel_prefix() -> String {
 	format!("[tunnel.{}]", next_counter())
 }
@@ -138,6 +149,8 @@ impl Clone for Box<dyn LogSink> {
 #[derive(Clone)]
 pub struct StdioLogSink {
 	level: Level,
+	/// Whether logs go to stderr. Results are always written to stdout.
+	stderr: bool,
 }
 
 impl LogSink for StdioLogSink {
@@ -146,7 +159,11 @@ impl LogSink for StdioLogSink {
 			return;
 		}
 
-		emit(level, prefix, message);
+		if self.stderr {
+			eprint!("{}", format(level, prefix, message, false));
+		} else {
+			emit(level, prefix, message);
+		}
 	}
 
 	fn write_result(&self, message: &str) {
@@ -163,6 +180,7 @@ pub struct FileLogSink {
 impl FileLogSink {
 	pub fn new(level: Level, path: &Path) -> std::io::Result<Self> {
 		let file = std::fs::File::create(path)?;
+		crate::util::permissions::restrict_file(path)?;
 		Ok(Self {
 			level,
 			file: Arc::new(std::sync::Mutex::new(file)),
@@ -185,6 +203,159 @@ impl LogSink for FileLogSink {
 	fn write_result(&self, _message: &str) {}
 }
 
+#[cfg(target_os = "linux")]
+fn journald_sink(level: Level) -> std::io::Result<Box<dyn LogSink>> {
+	Ok(Box::new(JournaldLogSink::new(level)?))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn journald_sink(_level: Level) -> std::io::Result<Box<dyn LogSink>> {
+	Err(std::io::Error::new(
+		std::io::ErrorKind::Unsupported,
+		"journald is only available on Linux",
+	))
+}
+
+/// Sink that writes to the systemd journal using its native protocol, which
+/// keeps the priority of each message and adds fields that can be filtered
+/// on, such as `journalctl VSCODE_CLI_CONNECTION_ID=3`.
+#[cfg(target_os = "linux")]
+#[derive(Clone)]
+pub struct JournaldLogSink {
+	level: Level,
+	socket: Arc<std::os::unix::net::UnixDatagram>,
+	/// Name of the systemd unit the CLI runs in, if any.
+	unit: Option<String>,
+	/// Fields for the configured tags, like `VSCODE_CLI_TAG_TEAM`.
+	tags: Arc<Vec<(String, String)>>,
+}
+
+#[cfg(target_os = "linux")]
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+#[cfg(target_os = "linux")]
+impl JournaldLogSink {
+	pub fn new(level: Level) -> std::io::Result<Self> {
+		let socket = std::os::unix::net::UnixDatagram::unbound()?;
+		socket.connect(JOURNALD_SOCKET)?;
+		Ok(Self {
+			level,
+			socket: Arc::new(socket),
+			unit: systemd_unit(),
+			tags: Arc::new(
+				crate::util::tags::tags()
+					.into_iter()
+					.map(|(k, v)| (format!("VSCODE_CLI_TAG_{}", k.to_ascii_uppercase()), v))
+					.collect(),
+			),
+		})
+	}
+
+	fn serialize(&self, level: Level, prefix: &str, message: &str) -> Vec<u8> {
+		let mut buf = vec![];
+		journald_field(&mut buf, "MESSAGE", &format!("{}{}", prefix, message));
+		journald_field(&mut buf, "PRIORITY", syslog_priority(level));
+		journald_field(&mut buf, "SYSLOG_IDENTIFIER", APPLICATION_NAME);
+		if let Some(unit) = &self.unit {
+			journald_field(&mut buf, "VSCODE_CLI_UNIT", unit);
+		}
+		if let Some(id) = connection_id(prefix) {
+			journald_field(&mut buf, "VSCODE_CLI_CONNECTION_ID", id);
+		}
+		for (key, value) in self.tags.iter() {
+			journald_field(&mut buf, key, value);
+		}
+		buf
+	}
+}
+
+#[cfg(target_os = "linux")]
+impl LogSink for JournaldLogSink {
+	fn write_log(&self, level: Level, prefix: &str, message: &str) {
+		if level < self.level {
+			return;
+		}
+
+		let entry = self.serialize(level, prefix, message);
+		// entries too large for a datagram, or a journal that went away
+		if self.socket.send(&entry).is_err() {
+			eprint!("{}", format(level, prefix, message, false));
+		}
+	}
+
+	fn write_result(&self, message: &str) {
+		println!("{}", message);
+	}
+}
+
+/// Appends a field in the journal's export format. Values containing
+/// newlines are length-prefixed.
+#[cfg(target_os = "linux")]
+fn journald_field(buf: &mut Vec<u8>, key: &str, value: &str) {
+	buf.extend_from_slice(key.as_bytes());
+	if value.contains('\n') {
+		buf.push(b'\n');
+		buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+	} else {
+		buf.push(b'=');
+	}
+	buf.extend_from_slice(value.as_bytes());
+	buf.push(b'\n');
+}
+
+#[cfg(target_os = "linux")]
+fn syslog_priority(level: Level) -> &'static str {
+	match level {
+		Level::Critical => "2",
+		Level::Error => "3",
+		Level::Warn => "4",
+		Level::Info => "6",
+		Level::Trace | Level::Debug | Level::Off => "7",
+	}
+}
+
+/// Gets the ID of the control connection from a prefix like `[rpc.3] `.
+#[cfg(target_os = "linux")]
+fn connection_id(prefix: &str) -> Option<&str> {
+	let start = prefix.find("[rpc.")? + "[rpc.".len();
+	let len = prefix[start..].find(']')?;
+	Some(&prefix[start..start + len])
+}
+
+/// Gets whether stderr is connected to the journal, as it is for systemd
+/// services. systemd sets `JOURNAL_STREAM` to the device and inode of the
+/// stream, which is compared since child processes may inherit the variable.
+#[cfg(target_os = "linux")]
+fn is_journald_stream() -> bool {
+	let expected = match std::env::var("JOURNAL_STREAM") {
+		Ok(s) => s,
+		Err(_) => return false,
+	};
+
+	let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+	if unsafe { libc::fstat(libc::STDERR_FILENO, &mut stat) } != 0 {
+		return false;
+	}
+
+	expected == format!("{}:{}", stat.st_dev, stat.st_ino)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_journald_stream() -> bool {
+	false
+}
+
+/// Gets the systemd service the process runs in from its cgroup.
+#[cfg(target_os = "linux")]
+fn systemd_unit() -> Option<String> {
+	let cgroup = std::fs::read_to_string("/proc/self/cgroup").ok()?;
+	cgroup
+		.lines()
+		.flat_map(|l| l.rsplit('/'))
+		.find(|segment| segment.ends_with(".service"))
+		.map(|s| s.to_string())
+}
+
 impl Logger {
 	pub fn test() -> Self {
 		Self {
@@ -197,11 +368,55 @@ impl Logger {
 	pub fn new(tracer: Tracer, level: Level) -> Self {
 		Self {
 			tracer: Arc::new(tracer),
-			sink: vec![Box::new(StdioLogSink { level })],
+			sink: vec![Box::new(StdioLogSink {
+				level,
+				stderr: false,
+			})],
 			prefix: None,
 		}
 	}
 
+	/// Creates a logger that writes to the target, and also to the file if
+	/// one is given.
+	pub fn for_target(
+		tracer: Tracer,
+		level: Level,
+		target: LogTarget,
+		file: Option<&Path>,
+	) -> std::io::Result<Self> {
+		let stdio = |level, stderr| Box::new(StdioLogSink { level, stderr });
+		let primary: Box<dyn LogSink> = match target {
+			LogTarget::Journald => journald_sink(level)?,
+			LogTarget::Auto if is_journald_stream() => match journald_sink(level) {
+				Ok(s) => s,
+				Err(_) => stdio(level, false),
+			},
+			LogTarget::Auto => stdio(level, false),
+			LogTarget::Stderr => stdio(level, true),
+			// still print results, such as the output of `tunnel status`
+			LogTarget::File => stdio(Level::Off, false),
+		};
+
+		let mut sink = vec![primary];
+
+		match file {
+			Some(f) => sink.push(Box::new(FileLogSink::new(level, f)?)),
+			None if target == LogTarget::File => {
+				return Err(std::io::Error::new(
+					std::io::ErrorKind::InvalidInput,
+					"--log-target file requires --log-to-file",
+				))
+			}
+			None => {}
+		}
+
+		Ok(Self {
+			tracer: Arc::new(tracer),
+			sink,
+			prefix: None,
+		})
+	}
+
 	pub fn span(&self, name: &str) -> SpanBuilder {
 		self.tracer.span_builder(format!("serverlauncher/{}", name))
 	}
@@ -453,3 +668,25 @@ macro_rules! spanf {
 		t
 	}};
 }
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_connection_id() {
+		assert_eq!(connection_id("[tunnel.0] [rpc.12] "), Some("12"));
+		assert_eq!(connection_id("[codeserver.3] "), None);
+	}
+
+	#[test]
+	fn test_journald_field() {
+		let mut buf = vec![];
+		journald_field(&mut buf, "PRIORITY", "6");
+		journald_field(&mut buf, "MESSAGE", "a\nb");
+		assert_eq!(
+			buf,
+			b"PRIORITY=6\nMESSAGE\n\x03\0\0\0\0\0\0\0a\nb\n".to_vec()
+		);
+	}
+}
@@ -3,6 +3,8 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
+use bytes::Buf;
+use serde::Deserialize;
 use tokio::{
 	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
 	pin,
@@ -13,7 +15,7 @@ use tokio_util::codec::Decoder;
 use crate::{
 	rpc::{self, MaybeSync, Serialization},
 	util::{
-		errors::{AnyError, InvalidRpcDataError},
+		errors::{AnyError, CodeError, InvalidRpcDataError},
 		sync::{Barrier, Receivable},
 	},
 };
@@ -48,7 +50,7 @@ pub async fn start_msgpack_rpc<C: Send + Sync + 'static, S: Clone>(
 ) -> io::Result<Option<S>> {
 	let (write_tx, mut write_rx) = mpsc::channel::<Vec<u8>>(8);
 	let mut read = BufReader::new(read);
-	let mut decoder = U32PrefixedCodec {};
+	let mut decoder = U32PrefixedCodec::new(DEFAULT_MAX_FRAME_SIZE);
 	let mut decoder_buf = bytes::BytesMut::new();
 
 	let shutdown_fut = shutdown_rx.wait();
@@ -60,6 +62,15 @@ pub async fn start_msgpack_rpc<C: Send + Sync + 'static, S: Clone>(
 				r?;
 
 				while let Some(frame) = decoder.decode(&mut decoder_buf)? {
+					let frame = match frame {
+						Frame::Message(m) => m,
+						Frame::TooLarge(len) => {
+							let err = CodeError::FrameTooLarge(len, decoder.max_frame_size());
+							let _ = write_tx.send(dispatcher.protocol_error(&err.into())).await;
+							continue;
+						}
+					};
+
 					match dispatcher.dispatch(&frame) {
 						MaybeSync::Sync(Some(v)) => {
 							let _ = write_tx.send(v).await;
@@ -100,17 +111,56 @@ pub async fn start_msgpack_rpc<C: Send + Sync + 'static, S: Clone>(
 	}
 }
 
+/// Largest message read by default, which leaves room for the largest file
+/// reads and writes.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
 /// Reader that reads length-prefixed msgpack messages in a cancellation-safe
-/// way using Tokio's codecs.
-pub struct U32PrefixedCodec {}
+/// way using Tokio's codecs. Messages larger than the maximum aren't
+/// buffered; their bytes are discarded as they arrive.
+pub struct U32PrefixedCodec {
+	max_frame_size: usize,
+	/// Bytes remaining in a message that's being discarded.
+	discarding: usize,
+}
+
+/// Frame read by the `U32PrefixedCodec`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Frame {
+	Message(Vec<u8>),
+	/// A message of the given length that was larger than the maximum.
+	TooLarge(usize),
+}
 
 const U32_SIZE: usize = 4;
 
+impl U32PrefixedCodec {
+	pub fn new(max_frame_size: usize) -> Self {
+		Self {
+			max_frame_size,
+			discarding: 0,
+		}
+	}
+
+	pub fn max_frame_size(&self) -> usize {
+		self.max_frame_size
+	}
+}
+
 impl tokio_util::codec::Decoder for U32PrefixedCodec {
-	type Item = Vec<u8>;
+	type Item = Frame;
 	type Error = io::Error;
 
 	fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+		if self.discarding > 0 {
+			let n = self.discarding.min(src.len());
+			src.advance(n);
+			self.discarding -= n;
+			if self.discarding > 0 {
+				return Ok(None);
+			}
+		}
+
 		if src.len() < 4 {
 			src.reserve(U32_SIZE - src.len());
 			return Ok(None);
@@ -118,14 +168,91 @@ impl tokio_util::codec::Decoder for U32PrefixedCodec {
 
 		let mut be_bytes = [0; U32_SIZE];
 		be_bytes.copy_from_slice(&src[..U32_SIZE]);
-		let required_len = U32_SIZE + (u32::from_be_bytes(be_bytes) as usize);
+		let len = u32::from_be_bytes(be_bytes) as usize;
+		if len > self.max_frame_size {
+			src.advance(U32_SIZE);
+			self.discarding = len;
+			return Ok(Some(Frame::TooLarge(len)));
+		}
+
+		let required_len = U32_SIZE + len;
 		if src.len() < required_len {
 			src.reserve(required_len - src.len());
 			return Ok(None);
 		}
 
-		let msg = src[U32_SIZE..].to_vec();
-		src.resize(0, 0);
-		Ok(Some(msg))
+		let msg = src[U32_SIZE..required_len].to_vec();
+		src.advance(required_len);
+		Ok(Some(Frame::Message(msg)))
+	}
+}
+
+/// Reader that reads unprefixed, back-to-back msgpack messages, such as those
+/// sent by the control server, in a cancellation-safe way. Each item is the
+/// raw bytes of a single message.
+pub struct MsgPackCodec {}
+
+impl tokio_util::codec::Decoder for MsgPackCodec {
+	type Item = Vec<u8>;
+	type Error = io::Error;
+
+	fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+		let mut cursor = io::Cursor::new(src.as_ref());
+		match serde::de::IgnoredAny::deserialize(&mut rmp_serde::Deserializer::new(&mut cursor)) {
+			Ok(_) => {
+				let len = cursor.position() as usize;
+				Ok(Some(src.split_to(len).to_vec()))
+			}
+			Err(rmp_serde::decode::Error::InvalidMarkerRead(e))
+			| Err(rmp_serde::decode::Error::InvalidDataRead(e))
+				if e.kind() == io::ErrorKind::UnexpectedEof =>
+			{
+				Ok(None)
+			}
+			Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_u32_prefixed_codec_discards_large_frames() {
+		let mut buf = bytes::BytesMut::new();
+		buf.extend_from_slice(&5u32.to_be_bytes());
+		buf.extend_from_slice(b"too");
+
+		let mut codec = U32PrefixedCodec::new(4);
+		assert_eq!(codec.decode(&mut buf).unwrap(), Some(Frame::TooLarge(5)));
+		assert_eq!(codec.decode(&mut buf).unwrap(), None);
+		assert!(buf.is_empty());
+
+		buf.extend_from_slice(b"ng");
+		buf.extend_from_slice(&2u32.to_be_bytes());
+		buf.extend_from_slice(b"ok");
+		assert_eq!(
+			codec.decode(&mut buf).unwrap(),
+			Some(Frame::Message(b"ok".to_vec()))
+		);
+	}
+
+	#[test]
+	fn test_msgpack_codec_splits_messages() {
+		let a = rmp_serde::to_vec_named(&("hello", 1)).unwrap();
+		let b = rmp_serde::to_vec_named(&vec![0u8; 300]).unwrap();
+
+		let mut buf = bytes::BytesMut::new();
+		buf.extend_from_slice(&a);
+		buf.extend_from_slice(&b[..b.len() - 1]);
+
+		let mut codec = MsgPackCodec {};
+		assert_eq!(codec.decode(&mut buf).unwrap(), Some(a));
+		assert_eq!(codec.decode(&mut buf).unwrap(), None);
+
+		buf.extend_from_slice(&b[b.len() - 1..]);
+		assert_eq!(codec.decode(&mut buf).unwrap(), Some(b));
+		assert!(buf.is_empty());
 	}
 }
@@ -7,7 +7,10 @@ use std::fmt;
 
 use serde::{Deserialize, Serialize};
 
-use crate::constants::{APPLICATION_NAME_MAP, PRODUCT_NAME_LONG_MAP, SERVER_NAME_MAP};
+use crate::{
+	constants::{APPLICATION_NAME_MAP, PRODUCT_NAME_LONG_MAP, SERVER_NAME_MAP},
+	product::product_config,
+};
 
 #[derive(clap::ArgEnum, Copy, Clone, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Quality {
@@ -40,6 +43,10 @@ impl Quality {
 
 	/// Product long name
 	pub fn get_long_name(&self) -> &'static str {
+		if let Some(name) = product_config().and_then(|p| p.name_long.as_deref()) {
+			return name;
+		}
+
 		PRODUCT_NAME_LONG_MAP
 			.as_ref()
 			.and_then(|m| m.get(self))
@@ -49,6 +56,10 @@ impl Quality {
 
 	/// Product application name
 	pub fn get_application_name(&self) -> &'static str {
+		if let Some(name) = product_config().and_then(|p| p.application_name.as_deref()) {
+			return name;
+		}
+
 		APPLICATION_NAME_MAP
 			.as_ref()
 			.and_then(|m| m.get(self))
@@ -58,10 +69,14 @@ impl Quality {
 
 	/// Server application name
 	pub fn server_entrypoint(&self) -> String {
-		let mut server_name = SERVER_NAME_MAP
-			.as_ref()
-			.and_then(|m| m.get(self))
-			.map(|s| s.as_str())
+		let mut server_name = product_config()
+			.and_then(|p| p.server_application_name.as_deref())
+			.or_else(|| {
+				SERVER_NAME_MAP
+					.as_ref()
+					.and_then(|m| m.get(self))
+					.map(|s| s.as_str())
+			})
 			.unwrap_or("code-server-oss")
 			.to_string();
 
@@ -95,7 +110,11 @@ impl TryFrom<&str> for Quality {
 	}
 }
 
-#[derive(clap::ArgEnum, Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// Levels are ordered from most to least private.
+#[derive(
+	clap::ArgEnum, Copy, Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
 pub enum TelemetryLevel {
 	Off,
 	Crash,
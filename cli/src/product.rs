@@ -0,0 +1,89 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Product identity that's used instead of the one the CLI was built with,
+//! from a product config file given with `--product-config`, so the CLI can
+//! drive servers of other builds of the editor, such as VSCodium. The file
+//! has the same fields as the editor's product.json, so that can be used.
+
+use std::{path::Path, sync::RwLock};
+
+use lazy_static::lazy_static;
+use serde::Deserialize;
+
+use crate::util::errors::{wrap, WrappedError};
+
+#[derive(Deserialize, Default, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ProductConfig {
+	/// Long name of the product, such as "VSCodium".
+	pub name_long: Option<String>,
+	/// Name of the product's application, such as "codium".
+	pub application_name: Option<String>,
+	/// Name of the server's entrypoint in its `bin` folder, without an
+	/// extension, such as "codium-server".
+	pub server_application_name: Option<String>,
+	/// Update service that builds are downloaded from.
+	pub update_url: Option<String>,
+}
+
+lazy_static! {
+	static ref PRODUCT_CONFIG: RwLock<Option<&'static ProductConfig>> = RwLock::new(None);
+}
+
+/// Reads a product config file.
+pub fn load_product_config(path: &Path) -> Result<ProductConfig, WrappedError> {
+	let contents = std::fs::read_to_string(path)
+		.map_err(|e| wrap(e, format!("error reading {}", path.display())))?;
+	serde_json::from_str(&contents)
+		.map_err(|e| wrap(e, format!("error parsing {}", path.display())))
+}
+
+/// Sets the product config that's used instead of the built-in identity.
+/// Should be called at startup.
+pub fn install_product_config(config: ProductConfig) {
+	// kept for the life of the process, so names can be borrowed statically
+	*PRODUCT_CONFIG.write().unwrap() = Some(Box::leak(Box::new(config)));
+}
+
+/// Gets the installed product config, if any.
+pub fn product_config() -> Option<&'static ProductConfig> {
+	*PRODUCT_CONFIG.read().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_load_product_config() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("product.json");
+		std::fs::write(
+			&path,
+			r#"{
+				"nameShort": "VSCodium",
+				"nameLong": "VSCodium",
+				"applicationName": "codium",
+				"serverApplicationName": "codium-server",
+				"updateUrl": "https://example.com/update"
+			}"#,
+		)
+		.unwrap();
+
+		assert_eq!(
+			load_product_config(&path).unwrap(),
+			ProductConfig {
+				name_long: Some("VSCodium".to_string()),
+				application_name: Some("codium".to_string()),
+				server_application_name: Some("codium-server".to_string()),
+				update_url: Some("https://example.com/update".to_string()),
+			}
+		);
+
+		std::fs::write(&path, "not json").unwrap();
+		assert!(load_product_config(&path).is_err());
+	}
+}
@@ -4,23 +4,29 @@
  *--------------------------------------------------------------------------------------------*/
 
 use std::{
-	collections::HashMap,
+	collections::{HashMap, HashSet},
 	future,
 	sync::{
 		atomic::{AtomicU32, Ordering},
 		Arc, Mutex,
 	},
+	time::{Duration, Instant},
 };
 
-use crate::log;
+use crate::{json_rpc::MsgPackValue, log};
 use futures::{future::BoxFuture, Future, FutureExt};
+use opentelemetry::{trace::TraceContextExt, KeyValue};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use tokio::{
-	io::{AsyncReadExt, AsyncWriteExt, DuplexStream, WriteHalf},
-	sync::{mpsc, oneshot},
+	io::{AsyncReadExt, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf},
+	sync::{mpsc, oneshot, watch},
 };
+use tokio_util::sync::CancellationToken;
 
-use crate::util::errors::AnyError;
+use crate::util::{
+	errors::{AnyError, CodeError, ErrorDetails},
+	rate_limit::{RateLimit, TokenBucket},
+};
 
 pub type SyncMethod = Arc<dyn Send + Sync + Fn(Option<u32>, &[u8]) -> Option<Vec<u8>>>;
 pub type AsyncMethod =
@@ -31,10 +37,16 @@ pub type Duplex = Arc<
 		+ Fn(Option<u32>, &[u8]) -> (Option<StreamDto>, BoxFuture<'static, Option<Vec<u8>>>),
 >;
 
+/// Wraps calls to registered methods, see `RpcMethodBuilder::layer`.
+pub type Layer = Arc<dyn Send + Sync + Fn(&CallInfo, Next) -> Result<MaybeSync, AnyError>>;
+
 pub enum Method {
 	Sync(SyncMethod),
 	Async(AsyncMethod),
 	Duplex(Duplex),
+	/// Registered with `register_chunked`. Its chunks are sent by the
+	/// transport, like the streams of duplex methods.
+	Chunked(Duplex),
 }
 
 /// Serialization is given to the RpcBuilder and defines how data gets serialized
@@ -50,6 +62,8 @@ pub struct RpcBuilder<S> {
 	serializer: Arc<S>,
 	methods: HashMap<&'static str, Method>,
 	calls: Arc<Mutex<HashMap<u32, DispatchMethod>>>,
+	stream_waiters: Arc<Mutex<HashMap<u32, StreamWaiter>>>,
+	chunk_handlers: ChunkHandlers,
 }
 
 impl<S: Serialization> RpcBuilder<S> {
@@ -59,6 +73,8 @@ impl<S: Serialization> RpcBuilder<S> {
 			serializer: Arc::new(serializer),
 			methods: HashMap::new(),
 			calls: Arc::new(std::sync::Mutex::new(HashMap::new())),
+			stream_waiters: Arc::new(std::sync::Mutex::new(HashMap::new())),
+			chunk_handlers: Arc::new(std::sync::Mutex::new(HashMap::new())),
 		}
 	}
 
@@ -68,6 +84,8 @@ impl<S: Serialization> RpcBuilder<S> {
 		RpcCaller {
 			serializer: self.serializer.clone(),
 			calls: self.calls.clone(),
+			stream_waiters: self.stream_waiters.clone(),
+			chunk_handlers: self.chunk_handlers.clone(),
 			sender,
 		}
 	}
@@ -79,6 +97,12 @@ impl<S: Serialization> RpcBuilder<S> {
 			serializer: self.serializer,
 			methods: self.methods,
 			calls: self.calls,
+			stream_waiters: self.stream_waiters,
+			chunk_handlers: self.chunk_handlers,
+			layers: vec![],
+			rate_limits: HashMap::new(),
+			slow_call_threshold: None,
+			violations: None,
 		}
 	}
 }
@@ -88,15 +112,75 @@ pub struct RpcMethodBuilder<S, C> {
 	serializer: Arc<S>,
 	methods: HashMap<&'static str, Method>,
 	calls: Arc<Mutex<HashMap<u32, DispatchMethod>>>,
+	stream_waiters: Arc<Mutex<HashMap<u32, StreamWaiter>>>,
+	chunk_handlers: ChunkHandlers,
+	layers: Vec<Layer>,
+	rate_limits: HashMap<&'static str, Mutex<TokenBucket>>,
+	slow_call_threshold: Option<Duration>,
+	violations: Option<Violations>,
 }
 
-#[derive(Serialize)]
+/// Receives the ways the remote broke the protocol, see `RpcMethodBuilder::strict`.
+type Violations = mpsc::UnboundedSender<CodeError>;
+
+#[derive(Serialize, Deserialize)]
 struct DuplexStreamStarted {
 	pub for_request_id: u32,
 	pub stream_ids: Vec<u32>,
 }
 
 impl<S: Serialization, C: Send + Sync + 'static> RpcMethodBuilder<S, C> {
+	/// Adds a layer that wraps every call to a registered method, for
+	/// concerns such as auth checks, logging, and metrics. Layers run in the
+	/// order they're added, and each calls `next.run()` to run the layers
+	/// after it and then the method. Returning an error instead rejects the
+	/// call with that error.
+	pub fn layer<F>(&mut self, layer: F)
+	where
+		F: Fn(&CallInfo, Next) -> Result<MaybeSync, AnyError> + Send + Sync + 'static,
+	{
+		self.layers.push(Arc::new(layer));
+	}
+
+	/// Adds a layer that's called with the name of each registered method
+	/// when it's dispatched, which can be used for instrumentation.
+	pub fn observe_calls<F>(&mut self, observer: F)
+	where
+		F: Fn(&'static str) + Send + Sync + 'static,
+	{
+		self.layer(move |call, next| {
+			observer(call.method);
+			next.run(call)
+		});
+	}
+
+	/// Limits how often a registered method can be called through the built
+	/// dispatcher. Calls over the limit fail with `CodeError::RateLimited`
+	/// without reaching the method, and notifications are dropped.
+	pub fn rate_limit(&mut self, method_name: &str, limit: RateLimit) {
+		if let Some((name, _)) = self.methods.get_key_value(method_name) {
+			self.rate_limits
+				.insert(*name, Mutex::new(TokenBucket::new(limit)));
+		}
+	}
+
+	/// Logs calls to registered methods that take longer than the threshold,
+	/// with the shape of their params, and adds an event for them to the span
+	/// that was current when they were dispatched. Duplex and chunked methods
+	/// last as long as their streams, so aren't logged.
+	pub fn log_slow_calls(&mut self, threshold: Duration) {
+		self.slow_call_threshold = Some(threshold);
+	}
+
+	/// Reports messages the dispatcher would otherwise tolerate, such as calls
+	/// to unknown methods, reused request IDs, data for streams that aren't
+	/// open, and messages that can't be read. The transport can close the
+	/// connection when they're received, so bugs in clients surface while
+	/// they're developed.
+	pub fn strict(&mut self, violations: mpsc::UnboundedSender<CodeError>) {
+		self.violations = Some(violations);
+	}
+
 	/// Registers a synchronous rpc call that returns its result directly.
 	pub fn register_sync<P, R, F>(&mut self, method_name: &'static str, callback: F)
 	where
@@ -115,10 +199,7 @@ impl<S: Serialization, C: Send + Sync + 'static> RpcMethodBuilder<S, C> {
 						return id.map(|id| {
 							serial.serialize(&ErrorResponse {
 								id,
-								error: ResponseError {
-									code: 0,
-									message: format!("{:?}", err),
-								},
+								error: ResponseError::invalid_params(err),
 							})
 						})
 					}
@@ -129,10 +210,7 @@ impl<S: Serialization, C: Send + Sync + 'static> RpcMethodBuilder<S, C> {
 					Err(err) => id.map(|id| {
 						serial.serialize(&ErrorResponse {
 							id,
-							error: ResponseError {
-								code: -1,
-								message: format!("{:?}", err),
-							},
+							error: ResponseError::from_error(&err),
 						})
 					}),
 				}
@@ -159,10 +237,7 @@ impl<S: Serialization, C: Send + Sync + 'static> RpcMethodBuilder<S, C> {
 						return future::ready(id.map(|id| {
 							serial.serialize(&ErrorResponse {
 								id,
-								error: ResponseError {
-									code: 0,
-									message: format!("{:?}", err),
-								},
+								error: ResponseError::invalid_params(err),
 							})
 						}))
 						.boxed();
@@ -180,10 +255,7 @@ impl<S: Serialization, C: Send + Sync + 'static> RpcMethodBuilder<S, C> {
 						Err(err) => id.map(|id| {
 							serial.serialize(&ErrorResponse {
 								id,
-								error: ResponseError {
-									code: -1,
-									message: format!("{:?}", err),
-								},
+								error: ResponseError::from_error(&err),
 							})
 						}),
 					}
@@ -220,10 +292,7 @@ impl<S: Serialization, C: Send + Sync + 'static> RpcMethodBuilder<S, C> {
 							future::ready(id.map(|id| {
 								serial.serialize(&ErrorResponse {
 									id,
-									error: ResponseError {
-										code: 0,
-										message: format!("{:?}", err),
-									},
+									error: ResponseError::invalid_params(err),
 								})
 							}))
 							.boxed(),
@@ -238,6 +307,7 @@ impl<S: Serialization, C: Send + Sync + 'static> RpcMethodBuilder<S, C> {
 				let mut dto = StreamDto {
 					req_id: id.unwrap_or(0),
 					streams: Vec::with_capacity(streams),
+					chunks: None,
 				};
 				let mut servers = Vec::with_capacity(streams);
 
@@ -253,10 +323,7 @@ impl<S: Serialization, C: Send + Sync + 'static> RpcMethodBuilder<S, C> {
 						Err(err) => id.map(|id| {
 							serial.serialize(&ErrorResponse {
 								id,
-								error: ResponseError {
-									code: -1,
-									message: format!("{:?}", err),
-								},
+								error: ResponseError::from_error(&err),
 							})
 						}),
 					}
@@ -267,28 +334,261 @@ impl<S: Serialization, C: Send + Sync + 'static> RpcMethodBuilder<S, C> {
 		);
 	}
 
+	/// Registers an async rpc call whose response is preceded by a sequence
+	/// of chunks, so that large payloads don't need to be held in memory and
+	/// sent in a single message. Callers receive the chunks with
+	/// `RpcCaller::call_chunked`.
+	pub fn register_chunked<P, T, R, Fut, F>(&mut self, method_name: &'static str, callback: F)
+	where
+		P: DeserializeOwned + Send + 'static,
+		T: Serialize + Send + 'static,
+		R: Serialize + Send + Sync + 'static,
+		Fut: Future<Output = Result<R, AnyError>> + Send,
+		F: (Fn(ChunkSender<T>, P, Arc<C>) -> Fut) + Clone + Send + Sync + 'static,
+	{
+		let serial = self.serializer.clone();
+		let context = self.context.clone();
+		self.methods.insert(
+			method_name,
+			Method::Chunked(Arc::new(move |id, body| {
+				let param = match serial.deserialize::<RequestParams<P>>(body) {
+					Ok(p) => p,
+					Err(err) => {
+						return (
+							None,
+							future::ready(id.map(|id| {
+								serial.serialize(&ErrorResponse {
+									id,
+									error: ResponseError::invalid_params(err),
+								})
+							}))
+							.boxed(),
+						);
+					}
+				};
+
+				let callback = callback.clone();
+				let context = context.clone();
+
+				let (tx, rx) = mpsc::channel(CHUNK_BUFFER);
+				let (sent_tx, sent_rx) = oneshot::channel();
+				let chunk_serial = serial.clone();
+				let sender = ChunkSender {
+					tx,
+					serialize: Box::new(move |chunk: &T| {
+						chunk_serial.serialize(&FullRequest {
+							id: None,
+							method: METHOD_RESPONSE_CHUNK,
+							params: ResponseChunkParams {
+								id: id.unwrap_or(0),
+								chunk,
+							},
+						})
+					}),
+				};
+
+				// notifications have no response for chunks to precede, so the
+				// receiver is dropped and chunks are discarded
+				let dto = id.map(|req_id| StreamDto {
+					req_id,
+					streams: vec![],
+					chunks: Some(ResponseChunks { rx, _sent: sent_tx }),
+				});
+
+				let serial = serial.clone();
+				let fut = async move {
+					let result = callback(sender, param.params, context).await;
+					// the response is sent after the last chunk
+					let _ = sent_rx.await;
+					match result {
+						Ok(r) => id.map(|id| serial.serialize(&SuccessResponse { id, result: r })),
+						Err(err) => id.map(|id| {
+							serial.serialize(&ErrorResponse {
+								id,
+								error: ResponseError::from_error(&err),
+							})
+						}),
+					}
+				};
+
+				(dto, fut.boxed())
+			})),
+		);
+	}
+
 	/// Builds into a usable, sync rpc dispatcher.
 	pub fn build(mut self, log: log::Logger) -> RpcDispatcher<S, C> {
-		let streams: Arc<tokio::sync::Mutex<HashMap<u32, WriteHalf<DuplexStream>>>> =
-			Arc::new(tokio::sync::Mutex::new(HashMap::new()));
+		let rate_limits = std::mem::take(&mut self.rate_limits);
+		if !rate_limits.is_empty() {
+			self.layer(move |call, next| match rate_limits.get(call.method) {
+				Some(bucket) if !bucket.lock().unwrap().try_take() => {
+					Err(CodeError::RateLimited(call.method.to_string()).into())
+				}
+				_ => next.run(call),
+			});
+		}
+
+		if let Some(threshold) = self.slow_call_threshold {
+			let log = log.clone();
+			let serial = self.serializer.clone();
+			let streaming: HashSet<&'static str> = self
+				.methods
+				.iter()
+				.filter(|(_, m)| matches!(m, Method::Duplex(_) | Method::Chunked(_)))
+				.map(|(name, _)| *name)
+				.collect();
+			self.layer(move |call, next| {
+				// stream methods wait on the transport, so are slow when it is
+				if STREAM_METHODS.contains(&call.method) || streaming.contains(call.method) {
+					return next.run(call);
+				}
+
+				let timed = TimedCall {
+					log: log.clone(),
+					serializer: serial.clone(),
+					method: call.method,
+					body: (call.body.len() <= MAX_KEPT_BODY_LEN).then(|| call.body.to_vec()),
+					body_len: call.body.len(),
+					threshold,
+					cx: opentelemetry::Context::current(),
+					started: Instant::now(),
+				};
+
+				match next.run(call)? {
+					MaybeSync::Sync(r) => {
+						timed.finish();
+						Ok(MaybeSync::Sync(r))
+					}
+					r => Ok(r.map_future(move |fut| {
+						async move {
+							let r = fut.await;
+							timed.finish();
+							r
+						}
+						.boxed()
+					})),
+				}
+			});
+		}
+
+		let streams: IncomingStreams = Arc::new(tokio::sync::Mutex::new(HashMap::new()));
+		let stream_limits: StreamLimits = Arc::new(Mutex::new(HashMap::new()));
 
 		let s1 = streams.clone();
+		let violations = self.violations.clone();
 		self.register_async(METHOD_STREAM_ENDED, move |m: StreamEndedParams, _| {
 			let s1 = s1.clone();
+			let violations = violations.clone();
 			async move {
-				s1.lock().await.remove(&m.stream);
+				// the read half may still be held by a reader, so shut down the
+				// write half explicitly to signal EOF to the other side
+				match s1.lock().await.remove(&m.stream) {
+					Some(mut stream) => {
+						let _ = stream.write.shutdown().await;
+					}
+					None => report_violation(
+						&violations,
+						format!("stream {} was ended but isn't open", m.stream),
+					),
+				}
 				Ok(())
 			}
 		});
 
 		let s2 = streams.clone();
+		let serial = self.serializer.clone();
+		let violations = self.violations.clone();
 		self.register_async(METHOD_STREAM_DATA, move |m: StreamDataIncomingParams, _| {
 			let s2 = s2.clone();
+			let serial = serial.clone();
+			let violations = violations.clone();
 			async move {
 				let mut lock = s2.lock().await;
-				if let Some(stream) = lock.get_mut(&m.stream) {
-					let _ = stream.write_all(&m.segment).await;
+				match lock.get_mut(&m.stream) {
+					Some(stream) => {
+						let _ = stream.write.write_all(&m.segment).await;
+						stream.received += m.segment.len() as u64;
+						if let Some(limit) = stream.next_limit() {
+							stream
+								.credit_tx
+								.send(credit_message(&*serial, m.stream, limit))
+								.ok();
+						}
+					}
+					None => report_violation(
+						&violations,
+						format!("data was sent on stream {}, which isn't open", m.stream),
+					),
+				}
+				Ok(())
+			}
+		});
+
+		let limits = stream_limits.clone();
+		self.register_sync(METHOD_STREAM_CREDIT, move |m: StreamCreditParams, _| {
+			if let Some(tx) = limits.lock().unwrap().get(&m.stream) {
+				tx.send_replace(Some(m.limit));
+			}
+			Ok(())
+		});
+
+		let in_flight: InFlightCalls = Arc::new(Mutex::new(HashMap::new()));
+		let cancellable = in_flight.clone();
+		self.register_sync(METHOD_CANCEL, move |m: CancelParams, _| {
+			if let Some(token) = cancellable.lock().unwrap().get(&m.id) {
+				token.cancel();
+			}
+			Ok(())
+		});
+
+		let s3 = streams.clone();
+		let limits = stream_limits.clone();
+		let serial = self.serializer.clone();
+		let waiters = self.stream_waiters.clone();
+		self.register_async(METHOD_STREAMS_STARTED, move |m: DuplexStreamStarted, _| {
+			let s3 = s3.clone();
+			let limits = limits.clone();
+			let serial = serial.clone();
+			let waiter = waiters.lock().unwrap().remove(&m.for_request_id);
+			// watched before this returns, since credit can arrive right after
+			let limit_rxs: Vec<_> = match waiter {
+				Some(_) => m
+					.stream_ids
+					.iter()
+					.map(|id| watch_limit(&limits, *id))
+					.collect(),
+				None => vec![],
+			};
+			async move {
+				let waiter = match waiter {
+					Some(w) => w,
+					None => return Ok(()),
+				};
+
+				let mut locals = Vec::with_capacity(m.stream_ids.len());
+				let mut lock = s3.lock().await;
+				for (stream_id, limit_rx) in m.stream_ids.into_iter().zip(limit_rxs) {
+					let (local, remote) = tokio::io::duplex(8192);
+					let (read, write) = tokio::io::split(remote);
+					lock.insert(stream_id, IncomingStream::new(write, waiter.sender.clone()));
+					waiter
+						.sender
+						.send(credit_message(&*serial, stream_id, STREAM_WINDOW))
+						.ok();
+
+					let sender = waiter.sender.clone();
+					tokio::spawn(forward_stream(
+						serial.clone(),
+						stream_id,
+						read,
+						limits.clone(),
+						limit_rx,
+						move |body| future::ready(sender.send(body).is_ok()).boxed(),
+					));
+					locals.push(local);
 				}
+
+				waiter.tx.send(locals).ok();
 				Ok(())
 			}
 		});
@@ -297,15 +597,301 @@ impl<S: Serialization, C: Send + Sync + 'static> RpcMethodBuilder<S, C> {
 			log,
 			context: self.context,
 			calls: self.calls,
+			chunk_handlers: self.chunk_handlers,
 			serializer: self.serializer,
 			methods: Arc::new(self.methods),
 			streams,
+			stream_limits,
+			in_flight,
+			layers: Arc::new(self.layers),
+			violations: self.violations,
+		}
+	}
+}
+
+fn report_violation(violations: &Option<Violations>, message: String) {
+	if let Some(tx) = violations {
+		tx.send(CodeError::ProtocolViolation(message)).ok();
+	}
+}
+
+type InFlightCalls = Arc<Mutex<HashMap<u32, CancellationToken>>>;
+
+struct InFlightGuard {
+	in_flight: InFlightCalls,
+	id: u32,
+}
+
+impl Drop for InFlightGuard {
+	fn drop(&mut self) {
+		self.in_flight.lock().unwrap().remove(&self.id);
+	}
+}
+
+/// Serializes a notification that tells the remote a message it sent
+/// couldn't be read or broke the protocol.
+pub fn protocol_error(serializer: &impl Serialization, err: &AnyError) -> Vec<u8> {
+	serializer.serialize(&FullRequest {
+		id: None,
+		method: METHOD_PROTOCOL_ERROR,
+		params: ResponseError::from_error(err),
+	})
+}
+
+/// Methods used by the transport for streams, which aren't timed by
+/// `log_slow_calls`.
+const STREAM_METHODS: &[&str] = &[
+	METHOD_STREAMS_STARTED,
+	METHOD_STREAM_DATA,
+	METHOD_STREAM_ENDED,
+	METHOD_STREAM_CREDIT,
+];
+
+/// Largest request that's kept while a call runs, so that its params can be
+/// logged if it's slow.
+const MAX_KEPT_BODY_LEN: usize = 64 * 1024;
+
+/// Params of slow calls are truncated to this many characters.
+const MAX_LOGGED_PARAMS_LEN: usize = 1024;
+
+/// A call being timed by `log_slow_calls`.
+struct TimedCall<S> {
+	log: log::Logger,
+	serializer: Arc<S>,
+	method: &'static str,
+	body: Option<Vec<u8>>,
+	body_len: usize,
+	threshold: Duration,
+	cx: opentelemetry::Context,
+	started: Instant,
+}
+
+impl<S: Serialization> TimedCall<S> {
+	fn finish(self) {
+		let elapsed = self.started.elapsed();
+		if elapsed < self.threshold {
+			return;
 		}
+
+		let params = match &self.body {
+			Some(body) => describe_params(&*self.serializer, body),
+			None => format!("<{} bytes>", self.body_len),
+		};
+		info!(
+			self.log,
+			"Slow RPC call: {} took {:?}, params: {}", self.method, elapsed, params
+		);
+		self.cx.span().add_event(
+			"rpc.slow_call",
+			vec![
+				KeyValue::new("method", self.method),
+				KeyValue::new("duration_ms", elapsed.as_millis() as f64),
+				KeyValue::new("params", params),
+			],
+		);
+	}
+}
+
+/// Formats the params of a request for logging. Params can hold paths, file
+/// contents, and environments, so only their shape is kept: strings are
+/// replaced by their length, and arrays of numbers, which byte arrays are
+/// read as, by theirs.
+fn describe_params(serializer: &impl Serialization, body: &[u8]) -> String {
+	let mut params = match serializer.deserialize::<RequestParams<MsgPackValue>>(body) {
+		Ok(p) => redact_params(p.params.0).to_string(),
+		Err(_) => return format!("<{} bytes>", body.len()),
+	};
+
+	if let Some((i, _)) = params.char_indices().nth(MAX_LOGGED_PARAMS_LEN) {
+		params.truncate(i);
+		params.push_str("...");
+	}
+
+	params
+}
+
+fn redact_params(value: serde_json::Value) -> serde_json::Value {
+	use serde_json::Value;
+	match value {
+		Value::String(s) => format!("<{} chars>", s.chars().count()).into(),
+		Value::Array(a) if !a.is_empty() && a.iter().all(Value::is_number) => {
+			format!("<{} items>", a.len()).into()
+		}
+		Value::Array(a) => Value::Array(a.into_iter().map(redact_params).collect()),
+		Value::Object(o) => {
+			Value::Object(o.into_iter().map(|(k, v)| (k, redact_params(v))).collect())
+		}
+		v => v,
+	}
+}
+
+/// Completes the outbound call with the given ID, if it's still pending.
+fn resolve_call(calls: &Mutex<HashMap<u32, DispatchMethod>>, id: u32, outcome: Outcome) {
+	// release the lock first, as completing a batch resolves the calls in it
+	let cb = calls.lock().unwrap().remove(&id);
+	if let Some(cb) = cb {
+		cb(outcome);
 	}
 }
 
 type DispatchMethod = Box<dyn Send + Sync + FnOnce(Outcome)>;
 
+/// A call made with `call_with_streams` that's waiting for the remote to
+/// start its streams.
+struct StreamWaiter {
+	tx: oneshot::Sender<Vec<DuplexStream>>,
+	sender: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+/// Number of bytes the remote can send on a stream beyond what's been
+/// written to its local end. Streams use credit-based flow control: the
+/// receiver sends `stream_credit` with the total number of bytes the sender
+/// may send, and raises it as data is consumed. Senders don't limit streams
+/// until they're given credit, so peers without flow control still work.
+const STREAM_WINDOW: u64 = 512 * 1024;
+
+/// Number of chunks a chunked method can send before it waits for the
+/// transport to write them.
+const CHUNK_BUFFER: usize = 8;
+
+/// Handlers for the chunks of outbound calls made with `call_chunked`, which
+/// are given the serialized `response_chunk` message.
+type ChunkHandlers = Arc<Mutex<HashMap<u32, Box<dyn Send + Sync + Fn(&[u8])>>>>;
+
+/// Sends chunks of the response to a call to a method registered with
+/// `register_chunked`. Chunks can only be sent until the method returns.
+pub struct ChunkSender<T> {
+	tx: mpsc::Sender<Vec<u8>>,
+	serialize: Box<dyn Send + Sync + Fn(&T) -> Vec<u8>>,
+}
+
+impl<T> ChunkSender<T> {
+	/// Sends a chunk, waiting if the transport is behind. Returns false if
+	/// the chunk can't be delivered, such as when the connection closed or
+	/// the call was a notification.
+	pub async fn send(&self, chunk: T) -> bool {
+		self.tx.send((self.serialize)(&chunk)).await.is_ok()
+	}
+}
+
+/// Chunks of a response, which the transport writes before the response.
+struct ResponseChunks {
+	rx: mpsc::Receiver<Vec<u8>>,
+	/// Dropped once the chunks have been written, letting the response be
+	/// sent.
+	_sent: oneshot::Sender<()>,
+}
+
+type IncomingStreams = Arc<tokio::sync::Mutex<HashMap<u32, IncomingStream>>>;
+
+/// Limits the remote granted for sending on each stream, or None if it
+/// hasn't granted any.
+type StreamLimits = Arc<Mutex<HashMap<u32, watch::Sender<Option<u64>>>>>;
+
+/// Local end of a stream that data from the remote is written to.
+struct IncomingStream {
+	write: WriteHalf<DuplexStream>,
+	/// Bytes received on the stream.
+	received: u64,
+	/// Limit last granted to the remote.
+	granted: u64,
+	/// Sends credit messages to the remote.
+	credit_tx: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+impl IncomingStream {
+	fn new(write: WriteHalf<DuplexStream>, credit_tx: mpsc::UnboundedSender<Vec<u8>>) -> Self {
+		Self {
+			write,
+			received: 0,
+			granted: STREAM_WINDOW,
+			credit_tx,
+		}
+	}
+
+	/// Gets a new limit to grant the remote, once it's used enough of the
+	/// window that granting more is worth a message.
+	fn next_limit(&mut self) -> Option<u64> {
+		let limit = self.received + STREAM_WINDOW;
+		if limit < self.granted + STREAM_WINDOW / 2 {
+			return None;
+		}
+
+		self.granted = limit;
+		Some(limit)
+	}
+}
+
+fn credit_message<S: Serialization>(serial: &S, stream: u32, limit: u64) -> Vec<u8> {
+	serial.serialize(&FullRequest {
+		id: None,
+		method: METHOD_STREAM_CREDIT,
+		params: StreamCreditParams { stream, limit },
+	})
+}
+
+/// Starts tracking the limit the remote grants for sending on the stream.
+fn watch_limit(limits: &StreamLimits, stream_id: u32) -> watch::Receiver<Option<u64>> {
+	let (tx, rx) = watch::channel(None);
+	limits.lock().unwrap().insert(stream_id, tx);
+	rx
+}
+
+/// Sends data read from the local end of a stream to the remote, until the
+/// stream is closed. Waits for credit before reading more once the remote's
+/// limit is reached, which backs up into whatever writes to the stream.
+async fn forward_stream<S, F>(
+	serial: Arc<S>,
+	stream_id: u32,
+	mut read: ReadHalf<DuplexStream>,
+	limits: StreamLimits,
+	mut limit_rx: watch::Receiver<Option<u64>>,
+	send: F,
+) where
+	S: Serialization,
+	F: Fn(Vec<u8>) -> BoxFuture<'static, bool>,
+{
+	let mut buf = vec![0; 4096];
+	let mut sent = 0u64;
+	let ended = loop {
+		let blocked = matches!(*limit_rx.borrow(), Some(limit) if sent >= limit);
+		if blocked {
+			if limit_rx.changed().await.is_err() {
+				break false;
+			}
+			continue;
+		}
+
+		match read.read(&mut buf).await {
+			Ok(0) | Err(_) => break true,
+			Ok(n) => {
+				let body = serial.serialize(&FullRequest {
+					id: None,
+					method: METHOD_STREAM_DATA,
+					params: StreamDataParams {
+						segment: &buf[..n],
+						stream: stream_id,
+					},
+				});
+				if !send(body).await {
+					break false;
+				}
+				sent += n as u64;
+			}
+		}
+	};
+
+	limits.lock().unwrap().remove(&stream_id);
+	if ended {
+		send(serial.serialize(&FullRequest {
+			id: None,
+			method: METHOD_STREAM_ENDED,
+			params: StreamEndedParams { stream: stream_id },
+		}))
+		.await;
+	}
+}
+
 /// Dispatcher returned from a Builder that provides a transport-agnostic way to
 /// deserialize and dispatch RPC calls. This structure may get more advanced as
 /// time goes on...
@@ -313,6 +899,8 @@ type DispatchMethod = Box<dyn Send + Sync + FnOnce(Outcome)>;
 pub struct RpcCaller<S: Serialization> {
 	serializer: Arc<S>,
 	calls: Arc<Mutex<HashMap<u32, DispatchMethod>>>,
+	stream_waiters: Arc<Mutex<HashMap<u32, StreamWaiter>>>,
+	chunk_handlers: ChunkHandlers,
 	sender: mpsc::UnboundedSender<Vec<u8>>,
 }
 
@@ -343,6 +931,50 @@ impl<S: Serialization> RpcCaller<S> {
 
 	/// Enqueues an outbound call, returning its result.
 	pub fn call<M, A, R>(&self, method: M, params: A) -> oneshot::Receiver<Result<R, ResponseError>>
+	where
+		M: AsRef<str> + serde::Serialize,
+		A: Serialize,
+		R: DeserializeOwned + Send + 'static,
+	{
+		self.call_with_id(next_message_id(), method, params)
+	}
+
+	/// Enqueues an outbound call that can be aborted with `cancel`, returning
+	/// the call's ID along with its result.
+	#[allow(clippy::type_complexity)]
+	pub fn call_cancellable<M, A, R>(
+		&self,
+		method: M,
+		params: A,
+	) -> (u32, oneshot::Receiver<Result<R, ResponseError>>)
+	where
+		M: AsRef<str> + serde::Serialize,
+		A: Serialize,
+		R: DeserializeOwned + Send + 'static,
+	{
+		let id = next_message_id();
+		(id, self.call_with_id(id, method, params))
+	}
+
+	/// Asks the remote to stop handling a call. Unless it finished first, the
+	/// call fails with a cancellation error. Remotes that don't support
+	/// cancellation ignore the request.
+	pub fn cancel(&self, id: u32) -> bool {
+		self.notify(METHOD_CANCEL, CancelParams { id })
+	}
+
+	/// Enqueues an outbound call to a method the remote registered with
+	/// `register_duplex`. Returns the streams the remote started for the
+	/// call, followed by the call's eventual result.
+	#[allow(clippy::type_complexity)]
+	pub fn call_with_streams<M, A, R>(
+		&self,
+		method: M,
+		params: A,
+	) -> (
+		oneshot::Receiver<Vec<DuplexStream>>,
+		oneshot::Receiver<Result<R, ResponseError>>,
+	)
 	where
 		M: AsRef<str> + serde::Serialize,
 		A: Serialize,
@@ -350,6 +982,67 @@ impl<S: Serialization> RpcCaller<S> {
 	{
 		let (tx, rx) = oneshot::channel();
 		let id = next_message_id();
+		self.stream_waiters.lock().unwrap().insert(
+			id,
+			StreamWaiter {
+				tx,
+				sender: self.sender.clone(),
+			},
+		);
+
+		let result = self.call_with_id(id, method, params);
+		(rx, result)
+	}
+
+	/// Enqueues an outbound call to a method the remote registered with
+	/// `register_chunked`. Returns the chunks of the response, which all
+	/// arrive before the call's eventual result, and the call's ID, which can
+	/// be given to `cancel`.
+	#[allow(clippy::type_complexity)]
+	pub fn call_chunked<M, A, T, R>(
+		&self,
+		method: M,
+		params: A,
+	) -> (
+		u32,
+		mpsc::UnboundedReceiver<T>,
+		oneshot::Receiver<Result<R, ResponseError>>,
+	)
+	where
+		M: AsRef<str> + serde::Serialize,
+		A: Serialize,
+		T: DeserializeOwned + Send + 'static,
+		R: DeserializeOwned + Send + 'static,
+	{
+		let (tx, rx) = mpsc::unbounded_channel();
+		let id = next_message_id();
+		let serializer = self.serializer.clone();
+		self.chunk_handlers.lock().unwrap().insert(
+			id,
+			Box::new(move |body| {
+				if let Ok(p) = serializer.deserialize::<RequestParams<ResponseChunkParams<T>>>(body)
+				{
+					tx.send(p.params.chunk).ok();
+				}
+			}),
+		);
+
+		let result = self.call_with_id(id, method, params);
+		(id, rx, result)
+	}
+
+	fn call_with_id<M, A, R>(
+		&self,
+		id: u32,
+		method: M,
+		params: A,
+	) -> oneshot::Receiver<Result<R, ResponseError>>
+	where
+		M: AsRef<str> + serde::Serialize,
+		A: Serialize,
+		R: DeserializeOwned + Send + 'static,
+	{
+		let (tx, rx) = oneshot::channel();
 		let body = self.serializer.serialize(&FullRequest {
 			id: Some(id),
 			method,
@@ -358,13 +1051,46 @@ impl<S: Serialization> RpcCaller<S> {
 
 		if self.sender.send(body).is_err() {
 			drop(tx);
+			self.stream_waiters.lock().unwrap().remove(&id);
+			self.chunk_handlers.lock().unwrap().remove(&id);
 			return rx;
 		}
 
+		self.register_call(id, tx);
+		rx
+	}
+
+	/// Starts a batch of calls, which are sent to the remote together.
+	pub fn batch(&self) -> RpcBatch<S> {
+		RpcBatch {
+			caller: RpcCaller {
+				serializer: self.serializer.clone(),
+				calls: self.calls.clone(),
+				stream_waiters: self.stream_waiters.clone(),
+				chunk_handlers: self.chunk_handlers.clone(),
+				sender: self.sender.clone(),
+			},
+			ids: vec![],
+			calls: vec![],
+		}
+	}
+
+	/// Adds a handler for the response to the call with the given ID.
+	fn register_call<R>(&self, id: u32, tx: oneshot::Sender<Result<R, ResponseError>>)
+	where
+		R: DeserializeOwned + Send + 'static,
+	{
 		let serializer = self.serializer.clone();
+		let stream_waiters = self.stream_waiters.clone();
+		let chunk_handlers = self.chunk_handlers.clone();
 		self.calls.lock().unwrap().insert(
 			id,
 			Box::new(move |body| {
+				// streams are always started, and chunks sent, before the call
+				// completes, so any waiter still present at this point will
+				// never be resolved.
+				stream_waiters.lock().unwrap().remove(&id);
+				chunk_handlers.lock().unwrap().remove(&id);
 				match body {
 					Outcome::Error(e) => tx.send(Err(e)).ok(),
 					Outcome::Success(r) => match serializer.deserialize::<SuccessResponse<R>>(&r) {
@@ -373,14 +1099,127 @@ impl<S: Serialization> RpcCaller<S> {
 							.send(Err(ResponseError {
 								code: 0,
 								message: err.to_string(),
+								data: Some(ErrorDetails::INTERNAL),
 							}))
 							.ok(),
 					},
 				};
 			}),
 		);
+	}
+}
 
-		rx
+/// Calls that are sent to the remote in a single message. The remote
+/// responds once all of them complete, with each of their responses.
+pub struct RpcBatch<S: Serialization> {
+	caller: RpcCaller<S>,
+	ids: Vec<u32>,
+	calls: Vec<serde_bytes::ByteBuf>,
+}
+
+impl<S: Serialization> RpcBatch<S> {
+	/// Adds a call to the batch, returning its ID, which can be given to
+	/// `RpcCaller::cancel`, and its eventual result. Methods the remote
+	/// registered with `register_duplex` or `register_chunked` can't be
+	/// called in a batch.
+	#[allow(clippy::type_complexity)]
+	pub fn call<M, A, R>(
+		&mut self,
+		method: M,
+		params: A,
+	) -> (u32, oneshot::Receiver<Result<R, ResponseError>>)
+	where
+		M: AsRef<str> + serde::Serialize,
+		A: Serialize,
+		R: DeserializeOwned + Send + 'static,
+	{
+		let id = next_message_id();
+		let (tx, rx) = oneshot::channel();
+		self.caller.register_call(id, tx);
+		self.ids.push(id);
+		self.calls.push(serde_bytes::ByteBuf::from(
+			self.caller.serializer.serialize(&FullRequest {
+				id: Some(id),
+				method,
+				params,
+			}),
+		));
+		(id, rx)
+	}
+
+	/// Sends the batch. Returns whether the message was enqueued.
+	pub fn send(self) -> bool {
+		let RpcBatch { caller, ids, calls } = self;
+		if calls.is_empty() {
+			return true;
+		}
+
+		let batch_id = next_message_id();
+		let serializer = caller.serializer.clone();
+		let pending = caller.calls.clone();
+		let batch_ids = ids.clone();
+		caller.calls.lock().unwrap().insert(
+			batch_id,
+			Box::new(move |outcome| resolve_batch(&*serializer, &pending, &batch_ids, outcome)),
+		);
+
+		let body = caller.serializer.serialize(&FullRequest {
+			id: Some(batch_id),
+			method: METHOD_BATCH,
+			params: BatchParams { calls },
+		});
+		if caller.sender.send(body).is_err() {
+			let mut pending = caller.calls.lock().unwrap();
+			for id in ids.iter().chain(std::iter::once(&batch_id)) {
+				pending.remove(id);
+			}
+			return false;
+		}
+
+		true
+	}
+}
+
+/// Completes the calls in a batch from the batch's response. If the batch
+/// failed, such as when the remote doesn't support batches, each call fails
+/// with its error.
+fn resolve_batch<S: Serialization>(
+	serializer: &S,
+	calls: &Mutex<HashMap<u32, DispatchMethod>>,
+	ids: &[u32],
+	outcome: Outcome,
+) {
+	let err = match outcome {
+		Outcome::Error(e) => e,
+		Outcome::Success(r) => match serializer.deserialize::<SuccessResponse<BatchResult>>(&r) {
+			Ok(r) => {
+				for response in r.result.responses.into_iter().flatten() {
+					let (id, error) = match serializer.deserialize::<PartialIncoming>(&response) {
+						Ok(PartialIncoming {
+							id: Some(id),
+							error,
+							..
+						}) => (id, error),
+						_ => continue,
+					};
+					let outcome = match error {
+						Some(e) => Outcome::Error(e),
+						None => Outcome::Success(response.into_vec()),
+					};
+					resolve_call(calls, id, outcome);
+				}
+				return;
+			}
+			Err(err) => ResponseError {
+				code: 0,
+				message: err.to_string(),
+				data: Some(ErrorDetails::INTERNAL),
+			},
+		},
+	};
+
+	for id in ids {
+		resolve_call(calls, *id, Outcome::Error(err.clone()));
 	}
 }
 
@@ -394,7 +1233,13 @@ pub struct RpcDispatcher<S, C> {
 	serializer: Arc<S>,
 	methods: Arc<HashMap<&'static str, Method>>,
 	calls: Arc<Mutex<HashMap<u32, DispatchMethod>>>,
-	streams: Arc<tokio::sync::Mutex<HashMap<u32, WriteHalf<DuplexStream>>>>,
+	chunk_handlers: ChunkHandlers,
+	streams: IncomingStreams,
+	stream_limits: StreamLimits,
+	/// Async calls that are running, which the remote can cancel.
+	in_flight: InFlightCalls,
+	layers: Arc<Vec<Layer>>,
+	violations: Option<Violations>,
 }
 
 static MESSAGE_ID_COUNTER: AtomicU32 = AtomicU32::new(0);
@@ -403,6 +1248,12 @@ fn next_message_id() -> u32 {
 }
 
 impl<S: Serialization, C: Send + Sync> RpcDispatcher<S, C> {
+	/// Serializes a notification that tells the remote a message it sent
+	/// couldn't be read, for errors that can't be tied to a request.
+	pub fn protocol_error(&self, err: &AnyError) -> Vec<u8> {
+		protocol_error(&*self.serializer, err)
+	}
+
 	/// Runs the incoming request, returning the result of the call synchronously
 	/// or in a future. (The caller can then decide whether to run the future
 	/// sequentially in its receive loop, or not.)
@@ -412,48 +1263,244 @@ impl<S: Serialization, C: Send + Sync> RpcDispatcher<S, C> {
 	pub fn dispatch(&self, body: &[u8]) -> MaybeSync {
 		let partial = match self.serializer.deserialize::<PartialIncoming>(body) {
 			Ok(b) => b,
-			Err(_err) => {
+			Err(err) => {
 				warning!(self.log, "Failed to deserialize request, hex: {:X?}", body);
+				report_violation(
+					&self.violations,
+					format!("a message couldn't be read: {}", err),
+				);
 				return MaybeSync::Sync(None);
 			}
 		};
 		let id = partial.id;
 
+		if partial.method.as_deref() == Some(METHOD_BATCH) {
+			return self.dispatch_batch(id, body);
+		}
+
+		if partial.method.as_deref() == Some(METHOD_PROTOCOL_ERROR) {
+			if let Ok(p) = self
+				.serializer
+				.deserialize::<RequestParams<ResponseError>>(body)
+			{
+				warning!(
+					self.log,
+					"Remote couldn't read a message: {}",
+					p.params.message
+				);
+			}
+			return MaybeSync::Sync(None);
+		}
+
+		if partial.method.as_deref() == Some(METHOD_RESPONSE_CHUNK) {
+			if let Ok(p) = self
+				.serializer
+				.deserialize::<RequestParams<ResponseChunkId>>(body)
+			{
+				if let Some(handler) = self.chunk_handlers.lock().unwrap().get(&p.params.id) {
+					handler(body);
+				}
+			}
+			return MaybeSync::Sync(None);
+		}
+
 		if let Some(method_name) = partial.method {
-			let method = self.methods.get(method_name.as_str());
-			match method {
-				Some(Method::Sync(callback)) => MaybeSync::Sync(callback(id, body)),
-				Some(Method::Async(callback)) => MaybeSync::Future(callback(id, body)),
-				Some(Method::Duplex(callback)) => MaybeSync::Stream(callback(id, body)),
+			match self.methods.get_key_value(method_name.as_str()) {
+				Some((name, method)) => {
+					let invoke = || match method {
+						Method::Sync(callback) => MaybeSync::Sync(callback(id, body)),
+						Method::Async(callback) => {
+							MaybeSync::Future(self.cancellable(id, callback(id, body)))
+						}
+						Method::Duplex(callback) | Method::Chunked(callback) => {
+							let (dto, fut) = callback(id, body);
+							MaybeSync::Stream((dto, self.cancellable(id, fut)))
+						}
+					};
+
+					let call = CallInfo {
+						method: *name,
+						id,
+						body,
+					};
+					let next = Next {
+						layers: &self.layers,
+						invoke: &invoke,
+					};
+					match next.run(&call) {
+						Ok(r) => r,
+						Err(e) => MaybeSync::Sync(id.map(|id| {
+							self.serializer.serialize(&ErrorResponse {
+								id,
+								error: ResponseError::from_error(&e),
+							})
+						})),
+					}
+				}
 				None => MaybeSync::Sync(id.map(|id| {
+					report_violation(
+						&self.violations,
+						format!("method {} doesn't exist", method_name),
+					);
 					self.serializer.serialize(&ErrorResponse {
 						id,
 						error: ResponseError {
 							code: -1,
 							message: format!("Method not found: {}", method_name),
+							data: Some(ErrorDetails::METHOD_NOT_FOUND),
 						},
 					})
 				})),
 			}
-		} else if let Some(err) = partial.error {
-			if let Some(cb) = self.calls.lock().unwrap().remove(&id.unwrap()) {
-				cb(Outcome::Error(err));
-			}
-			MaybeSync::Sync(None)
 		} else {
-			if let Some(cb) = self.calls.lock().unwrap().remove(&id.unwrap()) {
-				cb(Outcome::Success(body.to_vec()));
-			}
+			let outcome = match partial.error {
+				Some(err) => Outcome::Error(err),
+				None => Outcome::Success(body.to_vec()),
+			};
+			resolve_call(&self.calls, id.unwrap(), outcome);
 			MaybeSync::Sync(None)
 		}
 	}
 
+	/// Dispatches each call in a batch, responding with all of their
+	/// responses once every call completes.
+	fn dispatch_batch(&self, id: Option<u32>, body: &[u8]) -> MaybeSync {
+		let calls = match self
+			.serializer
+			.deserialize::<RequestParams<BatchParams>>(body)
+		{
+			Ok(p) => p.params.calls,
+			Err(err) => {
+				return MaybeSync::Sync(id.map(|id| {
+					self.serializer.serialize(&ErrorResponse {
+						id,
+						error: ResponseError::invalid_params(err),
+					})
+				}))
+			}
+		};
+
+		let responses = calls.iter().map(|call| {
+			// streams and chunks are sent on the transport, which batches don't
+			// have
+			if let Some(call_id) = self.duplex_call_id(call) {
+				let response = self.serializer.serialize(&ErrorResponse {
+					id: call_id,
+					error: ResponseError {
+						code: -1,
+						message: "methods with streams can't be called in a batch".to_string(),
+						data: Some(ErrorDetails::INVALID_PARAMS),
+					},
+				});
+				return future::ready(Some(response)).boxed();
+			}
+
+			match self.dispatch(call) {
+				MaybeSync::Sync(v) => future::ready(v).boxed(),
+				MaybeSync::Future(fut) => fut,
+				MaybeSync::Stream((_, fut)) => fut,
+			}
+		});
+
+		let serial = self.serializer.clone();
+		let fut = futures::future::join_all(responses).map(move |responses| {
+			id.map(|id| {
+				serial.serialize(&SuccessResponse {
+					id,
+					result: BatchResult {
+						responses: responses
+							.into_iter()
+							.map(|r| r.map(serde_bytes::ByteBuf::from))
+							.collect(),
+					},
+				})
+			})
+		});
+
+		MaybeSync::Future(self.cancellable(id, fut.boxed()))
+	}
+
+	/// Gets the ID of the call if it's to a method registered with
+	/// `register_duplex` or `register_chunked`.
+	fn duplex_call_id(&self, body: &[u8]) -> Option<u32> {
+		let partial = self.serializer.deserialize::<PartialIncoming>(body).ok()?;
+		match self.methods.get(partial.method?.as_str()) {
+			Some(Method::Duplex(_) | Method::Chunked(_)) => partial.id,
+			_ => None,
+		}
+	}
+
+	/// Makes the handler's future stop, by being dropped, if the remote
+	/// cancels the call. Notifications can't be cancelled.
+	fn cancellable(
+		&self,
+		id: Option<u32>,
+		fut: BoxFuture<'static, Option<Vec<u8>>>,
+	) -> BoxFuture<'static, Option<Vec<u8>>> {
+		let id = match id {
+			Some(id) => id,
+			None => return fut,
+		};
+
+		let token = CancellationToken::new();
+		if self
+			.in_flight
+			.lock()
+			.unwrap()
+			.insert(id, token.clone())
+			.is_some()
+		{
+			report_violation(
+				&self.violations,
+				format!("request ID {} was reused while its call is running", id),
+			);
+		}
+		let guard = InFlightGuard {
+			in_flight: self.in_flight.clone(),
+			id,
+		};
+		let serial = self.serializer.clone();
+		async move {
+			// also removes the call if the future is dropped, such as when the
+			// batch it's in is cancelled
+			let _guard = guard;
+			tokio::select! {
+				r = fut => r,
+				_ = token.cancelled() => Some(serial.serialize(&ErrorResponse {
+					id,
+					error: ResponseError::from_error(&CodeError::RequestCancelled.into()),
+				})),
+			}
+		}
+		.boxed()
+	}
+
 	/// Registers a stream call returned from dispatch().
 	pub async fn register_stream(
 		&self,
 		write_tx: mpsc::Sender<impl 'static + From<Vec<u8>> + Send>,
-		dto: StreamDto,
+		mut dto: StreamDto,
 	) {
+		if let Some(mut chunks) = dto.chunks.take() {
+			let write_tx = write_tx.clone();
+			tokio::spawn(async move {
+				while let Some(chunk) = chunks.rx.recv().await {
+					if write_tx.send(chunk.into()).await.is_err() {
+						return;
+					}
+				}
+			});
+			return;
+		}
+
+		// watched before the remote knows about the streams, since it can
+		// send credit as soon as it does
+		let limit_rxs: Vec<_> = dto
+			.streams
+			.iter()
+			.map(|(id, _)| watch_limit(&self.stream_limits, *id))
+			.collect();
+
 		let r = write_tx
 			.send(
 				self.serializer
@@ -470,56 +1517,44 @@ impl<S: Serialization, C: Send + Sync> RpcDispatcher<S, C> {
 			.await;
 
 		if r.is_err() {
+			for (id, _) in &dto.streams {
+				self.stream_limits.lock().unwrap().remove(id);
+			}
 			return;
 		}
 
+		// credit is sent from the stream_data handler, which can't wait on
+		// the socket's queue, so it goes through an unbounded channel
+		let (credit_tx, mut credit_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+		let credit_write_tx = write_tx.clone();
+		tokio::spawn(async move {
+			while let Some(m) = credit_rx.recv().await {
+				if credit_write_tx.send(m.into()).await.is_err() {
+					return;
+				}
+			}
+		});
+
 		let mut streams_map = self.streams.lock().await;
-		for (stream_id, duplex) in dto.streams {
-			let (mut read, write) = tokio::io::split(duplex);
-			streams_map.insert(stream_id, write);
+		for ((stream_id, duplex), limit_rx) in dto.streams.into_iter().zip(limit_rxs) {
+			let (read, write) = tokio::io::split(duplex);
+			streams_map.insert(stream_id, IncomingStream::new(write, credit_tx.clone()));
+			credit_tx
+				.send(credit_message(&*self.serializer, stream_id, STREAM_WINDOW))
+				.ok();
 
 			let write_tx = write_tx.clone();
-			let serial = self.serializer.clone();
-			tokio::spawn(async move {
-				let mut buf = vec![0; 4096];
-				loop {
-					match read.read(&mut buf).await {
-						Ok(0) | Err(_) => break,
-						Ok(n) => {
-							let r = write_tx
-								.send(
-									serial
-										.serialize(&FullRequest {
-											id: None,
-											method: METHOD_STREAM_DATA,
-											params: StreamDataParams {
-												segment: &buf[..n],
-												stream: stream_id,
-											},
-										})
-										.into(),
-								)
-								.await;
-
-							if r.is_err() {
-								return;
-							}
-						}
-					}
-				}
-
-				let _ = write_tx
-					.send(
-						serial
-							.serialize(&FullRequest {
-								id: None,
-								method: METHOD_STREAM_ENDED,
-								params: StreamEndedParams { stream: stream_id },
-							})
-							.into(),
-					)
-					.await;
-			});
+			tokio::spawn(forward_stream(
+				self.serializer.clone(),
+				stream_id,
+				read,
+				self.stream_limits.clone(),
+				limit_rx,
+				move |body| {
+					let write_tx = write_tx.clone();
+					async move { write_tx.send(body.into()).await.is_ok() }.boxed()
+				},
+			));
 		}
 	}
 
@@ -531,6 +1566,11 @@ impl<S: Serialization, C: Send + Sync> RpcDispatcher<S, C> {
 const METHOD_STREAMS_STARTED: &str = "streams_started";
 const METHOD_STREAM_DATA: &str = "stream_data";
 const METHOD_STREAM_ENDED: &str = "stream_ended";
+const METHOD_STREAM_CREDIT: &str = "stream_credit";
+const METHOD_CANCEL: &str = "cancel";
+const METHOD_BATCH: &str = "batch";
+const METHOD_PROTOCOL_ERROR: &str = "protocol_error";
+const METHOD_RESPONSE_CHUNK: &str = "response_chunk";
 
 trait AssertIsSync: Sync {}
 impl<S: Serialization, C: Send + Sync> AssertIsSync for RpcDispatcher<S, C> {}
@@ -562,6 +1602,43 @@ struct StreamEndedParams {
 	pub stream: u32,
 }
 
+/// Total number of bytes the remote may send on the stream.
+#[derive(Serialize, Deserialize)]
+struct StreamCreditParams {
+	pub stream: u32,
+	pub limit: u64,
+}
+
+/// Calls in a batch, each serialized as a complete request.
+#[derive(Serialize, Deserialize)]
+struct BatchParams {
+	pub calls: Vec<serde_bytes::ByteBuf>,
+}
+
+/// Serialized responses to the calls in a batch, in the same order. Calls
+/// that were notifications have no response.
+#[derive(Serialize, Deserialize)]
+struct BatchResult {
+	pub responses: Vec<Option<serde_bytes::ByteBuf>>,
+}
+
+/// A chunk of the response to the call with the ID.
+#[derive(Serialize, Deserialize)]
+struct ResponseChunkParams<T> {
+	pub id: u32,
+	pub chunk: T,
+}
+
+#[derive(Deserialize)]
+struct ResponseChunkId {
+	pub id: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CancelParams {
+	pub id: u32,
+}
+
 #[derive(Serialize)]
 pub struct FullRequest<M: AsRef<str>, P> {
 	pub id: Option<u32>,
@@ -586,10 +1663,34 @@ struct ErrorResponse {
 	pub error: ResponseError,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ResponseError {
 	pub code: i32,
 	pub message: String,
+	/// Machine-readable details of the error. Missing from responses sent
+	/// by older versions of the CLI.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub data: Option<ErrorDetails>,
+}
+
+impl ResponseError {
+	/// Creates a response for parameters that couldn't be deserialized.
+	fn invalid_params(err: impl std::fmt::Debug) -> Self {
+		Self {
+			code: 0,
+			message: format!("{:?}", err),
+			data: Some(ErrorDetails::INVALID_PARAMS),
+		}
+	}
+
+	/// Creates a response for an error returned from a method handler.
+	fn from_error(err: &AnyError) -> Self {
+		Self {
+			code: -1,
+			message: format!("{:?}", err),
+			data: Some(err.details()),
+		}
+	}
 }
 
 enum Outcome {
@@ -600,6 +1701,7 @@ enum Outcome {
 pub struct StreamDto {
 	req_id: u32,
 	streams: Vec<(u32, DuplexStream)>,
+	chunks: Option<ResponseChunks>,
 }
 
 pub enum MaybeSync {
@@ -607,3 +1709,49 @@ pub enum MaybeSync {
 	Future(BoxFuture<'static, Option<Vec<u8>>>),
 	Sync(Option<Vec<u8>>),
 }
+
+impl MaybeSync {
+	/// Replaces the future of an async or duplex call, such as to wrap it
+	/// in a layer. Sync results are returned as-is.
+	pub fn map_future(
+		self,
+		f: impl FnOnce(BoxFuture<'static, Option<Vec<u8>>>) -> BoxFuture<'static, Option<Vec<u8>>>,
+	) -> Self {
+		match self {
+			MaybeSync::Stream((dto, fut)) => MaybeSync::Stream((dto, f(fut))),
+			MaybeSync::Future(fut) => MaybeSync::Future(f(fut)),
+			MaybeSync::Sync(r) => MaybeSync::Sync(r),
+		}
+	}
+}
+
+/// A call to a registered method, given to layers.
+pub struct CallInfo<'a> {
+	pub method: &'static str,
+	/// ID of the request, or None if it's a notification.
+	pub id: Option<u32>,
+	/// The serialized request.
+	pub body: &'a [u8],
+}
+
+/// The rest of the layers for a call, and then the method.
+pub struct Next<'a> {
+	layers: &'a [Layer],
+	invoke: &'a dyn Fn() -> MaybeSync,
+}
+
+impl Next<'_> {
+	/// Runs the next layer, or the method if this is the last layer.
+	pub fn run(self, call: &CallInfo) -> Result<MaybeSync, AnyError> {
+		match self.layers.split_first() {
+			Some((layer, layers)) => layer(
+				call,
+				Next {
+					layers,
+					invoke: self.invoke,
+				},
+			),
+			None => Ok((self.invoke)()),
+		}
+	}
+}
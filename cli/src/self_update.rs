@@ -3,35 +3,78 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
-use std::{fs, path::Path, process::Command};
+use std::{
+	fs,
+	path::{Path, PathBuf},
+	process::Command,
+	sync::atomic::{AtomicBool, Ordering},
+};
 use tempfile::tempdir;
 
 use crate::{
 	constants::{VSCODE_CLI_COMMIT, VSCODE_CLI_QUALITY},
 	options::Quality,
+	state::{LauncherPaths, PersistedState},
 	update_service::{unzip_downloaded_release, Platform, Release, TargetKind, UpdateService},
 	util::{
-		errors::{wrap, AnyError, CorruptDownload, UpdatesNotConfigured},
-		http,
+		errors::{wrap, AnyError, CodeError, CorruptDownload, UpdatesNotConfigured},
 		io::{ReportCopyProgress, SilentCopyProgress},
 	},
 };
 
+/// Quality of builds the CLI updates to, when it's been changed from the
+/// quality the CLI was built with using `code update --channel`.
+fn update_channel_state(paths: &LauncherPaths) -> PersistedState<Option<Quality>> {
+	PersistedState::new(paths.root().join("update_channel.json"))
+}
+
+/// Sets the quality of builds the CLI updates to. Choosing the quality the
+/// CLI was built with goes back to tracking it.
+pub fn set_update_channel(paths: &LauncherPaths, channel: Quality) -> Result<(), AnyError> {
+	let built_with = VSCODE_CLI_QUALITY.and_then(|q| Quality::try_from(q).ok());
+	let channel = (built_with != Some(channel)).then_some(channel);
+	update_channel_state(paths).save(channel)?;
+	Ok(())
+}
+
+static ALLOW_UNSIGNED_UPDATES: AtomicBool = AtomicBool::new(false);
+
+/// Sets whether updates whose signatures can't be verified are installed.
+/// Should be called at startup.
+pub fn install_allow_unsigned_updates(allow: bool) {
+	ALLOW_UNSIGNED_UPDATES.store(allow, Ordering::Relaxed);
+}
+
+/// Gets whether binaries can be installed without the archives they came
+/// from, which is where signatures are embedded in binaries, or where they
+/// aren't checked.
+pub fn can_install_bare_binaries() -> bool {
+	ALLOW_UNSIGNED_UPDATES.load(Ordering::Relaxed)
+		|| cfg!(any(target_os = "windows", target_os = "macos"))
+}
+
 pub struct SelfUpdate<'a> {
 	commit: &'static str,
 	quality: Quality,
 	platform: Platform,
 	update_service: &'a UpdateService,
+	/// Where the CLI that an update replaces is kept, for rollbacks.
+	previous_path: PathBuf,
 }
 
 impl<'a> SelfUpdate<'a> {
-	pub fn new(update_service: &'a UpdateService) -> Result<Self, AnyError> {
+	/// Creates an updater that tracks the channel set with
+	/// `set_update_channel`, or else the quality the CLI was built with.
+	pub fn new(update_service: &'a UpdateService, paths: &LauncherPaths) -> Result<Self, AnyError> {
 		let commit = VSCODE_CLI_COMMIT
 			.ok_or_else(|| UpdatesNotConfigured("unknown build commit".to_string()))?;
 
-		let quality = VSCODE_CLI_QUALITY
-			.ok_or_else(|| UpdatesNotConfigured("no configured quality".to_string()))
-			.and_then(|q| Quality::try_from(q).map_err(UpdatesNotConfigured))?;
+		let quality = match update_channel_state(paths).load() {
+			Some(channel) => channel,
+			None => VSCODE_CLI_QUALITY
+				.ok_or_else(|| UpdatesNotConfigured("no configured quality".to_string()))
+				.and_then(|q| Quality::try_from(q).map_err(UpdatesNotConfigured))?,
+		};
 
 		let platform = Platform::env_default().ok_or_else(|| {
 			UpdatesNotConfigured("Unknown platform, please report this error".to_string())
@@ -42,13 +85,24 @@ impl<'a> SelfUpdate<'a> {
 			quality,
 			platform,
 			update_service,
+			previous_path: paths
+				.root()
+				.join(format!("cli-previous{}", std::env::consts::EXE_SUFFIX)),
 		})
 	}
 
+	pub fn quality(&self) -> Quality {
+		self.quality
+	}
+
+	pub fn platform(&self) -> Platform {
+		self.platform
+	}
+
 	/// Gets the current release
 	pub async fn get_current_release(&self) -> Result<Release, AnyError> {
 		self.update_service
-			.get_latest_commit(self.platform, TargetKind::Cli, self.quality)
+			.get_latest_commit(self.platform, TargetKind::Cli, self.quality, None)
 			.await
 	}
 
@@ -61,28 +115,216 @@ impl<'a> SelfUpdate<'a> {
 	pub async fn do_update(
 		&self,
 		release: &Release,
-		progress: impl ReportCopyProgress,
+		mut progress: impl ReportCopyProgress + Send,
 	) -> Result<(), AnyError> {
-		// 1. Download the archive into a temporary directory
 		let tempdir = tempdir().map_err(|e| wrap(e, "Failed to create temp dir"))?;
+
+		// Patch the running CLI if there's a delta to the release, which is much
+		// smaller than the full download. Deltas have no detached signatures, so
+		// they're only used where binaries have embedded ones, unless those
+		// aren't checked anyway.
+		if can_install_bare_binaries() {
+			let current =
+				std::env::current_exe().map_err(|e| wrap(e, "could not get current exe"))?;
+			let patched = tempdir.path().join("patched-cli");
+			if self
+				.update_service
+				.patch_cli_with_delta(&current, self.commit, release, &patched, &mut progress)
+				.await
+			{
+				return self.install_bare_binary(&patched).await;
+			}
+		}
+
+		// 1. Download the archive into a temporary directory
 		let stream = self.update_service.get_download_stream(release).await?;
+		let archive_url = stream.url.clone();
 		let archive_path = tempdir.path().join(stream.url_path_basename().unwrap());
-		http::download_into_file(&archive_path, progress, stream).await?;
+		self.update_service
+			.download_into_file(&archive_path, progress, stream)
+			.await?;
+		self.update_service
+			.verify_download(release, &archive_path)
+			.await?;
 
 		// 2. Unzip the archive and get the binary
-		let target_path =
-			std::env::current_exe().map_err(|e| wrap(e, "could not get current exe"))?;
-		let staging_path = target_path.with_extension(".update");
 		let archive_contents_path = tempdir.path().join("content");
 		// unzipping the single binary is pretty small and fast--don't bother with passing progress
 		unzip_downloaded_release(&archive_path, &archive_contents_path, SilentCopyProgress())?;
-		copy_updated_cli_to_path(&archive_contents_path, &staging_path)?;
+		let binary = find_updated_cli(&archive_contents_path)?;
+
+		// 3. Make sure it's signed by the publisher before swapping binaries
+		if !ALLOW_UNSIGNED_UPDATES.load(Ordering::Relaxed) {
+			self.verify_signature(&binary, Some(&archive_path), archive_url)
+				.await?;
+		}
+
+		self.install(&binary)
+	}
+
+	/// Checks the Authenticode signature of the binary, which must be from the
+	/// CLI's publisher rather than any trusted one.
+	#[cfg(target_os = "windows")]
+	async fn verify_signature(
+		&self,
+		binary: &Path,
+		_archive: Option<&Path>,
+		_archive_url: Option<url::Url>,
+	) -> Result<(), AnyError> {
+		use crate::constants::{
+			VSCODE_CLI_WIN32_SIGNER_SUBJECT, VSCODE_CLI_WIN32_SIGNER_THUMBPRINTS,
+		};
+
+		let (subject, thumbprints) = VSCODE_CLI_WIN32_SIGNER_SUBJECT
+			.zip(VSCODE_CLI_WIN32_SIGNER_THUMBPRINTS)
+			.ok_or_else(|| {
+				CodeError::UpdateSignatureNotVerified(
+					"this build has no publisher to check signatures against".to_string(),
+				)
+			})?;
+
+		let script = format!(
+			"$s = Get-AuthenticodeSignature -LiteralPath '{}'; \"$($s.Status)|$($s.SignerCertificate.Thumbprint)|$($s.SignerCertificate.Subject)\"",
+			binary.display().to_string().replace('\'', "''")
+		);
+		let o = Command::new("powershell")
+			.args(["-NoProfile", "-NonInteractive", "-Command", &script])
+			.output()
+			.map_err(|e| wrap(e, "error running powershell"))?;
+
+		// the subject is last, since it may contain anything
+		let stdout = String::from_utf8_lossy(&o.stdout);
+		let mut parts = stdout.trim().splitn(3, '|');
+		let status = parts.next().unwrap_or_default();
+		let thumbprint = parts.next().unwrap_or_default();
+		let signer = parts.next().unwrap_or_default();
+
+		if status != "Valid" {
+			return Err(CodeError::UpdateSignatureNotVerified(format!(
+				"its Authenticode signature is {}",
+				status
+			))
+			.into());
+		}
+		if signer != subject
+			|| !thumbprints
+				.split(',')
+				.any(|t| t.trim().eq_ignore_ascii_case(thumbprint))
+		{
+			return Err(CodeError::UpdateSignatureNotVerified(format!(
+				"it's signed by {} ({}), not the CLI's publisher",
+				signer, thumbprint
+			))
+			.into());
+		}
+
+		Ok(())
+	}
+
+	/// Checks the code signature of the binary, which must be from the CLI's
+	/// Developer ID, since every binary has an ad-hoc signature on Apple
+	/// Silicon.
+	#[cfg(target_os = "macos")]
+	async fn verify_signature(
+		&self,
+		binary: &Path,
+		_archive: Option<&Path>,
+		_archive_url: Option<url::Url>,
+	) -> Result<(), AnyError> {
+		let team_id = crate::constants::VSCODE_CLI_MACOS_TEAM_ID.ok_or_else(|| {
+			CodeError::UpdateSignatureNotVerified(
+				"this build has no team ID to check signatures against".to_string(),
+			)
+		})?;
+		let requirement = format!(
+			"=anchor apple generic and certificate leaf[subject.OU] = \"{}\"",
+			team_id
+		);
+
+		let o = Command::new("codesign")
+			.args(["--verify", "--strict", "-R", &requirement])
+			.arg(binary)
+			.output()
+			.map_err(|e| wrap(e, "error running codesign"))?;
+
+		if !o.status.success() {
+			return Err(CodeError::UpdateSignatureNotVerified(
+				String::from_utf8_lossy(&o.stderr).trim().to_string(),
+			)
+			.into());
+		}
+
+		Ok(())
+	}
+
+	/// Checks the archive the binary came from against the detached signature
+	/// that's published next to it, as `<archive url>.sig`.
+	#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+	async fn verify_signature(
+		&self,
+		_binary: &Path,
+		archive: Option<&Path>,
+		archive_url: Option<url::Url>,
+	) -> Result<(), AnyError> {
+		let archive = archive.ok_or_else(|| {
+			CodeError::UpdateSignatureNotVerified("it wasn't downloaded in full".to_string())
+		})?;
+		let key = crate::constants::VSCODE_CLI_UPDATE_SIGNING_KEY.ok_or_else(|| {
+			CodeError::UpdateSignatureNotVerified(
+				"this build has no key to check signatures with".to_string(),
+			)
+		})?;
+		let archive_url = archive_url.ok_or_else(|| {
+			CodeError::UpdateSignatureNotVerified("the download has no URL".to_string())
+		})?;
+
+		let signature = self
+			.update_service
+			.get_small_file(format!("{}.sig", archive_url))
+			.await?;
+		let contents = fs::read(archive).map_err(|e| wrap(e, "error reading download"))?;
+		verify_detached_signature(key, &signature, &contents)
+			.map_err(|e| CodeError::UpdateSignatureNotVerified(e).into())
+	}
+
+	/// Installs a binary that wasn't downloaded with its archive, such as one
+	/// downloaded by a running tunnel, once its signature is verified. See
+	/// `can_install_bare_binaries`.
+	pub async fn install_bare_binary(&self, binary: &Path) -> Result<(), AnyError> {
+		if !ALLOW_UNSIGNED_UPDATES.load(Ordering::Relaxed) {
+			self.verify_signature(binary, None, None).await?;
+		}
+
+		self.install(binary)
+	}
+
+	/// Reinstalls the CLI that the last update replaced. The CLI it replaces is
+	/// kept in turn, so a rollback can be undone by rolling back again.
+	pub fn rollback(&self) -> Result<(), AnyError> {
+		if !self.previous_path.is_file() {
+			return Err(CodeError::NoPreviousCli.into());
+		}
+
+		self.install(&self.previous_path)
+	}
+
+	/// Replaces the running CLI with the downloaded binary, keeping the
+	/// running one for rollbacks.
+	fn install(&self, binary: &Path) -> Result<(), AnyError> {
+		let tempdir = tempdir().map_err(|e| wrap(e, "Failed to create temp dir"))?;
+		let target_path =
+			std::env::current_exe().map_err(|e| wrap(e, "could not get current exe"))?;
+		let staging_path = target_path.with_extension(".update");
+		fs::copy(binary, &staging_path).map_err(|e| wrap(e, "error copying to staging file"))?;
 
 		// 3. Copy file metadata, make sure the new binary is executable\
 		copy_file_metadata(&target_path, &staging_path)
 			.map_err(|e| wrap(e, "failed to set file permissions"))?;
 		validate_cli_is_good(&staging_path)?;
 
+		fs::copy(&target_path, &self.previous_path)
+			.map_err(|e| wrap(e, "error keeping the previous CLI"))?;
+
 		// Try to rename the old CLI to the tempdir, where it can get cleaned up by the
 		// OS later. However, this can fail if the tempdir is on a different drive
 		// than the installation dir. In this case just rename it to ".old".
@@ -98,6 +340,25 @@ impl<'a> SelfUpdate<'a> {
 	}
 }
 
+/// Checks a base64 ed25519 signature of the contents against a base64 public
+/// key.
+#[cfg_attr(any(target_os = "windows", target_os = "macos"), allow(dead_code))]
+fn verify_detached_signature(key: &str, signature: &[u8], contents: &[u8]) -> Result<(), String> {
+	use ed25519_dalek::{PublicKey, Signature};
+
+	let key = base64::decode(key)
+		.ok()
+		.and_then(|k| PublicKey::from_bytes(&k).ok())
+		.ok_or_else(|| "this build's signing key is invalid".to_string())?;
+	let signature = base64::decode(String::from_utf8_lossy(signature).trim())
+		.ok()
+		.and_then(|s| Signature::try_from(s.as_slice()).ok())
+		.ok_or_else(|| "its signature is malformed".to_string())?;
+
+	key.verify_strict(contents, &signature)
+		.map_err(|_| "its signature doesn't match".to_string())
+}
+
 fn validate_cli_is_good(exe_path: &Path) -> Result<(), AnyError> {
 	let o = Command::new(exe_path)
 		.args(["--version"])
@@ -117,7 +378,7 @@ fn validate_cli_is_good(exe_path: &Path) -> Result<(), AnyError> {
 	Ok(())
 }
 
-fn copy_updated_cli_to_path(unzipped_content: &Path, staging_path: &Path) -> Result<(), AnyError> {
+fn find_updated_cli(unzipped_content: &Path) -> Result<PathBuf, AnyError> {
 	let unzipped_files = fs::read_dir(unzipped_content)
 		.map_err(|e| wrap(e, "could not read update contents"))?
 		.collect::<Vec<_>>();
@@ -132,9 +393,7 @@ fn copy_updated_cli_to_path(unzipped_content: &Path, staging_path: &Path) -> Res
 	let archive_file = unzipped_files[0]
 		.as_ref()
 		.map_err(|e| wrap(e, "error listing update files"))?;
-	fs::copy(archive_file.path(), staging_path)
-		.map_err(|e| wrap(e, "error copying to staging file"))?;
-	Ok(())
+	Ok(archive_file.path())
 }
 
 #[cfg(target_os = "windows")]
@@ -161,3 +420,25 @@ fn copy_file_metadata(from: &Path, to: &Path) -> Result<(), std::io::Error> {
 
 	Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use ed25519_dalek::{ExpandedSecretKey, PublicKey, SecretKey};
+
+	#[test]
+	fn test_verify_detached_signature() {
+		let secret = SecretKey::from_bytes(&[7; 32]).unwrap();
+		let public = PublicKey::from(&secret);
+		let signature = ExpandedSecretKey::from(&secret).sign(b"cli archive", &public);
+		let key = base64::encode(public.as_bytes());
+		let signature = base64::encode(signature.to_bytes());
+
+		assert!(verify_detached_signature(&key, signature.as_bytes(), b"cli archive").is_ok());
+		assert!(verify_detached_signature(&key, signature.as_bytes(), b"cli archivf").is_err());
+		assert!(verify_detached_signature(&key, b"not a signature", b"cli archive").is_err());
+		assert!(
+			verify_detached_signature("bad key", signature.as_bytes(), b"cli archive").is_err()
+		);
+	}
+}
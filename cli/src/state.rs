@@ -6,26 +6,47 @@
 extern crate dirs;
 
 use std::{
-	fs::{create_dir, read_to_string, remove_dir_all, write},
+	ffi::OsStr,
+	fs::{create_dir, read_dir, read_to_string, remove_dir_all},
+	io,
 	path::{Path, PathBuf},
 	sync::{Arc, Mutex},
 };
 
 use serde::{de::DeserializeOwned, Serialize};
+use sha2::{Digest, Sha256};
 
 use crate::{
 	constants::VSCODE_CLI_QUALITY,
 	download_cache::DownloadCache,
-	util::errors::{wrap, AnyError, NoHomeForLauncherError, WrappedError},
+	tunnels::{paths::SERVER_FOLDER_NAME, server_inventory::ServerInventory},
+	util::{
+		errors::{wrap, AnyError, NoHomeForLauncherError, WrappedError},
+		permissions::{
+			create_owned_private_dir, create_private_dir_all, restrict_dir, restrict_file,
+			write_private,
+		},
+		runtime_dir::runtime_dir,
+	},
 };
 
+pub mod config;
+mod migrations;
+
 const HOME_DIR_ALTS: [&str; 2] = ["$HOME", "~"];
+const CONFIG_FILE_NAME: &str = "config.json";
 
 #[derive(Clone)]
 pub struct LauncherPaths {
 	pub server_cache: DownloadCache,
 	pub cli_cache: DownloadCache,
+	/// Shared by clones, so servers set up concurrently don't overwrite each
+	/// other's records.
+	pub server_inventory: ServerInventory,
 	root: PathBuf,
+	/// Data dir that was given, if it's read-only and state is kept in the
+	/// root instead.
+	read_only_root: Option<PathBuf>,
 }
 
 struct PersistedStateContainer<T>
@@ -58,7 +79,7 @@ where
 	fn save(&mut self, state: T) -> Result<(), WrappedError> {
 		let s = serde_json::to_string(&state).unwrap();
 		self.state = Some(state);
-		write(&self.path, s).map_err(|e| {
+		write_private(&self.path, s).map_err(|e| {
 			wrap(
 				e,
 				format!("error saving launcher state into {}", self.path.display()),
@@ -107,46 +128,83 @@ where
 }
 
 impl LauncherPaths {
-	pub fn new(root: &Option<String>) -> Result<LauncherPaths, AnyError> {
-		let root = root.as_deref().unwrap_or("~/.vscode-cli");
-		let mut replaced = root.to_owned();
-		for token in HOME_DIR_ALTS {
-			if root.contains(token) {
-				if let Some(home) = dirs::home_dir() {
-					replaced = root.replace(token, &home.to_string_lossy())
-				} else {
-					return Err(AnyError::from(NoHomeForLauncherError()));
-				}
-			}
+	/// Gets paths in the data dir. If the data dir can't be written to, such
+	/// as when running from an immutable image, state is instead kept in the
+	/// writable dir, or in a private dir of the user in the runtime dir if
+	/// it's not given.
+	pub fn new(
+		root: &Option<String>,
+		writable_root: &Option<String>,
+	) -> Result<LauncherPaths, AnyError> {
+		let root = replace_home(root.as_deref().unwrap_or("~/.vscode-cli"))?;
+		let exists = root.exists() || create_dir(&root).and_then(|_| restrict_dir(&root)).is_ok();
+		if exists && is_writable(&root) {
+			return Ok(LauncherPaths::new_without_replacements(root));
 		}
 
-		if !Path::new(&replaced).exists() {
-			create_dir(&replaced)
-				.map_err(|e| wrap(e, format!("error creating directory {}", &replaced)))?;
-		}
+		let (writable, created) = match writable_root {
+			Some(w) => {
+				let w = replace_home(w)?;
+				let created = create_private_dir_all(&w);
+				(w, created)
+			}
+			None => {
+				// the runtime dir may be the shared temp dir
+				let w = runtime_dir().join(user_data_dir_name());
+				let created = create_owned_private_dir(&w);
+				(w, created)
+			}
+		};
+		created
+			.and_then(|_| {
+				if is_writable(&writable) {
+					Ok(())
+				} else {
+					Err(io::Error::from(io::ErrorKind::PermissionDenied))
+				}
+			})
+			.map_err(|e| {
+				wrap(
+					e,
+					format!(
+						"data directory {} is not writable, and neither is {}",
+						root.display(),
+						writable.display()
+					),
+				)
+			})?;
 
-		Ok(LauncherPaths::new_without_replacements(PathBuf::from(
-			replaced,
-		)))
+		seed_writable_root(&root, &writable);
+		Ok(LauncherPaths {
+			read_only_root: Some(root),
+			..LauncherPaths::new_without_replacements(writable)
+		})
 	}
 
 	pub fn new_without_replacements(root: PathBuf) -> LauncherPaths {
-		// cleanup folders that existed before the new LRU strategy:
-		let _ = std::fs::remove_dir_all(root.join("server-insiders"));
-		let _ = std::fs::remove_dir_all(root.join("server-stable"));
+		// failed migrations are retried the next time the CLI runs
+		let _ = migrations::migrate(&root);
 
 		LauncherPaths {
 			server_cache: DownloadCache::new(root.join("servers")),
 			cli_cache: DownloadCache::new(root.join("cli")),
+			server_inventory: ServerInventory::new(root.join("servers.json"), root.join("servers")),
 			root,
+			read_only_root: None,
 		}
 	}
 
-	/// Root directory for the server launcher
+	/// Root directory for the server launcher, where its state is kept.
 	pub fn root(&self) -> &Path {
 		&self.root
 	}
 
+	/// Gets the data dir that was given, if it's read-only so the root is a
+	/// different, writable directory.
+	pub fn read_only_root(&self) -> Option<&Path> {
+		self.read_only_root.as_deref()
+	}
+
 	/// Lockfile for the running tunnel
 	pub fn tunnel_lockfile(&self) -> PathBuf {
 		self.root.join(format!(
@@ -155,6 +213,38 @@ impl LauncherPaths {
 		))
 	}
 
+	/// Directory for the extension downloads cached by the tunnel, shared by
+	/// all server installs.
+	pub fn extension_cache_dir(&self) -> PathBuf {
+		self.root.join("extension-cache")
+	}
+
+	/// Data dir of an isolated server instance, see `ServeParams::instance`.
+	/// It's kept outside the server cache so it outlives server versions.
+	pub fn server_instance_dir(&self, instance: &str) -> PathBuf {
+		let hash = format!("{:x}", Sha256::digest(instance.as_bytes()));
+		self.server_instances_dir().join(&hash[..16])
+	}
+
+	/// Directory holding the data dirs of isolated server instances.
+	pub fn server_instances_dir(&self) -> PathBuf {
+		self.root.join("server-instances")
+	}
+
+	/// Directory for the named event store, see `util::event_store`.
+	pub fn event_store_dir(&self, name: &str) -> PathBuf {
+		self.root.join("events").join(name)
+	}
+
+	/// Configuration operators can provide, see `util::tags`. This is read
+	/// from the given data dir even if it's read-only.
+	pub fn config_file(&self) -> PathBuf {
+		self.read_only_root
+			.as_ref()
+			.unwrap_or(&self.root)
+			.join(CONFIG_FILE_NAME)
+	}
+
 	/// Suggested path for tunnel service logs, when using file logs
 	pub fn service_log_file(&self) -> PathBuf {
 		self.root.join("tunnel-service.log")
@@ -173,3 +263,112 @@ impl LauncherPaths {
 		})
 	}
 }
+
+/// Name of the fallback writable dir, which is per-user since it may be in
+/// the shared temp dir.
+#[cfg(unix)]
+fn user_data_dir_name() -> String {
+	format!("vscode-cli-data-{}", unsafe { libc::getuid() })
+}
+
+#[cfg(not(unix))]
+fn user_data_dir_name() -> String {
+	"vscode-cli-data".to_string()
+}
+
+fn replace_home(path: &str) -> Result<PathBuf, AnyError> {
+	let mut replaced = path.to_owned();
+	for token in HOME_DIR_ALTS {
+		if path.contains(token) {
+			if let Some(home) = dirs::home_dir() {
+				replaced = path.replace(token, &home.to_string_lossy())
+			} else {
+				return Err(AnyError::from(NoHomeForLauncherError()));
+			}
+		}
+	}
+
+	Ok(PathBuf::from(replaced))
+}
+
+/// Gets whether files can be created in the directory. Checking permissions
+/// isn't enough, since the filesystem may be mounted read-only.
+fn is_writable(dir: &Path) -> bool {
+	tempfile::tempfile_in(dir).is_ok()
+}
+
+/// Copies state from the read-only data dir, such as credentials baked into
+/// an image, where it's not already in the writable dir. Servers installed in
+/// the data dir are linked into the writable dir's cache, so they're used
+/// without being downloaded while their logs are written to the writable dir.
+fn seed_writable_root(read_only: &Path, writable: &Path) {
+	for entry in read_dir(read_only).into_iter().flatten().flatten() {
+		let path = entry.path();
+		let target = writable.join(entry.file_name());
+		if path.is_file()
+			&& path.extension() == Some(OsStr::new("json"))
+			&& entry.file_name() != CONFIG_FILE_NAME
+			&& !target.exists()
+		{
+			let _ = std::fs::copy(&path, &target).and_then(|_| restrict_file(&target));
+		}
+	}
+
+	for entry in read_dir(read_only.join("servers"))
+		.into_iter()
+		.flatten()
+		.flatten()
+	{
+		let server = entry.path().join(SERVER_FOLDER_NAME);
+		let target = writable.join("servers").join(entry.file_name());
+		if !server.is_dir() || target.exists() {
+			continue;
+		}
+
+		let linked = create_private_dir_all(&target)
+			.and_then(|_| symlink_dir(&server, &target.join(SERVER_FOLDER_NAME)));
+		if linked.is_err() {
+			// otherwise the cache would think the server is installed
+			let _ = remove_dir_all(&target);
+		}
+	}
+}
+
+#[cfg(unix)]
+fn symlink_dir(original: &Path, link: &Path) -> io::Result<()> {
+	std::os::unix::fs::symlink(original, link)
+}
+
+#[cfg(windows)]
+fn symlink_dir(original: &Path, link: &Path) -> io::Result<()> {
+	std::os::windows::fs::symlink_dir(original, link)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_seed_writable_root() {
+		let read_only = tempfile::tempdir().unwrap();
+		let writable = tempfile::tempdir().unwrap();
+		let server = read_only.path().join("servers/stable-abc/server");
+		std::fs::create_dir_all(&server).unwrap();
+		std::fs::write(server.join("product.json"), "{}").unwrap();
+		std::fs::write(read_only.path().join("token.json"), "baked").unwrap();
+		std::fs::write(read_only.path().join("code_tunnel.json"), "baked").unwrap();
+		std::fs::write(read_only.path().join(CONFIG_FILE_NAME), "{}").unwrap();
+		std::fs::write(writable.path().join("code_tunnel.json"), "newer").unwrap();
+
+		seed_writable_root(read_only.path(), writable.path());
+
+		let read = |name: &str| std::fs::read_to_string(writable.path().join(name)).ok();
+		assert_eq!(read("token.json").as_deref(), Some("baked"));
+		assert_eq!(read("code_tunnel.json").as_deref(), Some("newer"));
+		assert_eq!(read(CONFIG_FILE_NAME), None);
+		assert_eq!(
+			read("servers/stable-abc/server/product.json").as_deref(),
+			Some("{}")
+		);
+	}
+}
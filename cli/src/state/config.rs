@@ -0,0 +1,91 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! The data dir's `config.json`, which hosts use to configure the CLI. The
+//! CLI only reads it.
+
+use std::{collections::HashMap, fs, io};
+
+use serde::Deserialize;
+
+use crate::{
+	tunnels::{dev_tunnels::TunnelLimits, server_version_policy::ServerVersionPolicy},
+	update_service::CustomQuality,
+	util::{errors::CodeError, tags::Tags},
+};
+
+use super::LauncherPaths;
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct CliConfig {
+	/// Tags added to telemetry, see `load_tags`.
+	pub tags: Tags,
+	/// See `load_download_mirror`.
+	pub download_mirror: Option<String>,
+	/// Former name of `download_mirror`, used if it isn't set.
+	pub update_endpoint: Option<String>,
+	/// See `load_custom_qualities`.
+	pub custom_qualities: HashMap<String, CustomQuality>,
+	/// Versions of the server that clients may install.
+	pub server_versions: ServerVersionPolicy,
+	/// Extensions installed in every server started for clients.
+	pub default_extensions: Vec<String>,
+	/// Limits of the tunnel service account, see `TunnelLimits`.
+	pub tunnel_limits: TunnelLimits,
+	/// Largest request body that's sent through connected clients when the
+	/// host can't make the request itself.
+	pub max_delegated_body_size: Option<usize>,
+}
+
+impl CliConfig {
+	/// Reads the config file, or gives the default config if there isn't one.
+	/// Files that can't be parsed are an error rather than being ignored, so
+	/// settings such as the version policy can't silently stop applying.
+	pub fn load(paths: &LauncherPaths) -> Result<Self, CodeError> {
+		let path = paths.config_file();
+		let invalid = |e: &dyn std::fmt::Display| {
+			CodeError::InvalidConfigFile(path.display().to_string(), e.to_string())
+		};
+
+		match fs::read_to_string(&path) {
+			Ok(s) => serde_json::from_str(&s).map_err(|e| invalid(&e)),
+			Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+			Err(e) => Err(invalid(&e)),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_load() {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		assert!(CliConfig::load(&paths)
+			.unwrap()
+			.default_extensions
+			.is_empty());
+
+		fs::write(
+			paths.config_file(),
+			r#"{"default_extensions":["ms-python.python"],"unknown":1}"#,
+		)
+		.unwrap();
+		assert_eq!(
+			CliConfig::load(&paths).unwrap().default_extensions,
+			vec!["ms-python.python".to_string()]
+		);
+
+		fs::write(
+			paths.config_file(),
+			r#"{"server_versions":{"allowed_commits":"abc"}}"#,
+		)
+		.unwrap();
+		assert!(CliConfig::load(&paths).is_err());
+	}
+}
@@ -0,0 +1,111 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Migrates the data dir from layouts used by older versions of the CLI. The
+//! layout's version is kept in the data dir, and each migration runs once, in
+//! order, the first time a newer CLI uses the data dir. Since the CLI can exit
+//! before the version is saved, migrations must be safe to run again.
+//!
+//! Only servers have moved between layouts so far. Credentials (`token.json`
+//! or the OS keyring) and the tunnel's state (`code_tunnel.json`) have kept
+//! their names and places, so there's nothing to migrate for them. Migrations
+//! that touch them must move rather than remove them, since losing them signs
+//! the user out and unregisters the tunnel. Servers are caches that are
+//! downloaded again when they're needed, so old installs are removed instead.
+
+use std::{
+	io,
+	path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::util::errors::{wrap, WrappedError};
+
+use super::PersistedState;
+
+const LAYOUT_FILE_NAME: &str = "layout.json";
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+struct Layout {
+	version: u32,
+}
+
+struct Migration {
+	version: u32,
+	description: &'static str,
+	run: fn(&Path) -> io::Result<()>,
+}
+
+/// Migrations in the order they're run. Add new ones to the end.
+const MIGRATIONS: &[Migration] = &[Migration {
+	version: 1,
+	description: "removing servers installed before the download cache",
+	run: remove_pre_cache_servers,
+}];
+
+/// Runs migrations the data dir hasn't had yet. If one fails, later ones
+/// aren't run, and it's tried again the next time.
+pub fn migrate(root: &Path) -> Result<(), WrappedError> {
+	let layout = PersistedState::<Layout>::new(root.join(LAYOUT_FILE_NAME));
+	let current = layout.load().version;
+
+	for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+		(migration.run)(root).map_err(|e| {
+			wrap(
+				e,
+				format!("error {} in {}", migration.description, root.display()),
+			)
+		})?;
+		layout.save(Layout {
+			version: migration.version,
+		})?;
+	}
+
+	Ok(())
+}
+
+/// Removes the entry if it exists.
+fn remove_entry(path: PathBuf) -> io::Result<()> {
+	let r = match std::fs::symlink_metadata(&path) {
+		Ok(m) if m.is_dir() => std::fs::remove_dir_all(&path),
+		Ok(_) => std::fs::remove_file(&path),
+		Err(e) => Err(e),
+	};
+
+	match r {
+		Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+		r => r,
+	}
+}
+
+/// Servers were installed in a folder per quality before they were kept in
+/// the `servers` download cache.
+fn remove_pre_cache_servers(root: &Path) -> io::Result<()> {
+	remove_entry(root.join("server-insiders"))?;
+	remove_entry(root.join("server-stable"))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_migrate() {
+		let root = tempfile::tempdir().unwrap();
+		let legacy = root.path().join("server-stable");
+		std::fs::create_dir_all(legacy.join("abc")).unwrap();
+
+		migrate(root.path()).unwrap();
+		assert!(!legacy.exists());
+		let layout = PersistedState::<Layout>::new(root.path().join(LAYOUT_FILE_NAME));
+		assert_eq!(layout.load().version, MIGRATIONS.last().unwrap().version);
+
+		// migrations that ran aren't run again
+		std::fs::create_dir_all(&legacy).unwrap();
+		migrate(root.path()).unwrap();
+		assert!(legacy.exists());
+	}
+}
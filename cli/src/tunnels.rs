@@ -3,16 +3,38 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
+pub mod auto_update;
+pub mod client;
 pub mod code_server;
+pub mod connection_token;
 pub mod dev_tunnels;
+pub mod editor_hook;
+pub mod extension_cache;
 pub mod legal;
 pub mod paths;
+pub mod prebuilt_server;
+pub mod resource_limits;
+pub mod server_inventory;
+pub mod server_version_policy;
 pub mod shutdown_signal;
 pub mod singleton_client;
 pub mod singleton_server;
+pub mod support_bundle;
 pub mod protocol;
 
+mod client_state;
+mod clipboard;
+mod connection_quality;
 mod control_server;
+mod download_progress;
+mod env_probe;
+#[cfg(target_os = "windows")]
+mod event_log_windows;
+mod file_transfer;
+mod file_watcher;
+mod install_manifest;
+mod keepalive;
+mod metrics;
 mod nosleep;
 #[cfg(target_os = "linux")]
 mod nosleep_linux;
@@ -20,7 +42,11 @@ mod nosleep_linux;
 mod nosleep_macos;
 #[cfg(target_os = "windows")]
 mod nosleep_windows;
+mod notifier;
 mod port_forwarder;
+mod port_scan;
+#[cfg(feature = "pty")]
+mod pty;
 mod server_bridge;
 mod server_multiplexer;
 mod service;
@@ -31,9 +57,11 @@ mod service_macos;
 #[cfg(target_os = "windows")]
 mod service_windows;
 mod socket_signal;
+mod spawned_processes;
+mod state_dump;
 mod wsl_server;
 
-pub use control_server::{serve, Next};
+pub use control_server::{serve, serve_stream, Next, DEFAULT_SLOW_RPC_THRESHOLD};
 pub use nosleep::SleepInhibitor;
 pub use service::{
 	create_service_manager, ServiceContainer, ServiceManager, SERVICE_LOG_FILE_NAME,
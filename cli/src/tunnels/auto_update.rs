@@ -0,0 +1,117 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Schedule of the update checks a tunnel makes on its own, with
+//! `--notify-updates`, and the updates it installs, with
+//! `--auto-update-interval` and `--maintenance-window`.
+
+use std::time::Duration;
+
+use chrono::NaiveTime;
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// How often updates are checked for if they're only announced to clients.
+pub const DEFAULT_UPDATE_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How often a tunnel checks for CLI updates, and when it may install them.
+/// Clients are told about the updates it finds either way.
+#[derive(Clone, Copy, Debug)]
+pub struct AutoUpdateSchedule {
+	pub interval: Duration,
+	pub window: Option<MaintenanceWindow>,
+	/// Whether updates are installed, rather than only announced.
+	pub install: bool,
+}
+
+impl AutoUpdateSchedule {
+	/// Gets how long it is from the local time until updates may be installed.
+	pub fn until_window(&self, now: NaiveTime) -> Duration {
+		match &self.window {
+			Some(w) => w.until_open(now),
+			None => Duration::ZERO,
+		}
+	}
+}
+
+/// Time of day, in local time, that updates are installed in. It spans
+/// midnight if it ends before it starts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MaintenanceWindow {
+	start: NaiveTime,
+	end: NaiveTime,
+}
+
+impl MaintenanceWindow {
+	pub fn contains(&self, t: NaiveTime) -> bool {
+		if self.start <= self.end {
+			t >= self.start && t < self.end
+		} else {
+			t >= self.start || t < self.end
+		}
+	}
+
+	/// Gets how long it is from the time until the window next opens, which
+	/// is zero if it's open.
+	fn until_open(&self, t: NaiveTime) -> Duration {
+		if self.contains(t) {
+			return Duration::ZERO;
+		}
+
+		let secs = (self.start - t).num_seconds().rem_euclid(SECONDS_PER_DAY);
+		Duration::from_secs(secs as u64)
+	}
+}
+
+/// Parses a maintenance window given on the command line, such as
+/// 02:00-05:00.
+pub fn parse_maintenance_window(s: &str) -> Result<MaintenanceWindow, String> {
+	let (start, end) = s
+		.split_once('-')
+		.ok_or_else(|| format!("'{}' isn't a window such as 02:00-05:00", s))?;
+	let parse_time = |t: &str| {
+		NaiveTime::parse_from_str(t.trim(), "%H:%M")
+			.map_err(|_| format!("'{}' isn't a time such as 02:00", t))
+	};
+
+	let window = MaintenanceWindow {
+		start: parse_time(start)?,
+		end: parse_time(end)?,
+	};
+	if window.start == window.end {
+		return Err(format!("'{}' is an empty window", s));
+	}
+
+	Ok(window)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn time(h: u32, m: u32) -> NaiveTime {
+		NaiveTime::from_hms_opt(h, m, 0).unwrap()
+	}
+
+	#[test]
+	fn test_maintenance_window() {
+		let w = parse_maintenance_window("02:00-05:00").unwrap();
+		assert!(w.contains(time(2, 0)));
+		assert!(w.contains(time(4, 59)));
+		assert!(!w.contains(time(5, 0)));
+		assert_eq!(w.until_open(time(3, 0)), Duration::ZERO);
+		assert_eq!(w.until_open(time(1, 30)), Duration::from_secs(30 * 60));
+		assert_eq!(w.until_open(time(6, 0)), Duration::from_secs(20 * 60 * 60));
+
+		let w = parse_maintenance_window("23:00-01:00").unwrap();
+		assert!(w.contains(time(23, 30)));
+		assert!(w.contains(time(0, 30)));
+		assert!(!w.contains(time(1, 30)));
+
+		assert!(parse_maintenance_window("02:00").is_err());
+		assert!(parse_maintenance_window("02:00-25:00").is_err());
+		assert!(parse_maintenance_window("02:00-02:00").is_err());
+	}
+}
@@ -0,0 +1,1041 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Client for the msgpack protocol spoken by the control server. This is the
+//! counterpart to what VS Code uses when it connects to a tunnel, and lets
+//! other Rust tools drive a remote CLI over any established connection.
+
+use std::{io, path::Path, sync::Mutex};
+
+use futures::StreamExt;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::{
+	io::{
+		AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader, DuplexStream,
+	},
+	sync::{mpsc, oneshot},
+	task::JoinHandle,
+};
+use tokio_util::codec::{Decoder, FramedRead};
+
+use crate::{
+	log,
+	msgpack_rpc::{new_msgpack_rpc, MsgPackCaller, MsgPackCodec, MsgPackSerializer},
+	rpc::{MaybeSync, ResponseError, RpcBatch, RpcDispatcher},
+	util::{
+		errors::{wrap, AnyError, CodeError, InvalidRpcDataError, ServerHasClosed},
+		hash::hash_file,
+		sync::{new_barrier, Barrier},
+	},
+};
+
+use super::file_transfer::{sha256_hex, MAX_CHUNK_SIZE};
+use super::protocol::{
+	AcquireCliParams, AskpassParams, AskpassResult, CallServerHttpChunk, CallServerHttpParams,
+	CallServerHttpResult, Capabilities, ClientIdentity, ClipboardParams, ConnectionStatsResult,
+	DisconnectSessionParams, DownloadChunkParams, DownloadChunkResult, DryRunParams,
+	DumpStateResult, EmptyObject, EnvProbeResult, FileChecksumParams, FileChecksumResult,
+	ForwardParams, ForwardResult, FsChangeEvent, FsDirEntry, FsPathParams, FsReadDirResult,
+	FsReadResult, FsStatResult, FsWriteParams, GcResult, GetHostnameResponse, HealthResult,
+	KillSpawnedParams, ListSessionsResult, ListSpawnedResult, ListeningPort, ListeningPortsResult,
+	MetricsResult, OpenExternalParams, OpenInEditorParams, ProcessSignal, PtySize,
+	RenameTunnelParams, ServeParams, ShutdownParams, SignalSpawnedParams, SpawnHistoryEntry,
+	SpawnHistoryParams, SpawnHistoryResult, SpawnParams, SpawnResult, SpawnedProcessInfo,
+	SysInfoResult, UnforwardParams, UpdateParams, UpdateResult, UploadChunkParams,
+	UploadChunkResult, VersionParams, WatchParams,
+};
+
+/// A connection to a control server. Dropping the client closes the
+/// connection.
+pub struct ControlClient {
+	caller: MsgPackCaller,
+	version: VersionParams,
+	capabilities: Capabilities,
+	closed: Barrier<()>,
+	task: JoinHandle<()>,
+}
+
+struct ClientContext {
+	version_tx: Mutex<Option<oneshot::Sender<VersionParams>>>,
+}
+
+impl ControlClient {
+	/// Starts a client on the given transport, and waits for the server to
+	/// send its version before returning. The client's version is sent in
+	/// reply so both sides agree on the capabilities to use.
+	pub async fn connect(
+		log: log::Logger,
+		read: impl AsyncRead + Send + Unpin + 'static,
+		write: impl AsyncWrite + Send + Unpin + 'static,
+	) -> Result<Self, AnyError> {
+		let (msg_tx, msg_rx) = mpsc::unbounded_channel();
+		let (version_tx, version_rx) = oneshot::channel();
+		let mut rpc = new_msgpack_rpc();
+		let caller = rpc.get_caller(msg_tx);
+
+		let mut rpc = rpc.methods(ClientContext {
+			version_tx: Mutex::new(Some(version_tx)),
+		});
+		rpc.register_sync("version", |v: VersionParams, c| {
+			if let Some(tx) = c.version_tx.lock().unwrap().take() {
+				tx.send(v).ok();
+			}
+			Ok(EmptyObject {})
+		});
+		rpc.register_sync("ping", |_: EmptyObject, _| Ok(EmptyObject {}));
+
+		let dispatcher = rpc.build(log.clone());
+		let (closed, closer) = new_barrier();
+		let task = tokio::spawn(async move {
+			if let Err(e) = run_client_loop(dispatcher, read, write, msg_rx).await {
+				debug!(log, "control connection closed: {}", e);
+			}
+			closer.open(());
+		});
+
+		let version = match version_rx.await {
+			Ok(v) => v,
+			Err(_) => return Err(ServerHasClosed().into()),
+		};
+
+		let local = VersionParams::default();
+		let capabilities = local.capabilities.negotiate(version.capabilities);
+		caller.notify("version", local);
+
+		Ok(Self {
+			caller,
+			version,
+			capabilities,
+			closed,
+			task,
+		})
+	}
+
+	/// Gets the version the server reported when the connection was made.
+	pub fn version(&self) -> &VersionParams {
+		&self.version
+	}
+
+	/// Gets the features supported by both the client and server.
+	pub fn capabilities(&self) -> Capabilities {
+		self.capabilities
+	}
+
+	/// Calls an arbitrary method on the server. If the call is dropped before
+	/// it completes, the server is asked to cancel it.
+	pub fn call<P, R>(&self, method: &'static str, params: P) -> PendingCall<R>
+	where
+		P: Serialize,
+		R: DeserializeOwned + Send + 'static,
+	{
+		let (id, rx) = self.caller.call_cancellable(method, params);
+		PendingCall {
+			rx,
+			closed: self.closed.clone(),
+			cancel: CancelOnDrop(Some((self.caller.clone(), id))),
+		}
+	}
+
+	/// Calls a method the server registered with `register_chunked`. If the
+	/// call is dropped before it completes, the server is asked to cancel it.
+	pub fn call_chunked<P, T, R>(&self, method: &'static str, params: P) -> ChunkedCall<T, R>
+	where
+		P: Serialize,
+		T: DeserializeOwned + Send + 'static,
+		R: DeserializeOwned + Send + 'static,
+	{
+		let (id, chunks, rx) = self.caller.call_chunked(method, params);
+		ChunkedCall {
+			chunks,
+			result: PendingCall {
+				rx,
+				closed: self.closed.clone(),
+				cancel: CancelOnDrop(Some((self.caller.clone(), id))),
+			},
+		}
+	}
+
+	/// Starts a batch of calls that are sent to the server together, saving
+	/// round trips. Calls are sent individually to servers that don't
+	/// support batches.
+	pub fn batch(&self) -> CallBatch<'_> {
+		CallBatch {
+			client: self,
+			batch: self
+				.capabilities
+				.contains(Capabilities::BATCH)
+				.then(|| self.caller.batch()),
+		}
+	}
+
+	pub async fn ping(&self) -> Result<(), AnyError> {
+		self.call::<_, EmptyObject>("ping", EmptyObject {})
+			.wait()
+			.await
+			.map(|_| ())
+	}
+
+	pub async fn get_hostname(&self) -> Result<String, AnyError> {
+		self.call::<_, GetHostnameResponse>("gethostname", EmptyObject {})
+			.wait()
+			.await
+			.map(|r| r.value)
+	}
+
+	/// Gets the uptime and connection stats of the remote CLI.
+	pub async fn health(&self) -> Result<HealthResult, AnyError> {
+		self.call("health", EmptyObject {}).wait().await
+	}
+
+	/// Gets the server's metrics in the Prometheus text exposition format.
+	pub async fn metrics(&self) -> Result<String, AnyError> {
+		self.call::<_, MetricsResult>("metrics", EmptyObject {})
+			.wait()
+			.await
+			.map(|r| r.text)
+	}
+
+	/// Gets smoothed measurements of this connection's quality.
+	pub async fn stats(&self) -> Result<ConnectionStatsResult, AnyError> {
+		self.call("stats", EmptyObject {}).wait().await
+	}
+
+	/// Gets details about the remote host, such as its OS and free resources.
+	pub async fn sysinfo(&self) -> Result<SysInfoResult, AnyError> {
+		self.call("sysinfo", EmptyObject {}).wait().await
+	}
+
+	/// Detects the toolchains installed on the remote, such as git and node.
+	pub async fn env_probe(&self) -> Result<EnvProbeResult, AnyError> {
+		self.call("envprobe", EmptyObject {}).wait().await
+	}
+
+	/// Gets a snapshot of the server's internal state, with secrets removed,
+	/// for attaching to bug reports. Its `Display` is human-readable.
+	pub async fn dump_state(&self) -> Result<DumpStateResult, AnyError> {
+		self.call("dumpstate", EmptyObject {}).wait().await
+	}
+
+	/// Tells the server who this client is, so that it can be told apart
+	/// from others in `list_sessions`.
+	pub async fn identify(&self, identity: ClientIdentity) -> Result<(), AnyError> {
+		self.call::<_, EmptyObject>("identify", identity)
+			.wait()
+			.await
+			.map(|_| ())
+	}
+
+	/// Asks the client that spawned this process to open the file. Only
+	/// available on an editor hook connection. With `wait`, returns once the
+	/// file is closed.
+	pub async fn open_in_editor(&self, params: OpenInEditorParams) -> Result<(), AnyError> {
+		self.call::<_, EmptyObject>("openineditor", params)
+			.wait()
+			.await
+			.map(|_| ())
+	}
+
+	/// Shuts down the tunnel after notifying connected clients and waiting
+	/// for the grace period. Requires the admin token the tunnel was started
+	/// with.
+	pub async fn shutdown(&self, params: ShutdownParams) -> Result<(), AnyError> {
+		self.call::<_, EmptyObject>("shutdown", params)
+			.wait()
+			.await
+			.map(|_| ())
+	}
+
+	/// Asks the client that spawned this process to prompt the user for a
+	/// credential. Only available on an editor hook connection. Returns None
+	/// if the user cancelled.
+	pub async fn askpass(&self, prompt: String) -> Result<Option<String>, AnyError> {
+		self.call::<_, AskpassResult>("askpass", AskpassParams { prompt })
+			.wait()
+			.await
+			.map(|r| r.response)
+	}
+
+	/// Asks the client that spawned this process to open the URL in the
+	/// user's browser. Only available on an editor hook connection.
+	pub async fn open_external(&self, url: String) -> Result<(), AnyError> {
+		self.call::<_, EmptyObject>("openexternal", OpenExternalParams { url })
+			.wait()
+			.await
+			.map(|_| ())
+	}
+
+	/// Renames the tunnel the server is hosted on, without restarting it.
+	pub async fn rename_tunnel(&self, name: &str) -> Result<(), AnyError> {
+		self.call::<_, EmptyObject>(
+			"renametunnel",
+			RenameTunnelParams {
+				name: name.to_string(),
+			},
+		)
+		.wait()
+		.await
+		.map(|_| ())
+	}
+
+	/// Releases resources the server no longer needs, returning what was freed.
+	/// Dry runs return what would be freed instead.
+	pub async fn gc(&self, dry_run: bool) -> Result<GcResult, AnyError> {
+		self.call("gc", DryRunParams { dry_run }).wait().await
+	}
+
+	/// Lists the clients connected to the server.
+	pub async fn list_sessions(&self) -> Result<ListSessionsResult, AnyError> {
+		self.call("listsessions", EmptyObject {}).wait().await
+	}
+
+	/// Closes a client's connection, showing it the reason if given. Closing
+	/// another client's connection requires the admin token the tunnel was
+	/// started with.
+	pub async fn disconnect_session(
+		&self,
+		params: DisconnectSessionParams,
+	) -> Result<(), AnyError> {
+		self.call::<_, EmptyObject>("disconnectsession", params)
+			.wait()
+			.await
+			.map(|_| ())
+	}
+
+	/// Asks the server to start the VS Code server and attach a bridge to it.
+	pub async fn serve(&self, params: ServeParams) -> Result<(), AnyError> {
+		self.call::<_, EmptyObject>("serve", params)
+			.wait()
+			.await
+			.map(|_| ())
+	}
+
+	pub async fn update(&self, do_update: bool, dry_run: bool) -> Result<UpdateResult, AnyError> {
+		self.call("update", UpdateParams { do_update, dry_run })
+			.wait()
+			.await
+	}
+
+	/// Removes stopped servers, returning the paths that were deleted, or that
+	/// would be for dry runs.
+	pub async fn prune(&self, dry_run: bool) -> Result<Vec<String>, AnyError> {
+		self.call("prune", DryRunParams { dry_run }).wait().await
+	}
+
+	pub async fn call_server_http(
+		&self,
+		params: CallServerHttpParams,
+	) -> Result<CallServerHttpResult, AnyError> {
+		self.call("callserverhttp", params).wait().await
+	}
+
+	/// Like `call_server_http`, but receives the body as the server reads
+	/// it. Servers that don't support chunked responses send the whole body
+	/// in one chunk.
+	pub async fn call_server_http_chunked(
+		&self,
+		params: CallServerHttpParams,
+	) -> Result<ChunkedCall<CallServerHttpChunk, EmptyObject>, AnyError> {
+		if self.capabilities.contains(Capabilities::CHUNKED_RESPONSES) {
+			return Ok(self.call_chunked("callserverhttpchunked", params));
+		}
+
+		let r = self.call_server_http(params).await?;
+		let (tx, chunks) = mpsc::unbounded_channel();
+		tx.send(CallServerHttpChunk::Head {
+			status: r.status,
+			headers: r.headers,
+		})
+		.ok();
+		tx.send(CallServerHttpChunk::Body { data: r.body }).ok();
+
+		let (result_tx, rx) = oneshot::channel();
+		result_tx.send(Ok(EmptyObject {})).ok();
+		Ok(ChunkedCall {
+			chunks,
+			result: PendingCall {
+				rx,
+				closed: self.closed.clone(),
+				cancel: CancelOnDrop(None),
+			},
+		})
+	}
+
+	/// Forwards the port on the remote, returning the URI it's available on.
+	pub async fn forward(&self, params: ForwardParams) -> Result<String, AnyError> {
+		self.call::<_, ForwardResult>("forward", params)
+			.wait()
+			.await
+			.map(|r| r.uri)
+	}
+
+	pub async fn get_clipboard(&self) -> Result<String, AnyError> {
+		self.call::<_, ClipboardParams>("getclipboard", EmptyObject {})
+			.wait()
+			.await
+			.map(|r| r.text)
+	}
+
+	pub async fn set_clipboard(&self, text: String) -> Result<(), AnyError> {
+		self.call::<_, EmptyObject>("setclipboard", ClipboardParams { text })
+			.wait()
+			.await
+			.map(|_| ())
+	}
+
+	/// Lists ports that are being listened on by processes on the remote.
+	pub async fn listening_ports(&self) -> Result<Vec<ListeningPort>, AnyError> {
+		self.call::<_, ListeningPortsResult>("listeningports", EmptyObject {})
+			.wait()
+			.await
+			.map(|r| r.ports)
+	}
+
+	pub async fn unforward(&self, port: u16) -> Result<(), AnyError> {
+		self.call::<_, EmptyObject>("unforward", UnforwardParams { port })
+			.wait()
+			.await
+			.map(|_| ())
+	}
+
+	/// Downloads a CLI on the remote and runs it with the given arguments.
+	pub async fn acquire_cli(&self, params: AcquireCliParams) -> Result<SpawnResult, AnyError> {
+		self.call("acquire_cli", params).wait().await
+	}
+
+	/// Spawns a process on the remote. Its output streams should be read
+	/// while waiting for the result, as the connection stalls when they fill.
+	pub async fn spawn(&self, params: SpawnParams) -> Result<SpawnedProcess, AnyError> {
+		let (mut streams, result) = self.call_duplex("spawn", params, 3).await?;
+		Ok(SpawnedProcess {
+			stdin: streams.remove(0),
+			stdout: streams.remove(0),
+			stderr: streams.remove(0),
+			result,
+		})
+	}
+
+	/// Watches paths on the remote for changes. Changes are reported until
+	/// the watch is closed or dropped.
+	pub async fn watch(&self, params: WatchParams) -> Result<FileWatch, AnyError> {
+		let (mut streams, result) = self.call_duplex("watch", params, 1).await?;
+		Ok(FileWatch {
+			events: FramedRead::new(streams.remove(0), MsgPackCodec {}),
+			result,
+		})
+	}
+
+	/// Calls a method the server registered with `register_duplex`, waiting
+	/// for it to start the expected number of streams.
+	async fn call_duplex<P, R>(
+		&self,
+		method: &'static str,
+		params: P,
+		count: usize,
+	) -> Result<(Vec<DuplexStream>, PendingCall<R>), AnyError>
+	where
+		P: Serialize,
+		R: DeserializeOwned + Send + 'static,
+	{
+		let (streams_rx, result_rx) = self.caller.call_with_streams(method, params);
+		let result = PendingCall {
+			rx: result_rx,
+			closed: self.closed.clone(),
+			cancel: CancelOnDrop(None),
+		};
+
+		let streams = match wait_or_closed(streams_rx, self.closed.clone()).await {
+			Ok(s) => s,
+			// streams are never started if the call fails
+			Err(_) => {
+				result.wait().await?;
+				return Err(ServerHasClosed().into());
+			}
+		};
+
+		if streams.len() != count {
+			return Err(InvalidRpcDataError(format!(
+				"expected {} streams for {}, got {}",
+				count,
+				method,
+				streams.len()
+			))
+			.into());
+		}
+
+		Ok((streams, result))
+	}
+
+	/// Lists processes started on this connection that are still running.
+	pub async fn list_spawned(&self) -> Result<Vec<SpawnedProcessInfo>, AnyError> {
+		self.call::<_, ListSpawnedResult>("listspawned", EmptyObject {})
+			.wait()
+			.await
+			.map(|r| r.processes)
+	}
+
+	/// Gets processes that were spawned on the host and have exited, most
+	/// recent first.
+	pub async fn spawn_history(
+		&self,
+		limit: Option<usize>,
+	) -> Result<Vec<SpawnHistoryEntry>, AnyError> {
+		self.call::<_, SpawnHistoryResult>("spawnhistory", SpawnHistoryParams { limit })
+			.wait()
+			.await
+			.map(|r| r.entries)
+	}
+
+	/// Kills a process started on this connection, and its children.
+	pub async fn kill_spawned(&self, pid: u32) -> Result<(), AnyError> {
+		self.call::<_, EmptyObject>("killspawned", KillSpawnedParams { pid })
+			.wait()
+			.await
+			.map(|_| ())
+	}
+
+	pub async fn signal_spawned(&self, pid: u32, signal: ProcessSignal) -> Result<(), AnyError> {
+		self.call::<_, EmptyObject>("signalspawned", SignalSpawnedParams { pid, signal })
+			.wait()
+			.await
+			.map(|_| ())
+	}
+
+	pub async fn download_chunk(
+		&self,
+		params: DownloadChunkParams,
+	) -> Result<DownloadChunkResult, AnyError> {
+		self.call("downloadchunk", params).wait().await
+	}
+
+	pub async fn upload_chunk(&self, params: UploadChunkParams) -> Result<u64, AnyError> {
+		self.call::<_, UploadChunkResult>("uploadchunk", params)
+			.wait()
+			.await
+			.map(|r| r.size)
+	}
+
+	pub async fn file_checksum(
+		&self,
+		params: FileChecksumParams,
+	) -> Result<FileChecksumResult, AnyError> {
+		self.call("filechecksum", params).wait().await
+	}
+
+	pub async fn fs_stat(&self, path: &str) -> Result<FsStatResult, AnyError> {
+		self.call("fsstat", fs_path(path)).wait().await
+	}
+
+	pub async fn fs_readdir(&self, path: &str) -> Result<Vec<FsDirEntry>, AnyError> {
+		self.call::<_, FsReadDirResult>("fsreaddir", fs_path(path))
+			.wait()
+			.await
+			.map(|r| r.entries)
+	}
+
+	/// Reads a small file in full. Larger files should be downloaded with
+	/// `download_file`.
+	pub async fn fs_read(&self, path: &str) -> Result<Vec<u8>, AnyError> {
+		self.call::<_, FsReadResult>("fsread", fs_path(path))
+			.wait()
+			.await
+			.map(|r| r.data)
+	}
+
+	/// Replaces the contents of the file, creating it if necessary.
+	pub async fn fs_write(&self, path: &str, data: Vec<u8>) -> Result<(), AnyError> {
+		self.call::<_, EmptyObject>(
+			"fswrite",
+			FsWriteParams {
+				path: path.to_string(),
+				data,
+			},
+		)
+		.wait()
+		.await
+		.map(|_| ())
+	}
+
+	/// Uploads a local file to the remote path. If the remote file is a
+	/// prefix of the local one, for example from an interrupted upload, the
+	/// upload continues where it left off.
+	pub async fn upload_file(&self, local: &Path, remote: &str) -> Result<(), AnyError> {
+		let local_sum = hash_file(local, None)
+			.await
+			.map_err(|e| wrap(e, format!("error reading {}", local.display())))?;
+
+		let mut offset = match self
+			.file_checksum(FileChecksumParams {
+				path: remote.to_string(),
+				length: None,
+			})
+			.await
+		{
+			Ok(r) if r.size <= local_sum.size => {
+				let prefix = hash_file(local, Some(r.size))
+					.await
+					.map_err(|e| wrap(e, format!("error reading {}", local.display())))?;
+				if prefix.sha256 == r.sha256 {
+					r.size
+				} else {
+					0
+				}
+			}
+			_ => 0,
+		};
+
+		let mut file = tokio::fs::File::open(local)
+			.await
+			.map_err(|e| wrap(e, format!("error opening {}", local.display())))?;
+		file.seek(io::SeekFrom::Start(offset))
+			.await
+			.map_err(|e| wrap(e, format!("error reading {}", local.display())))?;
+
+		let mut first = true;
+		loop {
+			let mut data = Vec::with_capacity(MAX_CHUNK_SIZE as usize);
+			(&mut file)
+				.take(MAX_CHUNK_SIZE as u64)
+				.read_to_end(&mut data)
+				.await
+				.map_err(|e| wrap(e, format!("error reading {}", local.display())))?;
+
+			// always send the first chunk, even if empty, so that the remote
+			// file is created and any stale tail is removed.
+			if data.is_empty() && !first {
+				break;
+			}
+
+			let len = data.len() as u64;
+			self.upload_chunk(UploadChunkParams {
+				path: remote.to_string(),
+				offset,
+				sha256: Some(sha256_hex(&data)),
+				data,
+				truncate: first,
+			})
+			.await?;
+
+			offset += len;
+			first = false;
+		}
+
+		let remote_sum = self
+			.file_checksum(FileChecksumParams {
+				path: remote.to_string(),
+				length: None,
+			})
+			.await?;
+		if remote_sum.sha256 != local_sum.sha256 {
+			return Err(CodeError::TransferChecksumMismatch(remote.to_string()).into());
+		}
+
+		Ok(())
+	}
+
+	/// Downloads a remote file to the local path. If the local file is a
+	/// prefix of the remote one, only the remainder is downloaded.
+	pub async fn download_file(&self, remote: &str, local: &Path) -> Result<(), AnyError> {
+		let remote_sum = self
+			.file_checksum(FileChecksumParams {
+				path: remote.to_string(),
+				length: None,
+			})
+			.await?;
+
+		let mut offset = match hash_file(local, None).await {
+			Ok(l) if l.size <= remote_sum.size => {
+				let prefix = self
+					.file_checksum(FileChecksumParams {
+						path: remote.to_string(),
+						length: Some(l.size),
+					})
+					.await?;
+				if prefix.sha256 == l.sha256 {
+					l.size
+				} else {
+					0
+				}
+			}
+			_ => 0,
+		};
+
+		let mut file = tokio::fs::OpenOptions::new()
+			.write(true)
+			.create(true)
+			.truncate(false)
+			.open(local)
+			.await
+			.map_err(|e| wrap(e, format!("error opening {}", local.display())))?;
+		file.set_len(offset)
+			.await
+			.map_err(|e| wrap(e, format!("error writing {}", local.display())))?;
+		file.seek(io::SeekFrom::Start(offset))
+			.await
+			.map_err(|e| wrap(e, format!("error writing {}", local.display())))?;
+
+		while offset < remote_sum.size {
+			let chunk = self
+				.download_chunk(DownloadChunkParams {
+					path: remote.to_string(),
+					offset,
+					length: MAX_CHUNK_SIZE,
+				})
+				.await?;
+			if chunk.sha256 != sha256_hex(&chunk.data) {
+				return Err(CodeError::ChunkChecksumMismatch(offset).into());
+			}
+
+			file.write_all(&chunk.data)
+				.await
+				.map_err(|e| wrap(e, format!("error writing {}", local.display())))?;
+			offset += chunk.data.len() as u64;
+			if chunk.eof || chunk.data.is_empty() {
+				break;
+			}
+		}
+
+		file.flush()
+			.await
+			.map_err(|e| wrap(e, format!("error writing {}", local.display())))?;
+		drop(file);
+
+		let local_sum = hash_file(local, None)
+			.await
+			.map_err(|e| wrap(e, format!("error reading {}", local.display())))?;
+		if local_sum.sha256 != remote_sum.sha256 {
+			return Err(CodeError::TransferChecksumMismatch(local.display().to_string()).into());
+		}
+
+		Ok(())
+	}
+}
+
+fn fs_path(path: &str) -> FsPathParams {
+	FsPathParams {
+		path: path.to_string(),
+	}
+}
+
+impl Drop for ControlClient {
+	fn drop(&mut self) {
+		self.task.abort();
+	}
+}
+
+/// A process started with `ControlClient::spawn`.
+pub struct SpawnedProcess {
+	pub stdin: DuplexStream,
+	pub stdout: DuplexStream,
+	pub stderr: DuplexStream,
+	pub result: PendingCall<SpawnResult>,
+}
+
+impl SpawnedProcess {
+	/// Resizes the terminal of a process spawned with `SpawnParams::pty`. In
+	/// that mode all output arrives on `stdout`.
+	pub async fn resize(&mut self, size: PtySize) -> Result<(), AnyError> {
+		let msg = rmp_serde::to_vec_named(&size).expect("expected to serialize");
+		self.stderr
+			.write_all(&msg)
+			.await
+			.map_err(|e| wrap(e, "error sending resize"))?;
+		Ok(())
+	}
+}
+
+/// A watch started with `ControlClient::watch`.
+pub struct FileWatch {
+	events: FramedRead<DuplexStream, MsgPackCodec>,
+	result: PendingCall<EmptyObject>,
+}
+
+impl FileWatch {
+	/// Waits for the next change. Returns None once the watch has ended.
+	pub async fn next_event(&mut self) -> Option<Result<FsChangeEvent, AnyError>> {
+		let frame = match self.events.next().await? {
+			Ok(f) => f,
+			Err(e) => return Some(Err(wrap(e, "error reading watch events").into())),
+		};
+
+		Some(rmp_serde::from_slice(&frame).map_err(|e| InvalidRpcDataError(e.to_string()).into()))
+	}
+
+	/// Stops watching, waiting for the server to clean up.
+	pub async fn close(mut self) -> Result<(), AnyError> {
+		self.events.get_mut().shutdown().await.ok();
+		self.result.wait().await.map(|_| ())
+	}
+}
+
+/// Calls started with `ControlClient::batch`.
+pub struct CallBatch<'a> {
+	client: &'a ControlClient,
+	batch: Option<RpcBatch<MsgPackSerializer>>,
+}
+
+impl CallBatch<'_> {
+	/// Adds a call to the batch. Its result is available once the batch is
+	/// sent and the server responds.
+	pub fn call<P, R>(&mut self, method: &'static str, params: P) -> PendingCall<R>
+	where
+		P: Serialize,
+		R: DeserializeOwned + Send + 'static,
+	{
+		let batch = match &mut self.batch {
+			Some(b) => b,
+			None => return self.client.call(method, params),
+		};
+
+		let (id, rx) = batch.call(method, params);
+		PendingCall {
+			rx,
+			closed: self.client.closed.clone(),
+			cancel: CancelOnDrop(Some((self.client.caller.clone(), id))),
+		}
+	}
+
+	pub fn send(self) {
+		if let Some(batch) = self.batch {
+			batch.send();
+		}
+	}
+}
+
+/// A call that's been sent to the server and is awaiting its response.
+pub struct PendingCall<R> {
+	rx: oneshot::Receiver<Result<R, ResponseError>>,
+	closed: Barrier<()>,
+	cancel: CancelOnDrop,
+}
+
+impl<R> PendingCall<R> {
+	/// Waits for the call to complete, failing if the connection closes first.
+	pub async fn wait(self) -> Result<R, AnyError> {
+		let mut cancel = self.cancel;
+		let result = wait_or_closed(self.rx, self.closed).await;
+		cancel.0.take();
+
+		match result? {
+			Ok(r) => Ok(r),
+			Err(e) => Err(CodeError::TunnelRpcCallFailed(e).into()),
+		}
+	}
+}
+
+/// A call whose response is preceded by chunks, which all arrive before the
+/// call completes.
+pub struct ChunkedCall<T, R> {
+	pub chunks: mpsc::UnboundedReceiver<T>,
+	pub result: PendingCall<R>,
+}
+
+/// Cancels a call on the server when dropped, unless it's been taken. Streams
+/// of duplex calls are used after their call is dropped, so they aren't
+/// cancelled.
+struct CancelOnDrop(Option<(MsgPackCaller, u32)>);
+
+impl Drop for CancelOnDrop {
+	fn drop(&mut self) {
+		if let Some((caller, id)) = self.0.take() {
+			caller.cancel(id);
+		}
+	}
+}
+
+async fn wait_or_closed<T>(
+	mut rx: oneshot::Receiver<T>,
+	mut closed: Barrier<()>,
+) -> Result<T, ServerHasClosed> {
+	if closed.is_open() {
+		return rx.try_recv().map_err(|_| ServerHasClosed());
+	}
+
+	tokio::select! {
+		biased;
+		r = rx => r.map_err(|_| ServerHasClosed()),
+		_ = closed.wait() => Err(ServerHasClosed()),
+	}
+}
+
+async fn run_client_loop(
+	dispatcher: RpcDispatcher<MsgPackSerializer, ClientContext>,
+	read: impl AsyncRead + Unpin,
+	mut write: impl AsyncWrite + Unpin,
+	mut msg_rx: mpsc::UnboundedReceiver<Vec<u8>>,
+) -> io::Result<()> {
+	let mut read = BufReader::new(read);
+	let mut decoder = MsgPackCodec {};
+	let mut decoder_buf = bytes::BytesMut::new();
+
+	loop {
+		tokio::select! {
+			r = read.read_buf(&mut decoder_buf) => {
+				if r? == 0 {
+					return Ok(());
+				}
+
+				while let Some(frame) = decoder.decode(&mut decoder_buf)? {
+					// Handlers are run in order, rather than spawned, so that
+					// stream data is written in the order it was received.
+					let response = match dispatcher.dispatch(&frame) {
+						MaybeSync::Sync(v) => v,
+						MaybeSync::Future(fut) => fut.await,
+						MaybeSync::Stream((_, fut)) => fut.await,
+					};
+
+					if let Some(v) = response {
+						write_prefixed(&mut write, &v).await?;
+					}
+				}
+			},
+			Some(m) = msg_rx.recv() => {
+				write_prefixed(&mut write, &m).await?;
+			},
+		}
+
+		write.flush().await?;
+	}
+}
+
+/// The control server reads messages with a u32 length prefix, see
+/// `U32PrefixedCodec`.
+async fn write_prefixed(write: &mut (impl AsyncWrite + Unpin), msg: &[u8]) -> io::Result<()> {
+	write.write_all(&(msg.len() as u32).to_be_bytes()).await?;
+	write.write_all(msg).await
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{msgpack_rpc::start_msgpack_rpc, util::errors::wrap};
+
+	#[tokio::test]
+	async fn test_client_calls_and_streams() {
+		let log = log::Logger::test();
+		let (client_io, server_io) = tokio::io::duplex(8192);
+
+		let (msg_tx, msg_rx) = mpsc::unbounded_channel();
+		let mut rpc = new_msgpack_rpc();
+		let caller = rpc.get_caller(msg_tx);
+		let mut rpc = rpc.methods(());
+		rpc.register_sync("ping", |_: EmptyObject, _| Ok(EmptyObject {}));
+		rpc.register_chunked("count", |chunks, n: u32, _| async move {
+			for i in 0..n {
+				chunks.send(i).await;
+			}
+			Ok(n)
+		});
+		rpc.register_duplex("spawn", 3, |mut streams, _: SpawnParams, _| async move {
+			let mut stdin = streams.remove(0);
+			let mut stdout = streams.remove(0);
+			tokio::io::copy(&mut stdin, &mut stdout)
+				.await
+				.map_err(|e| wrap(e, "error copying"))?;
+			Ok(SpawnResult {
+				message: "done".to_string(),
+				exit_code: 0,
+				usage: None,
+			})
+		});
+
+		caller.notify("version", VersionParams::default());
+		let (server_read, server_write) = tokio::io::split(server_io);
+		let (shutdown_rx, shutdown_opener) = new_barrier::<()>();
+		let dispatcher = rpc.build(log.clone());
+		tokio::spawn(async move {
+			start_msgpack_rpc(dispatcher, server_read, server_write, msg_rx, shutdown_rx)
+				.await
+				.ok();
+			drop(shutdown_opener);
+		});
+
+		let (read, write) = tokio::io::split(client_io);
+		let client = ControlClient::connect(log, read, write).await.unwrap();
+		assert_eq!(
+			client.version().protocol_version,
+			VersionParams::default().protocol_version
+		);
+		assert_eq!(client.capabilities(), Capabilities::local());
+		client.ping().await.unwrap();
+
+		let mut batch = client.batch();
+		let ping = batch.call::<_, EmptyObject>("ping", EmptyObject {});
+		let missing = batch.call::<_, EmptyObject>("missing", EmptyObject {});
+		batch.send();
+		ping.wait().await.unwrap();
+		assert!(missing.wait().await.is_err());
+
+		let mut count = client.call_chunked::<_, u32, u32>("count", 3);
+		assert_eq!(count.result.wait().await.unwrap(), 3);
+		let mut chunks = vec![];
+		while let Some(chunk) = count.chunks.recv().await {
+			chunks.push(chunk);
+		}
+		assert_eq!(chunks, vec![0, 1, 2]);
+
+		let mut process = client
+			.spawn(SpawnParams {
+				command: "cat".to_string(),
+				args: vec![],
+				env: Default::default(),
+				pty: None,
+				cwd: None,
+				shell: false,
+				kill_on_disconnect: false,
+				usage_interval_ms: None,
+			})
+			.await
+			.unwrap();
+
+		process.stdin.write_all(b"hello world").await.unwrap();
+		drop(process.stdin);
+
+		let mut output = String::new();
+		process.stdout.read_to_string(&mut output).await.unwrap();
+		assert_eq!(output, "hello world");
+		assert_eq!(process.result.wait().await.unwrap().exit_code, 0);
+	}
+
+	#[tokio::test]
+	async fn test_dropped_call_is_cancelled() {
+		let log = log::Logger::test();
+		let (client_io, server_io) = tokio::io::duplex(8192);
+		let (started_tx, mut started_rx) = mpsc::unbounded_channel();
+
+		let (msg_tx, msg_rx) = mpsc::unbounded_channel();
+		let mut rpc = new_msgpack_rpc();
+		let caller = rpc.get_caller(msg_tx);
+		let mut rpc = rpc.methods(started_tx);
+		rpc.register_async("hang", |_: EmptyObject, started| async move {
+			// the receiver errors once the handler is dropped
+			let (_running, dropped) = oneshot::channel::<()>();
+			started.send(dropped).ok();
+			futures::future::pending::<()>().await;
+			Ok(EmptyObject {})
+		});
+
+		caller.notify("version", VersionParams::default());
+		let (server_read, server_write) = tokio::io::split(server_io);
+		let (shutdown_rx, _shutdown_opener) = new_barrier::<()>();
+		let dispatcher = rpc.build(log.clone());
+		tokio::spawn(start_msgpack_rpc(
+			dispatcher,
+			server_read,
+			server_write,
+			msg_rx,
+			shutdown_rx,
+		));
+
+		let (read, write) = tokio::io::split(client_io);
+		let client = ControlClient::connect(log, read, write).await.unwrap();
+
+		let call = tokio::spawn(client.call::<_, EmptyObject>("hang", EmptyObject {}).wait());
+		let dropped = started_rx.recv().await.unwrap();
+		call.abort();
+
+		let r = tokio::time::timeout(std::time::Duration::from_secs(5), dropped).await;
+		assert!(r.unwrap().is_err());
+	}
+}
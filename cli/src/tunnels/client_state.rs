@@ -0,0 +1,140 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Values that clients keep on the host, such as the last folder they opened,
+//! so they act the same when reconnecting from any device. Values are kept per
+//! client identity, which is self-reported, so they mustn't be secrets.
+
+use std::{
+	collections::HashMap,
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	state::{LauncherPaths, PersistedState},
+	util::errors::{AnyError, CodeError},
+};
+
+use super::protocol::ClientIdentity;
+
+/// Largest size of a client's values, as JSON.
+const MAX_CLIENT_SIZE: usize = 64 * 1024;
+/// Clients whose values are kept. Values of the client that set them least
+/// recently are removed to make room for new ones.
+const MAX_CLIENTS: usize = 64;
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+struct ClientValues {
+	/// Seconds since the epoch that a value was last set.
+	updated_at: u64,
+	values: HashMap<String, serde_json::Value>,
+}
+
+type Stored = HashMap<String, ClientValues>;
+
+/// Store shared by the connections of a server. Clones share its state.
+#[derive(Clone)]
+pub struct ClientStateStore {
+	state: PersistedState<Stored>,
+}
+
+impl ClientStateStore {
+	pub fn new(paths: &LauncherPaths) -> Self {
+		Self {
+			state: PersistedState::new(paths.root().join("client_state.json")),
+		}
+	}
+
+	pub fn get(&self, client: &ClientIdentity, key: &str) -> Option<serde_json::Value> {
+		self.state
+			.load()
+			.remove(&client_key(client))
+			.and_then(|mut c| c.values.remove(key))
+	}
+
+	/// Sets the client's value, or removes it if it's None.
+	pub fn set(
+		&self,
+		client: &ClientIdentity,
+		key: String,
+		value: Option<serde_json::Value>,
+	) -> Result<(), AnyError> {
+		let client_key = client_key(client);
+		self.state.update(|stored| {
+			let mut values = stored.get(&client_key).cloned().unwrap_or_default();
+			match value {
+				Some(v) => values.values.insert(key, v),
+				None => values.values.remove(&key),
+			};
+
+			let size = serde_json::to_vec(&values.values).unwrap().len();
+			if size > MAX_CLIENT_SIZE {
+				return Err(CodeError::ClientStateTooLarge(size, MAX_CLIENT_SIZE));
+			}
+
+			stored.remove(&client_key);
+			if !values.values.is_empty() {
+				while stored.len() >= MAX_CLIENTS {
+					let oldest = stored
+						.iter()
+						.min_by_key(|(_, c)| c.updated_at)
+						.map(|(k, _)| k.clone())
+						.unwrap();
+					stored.remove(&oldest);
+				}
+
+				values.updated_at = SystemTime::now()
+					.duration_since(UNIX_EPOCH)
+					.map(|d| d.as_secs())
+					.unwrap_or_default();
+				stored.insert(client_key, values);
+			}
+
+			Ok(())
+		})??;
+		Ok(())
+	}
+}
+
+fn client_key(client: &ClientIdentity) -> String {
+	format!("{}:{}", client.name, client.user.as_deref().unwrap_or(""))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn identity(name: &str) -> ClientIdentity {
+		ClientIdentity {
+			name: name.to_string(),
+			version: None,
+			user: Some("alice".to_string()),
+		}
+	}
+
+	#[test]
+	fn test_get_set() {
+		let dir = tempfile::tempdir().unwrap();
+		let store = ClientStateStore {
+			state: PersistedState::new(dir.path().join("client_state.json")),
+		};
+		let (a, b) = (identity("a"), identity("b"));
+
+		store
+			.set(&a, "folder".to_string(), Some("/home".into()))
+			.unwrap();
+		assert_eq!(store.get(&a, "folder"), Some("/home".into()));
+		assert_eq!(store.get(&b, "folder"), None);
+
+		let big = serde_json::Value::String("x".repeat(MAX_CLIENT_SIZE));
+		assert!(store.set(&a, "big".to_string(), Some(big)).is_err());
+		assert_eq!(store.get(&a, "big"), None);
+
+		store.set(&a, "folder".to_string(), None).unwrap();
+		assert_eq!(store.get(&a, "folder"), None);
+	}
+}
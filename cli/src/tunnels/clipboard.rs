@@ -0,0 +1,107 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::process::Stdio;
+
+use tokio::{io::AsyncWriteExt, process::Command};
+
+use crate::util::{
+	command::{capture_command_and_check_status, check_output_status},
+	errors::{wrap, AnyError, CodeError},
+};
+
+/// Commands that read and write the host's clipboard, as program and args.
+struct ClipboardTool {
+	get: (&'static str, &'static [&'static str]),
+	set: (&'static str, &'static [&'static str]),
+}
+
+#[cfg(target_os = "macos")]
+fn clipboard_tool() -> Result<ClipboardTool, CodeError> {
+	Ok(ClipboardTool {
+		get: ("pbpaste", &[]),
+		set: ("pbcopy", &[]),
+	})
+}
+
+#[cfg(target_os = "windows")]
+fn clipboard_tool() -> Result<ClipboardTool, CodeError> {
+	// text is passed on stdin, rather than as an argument, to avoid quoting
+	Ok(ClipboardTool {
+		get: (
+			"powershell",
+			&[
+				"-NoProfile",
+				"-NonInteractive",
+				"-Command",
+				"Get-Clipboard -Raw",
+			],
+		),
+		set: (
+			"powershell",
+			&[
+				"-NoProfile",
+				"-NonInteractive",
+				"-Command",
+				"Set-Clipboard -Value ([Console]::In.ReadToEnd())",
+			],
+		),
+	})
+}
+
+/// Uses the Wayland or X11 clipboard, whichever the session has. Hosts
+/// without a graphical session have no clipboard.
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+fn clipboard_tool() -> Result<ClipboardTool, CodeError> {
+	if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+		return Ok(ClipboardTool {
+			get: ("wl-paste", &["--no-newline"]),
+			set: ("wl-copy", &[]),
+		});
+	}
+
+	if std::env::var_os("DISPLAY").is_some() {
+		return Ok(ClipboardTool {
+			get: ("xclip", &["-selection", "clipboard", "-out"]),
+			set: ("xclip", &["-selection", "clipboard", "-in"]),
+		});
+	}
+
+	Err(CodeError::ClipboardUnavailable)
+}
+
+pub async fn get_clipboard() -> Result<String, AnyError> {
+	let (cmd, args) = clipboard_tool()?.get;
+	let output = capture_command_and_check_status(cmd, args).await?;
+	Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+pub async fn set_clipboard(text: &str) -> Result<(), AnyError> {
+	let (cmd, args) = clipboard_tool()?.set;
+	let mut child = Command::new(cmd)
+		.args(args)
+		.stdin(Stdio::piped())
+		// xclip and wl-copy fork to keep serving the clipboard, and would
+		// hold open any pipes for their output indefinitely.
+		.stdout(Stdio::null())
+		.stderr(Stdio::null())
+		.spawn()
+		.map_err(|e| wrap(e, format!("error running {}", cmd)))?;
+
+	let mut stdin = child.stdin.take().unwrap();
+	stdin
+		.write_all(text.as_bytes())
+		.await
+		.map_err(|e| wrap(e, format!("error writing to {}", cmd)))?;
+	// closing stdin tells the tool the text is complete
+	drop(stdin);
+
+	let output = child
+		.wait_with_output()
+		.await
+		.map_err(|e| wrap(e, format!("error running {}", cmd)))?;
+	check_output_status(output, || cmd.to_string())?;
+	Ok(())
+}
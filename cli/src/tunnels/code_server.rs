@@ -3,27 +3,33 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 use super::paths::{InstalledServer, ServerPaths};
-use crate::async_pipe::get_socket_name;
+use crate::async_pipe::get_private_socket_name;
 use crate::constants::{
 	APPLICATION_NAME, EDITOR_WEB_URL, QUALITYLESS_PRODUCT_NAME, QUALITYLESS_SERVER_NAME,
 };
 use crate::download_cache::DownloadCache;
 use crate::options::{Quality, TelemetryLevel};
 use crate::state::LauncherPaths;
-use crate::tunnels::paths::{get_server_folder_name, SERVER_FOLDER_NAME};
+use crate::tunnels::install_manifest::{verify_install_manifest, write_install_manifest};
+use crate::tunnels::paths::{get_server_folder_name, instance_arg, SERVER_FOLDER_NAME};
+use crate::tunnels::prebuilt_server::PrebuiltServer;
+use crate::tunnels::resource_limits::ResourceLimits;
+use crate::tunnels::server_version_policy::ServerVersionPolicy;
 use crate::update_service::{
 	unzip_downloaded_release, Platform, Release, TargetKind, UpdateService,
 };
 use crate::util::command::{capture_command, kill_tree};
 use crate::util::errors::{wrap, AnyError, CodeError, ExtensionInstallFailed, WrappedError};
-use crate::util::http::{self, BoxedHttp};
-use crate::util::io::SilentCopyProgress;
+use crate::util::http::BoxedHttp;
+use crate::util::io::{ReportCopyProgress, SilentCopyProgress};
 use crate::util::machine::process_exists;
+use crate::util::permissions::{create_private_dir_all, write_private};
 use crate::{debug, info, log, spanf, trace, warning};
 use lazy_static::lazy_static;
 use opentelemetry::KeyValue;
 use regex::Regex;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::fs::File;
 use std::io::Write;
@@ -66,8 +72,29 @@ pub struct CodeServerArgs {
 	pub connection_token: Option<String>,
 	pub connection_token_file: Option<String>,
 	pub without_connection_token: bool,
+	// environment variables set on the server process
+	pub server_env: HashMap<String, String>,
+	// caps on the memory and CPU the server can use
+	pub resource_limits: ResourceLimits,
+	// flags from clients, which must be in `ALLOWED_EXTRA_ARGS`
+	pub extra_args: Vec<String>,
 }
 
+/// Settings a server is started with that clients attaching to it once it's
+/// running can't change. They're recorded with its pid, so clients that want
+/// other settings aren't attached to it.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ServerLaunchSettings {
+	#[serde(default)]
+	pub server_env: BTreeMap<String, String>,
+	#[serde(default)]
+	pub telemetry_level: Option<TelemetryLevel>,
+}
+
+/// Server flags that clients may give when starting servers. Flags that take
+/// a value must be given as `--flag=value`.
+const ALLOWED_EXTRA_ARGS: &[&str] = &["--log", "--enable-proposed-api", "--locale"];
+
 impl CodeServerArgs {
 	pub fn log_level(&self) -> log::Level {
 		if self.verbose {
@@ -81,6 +108,17 @@ impl CodeServerArgs {
 		self.telemetry_level == Some(TelemetryLevel::Off)
 	}
 
+	pub fn launch_settings(&self) -> ServerLaunchSettings {
+		ServerLaunchSettings {
+			server_env: self
+				.server_env
+				.iter()
+				.map(|(k, v)| (k.clone(), v.clone()))
+				.collect(),
+			telemetry_level: self.telemetry_level,
+		}
+	}
+
 	pub fn command_arguments(&self) -> Vec<String> {
 		let mut args = Vec::new();
 		if let Some(i) = &self.socket_path {
@@ -112,6 +150,7 @@ impl CodeServerArgs {
 		if let Some(i) = self.log {
 			args.push(format!("--log={}", i));
 		}
+		args.extend(self.extra_args.iter().cloned());
 
 		for extension in &self.install_extensions {
 			args.push(format!("--install-extension={}", extension));
@@ -143,21 +182,254 @@ impl CodeServerArgs {
 	}
 }
 
+impl CodeServerArgs {
+	/// Gets a builder for new arguments.
+	pub fn builder() -> CodeServerArgsBuilder {
+		CodeServerArgsBuilder::default()
+	}
+
+	/// Gets a builder that starts from these arguments.
+	pub fn to_builder(&self) -> CodeServerArgsBuilder {
+		CodeServerArgsBuilder { args: self.clone() }
+	}
+}
+
+/// Builds `CodeServerArgs`, checking that the combination of arguments is one
+/// the server accepts. Without this, bad combinations only fail once the
+/// server is started, where the error is harder to attribute.
+#[derive(Default)]
+pub struct CodeServerArgsBuilder {
+	args: CodeServerArgs,
+}
+
+impl CodeServerArgsBuilder {
+	pub fn host(mut self, host: impl Into<String>) -> Self {
+		self.args.host = Some(host.into());
+		self
+	}
+
+	pub fn port(mut self, port: u16) -> Self {
+		self.args.port = Some(port);
+		self
+	}
+
+	pub fn socket_path(mut self, path: impl Into<String>) -> Self {
+		self.args.socket_path = Some(path.into());
+		self
+	}
+
+	pub fn telemetry_level(mut self, level: TelemetryLevel) -> Self {
+		self.args.telemetry_level = Some(level);
+		self
+	}
+
+	pub fn log(mut self, level: log::Level) -> Self {
+		self.args.log = Some(level);
+		self
+	}
+
+	pub fn verbose(mut self, verbose: bool) -> Self {
+		self.args.verbose = verbose;
+		self
+	}
+
+	pub fn accept_server_license_terms(mut self, accept: bool) -> Self {
+		self.args.accept_server_license_terms = accept;
+		self
+	}
+
+	/// Adds extensions to install. Extensions that are already in the list
+	/// are skipped.
+	pub fn install_extensions(mut self, ids: impl IntoIterator<Item = String>) -> Self {
+		for id in ids {
+			if !contains_extension(&self.args.install_extensions, &id) {
+				self.args.install_extensions.push(id);
+			}
+		}
+		self
+	}
+
+	/// Adds extensions to uninstall. Extensions that are already in the list
+	/// are skipped.
+	pub fn uninstall_extensions(mut self, ids: impl IntoIterator<Item = String>) -> Self {
+		for id in ids {
+			if !contains_extension(&self.args.uninstall_extensions, &id) {
+				self.args.uninstall_extensions.push(id);
+			}
+		}
+		self
+	}
+
+	pub fn list_extensions(mut self, show_versions: bool, category: Option<String>) -> Self {
+		self.args.list_extensions = true;
+		self.args.show_versions = show_versions;
+		self.args.category = category;
+		self
+	}
+
+	pub fn pre_release(mut self, pre_release: bool) -> Self {
+		self.args.pre_release = pre_release;
+		self
+	}
+
+	pub fn force(mut self, force: bool) -> Self {
+		self.args.force = force;
+		self
+	}
+
+	pub fn start_server(mut self, start: bool) -> Self {
+		self.args.start_server = start;
+		self
+	}
+
+	pub fn connection_token(mut self, token: impl Into<String>) -> Self {
+		self.args.connection_token = Some(token.into());
+		self
+	}
+
+	pub fn connection_token_file(mut self, path: impl Into<String>) -> Self {
+		self.args.connection_token_file = Some(path.into());
+		self
+	}
+
+	pub fn without_connection_token(mut self, without: bool) -> Self {
+		self.args.without_connection_token = without;
+		self
+	}
+
+	/// Sets an environment variable on the server process.
+	pub fn server_env(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+		self.args.server_env.insert(key.into(), value.into());
+		self
+	}
+
+	pub fn resource_limits(mut self, limits: ResourceLimits) -> Self {
+		self.args.resource_limits = limits;
+		self
+	}
+
+	/// Adds flags given by a client, see `ALLOWED_EXTRA_ARGS`.
+	pub fn extra_args(mut self, args: impl IntoIterator<Item = String>) -> Self {
+		self.args.extra_args.extend(args);
+		self
+	}
+
+	/// Validates and returns the arguments.
+	pub fn build(self) -> Result<CodeServerArgs, CodeError> {
+		let a = &self.args;
+		let invalid = |m: String| Err(CodeError::InvalidServerArgs(m));
+
+		if a.socket_path.is_some() && (a.host.is_some() || a.port.is_some()) {
+			return invalid("a socket path cannot be used with a host or port".to_string());
+		}
+		if let Some(p) = &a.socket_path {
+			match Path::new(p).parent() {
+				Some(d) if d.as_os_str().is_empty() || d.is_dir() => {}
+				_ => return invalid(format!("the directory for socket {} does not exist", p)),
+			}
+		}
+
+		if a.resource_limits.memory_bytes == Some(0) || a.resource_limits.cpu_percent == Some(0) {
+			return invalid("resource limits must be greater than zero".to_string());
+		}
+
+		let token_options = [
+			a.connection_token.is_some(),
+			a.connection_token_file.is_some(),
+			a.without_connection_token,
+		];
+		if token_options.iter().filter(|o| **o).count() > 1 {
+			return invalid(
+				"only one of a connection token, token file, or no token can be given".to_string(),
+			);
+		}
+		if let Some(t) = &a.connection_token {
+			if t.is_empty()
+				|| !t
+					.chars()
+					.all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+			{
+				return invalid(
+					"connection tokens may only contain letters, numbers, '-' and '_'".to_string(),
+				);
+			}
+		}
+		if let Some(f) = &a.connection_token_file {
+			if !Path::new(f).is_file() {
+				return invalid(format!("connection token file {} does not exist", f));
+			}
+		}
+
+		for id in a.install_extensions.iter().chain(&a.uninstall_extensions) {
+			if id.trim().is_empty() || id.contains(char::is_whitespace) {
+				return invalid(format!("'{}' is not a valid extension", id));
+			}
+		}
+		if let Some(id) = a
+			.install_extensions
+			.iter()
+			.find(|id| contains_extension(&a.uninstall_extensions, id))
+		{
+			return invalid(format!("{} cannot be both installed and uninstalled", id));
+		}
+		if (a.pre_release || a.force) && a.install_extensions.is_empty() {
+			return invalid("--pre-release and --force require extensions to install".to_string());
+		}
+		if (a.show_versions || a.category.is_some()) && !a.list_extensions {
+			return invalid("--show-versions and --category require --list-extensions".to_string());
+		}
+
+		for arg in &a.extra_args {
+			let flag = arg.split_once('=').map_or(arg.as_str(), |(f, _)| f);
+			if !ALLOWED_EXTRA_ARGS.contains(&flag) || arg.contains('\0') {
+				return invalid(format!(
+					"'{}' is not allowed, only {} can be given",
+					arg,
+					ALLOWED_EXTRA_ARGS.join(", ")
+				));
+			}
+		}
+
+		Ok(self.args)
+	}
+}
+
+/// Extension IDs are case-insensitive.
+fn contains_extension(list: &[String], id: &str) -> bool {
+	list.iter().any(|e| e.eq_ignore_ascii_case(id))
+}
+
 /// Base server params that can be `resolve()`d to a `ResolvedServerParams`.
 /// Doing so fetches additional information like a commit ID if previously
 /// unspecified.
 pub struct ServerParamsRaw {
 	pub commit_id: Option<String>,
+	/// Release version, like 1.86.2, to use if there's no commit. Defaults to
+	/// the version policy's pinned version, and then the latest release. If a
+	/// version is pinned, it's an error to request another commit or version.
+	pub version: Option<String>,
 	pub quality: Quality,
+	/// Custom quality to fetch the server from, see `CustomQuality`.
+	pub custom_quality: Option<String>,
 	pub code_server_args: CodeServerArgs,
 	pub headless: bool,
 	pub platform: Platform,
+	/// Operator-provided install to use instead of downloading one.
+	pub prebuilt: Option<PrebuiltServer>,
+	/// Operator-provided archive to install from instead of downloading one.
+	pub archive: Option<PathBuf>,
+	/// Versions the host allows to be installed.
+	pub version_policy: Arc<ServerVersionPolicy>,
 }
 
 /// Server params that can be used to start a VS Code server.
 pub struct ResolvedServerParams {
 	pub release: Release,
 	pub code_server_args: CodeServerArgs,
+	/// Directory of an operator-provided install to run the server from.
+	pub prebuilt_dir: Option<PathBuf>,
+	/// Operator-provided archive to install the server from.
+	pub archive: Option<PathBuf>,
 }
 
 impl ResolvedServerParams {
@@ -176,9 +448,67 @@ impl ServerParamsRaw {
 		log: &log::Logger,
 		http: BoxedHttp,
 	) -> Result<ResolvedServerParams, AnyError> {
+		let (release, prebuilt_dir) = match &self.prebuilt {
+			Some(prebuilt) => (
+				Self::get_prebuilt_release(
+					prebuilt,
+					self.commit_id.as_deref(),
+					self.quality,
+					self.platform,
+				)?,
+				Some(prebuilt.dir.clone()),
+			),
+			// installs from archives mustn't need the network to find the commit
+			None if self.archive.is_some() && self.commit_id.is_none() => {
+				return Err(CodeError::ServerArchiveCommitRequired.into())
+			}
+			None => (self.get_or_fetch_commit_id(log, http).await?, None),
+		};
+
+		self.version_policy.check(&release)?;
+
 		Ok(ResolvedServerParams {
-			release: self.get_or_fetch_commit_id(log, http).await?,
+			release,
 			code_server_args: self.code_server_args,
+			archive: self.archive.filter(|_| prebuilt_dir.is_none()),
+			prebuilt_dir,
+		})
+	}
+
+	/// Gets the release of a prebuilt server, checking that it's the one the
+	/// client asked for.
+	fn get_prebuilt_release(
+		prebuilt: &PrebuiltServer,
+		commit_id: Option<&str>,
+		quality: Quality,
+		platform: Platform,
+	) -> Result<Release, AnyError> {
+		let mismatch = |reason: String| {
+			CodeError::InvalidPrebuiltServer(prebuilt.dir.display().to_string(), reason)
+		};
+
+		if let Some(c) = commit_id.filter(|c| *c != prebuilt.commit) {
+			return Err(mismatch(format!(
+				"it's commit {}, but {} was requested",
+				prebuilt.commit, c
+			))
+			.into());
+		}
+		if quality != prebuilt.quality {
+			return Err(mismatch(format!(
+				"it's {}, but {} was requested",
+				prebuilt.quality, quality
+			))
+			.into());
+		}
+
+		Ok(Release {
+			commit: prebuilt.commit.clone(),
+			quality: prebuilt.quality,
+			target: TargetKind::Server,
+			name: String::new(),
+			platform,
+			custom_quality: None,
 		})
 	}
 
@@ -192,19 +522,63 @@ impl ServerParamsRaw {
 			false => TargetKind::Web,
 		};
 
+		let update_service = UpdateService::new(log.clone(), http);
+		let pinned = self.version_policy.pinned_version();
+		if let (Some(pinned), Some(version)) = (pinned, &self.version) {
+			if version != pinned {
+				return Err(CodeError::ServerVersionPinned(pinned.to_string()).into());
+			}
+		}
+
 		if let Some(c) = &self.commit_id {
+			if let Some(pinned) = pinned {
+				let release = update_service
+					.get_release_by_semver_version(
+						self.platform,
+						target,
+						self.quality,
+						self.custom_quality.as_deref(),
+						pinned,
+					)
+					.await?;
+				if !release.commit.eq_ignore_ascii_case(c) {
+					return Err(CodeError::ServerVersionPinned(pinned.to_string()).into());
+				}
+			}
+
 			return Ok(Release {
 				commit: c.clone(),
 				quality: self.quality,
 				target,
 				name: String::new(),
 				platform: self.platform,
+				custom_quality: self.custom_quality.clone(),
 			});
 		}
 
-		UpdateService::new(log.clone(), http)
-			.get_latest_commit(self.platform, target, self.quality)
-			.await
+		match self.version.as_deref().or(pinned) {
+			Some(version) => {
+				update_service
+					.get_release_by_semver_version(
+						self.platform,
+						target,
+						self.quality,
+						self.custom_quality.as_deref(),
+						version,
+					)
+					.await
+			}
+			None => {
+				update_service
+					.get_latest_commit(
+						self.platform,
+						target,
+						self.quality,
+						self.custom_quality.as_deref(),
+					)
+					.await
+			}
+		}
 	}
 }
 
@@ -241,17 +615,20 @@ pub enum AnyCodeServer {
 }
 
 pub enum CodeServerOrigin {
-	/// A new code server, that opens the barrier when it exits.
-	New(Box<Child>),
+	/// A new code server, that opens the barrier when it exits. Its process
+	/// is reaped by `wait_for_exit` or `has_exited`.
+	New(Box<std::sync::Mutex<Child>>),
 	/// An existing code server with a PID.
 	Existing(u32),
+	/// A code server run by another supervisor, which isn't stopped by the CLI.
+	External,
 }
 
 impl CodeServerOrigin {
 	pub async fn wait_for_exit(&mut self) {
 		match self {
 			CodeServerOrigin::New(child) => {
-				child.wait().await.ok();
+				child.get_mut().unwrap().wait().await.ok();
 			}
 			CodeServerOrigin::Existing(pid) => {
 				let mut interval = interval(Duration::from_secs(30));
@@ -259,17 +636,40 @@ impl CodeServerOrigin {
 					interval.tick().await;
 				}
 			}
+			// its process isn't known, so it can't be watched
+			CodeServerOrigin::External => std::future::pending().await,
+		}
+	}
+
+	/// Gets whether the server exited. External servers aren't watched, so
+	/// they're never seen to exit.
+	pub fn has_exited(&self) -> bool {
+		match self {
+			// its process is a zombie until it's reaped, so it can't be polled
+			CodeServerOrigin::New(child) => !matches!(child.lock().unwrap().try_wait(), Ok(None)),
+			CodeServerOrigin::Existing(pid) => !process_exists(*pid),
+			CodeServerOrigin::External => false,
+		}
+	}
+
+	/// Gets the server's process ID, which isn't known for external servers.
+	pub fn pid(&self) -> Option<u32> {
+		match self {
+			CodeServerOrigin::New(child) => child.lock().unwrap().id(),
+			CodeServerOrigin::Existing(pid) => Some(*pid),
+			CodeServerOrigin::External => None,
 		}
 	}
 
 	pub async fn kill(&mut self) {
 		match self {
 			CodeServerOrigin::New(child) => {
-				child.kill().await.ok();
+				child.get_mut().unwrap().kill().await.ok();
 			}
 			CodeServerOrigin::Existing(pid) => {
 				kill_tree(*pid).await.ok();
 			}
+			CodeServerOrigin::External => {}
 		}
 	}
 }
@@ -311,6 +711,8 @@ pub struct ServerBuilder<'a> {
 	launcher_paths: &'a LauncherPaths,
 	server_paths: ServerPaths,
 	http: BoxedHttp,
+	/// Data dir of an isolated instance, if the server is one.
+	instance_dir: Option<PathBuf>,
 }
 
 impl<'a> ServerBuilder<'a> {
@@ -320,15 +722,34 @@ impl<'a> ServerBuilder<'a> {
 		launcher_paths: &'a LauncherPaths,
 		http: BoxedHttp,
 	) -> Self {
+		let mut server_paths = server_params
+			.as_installed_server()
+			.server_paths(launcher_paths);
+		// logs and the pidfile stay in the cache, since the install may be read-only
+		if let Some(dir) = &server_params.prebuilt_dir {
+			server_paths.executable = dir
+				.join("bin")
+				.join(server_params.release.quality.server_entrypoint());
+		}
+
 		Self {
 			logger,
 			server_params,
 			launcher_paths,
-			server_paths: server_params
-				.as_installed_server()
-				.server_paths(launcher_paths),
+			server_paths,
 			http,
+			instance_dir: None,
+		}
+	}
+
+	/// Runs an isolated server instance, with its own user data and
+	/// extensions in the directory, instead of the server shared by clients.
+	pub fn instance_dir(mut self, dir: Option<PathBuf>) -> Self {
+		if let Some(dir) = &dir {
+			self.server_paths = self.server_paths.for_instance(dir);
 		}
+		self.instance_dir = dir;
+		self
 	}
 
 	/// Gets any already-running server from this directory.
@@ -350,6 +771,21 @@ impl<'a> ServerBuilder<'a> {
 			return Ok(None);
 		}
 
+		// servers from before settings were recorded are taken to have none,
+		// so they're only used by clients that don't ask for any
+		let running_settings: ServerLaunchSettings =
+			fs::read_to_string(&self.server_paths.settingsfile)
+				.ok()
+				.and_then(|s| serde_json::from_str(&s).ok())
+				.unwrap_or_default();
+		if running_settings != self.server_params.code_server_args.launch_settings() {
+			return Err(CodeError::ServerSettingsMismatch(
+				self.server_params.release.quality,
+				self.server_params.release.commit.clone(),
+			)
+			.into());
+		}
+
 		do_extension_install_on_running_server(
 			&self.server_paths.executable,
 			&self.server_params.code_server_args.install_extensions,
@@ -368,6 +804,7 @@ impl<'a> ServerBuilder<'a> {
 				origin,
 			})))
 		} else if let Some(socket) = parse_socket_from(&contents) {
+			self.record_started(Some(pid), &socket);
 			Ok(Some(AnyCodeServer::Socket(SocketCodeServer {
 				commit_id: self.server_params.release.commit.to_owned(),
 				socket,
@@ -385,15 +822,57 @@ impl<'a> ServerBuilder<'a> {
 			"Installing and setting up {}...", QUALITYLESS_SERVER_NAME
 		);
 
+		if let Some(dir) = &self.instance_dir {
+			create_private_dir_all(dir)
+				.map_err(|e| wrap(e, "error creating server instance directory"))?;
+		}
+
+		if let Some(dir) = &self.server_params.prebuilt_dir {
+			info!(self.logger, "Using provided server in {}", dir.display());
+			return create_private_dir_all(&self.server_paths.server_dir)
+				.map_err(|e| AnyError::from(wrap(e, "error creating server directory")));
+		}
+
 		let update_service = UpdateService::new(self.logger.clone(), self.http.clone());
 		let name = get_server_folder_name(
 			self.server_params.release.quality,
 			&self.server_params.release.commit,
 		);
 
+		let key_files = server_key_files(self.server_params.release.quality);
+		if let Some(dir) = self.launcher_paths.server_cache.exists(&name) {
+			if let Err(problem) = verify_install_manifest(&dir, &key_files).await {
+				warning!(
+					self.logger,
+					"Server in {} is damaged ({}), reinstalling it",
+					dir.display(),
+					problem
+				);
+				self.launcher_paths.server_cache.delete(&name)?;
+			}
+		}
+
 		self.launcher_paths
 			.server_cache
 			.create(name, |target_dir| async move {
+				if let Some(archive) = &self.server_params.archive {
+					info!(self.logger, "Installing server from {}", archive.display());
+					let server_dir = target_dir.join(SERVER_FOLDER_NAME);
+					unzip_downloaded_release(archive, &server_dir, SilentCopyProgress())?;
+
+					// the archive's commit isn't known until it's unpacked
+					let release = &self.server_params.release;
+					let installed = PrebuiltServer::load(&server_dir, release.platform)?;
+					ServerParamsRaw::get_prebuilt_release(
+						&installed,
+						Some(&release.commit),
+						release.quality,
+						release.platform,
+					)?;
+					write_install_manifest(&target_dir, &key_files).await?;
+					return Ok(());
+				}
+
 				let tmpdir =
 					tempfile::tempdir().map_err(|e| wrap(e, "error creating temp download dir"))?;
 
@@ -409,23 +888,35 @@ impl<'a> ServerBuilder<'a> {
 					archive_path.display()
 				);
 
-				http::download_into_file(
-					&archive_path,
-					self.logger.get_download_logger("server download progress:"),
-					response,
-				)
-				.await?;
+				update_service
+					.download_into_file(
+						&archive_path,
+						self.logger.get_download_logger("server download progress:"),
+						response,
+					)
+					.await?;
+				update_service
+					.verify_download(&self.server_params.release, &archive_path)
+					.await?;
 
 				unzip_downloaded_release(
 					&archive_path,
 					&target_dir.join(SERVER_FOLDER_NAME),
 					SilentCopyProgress(),
 				)?;
+				write_install_manifest(&target_dir, &key_files).await?;
 
 				Ok(())
 			})
 			.await?;
 
+		if let Err(e) = self.launcher_paths.server_inventory.record_installed(
+			self.server_params.release.quality,
+			&self.server_params.release.commit,
+		) {
+			warning!(self.logger, "Error updating server inventory: {}", e);
+		}
+
 		debug!(self.logger, "Server setup complete");
 
 		Ok(())
@@ -465,8 +956,21 @@ impl<'a> ServerBuilder<'a> {
 		})
 	}
 
+	fn record_started(&self, pid: Option<u32>, socket: &Path) {
+		let release = &self.server_params.release;
+		if let Err(e) = self.launcher_paths.server_inventory.record_started(
+			release.quality,
+			&release.commit,
+			self.instance_dir.as_deref(),
+			pid,
+			socket,
+		) {
+			warning!(self.logger, "Error updating server inventory: {}", e);
+		}
+	}
+
 	pub async fn listen_on_default_socket(&self) -> Result<SocketCodeServer, AnyError> {
-		let requested_file = get_socket_name();
+		let requested_file = get_private_socket_name()?;
 		self.listen_on_socket(&requested_file).await
 	}
 
@@ -508,7 +1012,18 @@ impl<'a> ServerBuilder<'a> {
 			Ok(Ok(socket)) => Ok(socket),
 		}?;
 
+		// the server creates the socket with its umask, which may let others in
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::PermissionsExt;
+			if let Err(e) = fs::set_permissions(&socket, fs::Permissions::from_mode(0o700)) {
+				origin.kill().await;
+				return Err(wrap(e, "error restricting server socket").into());
+			}
+		}
+
 		info!(self.logger, "Server started");
+		self.record_started(origin.pid(), &socket);
 
 		Ok(SocketCodeServer {
 			commit_id: self.server_params.release.commit.to_owned(),
@@ -541,14 +1056,33 @@ impl<'a> ServerBuilder<'a> {
 
 		debug!(self.logger, "Starting server with command... {:?}", cmd);
 
-		let child = cmd
+		let limits = self
+			.server_params
+			.code_server_args
+			.resource_limits
+			.prepare(&mut cmd)
+			.map_err(|e| wrap(e, "error setting up resource limits for the server"))?;
+
+		let mut child = cmd
 			.stderr(std::process::Stdio::piped())
 			.stdout(std::process::Stdio::piped())
 			.spawn()
 			.map_err(|e| wrap(e, "error spawning server"))?;
 
-		self.server_paths
-			.write_pid(child.id().expect("expected server to have pid"))?;
+		if let Err(e) = limits.started(&child) {
+			// the server isn't run without the limits the operator asked for
+			child.start_kill().ok();
+			return Err(wrap(e, "error applying resource limits to the server").into());
+		}
+
+		let pid = child.id().expect("expected server to have pid");
+		self.server_paths.write_pid(pid)?;
+		let settings = self.server_params.code_server_args.launch_settings();
+		write_private(
+			&self.server_paths.settingsfile,
+			serde_json::to_string(&settings).unwrap(),
+		)
+		.map_err(|e| wrap(e, "error writing server settings"))?;
 
 		Ok(child)
 	}
@@ -568,7 +1102,11 @@ impl<'a> ServerBuilder<'a> {
 	fn get_base_command(&self) -> Command {
 		let mut cmd = Command::new(&self.server_paths.executable);
 		cmd.stdin(std::process::Stdio::null())
-			.args(self.server_params.code_server_args.command_arguments());
+			.args(self.server_params.code_server_args.command_arguments())
+			.envs(&self.server_params.code_server_args.server_env);
+		if let Some(dir) = &self.instance_dir {
+			cmd.arg(instance_arg(dir));
+		}
 		cmd
 	}
 }
@@ -656,7 +1194,7 @@ where
 		}
 	});
 
-	let origin = CodeServerOrigin::New(Box::new(child));
+	let origin = CodeServerOrigin::New(Box::new(std::sync::Mutex::new(child)));
 	(origin, listen_rx)
 }
 
@@ -762,10 +1300,21 @@ pub fn print_listening(log: &log::Logger, tunnel_name: &str) {
 	log.result(message);
 }
 
+/// Files of an installed server, relative to its cache dir, whose integrity
+/// is checked before it's reused.
+fn server_key_files(quality: Quality) -> Vec<PathBuf> {
+	let server = Path::new(SERVER_FOLDER_NAME);
+	vec![
+		server.join("bin").join(quality.server_entrypoint()),
+		server.join(if cfg!(windows) { "node.exe" } else { "node" }),
+	]
+}
+
 pub async fn download_cli_into_cache(
 	cache: &DownloadCache,
 	release: &Release,
 	update_service: &UpdateService,
+	progress: impl ReportCopyProgress + Send,
 ) -> Result<PathBuf, AnyError> {
 	let cache_name = format!(
 		"{}-{}-{}",
@@ -779,7 +1328,12 @@ pub async fn download_cli_into_cache(
 
 			let name = response.url_path_basename().unwrap();
 			let archive_path = tmpdir.path().join(name);
-			http::download_into_file(&archive_path, SilentCopyProgress(), response).await?;
+			update_service
+				.download_into_file(&archive_path, progress, response)
+				.await?;
+			update_service
+				.verify_download(release, &archive_path)
+				.await?;
 			unzip_downloaded_release(&archive_path, &target_dir, SilentCopyProgress())?;
 			Ok(())
 		})
@@ -797,3 +1351,123 @@ pub async fn download_cli_into_cache(
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_builder_dedupes_extensions() {
+		let args = CodeServerArgs::builder()
+			.install_extensions(vec!["ms-python.python".to_string()])
+			.install_extensions(vec![
+				"MS-Python.Python".to_string(),
+				"golang.go".to_string(),
+			])
+			.build()
+			.unwrap();
+		assert_eq!(
+			args.install_extensions,
+			vec!["ms-python.python", "golang.go"]
+		);
+	}
+
+	#[test]
+	fn test_builder_conflicts() {
+		assert!(CodeServerArgs::builder()
+			.socket_path("server.sock")
+			.port(8000)
+			.build()
+			.is_err());
+		assert!(CodeServerArgs::builder()
+			.connection_token("abc")
+			.without_connection_token(true)
+			.build()
+			.is_err());
+		assert!(CodeServerArgs::builder()
+			.install_extensions(vec!["golang.go".to_string()])
+			.uninstall_extensions(vec!["Golang.Go".to_string()])
+			.build()
+			.is_err());
+		assert!(CodeServerArgs::builder().force(true).build().is_err());
+	}
+
+	#[test]
+	fn test_builder_validates_values() {
+		assert!(CodeServerArgs::builder()
+			.connection_token("has spaces")
+			.build()
+			.is_err());
+		assert!(CodeServerArgs::builder()
+			.connection_token_file("/does/not/exist")
+			.build()
+			.is_err());
+		assert!(CodeServerArgs::builder()
+			.socket_path("/does/not/exist/server.sock")
+			.build()
+			.is_err());
+		assert!(CodeServerArgs::builder()
+			.install_extensions(vec!["".to_string()])
+			.build()
+			.is_err());
+		assert!(CodeServerArgs::builder()
+			.extra_args(vec!["--extensions-dir=/tmp".to_string()])
+			.build()
+			.is_err());
+		assert!(CodeServerArgs::builder()
+			.extra_args(vec!["--locale-x=de".to_string()])
+			.build()
+			.is_err());
+		assert!(CodeServerArgs::builder()
+			.resource_limits(ResourceLimits {
+				memory_bytes: Some(0),
+				cpu_percent: None,
+			})
+			.build()
+			.is_err());
+
+		let args = CodeServerArgs::builder()
+			.connection_token("a-valid_token1")
+			.server_env("A", "b")
+			.extra_args(vec!["--locale=de".to_string()])
+			.build()
+			.unwrap();
+		assert_eq!(
+			args.command_arguments(),
+			vec!["--connection-token=a-valid_token1", "--locale=de"]
+		);
+		assert_eq!(
+			args.launch_settings()
+				.server_env
+				.get("A")
+				.map(String::as_str),
+			Some("b")
+		);
+		assert_ne!(args.launch_settings(), ServerLaunchSettings::default());
+		assert_ne!(
+			args.launch_settings(),
+			args.to_builder()
+				.telemetry_level(TelemetryLevel::Off)
+				.build()
+				.unwrap()
+				.launch_settings()
+		);
+	}
+
+	#[test]
+	fn test_to_builder_keeps_args() {
+		let base = CodeServerArgs::builder()
+			.install_extensions(vec!["golang.go".to_string()])
+			.build()
+			.unwrap();
+		let args = base
+			.to_builder()
+			.install_extensions(vec!["ms-python.python".to_string()])
+			.build()
+			.unwrap();
+		assert_eq!(
+			args.install_extensions,
+			vec!["golang.go", "ms-python.python"]
+		);
+	}
+}
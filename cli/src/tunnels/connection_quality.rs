@@ -0,0 +1,108 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{sync::Mutex, time::Duration};
+
+use super::protocol::ConnectionStatsResult;
+
+/// Weight given to each new sample in the moving averages.
+const EMA_ALPHA: f64 = 0.2;
+
+/// Exponential moving average, which is empty until the first sample.
+#[derive(Default, Clone, Copy)]
+struct Ema(Option<f64>);
+
+impl Ema {
+	fn update(&mut self, sample: f64) {
+		self.0 = Some(match self.0 {
+			Some(v) => v + EMA_ALPHA * (sample - v),
+			None => sample,
+		});
+	}
+}
+
+#[derive(Default)]
+struct Averages {
+	rtt_ms: Ema,
+	loss: Ema,
+	tx_bytes_per_sec: Ema,
+	rx_bytes_per_sec: Ema,
+}
+
+/// Smoothed measurements of the quality of a single connection to the
+/// control server, which are kept for the lifetime of the connection.
+#[derive(Default)]
+pub struct ConnectionQuality {
+	averages: Mutex<Averages>,
+}
+
+impl ConnectionQuality {
+	/// Records the result of a ping to the client. `rtt` is None if the ping
+	/// went unanswered.
+	pub fn record_ping(&self, rtt: Option<Duration>) {
+		let mut averages = self.averages.lock().unwrap();
+		match rtt {
+			Some(rtt) => {
+				averages.rtt_ms.update(rtt.as_secs_f64() * 1000.0);
+				averages.loss.update(0.0);
+			}
+			None => averages.loss.update(1.0),
+		}
+	}
+
+	/// Records the number of bytes sent and received over the elapsed period.
+	pub fn record_transfer(&self, tx: usize, rx: usize, elapsed: Duration) {
+		let secs = elapsed.as_secs_f64();
+		if secs <= 0.0 {
+			return;
+		}
+
+		let mut averages = self.averages.lock().unwrap();
+		averages.tx_bytes_per_sec.update(tx as f64 / secs);
+		averages.rx_bytes_per_sec.update(rx as f64 / secs);
+	}
+
+	pub fn stats(&self) -> ConnectionStatsResult {
+		let averages = self.averages.lock().unwrap();
+		ConnectionStatsResult {
+			rtt_ms: averages.rtt_ms.0,
+			loss: averages.loss.0,
+			tx_bytes_per_sec: averages.tx_bytes_per_sec.0,
+			rx_bytes_per_sec: averages.rx_bytes_per_sec.0,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_ema() {
+		let mut ema = Ema::default();
+		assert_eq!(ema.0, None);
+		ema.update(10.0);
+		assert_eq!(ema.0, Some(10.0));
+		ema.update(20.0);
+		assert_eq!(ema.0, Some(12.0));
+	}
+
+	#[test]
+	fn test_records_loss_and_rtt() {
+		let quality = ConnectionQuality::default();
+		quality.record_ping(Some(Duration::from_millis(50)));
+		quality.record_ping(None);
+
+		let stats = quality.stats();
+		assert_eq!(stats.rtt_ms, Some(50.0));
+		assert_eq!(stats.loss, Some(0.2));
+		assert_eq!(stats.tx_bytes_per_sec, None);
+
+		quality.record_transfer(1000, 500, Duration::from_secs(2));
+		let stats = quality.stats();
+		assert_eq!(stats.tx_bytes_per_sec, Some(500.0));
+		assert_eq!(stats.rx_bytes_per_sec, Some(250.0));
+	}
+}
@@ -0,0 +1,86 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Connection token that servers are started with from a file, with
+//! `--use-connection-token-file`, so it can be rotated and given to clients
+//! without restarting the tunnel. Servers read the file when they start, so
+//! ones that are already running keep the token they started with.
+
+use std::{
+	io,
+	path::{Path, PathBuf},
+};
+
+use rand::{distributions::Alphanumeric, Rng};
+
+use crate::{state::LauncherPaths, util::permissions::write_private};
+
+const TOKEN_LENGTH: usize = 32;
+
+pub struct ConnectionTokenFile {
+	path: PathBuf,
+}
+
+impl ConnectionTokenFile {
+	pub fn new(paths: &LauncherPaths) -> Self {
+		Self::at(paths.root().join("connection-token"))
+	}
+
+	pub fn at(path: PathBuf) -> Self {
+		Self { path }
+	}
+
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+
+	pub fn read(&self) -> io::Result<String> {
+		std::fs::read_to_string(&self.path).map(|s| s.trim().to_string())
+	}
+
+	/// Reads the token, generating one if there isn't one yet.
+	pub fn get_or_create(&self) -> io::Result<String> {
+		match self.read() {
+			Ok(token) if !token.is_empty() => Ok(token),
+			_ => self.rotate(),
+		}
+	}
+
+	/// Replaces the token with a new one, which is returned.
+	pub fn rotate(&self) -> io::Result<String> {
+		let token: String = rand::thread_rng()
+			.sample_iter(&Alphanumeric)
+			.take(TOKEN_LENGTH)
+			.map(char::from)
+			.collect();
+
+		// renamed into place so servers starting meanwhile don't read half a token
+		let temp = self
+			.path
+			.with_extension(format!("tmp-{}", rand::random::<u32>()));
+		write_private(&temp, &token)?;
+		std::fs::rename(&temp, &self.path)?;
+		Ok(token)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_get_or_create_and_rotate() {
+		let dir = tempfile::tempdir().unwrap();
+		let file = ConnectionTokenFile::at(dir.path().join("connection-token"));
+
+		let token = file.get_or_create().unwrap();
+		assert_eq!(token.len(), TOKEN_LENGTH);
+		assert_eq!(file.get_or_create().unwrap(), token);
+
+		let rotated = file.rotate().unwrap();
+		assert_ne!(rotated, token);
+		assert_eq!(file.read().unwrap(), rotated);
+	}
+}
@@ -2,64 +2,125 @@
  *  Copyright (c) Microsoft Corporation. All rights reserved.
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
-use crate::async_pipe::get_socket_rw_stream;
+use crate::async_pipe::{
+	check_peer_is_current_user, get_socket_rw_stream, listen_socket_rw_stream, socket_stream_split,
+};
+use crate::auth::Auth;
 use crate::constants::{CONTROL_PORT, PRODUCT_NAME_LONG};
+use crate::json_rpc::{bridge_json_to_msgpack, new_json_rpc, start_json_rpc};
 use crate::log;
-use crate::msgpack_rpc::U32PrefixedCodec;
-use crate::rpc::{MaybeSync, RpcBuilder, RpcDispatcher, Serialization};
+use crate::msgpack_rpc::{Frame, MsgPackSerializer, U32PrefixedCodec, DEFAULT_MAX_FRAME_SIZE};
+use crate::options::Quality;
+use crate::rpc::{protocol_error, ChunkSender, MaybeSync, RpcBuilder, RpcCaller, RpcDispatcher};
 use crate::self_update::SelfUpdate;
-use crate::state::LauncherPaths;
+use crate::state::{config::CliConfig, LauncherPaths};
 use crate::tunnels::protocol::HttpRequestParams;
 use crate::tunnels::socket_signal::CloseReason;
-use crate::update_service::{Platform, Release, TargetKind, UpdateService};
+use crate::update_service::{
+	custom_quality, Platform, Release, TargetKind, UpdateService, DEFAULT_LISTED_RELEASES,
+};
 use crate::util::errors::{
-	wrap, AnyError, CodeError, InvalidRpcDataError, MismatchedLaunchModeError,
-	NoAttachedServerError,
+	wrap, AnyError, CodeError, MismatchedLaunchModeError, NoAttachedServerError,
 };
 use crate::util::http::{
-	DelegatedHttpRequest, DelegatedSimpleHttp, FallbackSimpleHttp, ReqwestSimpleHttp,
+	BoxedHttp, DelegatedHttpRequest, DelegatedSimpleHttp, FallbackSimpleHttp, ReqwestSimpleHttp,
+	TLS_BACKEND,
 };
 use crate::util::io::SilentCopyProgress;
 use crate::util::is_integrated_cli;
+use crate::util::machine;
+use crate::util::permissions::set_mode;
+use crate::util::rate_limit::RateLimit;
 use crate::util::sync::{new_barrier, Barrier};
+use crate::util::tags::tags;
 
 use futures::stream::FuturesUnordered;
 use futures::FutureExt;
 use opentelemetry::trace::SpanKind;
 use opentelemetry::KeyValue;
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
 use tokio::pin;
+use tokio::task::JoinHandle;
 use tokio_util::codec::Decoder;
 
+use serde::de::IgnoredAny;
 use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
-use std::sync::Arc;
-use std::time::Instant;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use sysinfo::{DiskExt, Pid, PidExt, ProcessExt, System, SystemExt};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, DuplexStream};
-use tokio::sync::{mpsc, Mutex};
+use tokio::sync::{mpsc, Mutex, Notify};
 
+use super::auto_update::AutoUpdateSchedule;
+use super::client_state::ClientStateStore;
+use super::clipboard::{get_clipboard, set_clipboard};
 use super::code_server::{
-	download_cli_into_cache, AnyCodeServer, CodeServerArgs, ServerBuilder, ServerParamsRaw,
-	SocketCodeServer,
+	download_cli_into_cache, AnyCodeServer, CodeServerArgs, CodeServerOrigin, ResolvedServerParams,
+	ServerBuilder, ServerLaunchSettings, ServerParamsRaw, SocketCodeServer,
+};
+use super::connection_quality::ConnectionQuality;
+use super::connection_token::ConnectionTokenFile;
+use super::dev_tunnels::{ActiveTunnel, DevTunnels};
+use super::download_progress::DownloadProgress;
+use super::editor_hook::EditorHook;
+use super::env_probe::probe_environment;
+use super::file_transfer::{
+	handle_download_chunk, handle_file_checksum, handle_fs_read, handle_fs_readdir, handle_fs_stat,
+	handle_fs_write, handle_upload_chunk,
+};
+use super::file_watcher::handle_watch;
+use super::keepalive::KeepAlive;
+use super::metrics::{MetricsSnapshot, ServerMetrics};
+use super::notifier::{serialize_notification, Notifier};
+use super::paths::{
+	get_server_folder_name, get_stale_instances, get_stopped_servers, prune_stale_instances,
+	prune_stopped_servers,
 };
-use super::dev_tunnels::ActiveTunnel;
-use super::paths::prune_stopped_servers;
 use super::port_forwarder::{PortForwarding, PortForwardingProcessor};
+use super::port_scan::list_listening_ports;
+use super::prebuilt_server::ServerPathPolicy;
 use super::protocol::{
-	AcquireCliParams, CallServerHttpParams, CallServerHttpResult, ClientRequestMethod, EmptyObject,
-	ForwardParams, ForwardResult, GetHostnameResponse, HttpBodyParams, HttpHeadersParams,
-	ServeParams, ServerLog, ServerMessageParams, SpawnParams, SpawnResult, ToClientRequest,
-	UnforwardParams, UpdateParams, UpdateResult, VersionParams,
+	AcquireCliParams, AdminParams, BridgeStateDump, CallServerHttpChunk, CallServerHttpParams,
+	CallServerHttpResult, Capabilities, ClientIdentity, ClientRequestMethod, ClipboardParams,
+	CodeServerExitedParams, CodeServerHealth, CodeServerStateDump, ConfigStateDump,
+	ConnectionTokenResult, DisconnectSessionParams, DownloadChunkParams, DrainingParams,
+	DryRunParams, DumpStateResult, EmptyObject, FileChecksumParams, ForwardParams, ForwardResult,
+	FsPathParams, FsWriteParams, GcResult, GetClientStateParams, GetClientStateResult,
+	GetHostnameResponse, HealthResult, HttpBodyParams, HttpHeadersParams, KillSpawnedParams,
+	ListReleasesParams, ListReleasesResult, ListServersResult, ListSessionsResult,
+	ListSpawnedResult, ListeningPortsResult, MetricsResult, ReleaseInfo, ReleaseTarget,
+	RenameTunnelParams, ServeParams, ServerHealthResult, ServerLog, ServerMessageParams,
+	SessionInfo, SetClientStateParams, ShutdownParams, ShutdownReason, ShuttingDownParams,
+	SignalSpawnedParams, SocketStateDump, SpawnHistoryParams, SpawnHistoryResult, SpawnParams,
+	SpawnResult, SysInfoResult, UnforwardParams, UpdateAvailableParams, UpdateParams,
+	UpdateRelease, UpdateResult, UploadChunkParams, VersionParams, WatchParams,
 };
+#[cfg(feature = "pty")]
+use super::pty::spawn_pty;
 use super::server_bridge::ServerBridge;
 use super::server_multiplexer::ServerMultiplexer;
+use super::server_version_policy::ServerVersionPolicy;
 use super::shutdown_signal::ShutdownSignal;
 use super::socket_signal::{
 	ClientMessageDecoder, ServerMessageDestination, ServerMessageSink, SocketSignal,
 };
+use super::spawned_processes::{command_line, SpawnHistory, SpawnedProcesses};
+use super::state_dump::{redact_args, redact_path};
 
 type HttpRequestsMap = Arc<std::sync::Mutex<HashMap<u32, DelegatedHttpRequest>>>;
-type CodeServerCell = Arc<Mutex<Option<SocketCodeServer>>>;
+/// Servers a connection's bridges attach to.
+type CodeServerMap = Arc<Mutex<HashMap<CodeServerKey, SocketCodeServer>>>;
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct CodeServerKey {
+	quality: Quality,
+	commit: String,
+	/// Isolated instance, see `ServeParams::instance`.
+	instance: Option<String>,
+	settings: ServerLaunchSettings,
+}
 
 struct HandlerContext {
 	/// Log handle for the server
@@ -70,8 +131,8 @@ struct HandlerContext {
 	socket_tx: mpsc::Sender<SocketSignal>,
 	/// Configured launcher paths.
 	launcher_paths: LauncherPaths,
-	/// Connected VS Code Server
-	code_server: CodeServerCell,
+	/// Connected VS Code Servers
+	code_servers: CodeServerMap,
 	/// Potentially many "websocket" connections to client
 	server_bridges: ServerMultiplexer,
 	// the cli arguments used to start the code server
@@ -84,8 +145,276 @@ struct HandlerContext {
 	http: Arc<FallbackSimpleHttp>,
 	/// requests being served by the client
 	http_requests: HttpRequestsMap,
+	/// state shared with other connections to the server
+	server_state: ServerState,
+	/// smoothed measurements of the connection's quality
+	quality: Arc<ConnectionQuality>,
+	/// processes started by the client that are still running
+	spawned: SpawnedProcesses,
+	/// ID of the socket in `ServerState::sockets`
+	socket_id: u32,
+	/// lets `code <file>` in spawned processes open files in the client
+	editor_hook: Option<EditorHook>,
+	/// channel to signal the server to respawn or shut down
+	server_tx: mpsc::Sender<ServerSignal>,
+	/// features supported by both sides, updated when the client sends its
+	/// version
+	capabilities: Arc<AtomicU32>,
+	/// sends notifications to this or other connected clients
+	notifier: Notifier,
+	/// results of checking the health of this connection's code servers
+	code_server_health: Arc<std::sync::Mutex<HashMap<CodeServerKey, CodeServerHealth>>>,
+}
+
+/// State shared between all connections to the control server.
+#[derive(Clone)]
+struct ServerState {
+	/// When the tunnel was last (re)connected.
+	tunnel_connected_at: SystemTime,
+	/// Handles for each connected socket, keyed by an ID that's unique to
+	/// the socket.
+	sockets: Arc<std::sync::Mutex<HashMap<u32, SocketHandles>>>,
+	/// Counters kept for the lifetime of the server.
+	metrics: Arc<ServerMetrics>,
+	/// Usage of processes that clients spawned, kept across restarts.
+	spawn_history: Option<Arc<SpawnHistory>>,
+	/// Secret that clients give to shut down the tunnel. Remote shutdown is
+	/// disabled if not set.
+	admin_token: Option<Arc<String>>,
+	/// Limits on how often each connection can call methods.
+	rate_limits: Arc<HashMap<String, RateLimit>>,
+	/// Operator-provided servers that are used instead of downloading one.
+	server_path_policy: Arc<ServerPathPolicy>,
+	/// Server versions that clients may install, read from the config file.
+	server_version_policy: Arc<ServerVersionPolicy>,
+	/// Extensions installed in every server started for clients, read from
+	/// the config file.
+	default_extensions: Arc<Vec<String>>,
+	/// Largest request body sent through clients, read from the config file.
+	max_delegated_body_size: Option<usize>,
+	/// Largest message that clients may send.
+	max_frame_size: usize,
+	/// Calls that take longer than this are logged.
+	slow_rpc_threshold: Duration,
+	/// Whether connections are closed when clients break the protocol.
+	strict_protocol: bool,
+	/// How often clients are pinged, adapted to the relay's idle timeout.
+	keepalive: Arc<KeepAlive>,
+	/// Sends notifications to connected sockets.
+	notifier: Notifier,
+	/// Values clients keep on the host, see `getclientstate`.
+	client_state: ClientStateStore,
+	/// Locks held while servers are set up, keyed by their release.
+	install_locks: Arc<std::sync::Mutex<HashMap<String, Arc<Mutex<()>>>>>,
+	/// Servers being set up, which are waited for before respawning.
+	installs: Arc<Installs>,
+	/// Held while the CLI is updated, so updates don't race on its staging
+	/// file.
+	updating: Arc<Mutex<()>>,
+	/// Newer CLI that clients were told about, see `updateavailable`.
+	available_update: Arc<std::sync::Mutex<Option<UpdateAvailableParams>>>,
+}
+
+impl ServerState {
+	fn new(
+		log: &log::Logger,
+		launcher_paths: &LauncherPaths,
+		admin_token: Option<&str>,
+		rate_limits: &[(String, RateLimit)],
+		server_path_policy: &ServerPathPolicy,
+		max_frame_size: usize,
+		slow_rpc_threshold: Duration,
+		strict_protocol: bool,
+	) -> Result<Self, CodeError> {
+		// fails closed, so a config that can't be read doesn't lift its policy
+		let config = CliConfig::load(launcher_paths)?;
+
+		let spawn_history = match SpawnHistory::open(
+			launcher_paths.event_store_dir("spawn-history"),
+			SPAWN_HISTORY_SEGMENT_SIZE,
+			SPAWN_HISTORY_SEGMENTS,
+		) {
+			Ok(h) => Some(Arc::new(h)),
+			Err(e) => {
+				warning!(log, "Spawned process history is unavailable: {}", e);
+				None
+			}
+		};
+
+		let server_version_policy = config.server_versions;
+		if server_version_policy.is_restricted() {
+			info!(
+				log,
+				"Server versions are restricted by {}",
+				launcher_paths.config_file().display()
+			);
+		}
+
+		let default_extensions = config.default_extensions;
+		if !default_extensions.is_empty() {
+			info!(
+				log,
+				"Servers are started with extensions {}",
+				default_extensions.join(", ")
+			);
+		}
+
+		Ok(ServerState {
+			tunnel_connected_at: SystemTime::now(),
+			sockets: Arc::new(std::sync::Mutex::new(HashMap::new())),
+			metrics: Arc::new(ServerMetrics::default()),
+			spawn_history,
+			admin_token: admin_token.map(|t| Arc::new(t.to_string())),
+			rate_limits: Arc::new(
+				DEFAULT_RATE_LIMITS
+					.iter()
+					.map(|(m, l)| (m.to_string(), *l))
+					.chain(rate_limits.iter().cloned())
+					.collect(),
+			),
+			server_path_policy: Arc::new(server_path_policy.clone()),
+			server_version_policy: Arc::new(server_version_policy),
+			default_extensions: Arc::new(default_extensions),
+			max_delegated_body_size: config.max_delegated_body_size,
+			max_frame_size,
+			slow_rpc_threshold,
+			strict_protocol,
+			keepalive: Arc::new(KeepAlive::default()),
+			notifier: Notifier::default(),
+			client_state: ClientStateStore::new(launcher_paths),
+			install_locks: Arc::new(std::sync::Mutex::new(HashMap::new())),
+			installs: Arc::new(Installs::default()),
+			updating: Arc::new(Mutex::new(())),
+			available_update: Arc::new(std::sync::Mutex::new(None)),
+		})
+	}
+
+	/// Counts an install of the release's server until the returned guard is
+	/// dropped, and gets the lock that's held while it's set up, so
+	/// concurrent serve calls wait for one install instead of racing. Fails
+	/// once installs are being drained.
+	fn start_install(
+		&self,
+		release: &Release,
+	) -> Result<(InstallGuard, Arc<Mutex<()>>), CodeError> {
+		let guard = self.installs.start()?;
+		let lock = self
+			.install_locks
+			.lock()
+			.unwrap()
+			.entry(get_server_folder_name(release.quality, &release.commit))
+			.or_default()
+			.clone();
+		Ok((guard, lock))
+	}
+
+	/// Stops new servers from being set up, and waits for the ones that are
+	/// to finish, up to the timeout. Returns whether they all finished.
+	async fn wait_for_installs(&self, timeout: Duration) -> bool {
+		self.installs.state.lock().unwrap().draining = true;
+		tokio::time::timeout(timeout, async {
+			while self.installs.state.lock().unwrap().running > 0 {
+				self.installs.finished.notified().await;
+			}
+		})
+		.await
+		.is_ok()
+	}
+}
+
+/// Counts the servers being set up, see `ServerState::start_install`.
+#[derive(Default)]
+struct Installs {
+	state: std::sync::Mutex<InstallsState>,
+	/// Notified when an install finishes.
+	finished: Notify,
+}
+
+#[derive(Default)]
+struct InstallsState {
+	running: usize,
+	/// Whether new installs are refused, set when the server is draining.
+	draining: bool,
+}
+
+impl Installs {
+	fn start(self: &Arc<Self>) -> Result<InstallGuard, CodeError> {
+		let mut state = self.state.lock().unwrap();
+		if state.draining {
+			return Err(CodeError::InstallsDraining);
+		}
+
+		state.running += 1;
+		Ok(InstallGuard(self.clone()))
+	}
+}
+
+/// Counts an install as running until it's dropped.
+struct InstallGuard(Arc<Installs>);
+
+impl Drop for InstallGuard {
+	fn drop(&mut self) {
+		self.0.state.lock().unwrap().running -= 1;
+		// stores a permit if the drain isn't waiting yet, so it's not missed
+		self.0.finished.notify_one();
+	}
+}
+
+struct SocketHandles {
+	server_bridges: ServerMultiplexer,
+	http_requests: HttpRequestsMap,
+	tx_counter: Arc<AtomicUsize>,
+	rx_counter: Arc<AtomicUsize>,
+	socket_tx: mpsc::Sender<SocketSignal>,
+	code_servers: CodeServerMap,
+	spawned: SpawnedProcesses,
+	connected_at: SystemTime,
+	/// Identity the client gave with `identify`.
+	identity: Option<ClientIdentity>,
 }
 
+/// Number of messages that can be queued to write to a socket.
+const SOCKET_QUEUE_SIZE: usize = 4;
+
+/// Size of the pipe between a JSON control socket connection and the server.
+const CONTROL_SOCKET_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Time clients get to save their work after a shutdown is requested, if the
+/// request doesn't say.
+const DEFAULT_SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(30);
+const MAX_SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(10 * 60);
+/// Calls that take longer than this are logged, unless overridden with
+/// `--slow-rpc-threshold`.
+pub const DEFAULT_SLOW_RPC_THRESHOLD: Duration = Duration::from_secs(10);
+/// How often servers the connection started are checked for having exited.
+const CODE_SERVER_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Times a server is restarted for a connection before it's left stopped, so
+/// a server that crashes on start isn't restarted forever.
+const MAX_CODE_SERVER_RESTARTS: u32 = 3;
+/// How often code servers are asked for their version to check they're
+/// responsive.
+const CODE_SERVER_HEALTH_INTERVAL: Duration = Duration::from_secs(30);
+const CODE_SERVER_HEALTH_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often the latest server is checked for and prefetched, with
+/// `--prefetch-server`.
+const SERVER_PREFETCH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How long respawning after an update waits for servers being set up.
+const INSTALL_DRAIN_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// How long a ping can go unanswered before it's considered lost.
+const PING_TIMEOUT: Duration = Duration::from_secs(10);
+/// Spawn history is kept in 4 segments of 256KB, several thousand entries.
+const SPAWN_HISTORY_SEGMENT_SIZE: u64 = 256 * 1024;
+const SPAWN_HISTORY_SEGMENTS: usize = 4;
+/// Number of history entries returned when the client doesn't give a limit.
+const DEFAULT_SPAWN_HISTORY_LIMIT: usize = 100;
+/// Limits on expensive methods, which can be overridden with `--rpc-rate-limit`.
+const DEFAULT_RATE_LIMITS: &[(&str, RateLimit)] = &[
+	("update", RateLimit::per_minute(5)),
+	("acquire_cli", RateLimit::per_minute(10)),
+	("serve", RateLimit::per_minute(30)),
+	("spawn", RateLimit::per_minute(60)),
+];
+
 static MESSAGE_ID_COUNTER: AtomicU32 = AtomicU32::new(0);
 
 // Gets a next incrementing number that can be used in logs
@@ -94,8 +423,13 @@ pub fn next_message_id() -> u32 {
 }
 
 impl HandlerContext {
+	fn capabilities(&self) -> Capabilities {
+		Capabilities::from_bits(self.capabilities.load(Ordering::SeqCst))
+	}
+
 	async fn dispose(&self) {
 		self.server_bridges.dispose().await;
+		self.spawned.kill_on_disconnect().await;
 		info!(self.log, "Disposed of connection to running server.");
 	}
 }
@@ -107,6 +441,8 @@ enum ServerSignal {
 	/// will stop the TCP listener and spawn the launcher again as a subprocess
 	/// with the same arguments we used.
 	Respawn,
+	/// Signalled when a client asked for the tunnel to shut down.
+	Shutdown(ShutdownSignal),
 }
 
 pub enum Next {
@@ -126,40 +462,141 @@ pub struct ServerTermination {
 // Runs the launcher server. Exits on a ctrl+c or when requested by a user.
 // Note that client connections may not be closed when this returns; use
 // `close_all_clients()` on the ServerTermination to make this happen.
+#[allow(clippy::too_many_arguments)] // necessary here
 pub async fn serve(
 	log: &log::Logger,
 	mut tunnel: ActiveTunnel,
 	launcher_paths: &LauncherPaths,
 	code_server_args: &CodeServerArgs,
 	platform: Platform,
+	http: BoxedHttp,
+	forward_socket_allowlist: &[PathBuf],
+	forward_host_allowlist: &[String],
+	admin_token: Option<&str>,
+	rate_limits: &[(String, RateLimit)],
+	metrics_socket: Option<&Path>,
+	metrics_socket_mode: Option<u32>,
+	control_socket: Option<&Path>,
+	server_path_policy: &ServerPathPolicy,
+	max_frame_size: usize,
+	slow_rpc_threshold: Duration,
+	strict_protocol: bool,
+	prefetch_quality: Option<Quality>,
+	auto_update: Option<AutoUpdateSchedule>,
 	mut shutdown_rx: Barrier<ShutdownSignal>,
 ) -> Result<ServerTermination, AnyError> {
 	let mut port = tunnel.add_port_direct(CONTROL_PORT).await?;
-	let mut forwarding = PortForwardingProcessor::new();
+	let mut forwarding = PortForwardingProcessor::new(
+		log.clone(),
+		forward_socket_allowlist.to_vec(),
+		forward_host_allowlist.to_vec(),
+	);
 	let (tx, mut rx) = mpsc::channel::<ServerSignal>(4);
 	let (exit_barrier, signal_exit) = new_barrier();
+	let server_state = ServerState::new(
+		log,
+		launcher_paths,
+		admin_token,
+		rate_limits,
+		server_path_policy,
+		max_frame_size,
+		slow_rpc_threshold,
+		strict_protocol,
+	)?;
+	let _metrics_socket = match metrics_socket {
+		Some(path) => Some(
+			MetricsSocket::start(
+				log.clone(),
+				path,
+				metrics_socket_mode,
+				server_state.clone(),
+				forwarding.handle(),
+				exit_barrier.clone(),
+			)
+			.await?,
+		),
+		None => None,
+	};
+	let _control_socket = match control_socket {
+		Some(path) => Some(
+			ControlSocket::start(
+				log.clone(),
+				path,
+				tx.clone(),
+				launcher_paths.clone(),
+				code_server_args.clone(),
+				forwarding.handle(),
+				platform,
+				server_state.clone(),
+				exit_barrier.clone(),
+			)
+			.await?,
+		),
+		None => None,
+	};
+	if let Some(quality) = prefetch_quality {
+		tokio::spawn(prefetch_servers(
+			log.clone(),
+			http.clone(),
+			server_state.clone(),
+			launcher_paths.clone(),
+			code_server_args.clone(),
+			platform,
+			quality,
+			exit_barrier.clone(),
+		));
+	}
+	if let Some(schedule) = auto_update {
+		tokio::spawn(auto_update_cli(
+			log.clone(),
+			http.clone(),
+			launcher_paths.clone(),
+			server_state.clone(),
+			schedule,
+			tx.clone(),
+			exit_barrier.clone(),
+		));
+	}
 
 	loop {
 		tokio::select! {
 			Ok(reason) = shutdown_rx.wait() => {
 				info!(log, "Shutting down: {}", reason);
-				drop(signal_exit);
+				if reason.restart_eta().is_some() {
+					notify_restarting(&server_state, reason).await;
+				}
+				if matches!(reason, ShutdownSignal::UpdateRestart) {
+					drain_installs(&log, &server_state).await;
+				}
+				signal_exit.open(reason);
 				return Ok(ServerTermination {
 					next: match reason {
 						ShutdownSignal::RpcRestartRequested => Next::Restart,
+						ShutdownSignal::UpdateRestart => Next::Respawn,
 						_ => Next::Exit,
 					},
 					tunnel,
 				});
 			},
-			c = rx.recv() => {
-				if let Some(ServerSignal::Respawn) = c {
-					drop(signal_exit);
+			c = rx.recv() => match c {
+				Some(ServerSignal::Respawn) => {
+					notify_restarting(&server_state, ShutdownSignal::UpdateRestart).await;
+					drain_installs(&log, &server_state).await;
+					signal_exit.open(ShutdownSignal::UpdateRestart);
 					return Ok(ServerTermination {
 						next: Next::Respawn,
 						tunnel,
 					});
 				}
+				Some(ServerSignal::Shutdown(reason)) => {
+					info!(log, "Shutting down: {}", reason);
+					signal_exit.open(reason);
+					return Ok(ServerTermination {
+						next: Next::Exit,
+						tunnel,
+					});
+				}
+				None => {}
 			},
 			Some(w) = forwarding.recv() => {
 				forwarding.process(w, &mut tunnel).await;
@@ -182,6 +619,7 @@ pub async fn serve(
 				let own_exit = exit_barrier.clone();
 				let own_code_server_args = code_server_args.clone();
 				let own_forwarding = forwarding.handle();
+				let own_state = server_state.clone();
 
 				tokio::spawn(async move {
 					use opentelemetry::trace::{FutureExt, TraceContextExt};
@@ -193,7 +631,7 @@ pub async fn serve(
 					debug!(own_log, "Serving new connection");
 
 					let (writehalf, readhalf) = socket.into_split();
-					let stats = process_socket(own_exit, readhalf, writehalf, own_log, own_tx, own_paths, own_code_server_args, own_forwarding, platform).with_context(cx.clone()).await;
+					let stats = process_socket(own_exit, readhalf, writehalf, own_log, own_tx, own_paths, own_code_server_args, own_forwarding, platform, own_state).with_context(cx.clone()).await;
 
 					cx.span().add_event(
 						"socket.bandwidth",
@@ -210,27 +648,434 @@ pub async fn serve(
 	}
 }
 
-struct SocketStats {
-	rx: usize,
-	tx: usize,
+/// Tells clients the tunnel is about to restart, so they can show that
+/// they're reconnecting instead of a generic disconnect.
+async fn notify_restarting(server_state: &ServerState, signal: ShutdownSignal) {
+	server_state
+		.notifier
+		.notify_all(ClientRequestMethod::restarting(signal.into()))
+		.await;
+}
+
+/// Waits for servers other clients are setting up before respawning into an
+/// updated CLI, so their installs aren't cut short and left for the new
+/// process to clean up.
+async fn drain_installs(log: &log::Logger, server_state: &ServerState) {
+	debug!(log, "Waiting for servers being set up before respawning");
+	if !server_state.wait_for_installs(INSTALL_DRAIN_TIMEOUT).await {
+		warning!(
+			log,
+			"Servers were still being set up after {:?}, respawning anyway",
+			INSTALL_DRAIN_TIMEOUT
+		);
+	}
+}
+
+/// Tells clients about the newer CLI, unless they were already told, so they
+/// can show that the host's CLI can be updated.
+async fn announce_update(log: &log::Logger, server_state: &ServerState, release: &Release) {
+	let params = UpdateAvailableParams {
+		name: release.name.clone(),
+		commit: release.commit.clone(),
+	};
+	let is_new = server_state
+		.available_update
+		.lock()
+		.unwrap()
+		.replace(params.clone())
+		.map_or(true, |prev| prev.commit != params.commit);
+	if is_new {
+		info!(log, "CLI update {} is available", release);
+		server_state
+			.notifier
+			.notify_all(ClientRequestMethod::updateavailable(params))
+			.await;
+	}
+}
+
+/// Checks for CLI updates on the schedule and tells clients about them. If
+/// it's installing them, that's done in its maintenance window, then the
+/// tunnel respawns into the new version, which waits for servers being set up
+/// and tells clients it's restarting.
+async fn auto_update_cli(
+	log: log::Logger,
+	http: BoxedHttp,
+	launcher_paths: LauncherPaths,
+	server_state: ServerState,
+	schedule: AutoUpdateSchedule,
+	server_tx: mpsc::Sender<ServerSignal>,
+	mut exit_barrier: Barrier<ShutdownSignal>,
+) {
+	loop {
+		tokio::select! {
+			_ = exit_barrier.wait() => return,
+			_ = tokio::time::sleep(schedule.interval) => {},
+		}
+
+		let update = async {
+			let update_service = UpdateService::new(log.clone(), http.clone());
+			let updater = SelfUpdate::new(&update_service, &launcher_paths)?;
+			let latest_release = updater.get_current_release().await?;
+			if updater.is_up_to_date_with(&latest_release) {
+				return Ok::<_, AnyError>(None);
+			}
+
+			announce_update(&log, &server_state, &latest_release).await;
+			if !schedule.install {
+				return Ok(None);
+			}
+
+			let wait = schedule.until_window(chrono::Local::now().time());
+			if !wait.is_zero() {
+				info!(
+					log,
+					"Update to {} is available, installing it in the maintenance window in {:?}",
+					latest_release,
+					wait
+				);
+				tokio::time::sleep(wait).await;
+			}
+
+			let _updating = match server_state.updating.try_lock() {
+				Ok(guard) => guard,
+				Err(_) => return Err(CodeError::UpdateInProgress.into()),
+			};
+			info!(log, "Updating CLI to {}", latest_release);
+			updater
+				.do_update(&latest_release, SilentCopyProgress())
+				.await?;
+			Ok(Some(latest_release))
+		};
+
+		tokio::select! {
+			_ = exit_barrier.wait() => return,
+			r = update => match r {
+				Ok(Some(release)) => {
+					info!(log, "Updated CLI to {}, respawning", release);
+					server_tx.send(ServerSignal::Respawn).await.ok();
+					return;
+				}
+				Ok(None) => debug!(log, "CLI is up to date"),
+				Err(e) => warning!(log, "Error checking for or installing CLI updates: {}", e),
+			},
+		}
+	}
+}
+
+/// Sets up the latest server of the quality, or the version policy's pinned
+/// version, when the tunnel starts and again whenever a new one is
+/// published, so the first client to connect doesn't wait for the install.
+async fn prefetch_servers(
+	log: log::Logger,
+	http: BoxedHttp,
+	server_state: ServerState,
+	launcher_paths: LauncherPaths,
+	code_server_args: CodeServerArgs,
+	platform: Platform,
+	quality: Quality,
+	mut exit_barrier: Barrier<ShutdownSignal>,
+) {
+	loop {
+		let prefetch = async {
+			let resolved = ServerParamsRaw {
+				commit_id: None,
+				version: None,
+				quality,
+				custom_quality: None,
+				code_server_args: code_server_args.clone(),
+				headless: true,
+				platform,
+				prebuilt: None,
+				archive: None,
+				version_policy: server_state.server_version_policy.clone(),
+			}
+			.resolve(&log, http.clone())
+			.await?;
+
+			let (_install, install_lock) = server_state.start_install(&resolved.release)?;
+			let _installing = install_lock.lock().await;
+			ServerBuilder::new(&log, &resolved, &launcher_paths, http.clone())
+				.setup()
+				.await?;
+			Ok::<_, AnyError>(resolved.release)
+		};
+
+		tokio::select! {
+			_ = exit_barrier.wait() => return,
+			r = prefetch => match r {
+				Ok(release) => debug!(log, "Prefetched server {}", release),
+				Err(e) => warning!(log, "Error prefetching server: {}", e),
+			},
+		}
+
+		tokio::select! {
+			_ = exit_barrier.wait() => return,
+			_ = tokio::time::sleep(SERVER_PREFETCH_INTERVAL) => {},
+		}
+	}
+}
+
+/// Local socket that serves only read-only methods, `ping`, `health`, and
+/// `metrics`, over JSON-RPC. This lets a sidecar collect metrics without
+/// access to the tunnel or the singleton's privileged methods. Stops
+/// listening when dropped.
+struct MetricsSocket {
+	path: PathBuf,
+	task: JoinHandle<()>,
+}
+
+#[derive(Clone)]
+struct MetricsContext {
+	server_state: ServerState,
+	port_forwarding: PortForwarding,
+}
+
+impl MetricsSocket {
+	async fn start(
+		log: log::Logger,
+		path: &Path,
+		mode: Option<u32>,
+		server_state: ServerState,
+		port_forwarding: PortForwarding,
+		exit_barrier: Barrier<ShutdownSignal>,
+	) -> Result<Self, AnyError> {
+		remove_stale_socket(path);
+		let mut listener = listen_socket_rw_stream(path).await?;
+		if let Some(mode) = mode {
+			set_mode(path, mode).map_err(|e| wrap(e, "error setting metrics socket mode"))?;
+		}
+		info!(log, "Serving metrics on {}", path.display());
+
+		let context = MetricsContext {
+			server_state,
+			port_forwarding,
+		};
+		let task = tokio::spawn(async move {
+			loop {
+				let stream = match listener.accept().await {
+					Ok(s) => s,
+					Err(e) => {
+						warning!(log, "Metrics socket closed: {}", e);
+						return;
+					}
+				};
+
+				let mut rpc = new_json_rpc().methods(context.clone());
+				rpc.register_sync("ping", |_: EmptyObject, _| Ok(EmptyObject {}));
+				rpc.register_sync("health", |_: EmptyObject, c| handle_health(&c.server_state));
+				rpc.register_sync("metrics", |_: EmptyObject, c| {
+					handle_metrics(&c.server_state, &c.port_forwarding)
+				});
+
+				let log = log.clone();
+				let exit_barrier = exit_barrier.clone();
+				let rpc = rpc.build(log.clone());
+				tokio::spawn(async move {
+					let (read, write) = socket_stream_split(stream);
+					if let Err(e) = start_json_rpc(rpc, read, write, (), exit_barrier).await {
+						debug!(log, "Metrics connection closed: {}", e);
+					}
+				});
+			}
+		});
+
+		Ok(Self {
+			path: path.to_owned(),
+			task,
+		})
+	}
 }
 
-#[derive(Copy, Clone)]
-struct MsgPackSerializer {}
+impl Drop for MetricsSocket {
+	fn drop(&mut self) {
+		self.task.abort();
+		#[cfg(unix)]
+		std::fs::remove_file(&self.path).ok();
+	}
+}
+
+/// Local socket that serves the same methods as the tunnel, for tools on the
+/// machine. The format is chosen by the first byte a client sends: `{` starts
+/// newline-delimited JSON, and anything else is read as the usual
+/// length-prefixed msgpack. Unlike on the tunnel, the server's `version`
+/// message is only sent once that byte arrives. Stops listening when dropped.
+struct ControlSocket {
+	path: PathBuf,
+	task: JoinHandle<()>,
+}
+
+impl ControlSocket {
+	#[allow(clippy::too_many_arguments)]
+	async fn start(
+		log: log::Logger,
+		path: &Path,
+		server_tx: mpsc::Sender<ServerSignal>,
+		launcher_paths: LauncherPaths,
+		code_server_args: CodeServerArgs,
+		port_forwarding: PortForwarding,
+		platform: Platform,
+		server_state: ServerState,
+		exit_barrier: Barrier<ShutdownSignal>,
+	) -> Result<Self, AnyError> {
+		remove_stale_socket(path);
+		let mut listener = listen_socket_rw_stream(path).await?;
+		info!(log, "Serving the control server on {}", path.display());
+
+		let task = tokio::spawn(async move {
+			loop {
+				let stream = match listener.accept().await {
+					Ok(s) => s,
+					Err(e) => {
+						warning!(log, "Control socket closed: {}", e);
+						return;
+					}
+				};
+
+				let log = log.prefixed(&log::new_rpc_prefix());
+				let server_tx = server_tx.clone();
+				let launcher_paths = launcher_paths.clone();
+				let code_server_args = code_server_args.clone();
+				let port_forwarding = port_forwarding.clone();
+				let server_state = server_state.clone();
+				let exit_barrier = exit_barrier.clone();
+				tokio::spawn(async move {
+					let (mut read, write) = socket_stream_split(stream);
+					let mut first = [0u8; 1];
+					if read.read_exact(&mut first).await.is_err() {
+						return;
+					}
+					let read = std::io::Cursor::new(first).chain(read);
+
+					if first[0] != b'{' {
+						process_socket(
+							exit_barrier,
+							read,
+							write,
+							log,
+							server_tx,
+							launcher_paths,
+							code_server_args,
+							port_forwarding,
+							platform,
+							server_state,
+						)
+						.await;
+						return;
+					}
+
+					debug!(log, "Using JSON for control socket connection");
+					let (client, server) = tokio::io::duplex(CONTROL_SOCKET_BUFFER_SIZE);
+					let (client_read, client_write) = tokio::io::split(client);
+					let (server_read, server_write) = tokio::io::split(server);
+					let (bridged, _) = tokio::join!(
+						bridge_json_to_msgpack(read, write, client_read, client_write),
+						process_socket(
+							exit_barrier,
+							server_read,
+							server_write,
+							log.clone(),
+							server_tx,
+							launcher_paths,
+							code_server_args,
+							port_forwarding,
+							platform,
+							server_state,
+						)
+					);
+					if let Err(e) = bridged {
+						debug!(log, "Closed JSON control connection: {}", e);
+					}
+				});
+			}
+		});
+
+		Ok(Self {
+			path: path.to_owned(),
+			task,
+		})
+	}
+}
 
-impl Serialization for MsgPackSerializer {
-	fn serialize(&self, value: impl serde::Serialize) -> Vec<u8> {
-		rmp_serde::to_vec_named(&value).expect("expected to serialize")
+impl Drop for ControlSocket {
+	fn drop(&mut self) {
+		self.task.abort();
+		#[cfg(unix)]
+		std::fs::remove_file(&self.path).ok();
 	}
+}
 
-	fn deserialize<P: serde::de::DeserializeOwned>(&self, b: &[u8]) -> Result<P, AnyError> {
-		rmp_serde::from_slice(b).map_err(|e| InvalidRpcDataError(e.to_string()).into())
+/// Removes a socket left over from a process that didn't exit cleanly.
+fn remove_stale_socket(path: &Path) {
+	#[cfg(unix)]
+	{
+		use std::os::unix::fs::FileTypeExt;
+		if std::fs::symlink_metadata(path).map_or(false, |m| m.file_type().is_socket()) {
+			std::fs::remove_file(path).ok();
+		}
 	}
+	#[cfg(not(unix))]
+	let _ = path;
+}
+
+/// Serves a single control connection over the given streams, without a
+/// tunnel. Forwarded ports are only made available locally. This is used to
+/// exercise the server in the self-test.
+pub async fn serve_stream(
+	log: log::Logger,
+	readhalf: impl AsyncRead + Send + Unpin + 'static,
+	writehalf: impl AsyncWrite + Unpin,
+	launcher_paths: LauncherPaths,
+	code_server_args: CodeServerArgs,
+	platform: Platform,
+) -> Result<(), AnyError> {
+	// neither is ever signalled, since there's no tunnel to restart or exit
+	let (exit_barrier, _signal_exit) = new_barrier();
+	let (server_tx, _server_rx) = mpsc::channel(4);
+	let server_state = ServerState::new(
+		&log,
+		&launcher_paths,
+		None,
+		&[],
+		&ServerPathPolicy::default(),
+		DEFAULT_MAX_FRAME_SIZE,
+		DEFAULT_SLOW_RPC_THRESHOLD,
+		false,
+	)?;
+
+	let mut forwarding = PortForwardingProcessor::new(log.clone(), vec![], vec![]);
+	let port_forwarding = forwarding.handle();
+	let forwarding_task = tokio::spawn(async move {
+		while let Some(req) = forwarding.recv().await {
+			forwarding.process_locally(req);
+		}
+	});
+
+	process_socket(
+		exit_barrier,
+		readhalf,
+		writehalf,
+		log,
+		server_tx,
+		launcher_paths,
+		code_server_args,
+		port_forwarding,
+		platform,
+		server_state,
+	)
+	.await;
+
+	forwarding_task.abort();
+	Ok(())
+}
+
+struct SocketStats {
+	rx: usize,
+	tx: usize,
 }
 
 #[allow(clippy::too_many_arguments)] // necessary here
 async fn process_socket(
-	mut exit_barrier: Barrier<()>,
+	mut exit_barrier: Barrier<ShutdownSignal>,
 	readhalf: impl AsyncRead + Send + Unpin + 'static,
 	mut writehalf: impl AsyncWrite + Unpin,
 	log: log::Logger,
@@ -239,19 +1084,62 @@ async fn process_socket(
 	code_server_args: CodeServerArgs,
 	port_forwarding: PortForwarding,
 	platform: Platform,
+	server_state: ServerState,
 ) -> SocketStats {
-	let (socket_tx, mut socket_rx) = mpsc::channel(4);
+	let (socket_tx, mut socket_rx) = mpsc::channel(SOCKET_QUEUE_SIZE);
 	let rx_counter = Arc::new(AtomicUsize::new(0));
+	let tx_counter = Arc::new(AtomicUsize::new(0));
+	let quality = Arc::new(ConnectionQuality::default());
 	let http_requests = Arc::new(std::sync::Mutex::new(HashMap::new()));
 	let server_bridges = ServerMultiplexer::new();
-	let (http_delegated, mut http_rx) = DelegatedSimpleHttp::new(log.clone());
-	let mut rpc = RpcBuilder::new(MsgPackSerializer {}).methods(HandlerContext {
+	let code_servers: CodeServerMap = Arc::new(Mutex::new(HashMap::new()));
+	let spawned = SpawnedProcesses::new(socket_tx.clone(), server_state.spawn_history.clone());
+	let socket_id = next_message_id();
+	server_state.sockets.lock().unwrap().insert(
+		socket_id,
+		SocketHandles {
+			server_bridges: server_bridges.clone(),
+			http_requests: http_requests.clone(),
+			tx_counter: tx_counter.clone(),
+			rx_counter: rx_counter.clone(),
+			socket_tx: socket_tx.clone(),
+			code_servers: code_servers.clone(),
+			spawned: spawned.clone(),
+			connected_at: SystemTime::now(),
+			identity: None,
+		},
+	);
+	server_state.metrics.record_connection();
+	let (mut http_delegated, mut http_rx) = DelegatedSimpleHttp::new(log.clone());
+	if let Some(size) = server_state.max_delegated_body_size {
+		http_delegated = http_delegated.with_max_body_size(size);
+	}
+	let capabilities = Arc::new(AtomicU32::new(
+		Capabilities::local()
+			.negotiate(Capabilities::legacy())
+			.bits(),
+	));
+	let (caller_tx, mut caller_rx) = mpsc::unbounded_channel();
+	let mut rpc = RpcBuilder::new(MsgPackSerializer {});
+	let caller = rpc.get_caller(caller_tx);
+	let editor_hook = match EditorHook::start(log.clone(), &launcher_paths, caller.clone()).await {
+		Ok(h) => Some(h),
+		Err(e) => {
+			warning!(
+				log,
+				"Opening files in the editor from terminals is unavailable: {}",
+				e
+			);
+			None
+		}
+	};
+	let mut rpc = rpc.methods(HandlerContext {
 		did_update: Arc::new(AtomicBool::new(false)),
 		socket_tx: socket_tx.clone(),
 		log: log.clone(),
 		launcher_paths,
 		code_server_args,
-		code_server: Arc::new(Mutex::new(None)),
+		code_servers,
 		server_bridges: server_bridges.clone(),
 		port_forwarding,
 		platform,
@@ -260,15 +1148,138 @@ async fn process_socket(
 			http_delegated,
 		)),
 		http_requests: http_requests.clone(),
+		server_state: server_state.clone(),
+		quality: quality.clone(),
+		spawned,
+		socket_id,
+		editor_hook,
+		server_tx: server_tx.clone(),
+		capabilities: capabilities.clone(),
+		notifier: server_state.notifier.clone(),
+		code_server_health: Arc::new(std::sync::Mutex::new(HashMap::new())),
+	});
+
+	rpc.log_slow_calls(server_state.slow_rpc_threshold);
+	let (violation_tx, mut violation_rx) = mpsc::unbounded_channel();
+	if server_state.strict_protocol {
+		rpc.strict(violation_tx);
+	}
+	let metrics = server_state.metrics.clone();
+	rpc.observe_calls(move |method| metrics.record_rpc_call(method));
+	let call_log = log.clone();
+	rpc.layer(move |call, next| {
+		let log = call_log.clone();
+		let (method, started) = (call.method, Instant::now());
+		Ok(next.run(call)?.map_future(move |fut| {
+			async move {
+				let r = fut.await;
+				trace!(log, "{} completed in {:?}", method, started.elapsed());
+				r
+			}
+			.boxed()
+		}))
 	});
 
 	rpc.register_sync("ping", |_: EmptyObject, _| Ok(EmptyObject {}));
+	rpc.register_sync("version", |v: VersionParams, c| {
+		let negotiated = Capabilities::local().negotiate(v.capabilities);
+		debug!(
+			c.log,
+			"Client {} negotiated capabilities {:#x}",
+			v.version,
+			negotiated.bits()
+		);
+		c.capabilities.store(negotiated.bits(), Ordering::SeqCst);
+		Ok(EmptyObject {})
+	});
 	rpc.register_sync("gethostname", |_: EmptyObject, _| handle_get_hostname());
+	rpc.register_sync("health", |_: EmptyObject, c| handle_health(&c.server_state));
+	rpc.register_sync("serverhealth", |_: EmptyObject, c| {
+		Ok(ServerHealthResult {
+			servers: c
+				.code_server_health
+				.lock()
+				.unwrap()
+				.values()
+				.cloned()
+				.collect(),
+		})
+	});
+	rpc.register_sync("metrics", |_: EmptyObject, c| {
+		handle_metrics(&c.server_state, &c.port_forwarding)
+	});
+	rpc.register_async("listservers", |_: EmptyObject, c| async move {
+		Ok(ListServersResult {
+			servers: c.launcher_paths.server_inventory.list().await,
+		})
+	});
+	rpc.register_sync("stats", |_: EmptyObject, c| Ok(c.quality.stats()));
+	rpc.register_sync("sysinfo", |_: EmptyObject, c| {
+		handle_sysinfo(&c.launcher_paths)
+	});
+	rpc.register_async("envprobe", |_: EmptyObject, _| async move {
+		Ok(probe_environment().await)
+	});
+	rpc.register_async("dumpstate", |_: EmptyObject, c| async move {
+		handle_dump_state(&c).await
+	});
+	rpc.register_async("shutdown", |p: ShutdownParams, c| async move {
+		handle_shutdown(&c, p).await
+	});
+	rpc.register_sync("getconnectiontoken", |p: AdminParams, c| {
+		handle_get_connection_token(c, p)
+	});
+	rpc.register_sync("rotateconnectiontoken", |p: AdminParams, c| {
+		handle_rotate_connection_token(c, p)
+	});
+	rpc.register_async("renametunnel", |p: RenameTunnelParams, c| async move {
+		handle_rename_tunnel(&c, p).await
+	});
+	rpc.register_sync("identify", |p: ClientIdentity, c| handle_identify(c, p));
+	rpc.register_sync("getclientstate", |p: GetClientStateParams, c| {
+		let client = client_identity(c)?;
+		Ok(GetClientStateResult {
+			value: c.server_state.client_state.get(&client, &p.key),
+		})
+	});
+	rpc.register_sync("setclientstate", |p: SetClientStateParams, c| {
+		let client = client_identity(c)?;
+		c.server_state
+			.client_state
+			.set(&client, p.key, p.value)
+			.map(|_| EmptyObject {})
+	});
+	rpc.register_async("listsessions", |_: EmptyObject, c| async move {
+		handle_list_sessions(&c).await
+	});
+	rpc.register_async(
+		"disconnectsession",
+		|p: DisconnectSessionParams, c| async move { handle_disconnect_session(&c, p).await },
+	);
 	rpc.register_async("serve", move |params: ServeParams, c| async move {
 		handle_serve(c, params).await
 	});
 	rpc.register_async("update", |p: UpdateParams, c| async move {
-		handle_update(&c.http, &c.log, &c.did_update, &p).await
+		let progress = download_progress(&c, "CLI update");
+		handle_update(
+			&c.http,
+			&c.log,
+			&c.launcher_paths,
+			&c.server_state,
+			&c.did_update,
+			&p,
+			progress,
+		)
+		.await
+	});
+	rpc.register_sync("rollback", |_: EmptyObject, c| {
+		handle_rollback(
+			&c.http,
+			&c.log,
+			&c.launcher_paths,
+			&c.server_state,
+			&c.did_update,
+		)
 	});
 	rpc.register_sync("servermsg", |m: ServerMessageParams, c| {
 		if let Err(e) = handle_server_message(&c.log, &c.server_bridges, m) {
@@ -276,29 +1287,125 @@ async fn process_socket(
 		}
 		Ok(EmptyObject {})
 	});
-	rpc.register_sync("prune", |_: EmptyObject, c| handle_prune(&c.launcher_paths));
+	rpc.register_sync("prune", |p: DryRunParams, c| {
+		handle_prune(&c.launcher_paths, p)
+	});
+	rpc.register_async(
+		"gc",
+		|p: DryRunParams, c| async move { handle_gc(&c, p).await },
+	);
 	rpc.register_async("callserverhttp", |p: CallServerHttpParams, c| async move {
-		let code_server = c.code_server.lock().await.clone();
+		let code_server = pick_code_server(&*c.code_servers.lock().await, &p)?;
 		handle_call_server_http(code_server, p).await
 	});
+	rpc.register_chunked(
+		"callserverhttpchunked",
+		|chunks, p: CallServerHttpParams, c| async move {
+			let code_server = pick_code_server(&*c.code_servers.lock().await, &p)?;
+			handle_call_server_http_chunked(code_server, p, chunks).await
+		},
+	);
 	rpc.register_async("forward", |p: ForwardParams, c| async move {
 		handle_forward(&c.log, &c.port_forwarding, p).await
 	});
+	rpc.register_async("getclipboard", |_: EmptyObject, _| async move {
+		Ok(ClipboardParams {
+			text: get_clipboard().await?,
+		})
+	});
+	rpc.register_async("setclipboard", |p: ClipboardParams, _| async move {
+		set_clipboard(&p.text).await.map(|_| EmptyObject {})
+	});
+	rpc.register_async("listeningports", |_: EmptyObject, _| async move {
+		Ok(ListeningPortsResult {
+			ports: list_listening_ports().await?,
+		})
+	});
 	rpc.register_async("unforward", |p: UnforwardParams, c| async move {
 		handle_unforward(&c.log, &c.port_forwarding, p).await
 	});
+	rpc.register_async("listreleases", |p: ListReleasesParams, c| async move {
+		handle_list_releases(&c.http, &c.log, c.platform, p).await
+	});
 	rpc.register_async("acquire_cli", |p: AcquireCliParams, c| async move {
-		handle_acquire_cli(&c.launcher_paths, &c.http, &c.log, p).await
+		let progress = download_progress(&c, "CLI");
+		handle_acquire_cli(&c.launcher_paths, &c.http, &c.log, &c.spawned, p, progress).await
 	});
-	rpc.register_duplex("spawn", 3, |mut streams, p: SpawnParams, c| async move {
-		handle_spawn(
-			&c.log,
-			p,
-			Some(streams.remove(0)),
-			Some(streams.remove(0)),
-			Some(streams.remove(0)),
-		)
-		.await
+	rpc.register_duplex(
+		"spawn",
+		3,
+		|mut streams, mut p: SpawnParams, c| async move {
+			let (stdin, stdout, stderr) = (streams.remove(0), streams.remove(0), streams.remove(0));
+			let capabilities = c.capabilities();
+			if let Some(hook) = c
+				.editor_hook
+				.as_ref()
+				.filter(|_| capabilities.contains(Capabilities::EDITOR_HOOK))
+			{
+				hook.apply_env(&mut p.env);
+			}
+			match p.pty {
+				Some(_) if !capabilities.contains(Capabilities::PTY) => {
+					Err(CodeError::PtyNotSupported.into())
+				}
+				#[cfg(feature = "pty")]
+				Some(size) => spawn_pty(&c.log, &c.spawned, p, size, stdin, stdout, stderr).await,
+				#[cfg(not(feature = "pty"))]
+				Some(_) => Err(CodeError::PtyNotSupported.into()),
+				None => {
+					handle_spawn(
+						&c.log,
+						&c.spawned,
+						p,
+						Some(stdin),
+						Some(stdout),
+						Some(stderr),
+					)
+					.await
+				}
+			}
+		},
+	);
+	rpc.register_duplex("watch", 1, |mut streams, p: WatchParams, c| async move {
+		let (read, write) = tokio::io::split(streams.remove(0));
+		handle_watch(&c.log, p, read, write).await
+	});
+	rpc.register_async("downloadchunk", |p: DownloadChunkParams, _| async move {
+		handle_download_chunk(p).await
+	});
+	rpc.register_async("uploadchunk", |p: UploadChunkParams, _| async move {
+		handle_upload_chunk(p).await
+	});
+	rpc.register_async("filechecksum", |p: FileChecksumParams, _| async move {
+		handle_file_checksum(p).await
+	});
+	rpc.register_async("fsstat", |p: FsPathParams, _| async move {
+		handle_fs_stat(p).await
+	});
+	rpc.register_async("fsreaddir", |p: FsPathParams, _| async move {
+		handle_fs_readdir(p).await
+	});
+	rpc.register_async("fsread", |p: FsPathParams, _| async move {
+		handle_fs_read(p).await
+	});
+	rpc.register_async("fswrite", |p: FsWriteParams, _| async move {
+		handle_fs_write(p).await
+	});
+	rpc.register_sync("spawnhistory", |p: SpawnHistoryParams, c| {
+		handle_spawn_history(&c.server_state, p)
+	});
+	rpc.register_sync("listspawned", |_: EmptyObject, c| {
+		Ok(ListSpawnedResult {
+			processes: c.spawned.list(),
+		})
+	});
+	rpc.register_async("killspawned", |p: KillSpawnedParams, c| async move {
+		info!(c.log, "Killing spawned process {}", p.pid);
+		c.spawned.kill(p.pid).await.map(|_| EmptyObject {})
+	});
+	rpc.register_sync("signalspawned", |p: SignalSpawnedParams, c| {
+		info!(c.log, "Sending {:?} to spawned process {}", p.signal, p.pid);
+		c.spawned.signal(p.pid, p.signal).map(|_| EmptyObject {})
 	});
 	rpc.register_sync("httpheaders", |p: HttpHeadersParams, c| {
 		if let Some(req) = c.http_requests.lock().unwrap().get(&p.req_id) {
@@ -306,7 +1413,7 @@ async fn process_socket(
 		}
 		Ok(EmptyObject {})
 	});
-	rpc.register_sync("unforward", move |p: HttpBodyParams, c| {
+	rpc.register_sync("httpbody", move |p: HttpBodyParams, c| {
 		let mut reqs = c.http_requests.lock().unwrap();
 		if let Some(req) = reqs.get(&p.req_id) {
 			if !p.segment.is_empty() {
@@ -324,12 +1431,41 @@ async fn process_socket(
 		let rx_counter = rx_counter.clone();
 		let socket_tx = socket_tx.clone();
 		let exit_barrier = exit_barrier.clone();
+		for (method, limit) in server_state.rate_limits.iter() {
+			rpc.rate_limit(method, *limit);
+		}
 		let rpc = rpc.build(log.clone());
+		let strict_protocol = server_state.strict_protocol;
+		let decoder = U32PrefixedCodec::new(server_state.max_frame_size);
+		let server_state = server_state.clone();
 		tokio::spawn(async move {
 			send_version(&socket_tx).await;
 
-			if let Err(e) =
-				handle_socket_read(&log, readhalf, exit_barrier, &socket_tx, rx_counter, &rpc).await
+			// only notified once it has the version, so it knows the protocol
+			server_state
+				.notifier
+				.add_socket(socket_id, socket_tx.clone());
+			let update = server_state.available_update.lock().unwrap().clone();
+			if let Some(update) = update {
+				socket_tx
+					.send(SocketSignal::notification(
+						ClientRequestMethod::updateavailable(update),
+					))
+					.await
+					.ok();
+			}
+
+			if let Err(e) = handle_socket_read(
+				&log,
+				readhalf,
+				decoder,
+				exit_barrier,
+				&socket_tx,
+				rx_counter,
+				&rpc,
+				strict_protocol,
+			)
+			.await
 			{
 				debug!(log, "closing socket reader: {}", e);
 				socket_tx
@@ -349,38 +1485,72 @@ async fn process_socket(
 		});
 	}
 
-	let mut tx_counter = 0;
+	tokio::spawn(sample_connection_quality(
+		caller,
+		quality,
+		server_state.keepalive.clone(),
+		tx_counter.clone(),
+		rx_counter.clone(),
+	));
 
 	loop {
 		tokio::select! {
-			_ = exit_barrier.wait() => {
+			// the reader also closes on exit, so check this first to make sure
+			// clients are told why they're being disconnected
+			biased;
+
+			r = exit_barrier.wait() => {
+				if let Ok(reason) = r {
+					let serialized =
+						serialize_notification(ClientRequestMethod::draining(reason.into()));
+					writehalf.write_all(&serialized).await.ok();
+				}
 				writehalf.shutdown().await.ok();
 				break;
 			},
 			Some(r) = http_rx.recv() => {
+				let negotiated = Capabilities::from_bits(capabilities.load(Ordering::SeqCst));
+				if r.body.is_some() && !negotiated.contains(Capabilities::HTTP_BODIES) {
+					continue; // dropping the request fails it
+				}
+
 				let id = next_message_id();
-				let serialized = rmp_serde::to_vec_named(&ToClientRequest {
-					id: None,
-					params: ClientRequestMethod::makehttpreq(HttpRequestParams {
+				let serialized = serialize_notification(ClientRequestMethod::makehttpreq(
+					HttpRequestParams {
 						url: &r.url,
 						method: r.method,
 						req_id: id,
-					}),
-				})
-				.unwrap();
+						body: r.body.as_deref(),
+					},
+				));
 				http_requests.lock().unwrap().insert(id, r);
 
-				tx_counter += serialized.len();
+				tx_counter.fetch_add(serialized.len(), Ordering::Relaxed);
 				if let Err(e) = writehalf.write_all(&serialized).await {
 					debug!(log, "Closing connection: {}", e);
 					break;
 				}
 			}
-			recv = socket_rx.recv() => match recv {
-				None => break,
-				Some(message) => match message {
+			Some(e) = violation_rx.recv() => {
+				warning!(log, "Closing connection: {}", e);
+				let serialized = protocol_error(&MsgPackSerializer {}, &e.into());
+				tx_counter.fetch_add(serialized.len(), Ordering::Relaxed);
+				writehalf.write_all(&serialized).await.ok();
+				writehalf.shutdown().await.ok();
+				break;
+			}
+			Some(m) = caller_rx.recv() => {
+				tx_counter.fetch_add(m.len(), Ordering::Relaxed);
+				if let Err(e) = writehalf.write_all(&m).await {
+					debug!(log, "Closing connection: {}", e);
+					break;
+				}
+			}
+			recv = socket_rx.recv() => match recv {
+				None => break,
+				Some(message) => match message {
 					SocketSignal::Send(bytes) => {
-						tx_counter += bytes.len();
+						tx_counter.fetch_add(bytes.len(), Ordering::Relaxed);
 						if let Err(e) = writehalf.write_all(&bytes).await {
 							debug!(log, "Closing connection: {}", e);
 							break;
@@ -395,30 +1565,89 @@ async fn process_socket(
 		}
 	}
 
+	server_state.sockets.lock().unwrap().remove(&socket_id);
+	server_state.notifier.remove_socket(socket_id);
+	server_state.metrics.record_closed_socket(
+		tx_counter.load(Ordering::Acquire) as u64,
+		rx_counter.load(Ordering::Acquire) as u64,
+	);
+
 	SocketStats {
-		tx: tx_counter,
+		tx: tx_counter.load(Ordering::Acquire),
 		rx: rx_counter.load(Ordering::Acquire),
 	}
 }
 
+/// Periodically pings the client and samples the connection's throughput,
+/// until the connection is closed. The pings also keep the connection to the
+/// relay from expiring, and pings after the connection was idle are used to
+/// find how far apart they can be.
+async fn sample_connection_quality(
+	caller: RpcCaller<MsgPackSerializer>,
+	quality: Arc<ConnectionQuality>,
+	keepalive: Arc<KeepAlive>,
+	tx_counter: Arc<AtomicUsize>,
+	rx_counter: Arc<AtomicUsize>,
+) {
+	let mut last_sample = Instant::now();
+	let mut last_tx = 0;
+	let mut last_rx = 0;
+
+	loop {
+		let interval = keepalive.interval();
+		tokio::time::sleep(interval).await;
+		let idle = tx_counter.load(Ordering::Relaxed) == last_tx
+			&& rx_counter.load(Ordering::Relaxed) == last_rx;
+
+		// Clients reply to unknown methods with an error, so any response
+		// is good enough to measure the round trip.
+		let sent_at = Instant::now();
+		let response = caller.call::<_, _, IgnoredAny>("ping", EmptyObject {});
+		match tokio::time::timeout(PING_TIMEOUT, response).await {
+			Ok(Ok(_)) => {
+				quality.record_ping(Some(sent_at.elapsed()));
+				if idle {
+					keepalive.record_answered(interval);
+				}
+			}
+			Ok(Err(_)) => return, // connection closed
+			Err(_) => {
+				quality.record_ping(None);
+				if idle {
+					keepalive.record_unanswered(interval);
+				}
+			}
+		}
+
+		let tx = tx_counter.load(Ordering::Relaxed);
+		let rx = rx_counter.load(Ordering::Relaxed);
+		quality.record_transfer(tx - last_tx, rx - last_rx, last_sample.elapsed());
+		last_sample = Instant::now();
+		last_tx = tx;
+		last_rx = rx;
+	}
+}
+
 async fn send_version(tx: &mpsc::Sender<SocketSignal>) {
-	tx.send(SocketSignal::from_message(&ToClientRequest {
-		id: None,
-		params: ClientRequestMethod::version(VersionParams::default()),
-	}))
+	tx.send(SocketSignal::notification(ClientRequestMethod::version(
+		VersionParams::default(),
+	)))
 	.await
 	.ok();
 }
+
+#[allow(clippy::too_many_arguments)]
 async fn handle_socket_read(
-	_log: &log::Logger,
+	log: &log::Logger,
 	readhalf: impl AsyncRead + Unpin,
-	mut closer: Barrier<()>,
+	mut decoder: U32PrefixedCodec,
+	mut closer: Barrier<ShutdownSignal>,
 	socket_tx: &mpsc::Sender<SocketSignal>,
 	rx_counter: Arc<AtomicUsize>,
 	rpc: &RpcDispatcher<MsgPackSerializer, HandlerContext>,
+	strict: bool,
 ) -> Result<(), std::io::Error> {
 	let mut readhalf = BufReader::new(readhalf);
-	let mut decoder = U32PrefixedCodec {};
 	let mut decoder_buf = bytes::BytesMut::new();
 
 	loop {
@@ -426,10 +1655,39 @@ async fn handle_socket_read(
 			r = readhalf.read_buf(&mut decoder_buf) => r,
 			_ = closer.wait() => Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "eof")),
 		}?;
+		if read_len == 0 {
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::UnexpectedEof,
+				"eof",
+			));
+		}
 
 		rx_counter.fetch_add(read_len, Ordering::Relaxed);
 
 		while let Some(frame) = decoder.decode(&mut decoder_buf)? {
+			let frame = match frame {
+				Frame::Message(m) => m,
+				Frame::TooLarge(len) => {
+					warning!(log, "Discarding a message of {} bytes from the client", len);
+					let err = CodeError::FrameTooLarge(len, decoder.max_frame_size());
+					let notification = rpc.protocol_error(&err.into());
+					if socket_tx
+						.send(SocketSignal::Send(notification))
+						.await
+						.is_err()
+					{
+						return Ok(());
+					}
+					if strict {
+						return Err(std::io::Error::new(
+							std::io::ErrorKind::InvalidData,
+							"the client sent a message over the size limit",
+						));
+					}
+					continue;
+				}
+			};
+
 			match rpc.dispatch(&frame) {
 				MaybeSync::Sync(Some(v)) => {
 					if socket_tx.send(SocketSignal::Send(v)).await.is_err() {
@@ -468,13 +1726,10 @@ struct ServerOutputSink {
 
 impl log::LogSink for ServerOutputSink {
 	fn write_log(&self, level: log::Level, _prefix: &str, message: &str) {
-		let s = SocketSignal::from_message(&ToClientRequest {
-			id: None,
-			params: ClientRequestMethod::serverlog(ServerLog {
-				line: message,
-				level: level.to_u8(),
-			}),
-		});
+		let s = SocketSignal::notification(ClientRequestMethod::serverlog(ServerLog {
+			line: message,
+			level: level.to_u8(),
+		}));
 
 		self.tx.try_send(s).ok();
 	}
@@ -486,19 +1741,72 @@ async fn handle_serve(
 	c: Arc<HandlerContext>,
 	params: ServeParams,
 ) -> Result<EmptyObject, AnyError> {
-	// fill params.extensions into code_server_args.install_extensions
-	let mut csa = c.code_server_args.clone();
-	csa.install_extensions.extend(params.extensions.into_iter());
+	let capabilities = c.capabilities();
+	let compress = params.compress && capabilities.contains(Capabilities::COMPRESS_DEFLATE);
+	if let Some(socket) = params.server_socket.as_deref() {
+		let server = get_external_server(&c, socket, &params).await?;
+		attach_server_bridge(
+			&c.log,
+			server,
+			c.socket_tx.clone(),
+			c.server_bridges.clone(),
+			params.socket_id,
+			compress,
+		)
+		.await?;
+		return Ok(EmptyObject {});
+	}
 
+	let mut csa = c
+		.code_server_args
+		.to_builder()
+		.install_extensions(params.extensions)
+		.install_extensions(c.server_state.default_extensions.iter().cloned())
+		.extra_args(params.server_args);
+	if let Some(level) = params.telemetry_level {
+		let host_level = c.code_server_args.telemetry_level;
+		csa = csa.telemetry_level(host_level.map_or(level, |l| l.min(level)));
+	}
+	if let Some(hook) = c
+		.editor_hook
+		.as_ref()
+		.filter(|_| capabilities.contains(Capabilities::EDITOR_HOOK))
+	{
+		for (key, value) in hook.server_env() {
+			csa = csa.server_env(key, value);
+		}
+	}
+	let csa = csa.build()?;
+
+	let prebuilt = c
+		.server_state
+		.server_path_policy
+		.resolve(params.server_path.as_deref(), c.platform)?;
+	let archive = match &params.server_archive {
+		Some(a) => Some(c.server_state.server_path_policy.resolve_archive(a)?),
+		None => None,
+	};
+	let quality = match &params.custom_quality {
+		Some(name) => custom_quality(name)?.quality,
+		None => params.quality,
+	};
 	let params_raw = ServerParamsRaw {
 		commit_id: params.commit_id,
-		quality: params.quality,
+		version: params.version,
+		quality,
+		custom_quality: params.custom_quality,
 		code_server_args: csa,
 		headless: true,
 		platform: c.platform,
+		prebuilt,
+		archive,
+		version_policy: c.server_state.server_version_policy.clone(),
 	};
 
-	let resolved = if params.use_local_download {
+	// downloads can only go through clients that stream http requests
+	let use_local_download =
+		params.use_local_download && capabilities.contains(Capabilities::HTTP_STREAMING);
+	let resolved = if use_local_download {
 		params_raw
 			.resolve(&c.log, Arc::new(c.http.delegated()))
 			.await
@@ -506,43 +1814,45 @@ async fn handle_serve(
 		params_raw.resolve(&c.log, c.http.clone()).await
 	}?;
 
-	let mut server_ref = c.code_server.lock().await;
-	let server = match &*server_ref {
-		Some(o) => o.clone(),
+	let key = CodeServerKey {
+		quality: resolved.release.quality,
+		commit: resolved.release.commit.clone(),
+		instance: params.instance,
+		settings: resolved.code_server_args.launch_settings(),
+	};
+	let instance_dir = key
+		.instance
+		.as_deref()
+		.map(|i| c.launcher_paths.server_instance_dir(i));
+	let existing = c.code_servers.lock().await.get(&key).cloned();
+	let server = match existing {
+		Some(o) => o,
 		None => {
-			let install_log = c.log.tee(ServerOutputSink {
-				tx: c.socket_tx.clone(),
-			});
-
-			macro_rules! do_setup {
-				($sb:expr) => {
-					match $sb.get_running().await? {
-						Some(AnyCodeServer::Socket(s)) => s,
-						Some(_) => return Err(AnyError::from(MismatchedLaunchModeError())),
-						None => {
-							$sb.setup().await?;
-							$sb.listen_on_default_socket().await?
-						}
-					}
-				};
+			// not holding the lock while installing, so serves for other servers
+			// can go on, and serves for this one wait on its install lock
+			let server =
+				start_code_server(&c, &resolved, instance_dir.clone(), use_local_download).await?;
+			let mut servers = c.code_servers.lock().await;
+			match servers.get(&key) {
+				Some(o) => o.clone(), // a concurrent serve started it, and watches it
+				None => {
+					servers.insert(key.clone(), server.clone());
+					tokio::spawn(check_code_server_health(
+						Arc::downgrade(&c),
+						key.clone(),
+						server.clone(),
+					));
+					tokio::spawn(watch_code_server(
+						Arc::downgrade(&c),
+						key,
+						server.clone(),
+						resolved,
+						instance_dir,
+						params.restart_on_crash,
+					));
+					server
+				}
 			}
-
-			let server = if params.use_local_download {
-				let sb = ServerBuilder::new(
-					&install_log,
-					&resolved,
-					&c.launcher_paths,
-					Arc::new(c.http.delegated()),
-				);
-				do_setup!(sb)
-			} else {
-				let sb =
-					ServerBuilder::new(&install_log, &resolved, &c.launcher_paths, c.http.clone());
-				do_setup!(sb)
-			};
-
-			server_ref.replace(server.clone());
-			server
 		}
 	};
 
@@ -552,12 +1862,277 @@ async fn handle_serve(
 		c.socket_tx.clone(),
 		c.server_bridges.clone(),
 		params.socket_id,
-		params.compress,
+		compress,
 	)
 	.await?;
 	Ok(EmptyObject {})
 }
 
+/// Installs and starts a server, or gets one that's already running from
+/// its install.
+async fn start_code_server(
+	c: &HandlerContext,
+	resolved: &ResolvedServerParams,
+	instance_dir: Option<PathBuf>,
+	use_local_download: bool,
+) -> Result<SocketCodeServer, AnyError> {
+	let install_log = c.log.tee(ServerOutputSink {
+		tx: c.socket_tx.clone(),
+	});
+
+	let (_install, install_lock) = c.server_state.start_install(&resolved.release)?;
+	let _installing = match install_lock.try_lock() {
+		Ok(guard) => guard,
+		Err(_) => {
+			info!(
+				c.log,
+				"Waiting for the server to be set up for another client"
+			);
+			install_lock.lock().await
+		}
+	};
+
+	macro_rules! do_setup {
+		($sb:expr) => {
+			match $sb.get_running().await? {
+				Some(AnyCodeServer::Socket(s)) => s,
+				Some(_) => return Err(AnyError::from(MismatchedLaunchModeError())),
+				None => {
+					$sb.setup().await?;
+					$sb.listen_on_default_socket().await?
+				}
+			}
+		};
+	}
+
+	let server = if use_local_download {
+		let sb = ServerBuilder::new(
+			&install_log,
+			resolved,
+			&c.launcher_paths,
+			Arc::new(c.http.delegated()),
+		)
+		.instance_dir(instance_dir);
+		do_setup!(sb)
+	} else {
+		let sb = ServerBuilder::new(&install_log, resolved, &c.launcher_paths, c.http.clone())
+			.instance_dir(instance_dir);
+		do_setup!(sb)
+	};
+
+	Ok(server)
+}
+
+/// Watches a server the connection started until the connection closes. If
+/// the server exits, it's forgotten so the next `serve` starts a new one, and
+/// the client is told. Servers that exit while the client is attached to
+/// servers are restarted if it asked for it.
+async fn watch_code_server(
+	c: Weak<HandlerContext>,
+	key: CodeServerKey,
+	mut server: SocketCodeServer,
+	resolved: ResolvedServerParams,
+	instance_dir: Option<PathBuf>,
+	restart_on_crash: bool,
+) {
+	let mut restarts = 0;
+	let mut interval = tokio::time::interval(CODE_SERVER_POLL_INTERVAL);
+	loop {
+		if matches!(*server.origin, CodeServerOrigin::External) {
+			return;
+		}
+
+		interval.tick().await;
+		let c = match c.upgrade() {
+			Some(c) => c,
+			None => return,
+		};
+		if !server.origin.has_exited() {
+			continue;
+		}
+
+		{
+			let mut servers = c.code_servers.lock().await;
+			if servers.get(&key).map(|s| &s.socket) != Some(&server.socket) {
+				return; // replaced or released by the connection
+			}
+			servers.remove(&key);
+		}
+
+		// servers shut themselves down after clients are gone for a while
+		let crashed = c.server_bridges.len() > 0;
+		let restarting = crashed && restart_on_crash && restarts < MAX_CODE_SERVER_RESTARTS;
+		warning!(
+			c.log,
+			"{} server {} exited{}",
+			key.quality,
+			key.commit,
+			if restarting { ", restarting it" } else { "" }
+		);
+		c.socket_tx
+			.send(SocketSignal::notification(
+				ClientRequestMethod::codeserverexited(CodeServerExitedParams {
+					quality: key.quality,
+					commit: key.commit.clone(),
+					instance: key.instance.clone(),
+					crashed,
+					restarting,
+				}),
+			))
+			.await
+			.ok();
+
+		if !restarting {
+			return;
+		}
+
+		// not holding the lock while reinstalling, so other serves can go on
+		restarts += 1;
+		server = match start_code_server(&c, &resolved, instance_dir.clone(), false).await {
+			Ok(s) => s,
+			Err(e) => {
+				warning!(c.log, "Error restarting {} server: {}", key.quality, e);
+				return;
+			}
+		};
+
+		let mut servers = c.code_servers.lock().await;
+		if servers.contains_key(&key) {
+			return; // a serve started one meanwhile, which is watched instead
+		}
+		servers.insert(key.clone(), server.clone());
+		tokio::spawn(check_code_server_health(
+			Arc::downgrade(&c),
+			key.clone(),
+			server.clone(),
+		));
+	}
+}
+
+/// Gets the server that another supervisor runs on the socket. It's kept
+/// with the servers the connection started, so it can be called over http,
+/// but it's never installed, updated, or stopped by the CLI.
+async fn get_external_server(
+	c: &Arc<HandlerContext>,
+	socket: &str,
+	params: &ServeParams,
+) -> Result<SocketCodeServer, AnyError> {
+	let socket = c.server_state.server_path_policy.resolve_socket(socket)?;
+	let commit = params
+		.commit_id
+		.clone()
+		.ok_or(CodeError::ExternalServerCommitRequired)?;
+	let server = SocketCodeServer {
+		commit_id: commit.clone(),
+		socket,
+		origin: Arc::new(CodeServerOrigin::External),
+	};
+
+	let key = CodeServerKey {
+		quality: params.quality,
+		commit,
+		instance: params.instance.clone(),
+		settings: ServerLaunchSettings::default(),
+	};
+	c.code_servers
+		.lock()
+		.await
+		.insert(key.clone(), server.clone());
+	tokio::spawn(check_code_server_health(
+		Arc::downgrade(c),
+		key,
+		server.clone(),
+	));
+	Ok(server)
+}
+
+/// Periodically requests the server's version over its socket, recording
+/// whether it answered for `serverhealth`, until the server is released or
+/// the connection closes. This lets clients tell a hung server apart from
+/// problems with their connection.
+async fn check_code_server_health(
+	c: Weak<HandlerContext>,
+	key: CodeServerKey,
+	server: SocketCodeServer,
+) {
+	let mut last_healthy_at = None;
+	let mut interval = tokio::time::interval(CODE_SERVER_HEALTH_INTERVAL);
+	loop {
+		interval.tick().await;
+		let c = match c.upgrade() {
+			Some(c) => c,
+			None => return,
+		};
+
+		let current = c
+			.code_servers
+			.lock()
+			.await
+			.get(&key)
+			.map(|s| s.socket.clone());
+		if current.as_ref() != Some(&server.socket) {
+			// a replacement server records its own health
+			if current.is_none() {
+				c.code_server_health.lock().unwrap().remove(&key);
+			}
+			return;
+		}
+
+		let check = tokio::time::timeout(CODE_SERVER_HEALTH_TIMEOUT, request_version(&server));
+		let error = match check.await {
+			Ok(Ok(())) => None,
+			Ok(Err(e)) => Some(e),
+			Err(_) => Some("timed out".to_string()),
+		};
+
+		let now = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map_or(0, |d| d.as_secs());
+		match &error {
+			None => last_healthy_at = Some(now),
+			Some(e) => warning!(
+				c.log,
+				"{} server {} failed its health check: {}",
+				key.quality,
+				key.commit,
+				e
+			),
+		}
+
+		c.code_server_health.lock().unwrap().insert(
+			key.clone(),
+			CodeServerHealth {
+				quality: key.quality,
+				commit: key.commit.clone(),
+				instance: key.instance.clone(),
+				healthy: error.is_none(),
+				last_checked_at: now,
+				last_healthy_at,
+				error,
+			},
+		);
+	}
+}
+
+async fn request_version(server: &SocketCodeServer) -> Result<(), String> {
+	let params = CallServerHttpParams {
+		path: "/version".to_string(),
+		method: "GET".to_string(),
+		headers: HashMap::new(),
+		body: None,
+		commit_id: None,
+		instance: None,
+	};
+	let res = send_server_http_request(server.clone(), params)
+		.await
+		.map_err(|e| e.to_string())?;
+	if res.status().is_success() {
+		Ok(())
+	} else {
+		Err(format!("it responded with status {}", res.status()))
+	}
+}
+
 async fn attach_server_bridge(
 	log: &log::Logger,
 	code_server: SocketCodeServer,
@@ -586,7 +2161,10 @@ async fn attach_server_bridge(
 		)
 	};
 
-	let attached_fut = ServerBridge::new(&code_server.socket, server_messages, decoder).await;
+	// servers run by other supervisors may be run by other users
+	let check_peer = !matches!(*code_server.origin, CodeServerOrigin::External);
+	let attached_fut =
+		ServerBridge::new(&code_server.socket, server_messages, decoder, check_peer).await;
 	match attached_fut {
 		Ok(a) => {
 			multiplexer.register(socket_id, a);
@@ -611,36 +2189,145 @@ fn handle_server_message(
 	}
 }
 
-fn handle_prune(paths: &LauncherPaths) -> Result<Vec<String>, AnyError> {
-	prune_stopped_servers(paths).map(|v| {
-		v.iter()
-			.map(|p| p.server_dir.display().to_string())
+fn handle_prune(paths: &LauncherPaths, params: DryRunParams) -> Result<Vec<String>, AnyError> {
+	let servers = if params.dry_run {
+		get_stopped_servers(paths)
+	} else {
+		prune_stopped_servers(paths)?
+	};
+
+	Ok(servers
+		.iter()
+		.map(|p| p.server_dir.display().to_string())
+		.collect())
+}
+
+async fn handle_gc(c: &HandlerContext, params: DryRunParams) -> Result<GcResult, AnyError> {
+	let dry_run = params.dry_run;
+	let mut http_requests_cleared = 0;
+	let idle_code_servers: Vec<_> = {
+		let sockets = c.server_state.sockets.lock().unwrap();
+		for s in sockets.values() {
+			let mut reqs = s.http_requests.lock().unwrap();
+			if dry_run {
+				http_requests_cleared += reqs.values().filter(|r| r.is_closed()).count();
+				continue;
+			}
+
+			let before = reqs.len();
+			reqs.retain(|_, r| !r.is_closed());
+			http_requests_cleared += before - reqs.len();
+		}
+
+		sockets
+			.values()
+			.filter(|s| s.server_bridges.len() == 0)
+			.map(|s| s.code_servers.clone())
 			.collect()
+	};
+
+	let mut code_servers_released = 0;
+	for servers in idle_code_servers {
+		let mut servers = servers.lock().await;
+		code_servers_released += servers.len();
+		if !dry_run {
+			servers.clear();
+		}
+	}
+
+	let mut cache_entries_removed = vec![];
+	let mut bytes_freed = 0;
+	// entries that processes run from, such as servers, aren't removed
+	let in_use = |p: &Path| machine::find_running_process(p).is_some();
+	for cache in [&c.launcher_paths.server_cache, &c.launcher_paths.cli_cache] {
+		let removed = if dry_run {
+			cache.trim_preview(in_use)
+		} else {
+			cache.trim(in_use)?
+		};
+		for (path, size) in removed {
+			cache_entries_removed.push(path.display().to_string());
+			bytes_freed += size;
+		}
+	}
+
+	let server_instances_removed: Vec<_> = if dry_run {
+		get_stale_instances(&c.launcher_paths)
+	} else {
+		prune_stale_instances(&c.launcher_paths)?
+	}
+	.iter()
+	.map(|d| d.display().to_string())
+	.collect();
+
+	if !dry_run {
+		info!(
+			c.log,
+			"Released {} code servers, {} http requests, {} cache entries ({} bytes), and {} server instances",
+			code_servers_released,
+			http_requests_cleared,
+			cache_entries_removed.len(),
+			bytes_freed,
+			server_instances_removed.len()
+		);
+	}
+
+	Ok(GcResult {
+		code_servers_released,
+		http_requests_cleared,
+		cache_entries_removed,
+		bytes_freed,
+		server_instances_removed,
 	})
 }
 
+fn download_progress(c: &HandlerContext, name: &str) -> DownloadProgress {
+	DownloadProgress::new(
+		c.log.clone(),
+		c.notifier.clone(),
+		c.socket_id,
+		name.to_string(),
+	)
+}
+
 async fn handle_update(
 	http: &Arc<FallbackSimpleHttp>,
 	log: &log::Logger,
+	launcher_paths: &LauncherPaths,
+	server_state: &ServerState,
 	did_update: &AtomicBool,
 	params: &UpdateParams,
+	progress: DownloadProgress,
 ) -> Result<UpdateResult, AnyError> {
 	if matches!(is_integrated_cli(), Ok(true)) || did_update.load(Ordering::SeqCst) {
 		return Ok(UpdateResult {
 			up_to_date: true,
 			did_update: false,
+			would_update_to: None,
 		});
 	}
 
 	let update_service = UpdateService::new(log.clone(), http.clone());
-	let updater = SelfUpdate::new(&update_service)?;
+	let updater = SelfUpdate::new(&update_service, launcher_paths)?;
 	let latest_release = updater.get_current_release().await?;
 	let up_to_date = updater.is_up_to_date_with(&latest_release);
 
+	if params.dry_run {
+		return Ok(UpdateResult {
+			up_to_date,
+			did_update: false,
+			would_update_to: (params.do_update && !up_to_date).then(|| UpdateRelease {
+				name: latest_release.name,
+				commit: latest_release.commit,
+			}),
+		});
+	}
+
 	if !params.do_update || up_to_date {
 		return Ok(UpdateResult {
 			up_to_date,
 			did_update: false,
+			would_update_to: None,
 		});
 	}
 
@@ -651,34 +2338,479 @@ async fn handle_update(
 		return Ok(UpdateResult {
 			up_to_date: true,
 			did_update: true, // well, another thread did, but same difference...
+			would_update_to: None,
 		});
 	}
 
+	// allow the update to be tried again if it fails or the call is cancelled
+	let reset = ResetOnDrop(did_update);
+	let _updating = server_state
+		.updating
+		.try_lock()
+		.map_err(|_| CodeError::UpdateInProgress)?;
 	info!(log, "Updating CLI to {}", latest_release);
 
-	updater
-		.do_update(&latest_release, SilentCopyProgress())
-		.await?;
+	updater.do_update(&latest_release, progress).await?;
+	std::mem::forget(reset);
 
 	Ok(UpdateResult {
 		up_to_date: true,
 		did_update: true,
+		would_update_to: None,
 	})
 }
 
+/// Reinstalls the CLI that the last update replaced. Like updates, the tunnel
+/// respawns into it once the connection closes.
+fn handle_rollback(
+	http: &Arc<FallbackSimpleHttp>,
+	log: &log::Logger,
+	launcher_paths: &LauncherPaths,
+	server_state: &ServerState,
+	did_update: &AtomicBool,
+) -> Result<EmptyObject, AnyError> {
+	// replaces the binary like an update, so mustn't run alongside one
+	let _updating = server_state
+		.updating
+		.try_lock()
+		.map_err(|_| CodeError::UpdateInProgress)?;
+	let update_service = UpdateService::new(log.clone(), http.clone());
+	let updater = SelfUpdate::new(&update_service, launcher_paths)?;
+
+	info!(log, "Rolling back the CLI to its previous version");
+	updater.rollback()?;
+	did_update.store(true, Ordering::SeqCst);
+
+	Ok(EmptyObject {})
+}
+
+struct ResetOnDrop<'a>(&'a AtomicBool);
+
+impl Drop for ResetOnDrop<'_> {
+	fn drop(&mut self) {
+		self.0.store(false, Ordering::SeqCst);
+	}
+}
+
 fn handle_get_hostname() -> Result<GetHostnameResponse, AnyError> {
 	Ok(GetHostnameResponse {
 		value: gethostname::gethostname().to_string_lossy().into_owned(),
 	})
 }
 
+fn handle_spawn_history(
+	server_state: &ServerState,
+	params: SpawnHistoryParams,
+) -> Result<SpawnHistoryResult, AnyError> {
+	let mut entries = match &server_state.spawn_history {
+		Some(h) => h.read_last(params.limit.unwrap_or(DEFAULT_SPAWN_HISTORY_LIMIT))?,
+		None => vec![],
+	};
+	entries.reverse();
+	Ok(SpawnHistoryResult { entries })
+}
+
+fn handle_health(server_state: &ServerState) -> Result<HealthResult, AnyError> {
+	let mut sys = System::new();
+	let pid = Pid::from_u32(std::process::id());
+	sys.refresh_process(pid);
+
+	let sockets = server_state.sockets.lock().unwrap();
+	Ok(HealthResult {
+		uptime_secs: sys.process(pid).map_or(0, |p| p.run_time()),
+		active_sockets: sockets.len(),
+		attached_bridges: sockets.values().map(|s| s.server_bridges.len()).sum(),
+		pending_http_requests: sockets
+			.values()
+			.map(|s| s.http_requests.lock().unwrap().len())
+			.sum(),
+		tunnel_connected_at: server_state
+			.tunnel_connected_at
+			.duration_since(UNIX_EPOCH)
+			.map_or(0, |d| d.as_secs()),
+	})
+}
+
+fn handle_metrics(
+	server_state: &ServerState,
+	port_forwarding: &PortForwarding,
+) -> Result<MetricsResult, AnyError> {
+	let sockets = server_state.sockets.lock().unwrap();
+	let snapshot = MetricsSnapshot {
+		live_bytes_tx: sockets
+			.values()
+			.map(|s| s.tx_counter.load(Ordering::Relaxed) as u64)
+			.sum(),
+		live_bytes_rx: sockets
+			.values()
+			.map(|s| s.rx_counter.load(Ordering::Relaxed) as u64)
+			.sum(),
+		active_sockets: sockets.len(),
+		attached_bridges: sockets.values().map(|s| s.server_bridges.len()).sum(),
+		forwarded_ports: port_forwarding.forwarded_ports().len(),
+		pending_http_requests: sockets
+			.values()
+			.map(|s| s.http_requests.lock().unwrap().len())
+			.sum(),
+	};
+
+	Ok(MetricsResult {
+		text: server_state.metrics.render(&snapshot, &tags()),
+	})
+}
+
+async fn handle_dump_state(c: &HandlerContext) -> Result<DumpStateResult, AnyError> {
+	// copy out the handles so the lock isn't held while reading code servers
+	let sockets: Vec<_> = c
+		.server_state
+		.sockets
+		.lock()
+		.unwrap()
+		.iter()
+		.map(|(id, s)| {
+			(
+				SocketStateDump {
+					id: *id,
+					current: *id == c.socket_id,
+					tx_bytes: s.tx_counter.load(Ordering::Relaxed) as u64,
+					rx_bytes: s.rx_counter.load(Ordering::Relaxed) as u64,
+					outbound_queue: SOCKET_QUEUE_SIZE - s.socket_tx.capacity(),
+					pending_http_requests: s.http_requests.lock().unwrap().len(),
+					spawned_processes: s.spawned.list().len(),
+					bridges: s
+						.server_bridges
+						.queue_depths()
+						.into_iter()
+						.map(|(id, write_queue)| BridgeStateDump { id, write_queue })
+						.collect(),
+					code_servers: vec![],
+				},
+				s.code_servers.clone(),
+			)
+		})
+		.collect();
+
+	let mut socket_dumps = Vec::with_capacity(sockets.len());
+	for (mut dump, code_servers) in sockets {
+		dump.code_servers = code_servers
+			.lock()
+			.await
+			.values()
+			.map(|s| CodeServerStateDump {
+				commit_id: s.commit_id.clone(),
+				socket: redact_path(&s.socket),
+			})
+			.collect();
+		dump.code_servers
+			.sort_by(|a, b| a.commit_id.cmp(&b.commit_id));
+		socket_dumps.push(dump);
+	}
+	socket_dumps.sort_by_key(|s| s.id);
+
+	let mut forwarded_ports = c.port_forwarding.forwarded_ports();
+	forwarded_ports.sort_unstable();
+	let mut server_env: Vec<_> = c.code_server_args.server_env.keys().cloned().collect();
+	server_env.sort();
+
+	Ok(DumpStateResult {
+		taken_at: SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map_or(0, |d| d.as_secs()),
+		cli_version: VersionParams::default().version,
+		tunnel_connected_at: c
+			.server_state
+			.tunnel_connected_at
+			.duration_since(UNIX_EPOCH)
+			.map_or(0, |d| d.as_secs()),
+		forwarded_ports,
+		sockets: socket_dumps,
+		config: ConfigStateDump {
+			platform: c.platform.to_string(),
+			data_dir: redact_path(c.launcher_paths.root()),
+			server_args: redact_args(c.code_server_args.command_arguments()),
+			server_env,
+		},
+	})
+}
+
+/// Checks the admin token a client gave, returning `disabled` if the tunnel
+/// wasn't started with one.
+fn check_admin_token(
+	c: &HandlerContext,
+	token: &str,
+	method: &str,
+	disabled: CodeError,
+) -> Result<(), AnyError> {
+	match &c.server_state.admin_token {
+		None => Err(disabled.into()),
+		Some(t) if !constant_time_eq(t.as_bytes(), token.as_bytes()) => {
+			warning!(
+				c.log,
+				"Rejected {} request with an incorrect admin token",
+				method
+			);
+			Err(CodeError::InvalidAdminToken.into())
+		}
+		Some(_) => Ok(()),
+	}
+}
+
+fn connection_token_file(c: &HandlerContext) -> Result<ConnectionTokenFile, AnyError> {
+	match &c.code_server_args.connection_token_file {
+		Some(path) => Ok(ConnectionTokenFile::at(PathBuf::from(path))),
+		None => Err(CodeError::ConnectionTokenFileNotUsed.into()),
+	}
+}
+
+fn handle_get_connection_token(
+	c: &HandlerContext,
+	params: AdminParams,
+) -> Result<ConnectionTokenResult, AnyError> {
+	check_admin_token(
+		c,
+		&params.token,
+		"getconnectiontoken",
+		CodeError::AdminTokenRequired("getting the connection token"),
+	)?;
+	let connection_token = connection_token_file(c)?
+		.read()
+		.map_err(|e| wrap(e, "error reading connection token"))?;
+	Ok(ConnectionTokenResult { connection_token })
+}
+
+/// Replaces the connection token. Servers that are running keep accepting the
+/// old token until they're restarted.
+fn handle_rotate_connection_token(
+	c: &HandlerContext,
+	params: AdminParams,
+) -> Result<ConnectionTokenResult, AnyError> {
+	check_admin_token(
+		c,
+		&params.token,
+		"rotateconnectiontoken",
+		CodeError::AdminTokenRequired("rotating the connection token"),
+	)?;
+	let connection_token = connection_token_file(c)?
+		.rotate()
+		.map_err(|e| wrap(e, "error rotating connection token"))?;
+	info!(
+		c.log,
+		"Connection {} rotated the connection token", c.socket_id
+	);
+	Ok(ConnectionTokenResult { connection_token })
+}
+
+async fn handle_shutdown(
+	c: &HandlerContext,
+	params: ShutdownParams,
+) -> Result<EmptyObject, AnyError> {
+	check_admin_token(
+		c,
+		&params.token,
+		"shutdown",
+		CodeError::RemoteShutdownDisabled,
+	)?;
+
+	let grace_period = params
+		.grace_period_ms
+		.map(Duration::from_millis)
+		.unwrap_or(DEFAULT_SHUTDOWN_GRACE_PERIOD)
+		.min(MAX_SHUTDOWN_GRACE_PERIOD);
+	let message = params
+		.reason
+		.unwrap_or_else(|| "The tunnel is being shut down by an administrator".to_string());
+	info!(
+		c.log,
+		"Connection {} requested shutdown in {:?}: {}", c.socket_id, grace_period, message
+	);
+
+	c.notifier
+		.notify_all(ClientRequestMethod::shuttingdown(ShuttingDownParams {
+			message,
+			grace_period_ms: grace_period.as_millis() as u64,
+		}))
+		.await;
+
+	let server_tx = c.server_tx.clone();
+	tokio::spawn(async move {
+		tokio::time::sleep(grace_period).await;
+		server_tx
+			.send(ServerSignal::Shutdown(ShutdownSignal::RpcShutdownRequested))
+			.await
+			.ok();
+	});
+
+	Ok(EmptyObject {})
+}
+
+/// Compares secrets without exiting early, so the time taken doesn't reveal
+/// how much of the secret was guessed correctly.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+	a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+async fn handle_rename_tunnel(
+	c: &HandlerContext,
+	params: RenameTunnelParams,
+) -> Result<EmptyObject, AnyError> {
+	// the tunnel's ID doesn't change, so the connection to it stays up
+	let auth = Auth::new(&c.launcher_paths, c.log.clone());
+	let mut dt = DevTunnels::new(&c.log, auth, &c.launcher_paths);
+	dt.rename_tunnel(&params.name).await?;
+	info!(c.log, "Renamed the tunnel to {}", params.name);
+
+	Ok(EmptyObject {})
+}
+
+fn handle_identify(c: &HandlerContext, identity: ClientIdentity) -> Result<EmptyObject, AnyError> {
+	info!(
+		c.log,
+		"Connection {} identified as {} {}",
+		c.socket_id,
+		identity.name,
+		identity.user.as_deref().unwrap_or("(no user)")
+	);
+
+	if let Some(s) = c.server_state.sockets.lock().unwrap().get_mut(&c.socket_id) {
+		s.identity = Some(identity);
+	}
+
+	Ok(EmptyObject {})
+}
+
+/// Gets the identity the connection gave in `identify`.
+fn client_identity(c: &HandlerContext) -> Result<ClientIdentity, AnyError> {
+	c.server_state
+		.sockets
+		.lock()
+		.unwrap()
+		.get(&c.socket_id)
+		.and_then(|s| s.identity.clone())
+		.ok_or_else(|| CodeError::ClientNotIdentified.into())
+}
+
+async fn handle_list_sessions(c: &HandlerContext) -> Result<ListSessionsResult, AnyError> {
+	// copy out the handles so the lock isn't held while reading code servers
+	let sockets: Vec<_> = c
+		.server_state
+		.sockets
+		.lock()
+		.unwrap()
+		.iter()
+		.map(|(id, s)| {
+			(
+				SessionInfo {
+					id: *id,
+					current: *id == c.socket_id,
+					connected_at: s
+						.connected_at
+						.duration_since(UNIX_EPOCH)
+						.map_or(0, |d| d.as_secs()),
+					client: s.identity.clone(),
+					tx_bytes: s.tx_counter.load(Ordering::Relaxed) as u64,
+					rx_bytes: s.rx_counter.load(Ordering::Relaxed) as u64,
+					code_servers: vec![],
+				},
+				s.code_servers.clone(),
+			)
+		})
+		.collect();
+
+	let mut sessions = Vec::with_capacity(sockets.len());
+	for (mut session, code_servers) in sockets {
+		session.code_servers = code_servers
+			.lock()
+			.await
+			.values()
+			.map(|s| s.commit_id.clone())
+			.collect();
+		session.code_servers.sort();
+		sessions.push(session);
+	}
+	sessions.sort_by_key(|s| s.id);
+
+	Ok(ListSessionsResult { sessions })
+}
+
+async fn handle_disconnect_session(
+	c: &HandlerContext,
+	params: DisconnectSessionParams,
+) -> Result<EmptyObject, AnyError> {
+	if params.id != c.socket_id {
+		check_admin_token(
+			c,
+			params.token.as_deref().unwrap_or_default(),
+			"disconnectsession",
+			CodeError::AdminTokenRequired("disconnecting other sessions"),
+		)?;
+	}
+
+	let socket_tx = match c.server_state.sockets.lock().unwrap().get(&params.id) {
+		Some(s) => s.socket_tx.clone(),
+		None => return Err(CodeError::NoSuchSession(params.id).into()),
+	};
+
+	let message = params
+		.reason
+		.unwrap_or_else(|| "Disconnected by the machine owner".to_string());
+	info!(
+		c.log,
+		"Connection {} disconnected connection {}: {}", c.socket_id, params.id, message
+	);
+
+	// tell the client why before closing, so it doesn't just try to reconnect
+	c.notifier
+		.notify(
+			params.id,
+			ClientRequestMethod::draining(DrainingParams {
+				reason: ShutdownReason::Disconnected,
+				message: message.clone(),
+				restart_eta_ms: None,
+			}),
+		)
+		.await;
+	socket_tx
+		.send(SocketSignal::CloseWith(CloseReason(message)))
+		.await
+		.ok();
+
+	Ok(EmptyObject {})
+}
+
+fn handle_sysinfo(launcher_paths: &LauncherPaths) -> Result<SysInfoResult, AnyError> {
+	let mut sys = System::new();
+	sys.refresh_memory();
+	sys.refresh_cpu();
+	sys.refresh_disks_list();
+
+	// the data directory is on the disk with the most specific mount point
+	let data_dir_free_space = sys
+		.disks()
+		.iter()
+		.filter(|d| launcher_paths.root().starts_with(d.mount_point()))
+		.max_by_key(|d| d.mount_point().as_os_str().len())
+		.map(|d| d.available_space());
+
+	Ok(SysInfoResult {
+		os_name: sys.name(),
+		os_version: sys.long_os_version(),
+		kernel_version: sys.kernel_version(),
+		arch: std::env::consts::ARCH.to_string(),
+		cpu_count: sys.cpus().len(),
+		total_memory: sys.total_memory(),
+		free_memory: sys.available_memory(),
+		data_dir_free_space,
+		tls_backend: TLS_BACKEND.to_string(),
+	})
+}
+
 async fn handle_forward(
 	log: &log::Logger,
 	port_forwarding: &PortForwarding,
 	params: ForwardParams,
 ) -> Result<ForwardResult, AnyError> {
 	info!(log, "Forwarding port {}", params.port);
-	let uri = port_forwarding.forward(params.port).await?;
+	let uri = port_forwarding.forward(params.port, params.target).await?;
 	Ok(ForwardResult { uri })
 }
 
@@ -692,21 +2824,96 @@ async fn handle_unforward(
 	Ok(EmptyObject {})
 }
 
+/// Gets the connection's server with the commit and instance the call gives,
+/// or its only server if it gives neither.
+fn pick_code_server(
+	servers: &HashMap<CodeServerKey, SocketCodeServer>,
+	params: &CallServerHttpParams,
+) -> Result<SocketCodeServer, AnyError> {
+	let mut matching = servers.iter().filter(|(k, _)| {
+		params.commit_id.as_ref().map_or(true, |c| &k.commit == c)
+			&& params
+				.instance
+				.as_ref()
+				.map_or(true, |i| k.instance.as_ref() == Some(i))
+	});
+	match (matching.next(), matching.next()) {
+		(Some((_, s)), None) => Ok(s.clone()),
+		(Some(_), Some(_)) => Err(CodeError::AmbiguousCodeServer.into()),
+		(None, _) => Err(NoAttachedServerError().into()),
+	}
+}
+
 async fn handle_call_server_http(
-	code_server: Option<SocketCodeServer>,
+	code_server: SocketCodeServer,
 	params: CallServerHttpParams,
 ) -> Result<CallServerHttpResult, AnyError> {
-	use hyper::{body, client::conn::Builder, Body, Request};
+	let response = send_server_http_request(code_server, params).await?;
 
-	// We use Hyper directly here since reqwest doesn't support sockets/pipes.
-	// See https://github.com/seanmonstar/reqwest/issues/39
+	Ok(CallServerHttpResult {
+		status: response.status().as_u16(),
+		headers: server_http_headers(&response),
+		body: hyper::body::to_bytes(response)
+			.await
+			.map_err(|e| wrap(e, "error reading response body"))?
+			.to_vec(),
+	})
+}
 
-	let socket = match &code_server {
-		Some(cs) => &cs.socket,
-		None => return Err(AnyError::from(NoAttachedServerError())),
+/// Like `handle_call_server_http`, but sends the body as it's read rather
+/// than buffering it, for large responses.
+async fn handle_call_server_http_chunked(
+	code_server: SocketCodeServer,
+	params: CallServerHttpParams,
+	chunks: ChunkSender<CallServerHttpChunk>,
+) -> Result<EmptyObject, AnyError> {
+	use hyper::body::HttpBody;
+
+	let mut response = send_server_http_request(code_server, params).await?;
+	let head = CallServerHttpChunk::Head {
+		status: response.status().as_u16(),
+		headers: server_http_headers(&response),
 	};
+	if !chunks.send(head).await {
+		return Ok(EmptyObject {});
+	}
 
-	let rw = get_socket_rw_stream(socket).await?;
+	while let Some(data) = response.body_mut().data().await {
+		let data = data.map_err(|e| wrap(e, "error reading response body"))?;
+		if !chunks
+			.send(CallServerHttpChunk::Body {
+				data: data.to_vec(),
+			})
+			.await
+		{
+			break;
+		}
+	}
+
+	Ok(EmptyObject {})
+}
+
+fn server_http_headers(response: &hyper::Response<hyper::Body>) -> HashMap<String, String> {
+	response
+		.headers()
+		.into_iter()
+		.map(|(k, v)| (k.to_string(), v.to_str().unwrap_or("").to_string()))
+		.collect()
+}
+
+async fn send_server_http_request(
+	code_server: SocketCodeServer,
+	params: CallServerHttpParams,
+) -> Result<hyper::Response<hyper::Body>, AnyError> {
+	use hyper::{client::conn::Builder, Body, Request};
+
+	// We use Hyper directly here since reqwest doesn't support sockets/pipes.
+	// See https://github.com/seanmonstar/reqwest/issues/39
+
+	let rw = get_socket_rw_stream(&code_server.socket).await?;
+	if !matches!(*code_server.origin, CodeServerOrigin::External) {
+		check_peer_is_current_user(&rw, &code_server.socket)?;
+	}
 
 	let (mut request_sender, connection) = Builder::new()
 		.handshake(rw)
@@ -733,17 +2940,36 @@ async fn handle_call_server_http(
 		.await
 		.map_err(|e| wrap(e, "error sending request"))?;
 
-	Ok(CallServerHttpResult {
-		status: response.status().as_u16(),
-		headers: response
-			.headers()
-			.into_iter()
-			.map(|(k, v)| (k.to_string(), v.to_str().unwrap_or("").to_string()))
-			.collect(),
-		body: body::to_bytes(response)
-			.await
-			.map_err(|e| wrap(e, "error reading response body"))?
-			.to_vec(),
+	Ok(response)
+}
+
+async fn handle_list_releases(
+	http: &Arc<FallbackSimpleHttp>,
+	log: &log::Logger,
+	server_platform: Platform,
+	params: ListReleasesParams,
+) -> Result<ListReleasesResult, AnyError> {
+	let (target, default_platform) = match params.target {
+		ReleaseTarget::Cli => (TargetKind::Cli, Platform::env_default()),
+		ReleaseTarget::Server => (TargetKind::Server, Some(server_platform)),
+	};
+	let platform = params
+		.platform
+		.or(default_platform)
+		.ok_or_else(|| CodeError::UnsupportedPlatform(std::env::consts::ARCH.to_string()))?;
+
+	let releases = UpdateService::new(log.clone(), http.clone())
+		.list_releases(
+			platform,
+			target,
+			params.quality,
+			params.custom_quality.as_deref(),
+			params.limit.unwrap_or(DEFAULT_LISTED_RELEASES),
+		)
+		.await?;
+
+	Ok(ListReleasesResult {
+		releases: releases.into_iter().map(ReleaseInfo::from).collect(),
 	})
 }
 
@@ -751,7 +2977,9 @@ async fn handle_acquire_cli(
 	paths: &LauncherPaths,
 	http: &Arc<FallbackSimpleHttp>,
 	log: &log::Logger,
+	spawned: &SpawnedProcesses,
 	params: AcquireCliParams,
+	progress: DownloadProgress,
 ) -> Result<SpawnResult, AnyError> {
 	let update_service = UpdateService::new(log.clone(), http.clone());
 
@@ -762,24 +2990,27 @@ async fn handle_acquire_cli(
 			platform: params.platform,
 			quality: params.quality,
 			target: TargetKind::Cli,
+			custom_quality: None,
 		},
 		None => {
 			update_service
-				.get_latest_commit(params.platform, TargetKind::Cli, params.quality)
+				.get_latest_commit(params.platform, TargetKind::Cli, params.quality, None)
 				.await?
 		}
 	};
 
-	let cli = download_cli_into_cache(&paths.cli_cache, &release, &update_service).await?;
+	let cli =
+		download_cli_into_cache(&paths.cli_cache, &release, &update_service, progress).await?;
 	let file = tokio::fs::File::open(cli)
 		.await
 		.map_err(|e| wrap(e, "error opening cli file"))?;
 
-	handle_spawn::<_, DuplexStream>(log, params.spawn, Some(file), None, None).await
+	handle_spawn::<_, DuplexStream>(log, spawned, params.spawn, Some(file), None, None).await
 }
 
 async fn handle_spawn<Stdin, StdoutAndErr>(
 	log: &log::Logger,
+	spawned: &SpawnedProcesses,
 	params: SpawnParams,
 	stdin: Option<Stdin>,
 	stdout: Option<StdoutAndErr>,
@@ -804,14 +3035,21 @@ where
 		};
 	}
 
-	let mut p = tokio::process::Command::new(&params.command)
-		.args(&params.args)
+	let (command, args) = command_line(&params);
+	let mut p = tokio::process::Command::new(command);
+	if let Some(cwd) = &params.cwd {
+		p.current_dir(cwd);
+	}
+
+	let mut p = p
+		.args(args)
 		.envs(&params.env)
 		.stdin(pipe_if_some!(stdin))
 		.stdout(pipe_if_some!(stdout))
 		.stderr(pipe_if_some!(stderr))
 		.spawn()
 		.map_err(CodeError::ProcessSpawnFailed)?;
+	let tracked = p.id().map(|pid| spawned.track(pid, &params));
 
 	let futs = FuturesUnordered::new();
 	if let (Some(mut a), Some(mut b)) = (p.stdout.take(), stdout) {
@@ -832,14 +3070,17 @@ where
 		r = &mut closed => r
 	};
 
+	let usage = tracked.map(|t| t.finish());
 	let r = match r {
 		Ok(e) => SpawnResult {
 			message: e.to_string(),
 			exit_code: e.code().unwrap_or(-1),
+			usage,
 		},
 		Err(e) => SpawnResult {
 			message: e.to_string(),
 			exit_code: -1,
+			usage,
 		},
 	};
 
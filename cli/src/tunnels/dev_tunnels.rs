@@ -8,6 +8,7 @@ use crate::constants::{
 	TUNNEL_SERVICE_USER_AGENT,
 };
 use crate::state::{LauncherPaths, PersistedState};
+use crate::tunnels::protocol::singleton::TunnelQuota;
 use crate::util::errors::{
 	wrap, AnyError, DevTunnelError, InvalidTunnelName, TunnelCreationFailed, WrappedError,
 };
@@ -186,6 +187,36 @@ impl ActiveTunnel {
 
 const VSCODE_CLI_TUNNEL_TAG: &str = "vscode-server-launcher";
 const MAX_TUNNEL_NAME_LENGTH: usize = 20;
+/// Number of tunnels the service allows on an account, unless the config
+/// gives another limit.
+const MAX_TUNNELS: usize = 10;
+
+/// Limits of the tunnel service account, read from `tunnel_limits` in the data
+/// dir's config.json for accounts whose limits differ from the defaults.
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct TunnelLimits {
+	/// Number of tunnels allowed on the account.
+	pub tunnels: Option<usize>,
+	/// Bytes the account's tunnels may transfer, if it has a bandwidth quota.
+	pub bandwidth_bytes: Option<u64>,
+}
+
+/// Gets the bytes the tunnel transferred, if the service reports them. The
+/// totals are read through JSON since only some versions of the service send
+/// them, either as a number or as a status with the current value.
+fn get_bytes_transferred(tunnel: &Tunnel) -> Option<u64> {
+	let status = serde_json::to_value(tunnel.status.as_ref()?).ok()?;
+	let total = |key: &str| {
+		let v = status.get(key)?;
+		v.as_u64().or_else(|| v.get("current")?.as_u64())
+	};
+
+	match (total("uploadTotal"), total("downloadTotal")) {
+		(None, None) => None,
+		(up, down) => Some(up.unwrap_or(0) + down.unwrap_or(0)),
+	}
+}
 
 fn get_host_token_from_tunnel(tunnel: &Tunnel) -> String {
 	tunnel
@@ -272,6 +303,29 @@ impl DevTunnels {
 		Ok(())
 	}
 
+	/// Gets how much of the account's limits are used, including by tunnels
+	/// not created by the CLI.
+	pub async fn get_quota(&mut self, limits: &TunnelLimits) -> Result<TunnelQuota, AnyError> {
+		let tunnels = spanf!(
+			self.log,
+			self.log.span("dev-tunnel.quota"),
+			self.client.list_all_tunnels(NO_REQUEST_OPTIONS)
+		)
+		.map_err(|e| wrap(e, "error listing tunnels"))?;
+
+		let bandwidth_bytes = tunnels
+			.iter()
+			.filter_map(get_bytes_transferred)
+			.reduce(|a, b| a + b);
+
+		Ok(TunnelQuota {
+			tunnels: tunnels.len(),
+			tunnel_limit: limits.tunnels.unwrap_or(MAX_TUNNELS),
+			bandwidth_bytes,
+			bandwidth_limit: limits.bandwidth_bytes,
+		})
+	}
+
 	/// Renames the current tunnel to the new name.
 	pub async fn rename_tunnel(&mut self, name: &str) -> Result<(), AnyError> {
 		is_valid_name(name)?;
@@ -498,7 +552,7 @@ impl DevTunnels {
 
 					return Err(AnyError::from(TunnelCreationFailed(
 						name.to_string(),
-						"You've exceeded the 10 machine limit for the port fowarding service. Please remove other machines before trying to add this machine.".to_string(),
+						format!("You've exceeded the {} machine limit for the port fowarding service. Please remove other machines before trying to add this machine.", MAX_TUNNELS),
 					)));
 				}
 				Err(e) => {
@@ -641,7 +695,8 @@ impl DevTunnels {
 				v.status
 					.as_ref()
 					.and_then(|s| s.host_connection_count.as_ref().map(|c| c.get_count()))
-					.unwrap_or(0) > 0 && v.tags.iter().any(|t| t == n)
+					.unwrap_or(0) > 0
+					&& v.tags.iter().any(|t| t == n)
 			})
 		};
 
@@ -0,0 +1,124 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::time::{Duration, Instant};
+
+use crate::{log, util::io::ReportCopyProgress};
+
+use super::{
+	notifier::Notifier,
+	protocol::{ClientRequestMethod, DownloadProgressParams},
+};
+
+/// Least time between reports, so fast downloads don't flood the client.
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Reports the progress of a download made for a client, with its speed and
+/// time remaining, to the log and to the client as `downloadprogress`.
+pub struct DownloadProgress {
+	log: log::Logger,
+	notifier: Notifier,
+	socket_id: u32,
+	name: String,
+	started: Instant,
+	last_reported: Option<Instant>,
+}
+
+impl DownloadProgress {
+	pub fn new(log: log::Logger, notifier: Notifier, socket_id: u32, name: String) -> Self {
+		Self {
+			log,
+			notifier,
+			socket_id,
+			name,
+			started: Instant::now(),
+			last_reported: None,
+		}
+	}
+}
+
+impl ReportCopyProgress for DownloadProgress {
+	fn report_progress(&mut self, bytes_so_far: u64, total_bytes: u64) {
+		let now = Instant::now();
+		let done = total_bytes > 0 && bytes_so_far >= total_bytes;
+		if !done
+			&& self
+				.last_reported
+				.map_or(false, |t| now.duration_since(t) < REPORT_INTERVAL)
+		{
+			return;
+		}
+		self.last_reported = Some(now);
+
+		let params = progress_params(
+			self.name.clone(),
+			bytes_so_far,
+			total_bytes,
+			now.duration_since(self.started),
+		);
+		debug!(
+			self.log,
+			"{} download: {}/{} bytes at {} bytes/s, {} remaining",
+			params.name,
+			params.bytes_so_far,
+			params.total_bytes,
+			params.bytes_per_second,
+			params
+				.eta_ms
+				.map(|ms| format!("{:?}", Duration::from_millis(ms)))
+				.unwrap_or_else(|| "unknown time".to_string()),
+		);
+		self.notifier.try_notify(
+			self.socket_id,
+			ClientRequestMethod::downloadprogress(params),
+		);
+	}
+}
+
+/// Computes the speed of a download, and its time remaining if its size and
+/// speed are known.
+fn progress_params(
+	name: String,
+	bytes_so_far: u64,
+	total_bytes: u64,
+	elapsed: Duration,
+) -> DownloadProgressParams {
+	let elapsed_ms = elapsed.as_millis() as u64;
+	let bytes_per_second = match elapsed_ms {
+		0 => 0,
+		ms => bytes_so_far * 1000 / ms,
+	};
+	let eta_ms = match (total_bytes, bytes_per_second) {
+		(0, _) | (_, 0) => None,
+		(total, rate) => Some(total.saturating_sub(bytes_so_far) * 1000 / rate),
+	};
+
+	DownloadProgressParams {
+		name,
+		bytes_so_far,
+		total_bytes,
+		bytes_per_second,
+		eta_ms,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_progress_params() {
+		let p = progress_params("cli".to_string(), 500, 2000, Duration::from_secs(2));
+		assert_eq!(p.bytes_per_second, 250);
+		assert_eq!(p.eta_ms, Some(6000));
+
+		let p = progress_params("cli".to_string(), 500, 0, Duration::from_secs(2));
+		assert_eq!(p.eta_ms, None);
+
+		let p = progress_params("cli".to_string(), 0, 2000, Duration::ZERO);
+		assert_eq!(p.bytes_per_second, 0);
+		assert_eq!(p.eta_ms, None);
+	}
+}
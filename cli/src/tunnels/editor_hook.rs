@@ -0,0 +1,259 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Lets `code <file>` run in a process spawned by a client open the file in
+//! that client's editor, git or ssh credential prompts show in the client, and
+//! URLs opened with `$BROWSER` or `xdg-open` open in the client's browser,
+//! like they do over SSH. Each connection listens on a socket whose path is
+//! given to the processes it spawns, along with shims for each of these that
+//! run this CLI.
+
+use std::{
+	collections::HashMap,
+	path::{Path, PathBuf},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::{sync::mpsc, task::JoinHandle};
+
+use crate::{
+	async_pipe::{get_socket_name, listen_socket_rw_stream, socket_stream_split, AsyncPipe},
+	constants::APPLICATION_NAME,
+	log,
+	msgpack_rpc::{new_msgpack_rpc, start_msgpack_rpc, MsgPackCaller},
+	state::LauncherPaths,
+	util::{
+		errors::{wrap, AnyError, CodeError, ServerHasClosed},
+		permissions::{create_private_dir_all, restrict_dir, write_private},
+		sync::new_barrier,
+	},
+};
+
+use super::protocol::{
+	AskpassParams, AskpassResult, EmptyObject, OpenExternalParams, OpenInEditorParams,
+	VersionParams,
+};
+
+/// Set in spawned processes to the path of the hook's socket.
+pub const EDITOR_HOOK_ENV: &str = "VSCODE_CLI_EDITOR_HOOK";
+
+pub struct EditorHook {
+	socket: PathBuf,
+	shims: Shims,
+	task: JoinHandle<()>,
+}
+
+struct Shims {
+	/// Directory containing the `code` shim, put first on the PATH.
+	dir: PathBuf,
+	askpass: PathBuf,
+	browser: PathBuf,
+}
+
+impl Drop for EditorHook {
+	fn drop(&mut self) {
+		self.task.abort();
+		#[cfg(unix)]
+		std::fs::remove_file(&self.socket).ok();
+	}
+}
+
+impl EditorHook {
+	/// Starts listening for editor requests, which are forwarded to the
+	/// client through `client`.
+	pub async fn start(
+		log: log::Logger,
+		paths: &LauncherPaths,
+		client: MsgPackCaller,
+	) -> Result<Self, AnyError> {
+		let shims = install_shims(paths)?;
+		let socket = get_socket_name();
+		let mut listener = listen_socket_rw_stream(&socket).await?;
+
+		let task = tokio::spawn(async move {
+			loop {
+				match listener.accept().await {
+					Ok(stream) => {
+						tokio::spawn(serve_connection(log.clone(), client.clone(), stream));
+					}
+					Err(e) => {
+						debug!(log, "editor hook closed: {}", e);
+						return;
+					}
+				}
+			}
+		});
+
+		Ok(Self {
+			socket,
+			shims,
+			task,
+		})
+	}
+
+	/// Variables that let a VS Code server started by this connection open
+	/// URLs in the client's browser.
+	pub fn server_env(&self) -> [(String, String); 2] {
+		[
+			(
+				EDITOR_HOOK_ENV.to_string(),
+				self.socket.to_string_lossy().to_string(),
+			),
+			(
+				"BROWSER".to_string(),
+				self.shims.browser.to_string_lossy().to_string(),
+			),
+		]
+	}
+
+	/// Adds the hook to the environment of a process to be spawned, putting
+	/// the `code` shim first on its PATH. Askpass and browser variables the
+	/// client set are kept.
+	pub fn apply_env(&self, env: &mut HashMap<String, String>) {
+		env.insert(
+			EDITOR_HOOK_ENV.to_string(),
+			self.socket.to_string_lossy().to_string(),
+		);
+
+		// xdg-open also uses $BROWSER on hosts without a desktop environment
+		env.entry("BROWSER".to_string())
+			.or_insert_with(|| self.shims.browser.to_string_lossy().to_string());
+
+		let askpass = self.shims.askpass.to_string_lossy().to_string();
+		for var in ["GIT_ASKPASS", "SSH_ASKPASS"] {
+			env.entry(var.to_string())
+				.or_insert_with(|| askpass.clone());
+		}
+		// ssh otherwise only uses askpass when there's no terminal and DISPLAY is set
+		env.entry("SSH_ASKPASS_REQUIRE".to_string())
+			.or_insert_with(|| "prefer".to_string());
+
+		let path = env
+			.get("PATH")
+			.cloned()
+			.or_else(|| std::env::var("PATH").ok())
+			.unwrap_or_default();
+		let dirs = std::iter::once(self.shims.dir.clone()).chain(std::env::split_paths(&path));
+		if let Ok(joined) = std::env::join_paths(dirs) {
+			env.insert("PATH".to_string(), joined.to_string_lossy().to_string());
+		}
+	}
+}
+
+/// Serves editor requests from one `code` invocation. The shim connects with
+/// a `ControlClient`, so the version is sent first like the control server
+/// does.
+async fn serve_connection(log: log::Logger, client: MsgPackCaller, stream: AsyncPipe) {
+	let (msg_tx, msg_rx) = mpsc::unbounded_channel();
+	let mut rpc = new_msgpack_rpc();
+	rpc.get_caller(msg_tx)
+		.notify("version", VersionParams::default());
+
+	let mut rpc = rpc.methods(client);
+	rpc.register_async("openineditor", |p: OpenInEditorParams, client| async move {
+		forward::<_, EmptyObject>(&client, "openineditor", p).await
+	});
+	rpc.register_async("askpass", |p: AskpassParams, client| async move {
+		forward::<_, AskpassResult>(&client, "askpass", p).await
+	});
+	rpc.register_async("openexternal", |p: OpenExternalParams, client| async move {
+		forward::<_, EmptyObject>(&client, "openexternal", p).await
+	});
+
+	let (read, write) = socket_stream_split(stream);
+	let (shutdown_rx, _shutdown_opener) = new_barrier::<()>();
+	if let Err(e) =
+		start_msgpack_rpc(rpc.build(log.clone()), read, write, msg_rx, shutdown_rx).await
+	{
+		debug!(log, "editor hook connection closed: {}", e);
+	}
+}
+
+/// Calls the method on the client, returning its response to the shim.
+async fn forward<P, R>(
+	client: &MsgPackCaller,
+	method: &'static str,
+	params: P,
+) -> Result<R, AnyError>
+where
+	P: Serialize,
+	R: DeserializeOwned + Send + 'static,
+{
+	match client.call::<_, _, R>(method, params).await {
+		Ok(Ok(r)) => Ok(r),
+		Ok(Err(e)) => Err(CodeError::TunnelRpcCallFailed(e).into()),
+		Err(_) => Err(ServerHasClosed().into()),
+	}
+}
+
+/// Writes the scripts that run this CLI.
+fn install_shims(paths: &LauncherPaths) -> Result<Shims, AnyError> {
+	let exe = std::env::current_exe().map_err(|e| wrap(e, "could not get current exe"))?;
+	let dir = paths.root().join("editor-shim");
+	create_private_dir_all(&dir).map_err(|e| wrap(e, "error creating editor shim directory"))?;
+
+	let askpass = write_shim(&dir.join("askpass"), &exe, "tunnel askpass -- ")?;
+	let browser = write_shim(&dir.join("browser"), &exe, "tunnel open-external -- ")?;
+	write_shim(&dir.join(APPLICATION_NAME), &exe, "")?;
+
+	Ok(Shims {
+		dir,
+		askpass,
+		browser,
+	})
+}
+
+/// Writes a script that runs `exe` with the given arguments prepended,
+/// returning its path.
+fn write_shim(path: &Path, exe: &Path, args: &str) -> Result<PathBuf, AnyError> {
+	#[cfg(windows)]
+	let (path, contents) = (
+		path.with_extension("cmd"),
+		format!("@\"{}\" {}%*\r\n", exe.display(), args),
+	);
+	#[cfg(not(windows))]
+	let (path, contents) = (
+		path.to_path_buf(),
+		format!("#!/bin/sh\nexec \"{}\" {}\"$@\"\n", exe.display(), args),
+	);
+
+	write_private(&path, contents)
+		.and_then(|_| restrict_dir(&path)) // scripts need the execute bit
+		.map_err(|e| wrap(e, format!("error writing {}", path.display())))?;
+
+	Ok(path)
+}
+
+/// Parses a `file[:line[:character]]` argument given to `code --goto`.
+pub fn parse_goto(arg: &str) -> (&str, Option<u32>) {
+	let mut parts = arg.rsplitn(3, ':');
+	let last = parts.next();
+	let second = parts.next();
+	let rest = parts.next();
+
+	match (
+		rest,
+		second.and_then(|s| s.parse().ok()),
+		last.and_then(|s| s.parse().ok()),
+	) {
+		(Some(file), Some(line), Some(_)) => (file, Some(line)),
+		(_, _, Some(line)) => (arg.rsplit_once(':').map_or(arg, |(f, _)| f), Some(line)),
+		_ => (arg, None),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse_goto() {
+		assert_eq!(parse_goto("file.txt"), ("file.txt", None));
+		assert_eq!(parse_goto("file.txt:12"), ("file.txt", Some(12)));
+		assert_eq!(parse_goto("file.txt:12:4"), ("file.txt", Some(12)));
+		assert_eq!(parse_goto("C:\\src\\a.rs:3"), ("C:\\src\\a.rs", Some(3)));
+		assert_eq!(parse_goto("a:b.txt"), ("a:b.txt", None));
+	}
+}
@@ -0,0 +1,103 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::time::Duration;
+
+use futures::future::join_all;
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::util::command::capture_command_and_check_status;
+
+use super::{
+	protocol::{EnvProbeResult, ToolInfo},
+	spawned_processes::default_shell,
+};
+
+lazy_static! {
+	static ref VERSION_RE: Regex = Regex::new(r"\d+\.\d+(\.\d+)?").unwrap();
+}
+
+/// How long a tool gets to print its version before it's considered missing.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Toolchains that are probed, and the commands each may be installed as,
+/// in order of preference.
+const TOOLS: &[(&str, &[&str])] = &[
+	("git", &["git"]),
+	("node", &["node"]),
+	("python", &["python3", "python"]),
+	("docker", &["docker"]),
+];
+
+/// Detects which common toolchains are installed on the host.
+pub async fn probe_environment() -> EnvProbeResult {
+	EnvProbeResult {
+		tools: join_all(
+			TOOLS
+				.iter()
+				.map(|(name, commands)| probe_tool(name, commands)),
+		)
+		.await,
+		default_shell: default_shell(),
+	}
+}
+
+async fn probe_tool(name: &str, commands: &[&str]) -> ToolInfo {
+	for command in commands {
+		let output = tokio::time::timeout(
+			PROBE_TIMEOUT,
+			capture_command_and_check_status(command, &["--version"]),
+		)
+		.await;
+
+		if let Ok(Ok(output)) = output {
+			// some tools, like Python 2, print their version to stderr
+			let text = if output.stdout.is_empty() {
+				String::from_utf8_lossy(&output.stderr)
+			} else {
+				String::from_utf8_lossy(&output.stdout)
+			};
+
+			return ToolInfo {
+				name: name.to_string(),
+				command: Some(command.to_string()),
+				version: parse_version(&text),
+				output: text.lines().next().map(|l| l.trim().to_string()),
+			};
+		}
+	}
+
+	ToolInfo {
+		name: name.to_string(),
+		command: None,
+		version: None,
+		output: None,
+	}
+}
+
+fn parse_version(output: &str) -> Option<String> {
+	VERSION_RE.find(output).map(|m| m.as_str().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse_version() {
+		assert_eq!(
+			parse_version("git version 2.39.2 (Apple Git-143)"),
+			Some("2.39.2".to_string())
+		);
+		assert_eq!(parse_version("v18.16.0\n"), Some("18.16.0".to_string()));
+		assert_eq!(parse_version("Python 3.11.4"), Some("3.11.4".to_string()));
+		assert_eq!(
+			parse_version("Docker version 24.0.2, build cb74dfc"),
+			Some("24.0.2".to_string())
+		);
+		assert_eq!(parse_version("unknown"), None);
+	}
+}
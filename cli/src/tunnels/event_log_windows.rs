@@ -0,0 +1,195 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Writes events from the tunnel service to the Windows Event Log, so they
+//! can be collected by monitoring tools. Events are written to the
+//! Application log under the `TUNNEL_ACTIVITY_NAME` source:
+//!
+//! | ID  | Category      | Type        | Event                               |
+//! |-----|---------------|-------------|-------------------------------------|
+//! | 1   | Lifecycle (1) | Information | The service started                 |
+//! | 2   | Lifecycle (1) | Information | The service stopped                 |
+//! | 3   | Lifecycle (1) | Error       | The service exited with an error    |
+//! | 100 | Log (2)       | Warning     | A warning was logged                |
+//! | 101 | Log (2)       | Error       | An error was logged                 |
+//!
+//! The source uses the message table of `EventCreate.exe`, which passes the
+//! event's text through for IDs up to 1000.
+
+use std::{ffi::OsStr, io, os::windows::ffi::OsStrExt, ptr, sync::Arc};
+
+use winapi::{
+	shared::minwindef::{DWORD, WORD},
+	um::{
+		winbase::{DeregisterEventSource, RegisterEventSourceW, ReportEventW},
+		winnt::{EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE, EVENTLOG_WARNING_TYPE, HANDLE},
+	},
+};
+use winreg::{
+	enums::{HKEY_LOCAL_MACHINE, KEY_ALL_ACCESS},
+	RegKey, RegValue,
+};
+
+use crate::{
+	constants::TUNNEL_ACTIVITY_NAME,
+	log::{Level, LogSink},
+	util::tags::tags,
+};
+
+const EVENT_LOG_KEY: &str = r"SYSTEM\CurrentControlSet\Services\EventLog\Application";
+const MESSAGE_FILE: &str = r"%SystemRoot%\System32\EventCreate.exe";
+
+const CATEGORY_LIFECYCLE: WORD = 1;
+const CATEGORY_LOG: WORD = 2;
+
+/// Events in the service's lifecycle.
+pub enum ServiceEvent<'a> {
+	Started,
+	Stopped,
+	Failed(&'a str),
+}
+
+impl ServiceEvent<'_> {
+	fn id(&self) -> DWORD {
+		match self {
+			ServiceEvent::Started => 1,
+			ServiceEvent::Stopped => 2,
+			ServiceEvent::Failed(_) => 3,
+		}
+	}
+
+	fn message(&self) -> String {
+		match self {
+			ServiceEvent::Started => format!("{} service started", TUNNEL_ACTIVITY_NAME),
+			ServiceEvent::Stopped => format!("{} service stopped", TUNNEL_ACTIVITY_NAME),
+			ServiceEvent::Failed(e) => format!("{} service failed: {}", TUNNEL_ACTIVITY_NAME, e),
+		}
+	}
+}
+
+/// Registers the service's event source, which lets the Event Viewer show
+/// the text of its events. This writes to HKLM, so it needs elevation.
+pub fn register_event_source() -> io::Result<()> {
+	let (key, _) = RegKey::predef(HKEY_LOCAL_MACHINE)
+		.create_subkey(format!(r"{}\{}", EVENT_LOG_KEY, TUNNEL_ACTIVITY_NAME))?;
+	key.set_raw_value(
+		"EventMessageFile",
+		&RegValue {
+			bytes: to_wide(MESSAGE_FILE)
+				.iter()
+				.flat_map(|c| c.to_le_bytes())
+				.collect(),
+			vtype: winreg::enums::REG_EXPAND_SZ,
+		},
+	)?;
+	key.set_value(
+		"TypesSupported",
+		&((EVENTLOG_ERROR_TYPE | EVENTLOG_WARNING_TYPE | EVENTLOG_INFORMATION_TYPE) as u32),
+	)
+}
+
+pub fn unregister_event_source() -> io::Result<()> {
+	match RegKey::predef(HKEY_LOCAL_MACHINE)
+		.open_subkey_with_flags(EVENT_LOG_KEY, KEY_ALL_ACCESS)?
+		.delete_subkey(TUNNEL_ACTIVITY_NAME)
+	{
+		Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+		_ => Ok(()),
+	}
+}
+
+struct EventSource(HANDLE);
+
+// the handle can be used from any thread
+unsafe impl Send for EventSource {}
+unsafe impl Sync for EventSource {}
+
+impl Drop for EventSource {
+	fn drop(&mut self) {
+		unsafe {
+			DeregisterEventSource(self.0);
+		}
+	}
+}
+
+/// Sink that writes warnings and errors to the event log, and can report
+/// lifecycle events. Works even if the source isn't registered, though the
+/// Event Viewer then notes that the event's description can't be found.
+#[derive(Clone)]
+pub struct EventLogSink {
+	source: Arc<EventSource>,
+	/// Configured tags, appended to each event's text.
+	tags: Arc<String>,
+}
+
+impl EventLogSink {
+	pub fn new() -> io::Result<Self> {
+		let name = to_wide(TUNNEL_ACTIVITY_NAME);
+		let handle = unsafe { RegisterEventSourceW(ptr::null(), name.as_ptr()) };
+		if handle.is_null() {
+			return Err(io::Error::last_os_error());
+		}
+
+		let tags = tags()
+			.into_iter()
+			.map(|(k, v)| format!("\n{}: {}", k, v))
+			.collect::<String>();
+
+		Ok(Self {
+			source: Arc::new(EventSource(handle)),
+			tags: Arc::new(tags),
+		})
+	}
+
+	pub fn report(&self, event: ServiceEvent) {
+		let event_type = match event {
+			ServiceEvent::Failed(_) => EVENTLOG_ERROR_TYPE,
+			_ => EVENTLOG_INFORMATION_TYPE,
+		};
+		self.write(event_type, CATEGORY_LIFECYCLE, event.id(), &event.message());
+	}
+
+	fn write(&self, event_type: WORD, category: WORD, id: DWORD, message: &str) {
+		let message = to_wide(&format!("{}{}", message, self.tags));
+		let mut strings = [message.as_ptr()];
+		// ignore errors, as there's nowhere else to report them
+		unsafe {
+			ReportEventW(
+				self.source.0,
+				event_type,
+				category,
+				id,
+				ptr::null_mut(),
+				strings.len() as WORD,
+				0,
+				strings.as_mut_ptr(),
+				ptr::null_mut(),
+			);
+		}
+	}
+}
+
+impl LogSink for EventLogSink {
+	fn write_log(&self, level: Level, prefix: &str, message: &str) {
+		let (event_type, id) = match level {
+			Level::Warn => (EVENTLOG_WARNING_TYPE, 100),
+			Level::Error | Level::Critical => (EVENTLOG_ERROR_TYPE, 101),
+			_ => return,
+		};
+
+		self.write(
+			event_type,
+			CATEGORY_LOG,
+			id,
+			&format!("{}{}", prefix, message),
+		);
+	}
+
+	fn write_result(&self, _message: &str) {}
+}
+
+fn to_wide(s: &str) -> Vec<u16> {
+	OsStr::new(s).encode_wide().chain(Some(0)).collect()
+}
@@ -0,0 +1,369 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! A caching proxy for the extension gallery. Servers started by the CLI are
+//! pointed at it, so extension packages downloaded by one server install are
+//! reused by every other install on the host.
+
+use std::{
+	convert::Infallible,
+	net::SocketAddr,
+	path::{Path, PathBuf},
+	sync::Arc,
+};
+
+use futures::TryStreamExt;
+use hyper::{
+	header::{ACCEPT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, HOST, TRANSFER_ENCODING},
+	server::conn::Http,
+	service::service_fn,
+	Body, Method, Request, Response, StatusCode,
+};
+use sha2::{Digest, Sha256};
+use tokio::{io::AsyncWriteExt, net::TcpListener, task::JoinHandle};
+use tokio_util::codec::{BytesCodec, FramedRead};
+
+use crate::{
+	log,
+	state::PersistedState,
+	util::{
+		errors::{wrap, AnyError, StatusError},
+		permissions::create_private_dir_all,
+	},
+};
+
+/// Port the proxy last listened on, within the cache directory.
+const PORT_FILE: &str = "proxy-port.json";
+const GALLERY_ORIGIN: &str = "https://marketplace.visualstudio.com";
+const GALLERY_SERVICE_PATH: &str = "/_apis/public/gallery";
+const GALLERY_ITEM_URL: &str = "https://marketplace.visualstudio.com/items";
+/// Gallery configuration that the server reads in place of the one in its
+/// product.json.
+const GALLERY_ENV_VAR: &str = "EXTENSIONS_GALLERY";
+/// Asset URLs in gallery responses are rewritten to `<proxy>/_cdn/<host>/...`
+/// so their downloads go through the cache.
+const CDN_PREFIX: &str = "/_cdn/";
+/// Only hosts the gallery serves assets from can be reached through the proxy.
+const CDN_HOST_SUFFIX: &str = ".vsassets.io";
+
+struct ProxyState {
+	log: log::Logger,
+	client: reqwest::Client,
+	cache_dir: PathBuf,
+	origin: String,
+}
+
+/// A running extension cache proxy. It stops when dropped.
+pub struct ExtensionCacheProxy {
+	addr: SocketAddr,
+	task: JoinHandle<()>,
+}
+
+impl Drop for ExtensionCacheProxy {
+	fn drop(&mut self) {
+		self.task.abort();
+	}
+}
+
+impl ExtensionCacheProxy {
+	/// Starts the proxy on a loopback port, storing downloads in `cache_dir`.
+	pub async fn start(log: log::Logger, cache_dir: PathBuf) -> Result<Self, AnyError> {
+		create_private_dir_all(&cache_dir)
+			.map_err(|e| wrap(e, format!("error creating {}", cache_dir.display())))?;
+		remove_temp_files(&cache_dir);
+
+		// Servers outlive the CLI and keep the address they were started with,
+		// so try to listen on the same port as last time.
+		let last_port = PersistedState::<u16>::new(cache_dir.join(PORT_FILE));
+		let listener = match TcpListener::bind(("127.0.0.1", last_port.load())).await {
+			Ok(l) => l,
+			Err(_) => TcpListener::bind(("127.0.0.1", 0))
+				.await
+				.map_err(|e| wrap(e, "error listening for extension cache proxy"))?,
+		};
+		let addr = listener
+			.local_addr()
+			.map_err(|e| wrap(e, "error getting extension cache proxy address"))?;
+		last_port.save(addr.port())?;
+
+		let state = Arc::new(ProxyState {
+			log,
+			client: reqwest::Client::new(),
+			cache_dir,
+			origin: format!("http://{}", addr),
+		});
+
+		debug!(state.log, "Extension cache proxy listening on {}", addr);
+
+		let task = tokio::spawn(async move {
+			while let Ok((stream, _)) = listener.accept().await {
+				let state = state.clone();
+				tokio::spawn(async move {
+					let service = service_fn(move |req| handle_request(state.clone(), req));
+					Http::new()
+						.http1_only(true)
+						.serve_connection(stream, service)
+						.await
+						.ok();
+				});
+			}
+		});
+
+		Ok(ExtensionCacheProxy { addr, task })
+	}
+
+	/// Environment variable to set on servers so they use the proxy.
+	pub fn server_env(&self) -> (String, String) {
+		let gallery = serde_json::json!({
+			"serviceUrl": format!("http://{}{}", self.addr, GALLERY_SERVICE_PATH),
+			"itemUrl": GALLERY_ITEM_URL,
+		});
+
+		(GALLERY_ENV_VAR.to_string(), gallery.to_string())
+	}
+}
+
+async fn handle_request(
+	state: Arc<ProxyState>,
+	req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+	match proxy_request(&state, req).await {
+		Ok(r) => Ok(r),
+		Err(e) => {
+			warning!(state.log, "Extension cache proxy error: {}", e);
+			Ok(Response::builder()
+				.status(StatusCode::BAD_GATEWAY)
+				.body(Body::from(e.to_string()))
+				.unwrap())
+		}
+	}
+}
+
+async fn proxy_request(state: &ProxyState, req: Request<Body>) -> Result<Response<Body>, AnyError> {
+	let path_and_query = req
+		.uri()
+		.path_and_query()
+		.map(|p| p.as_str())
+		.unwrap_or("/")
+		.to_owned();
+
+	let rest = match path_and_query.strip_prefix(CDN_PREFIX) {
+		Some(r) => r,
+		None => {
+			let url = format!("{}{}", GALLERY_ORIGIN, path_and_query);
+			return forward(state, req, url).await;
+		}
+	};
+
+	let host = rest.split('/').next().unwrap_or_default();
+	if !is_cdn_host(host) {
+		return Ok(Response::builder()
+			.status(StatusCode::FORBIDDEN)
+			.body(Body::empty())
+			.unwrap());
+	}
+
+	let url = format!("https://{}", rest);
+	if req.method() == Method::GET {
+		get_cached(state, url).await
+	} else {
+		forward(state, req, url).await
+	}
+}
+
+/// Forwards the request upstream as-is, except that asset URLs in gallery
+/// query results are rewritten to go through the proxy.
+async fn forward(
+	state: &ProxyState,
+	req: Request<Body>,
+	url: String,
+) -> Result<Response<Body>, AnyError> {
+	let (parts, body) = req.into_parts();
+	let body = hyper::body::to_bytes(body)
+		.await
+		.map_err(|e| wrap(e, "error reading request body"))?;
+
+	let mut headers = parts.headers;
+	// compressed responses couldn't be rewritten
+	for h in [HOST, ACCEPT_ENCODING, CONTENT_LENGTH] {
+		headers.remove(h);
+	}
+
+	let res = state
+		.client
+		.request(parts.method, &url)
+		.headers(headers)
+		.body(body)
+		.send()
+		.await
+		.map_err(|e| wrap(e, format!("error requesting {}", url)))?;
+
+	let is_json = res
+		.headers()
+		.get(CONTENT_TYPE)
+		.and_then(|v| v.to_str().ok())
+		.map(|v| v.contains("json"))
+		.unwrap_or(false);
+
+	let mut builder = Response::builder().status(res.status());
+	for (k, v) in res.headers() {
+		if !is_json || (k != CONTENT_LENGTH && k != TRANSFER_ENCODING) {
+			builder = builder.header(k, v);
+		}
+	}
+
+	let body = if is_json {
+		let text = res
+			.text()
+			.await
+			.map_err(|e| wrap(e, format!("error reading response from {}", url)))?;
+		Body::from(rewrite_asset_urls(&text, &state.origin))
+	} else {
+		Body::wrap_stream(res.bytes_stream())
+	};
+
+	Ok(builder.body(body).unwrap())
+}
+
+/// Serves a gallery asset from the cache, downloading it first if needed.
+/// Assets are immutable for a given URL, so entries never need revalidation.
+async fn get_cached(state: &ProxyState, url: String) -> Result<Response<Body>, AnyError> {
+	let path = state.cache_dir.join(cache_key(&url));
+
+	if !path.exists() {
+		trace!(state.log, "Extension cache miss for {}", url);
+		let res = state
+			.client
+			.get(&url)
+			.send()
+			.await
+			.map_err(|e| wrap(e, format!("error requesting {}", url)))?;
+
+		if !res.status().is_success() {
+			return Err(StatusError {
+				url,
+				status_code: res.status().as_u16(),
+				body: res.text().await.unwrap_or_default(),
+			}
+			.into());
+		}
+
+		// download to a temporary file first so concurrent and interrupted
+		// requests never see a partial entry.
+		let temp = path.with_extension(format!("tmp-{}", rand::random::<u32>()));
+		if let Err(e) = download_to(res, &url, &temp, &path).await {
+			tokio::fs::remove_file(&temp).await.ok();
+			return Err(e);
+		}
+	} else {
+		trace!(state.log, "Extension cache hit for {}", url);
+	}
+
+	let file = tokio::fs::File::open(&path)
+		.await
+		.map_err(|e| wrap(e, "error opening extension cache file"))?;
+	let len = file
+		.metadata()
+		.await
+		.map_err(|e| wrap(e, "error reading extension cache file"))?
+		.len();
+	let stream = FramedRead::new(file, BytesCodec::new()).map_ok(|b| b.freeze());
+
+	Ok(Response::builder()
+		.header(CONTENT_LENGTH, len)
+		.header(CONTENT_TYPE, "application/octet-stream")
+		.body(Body::wrap_stream(stream))
+		.unwrap())
+}
+
+/// Removes downloads left behind by a proxy that exited while they were in
+/// progress. Only one proxy runs at a time, in the singleton.
+fn remove_temp_files(cache_dir: &Path) {
+	if let Ok(entries) = std::fs::read_dir(cache_dir) {
+		for entry in entries.flatten() {
+			let path = entry.path();
+			let is_temp = path
+				.extension()
+				.map_or(false, |e| e.to_string_lossy().starts_with("tmp-"));
+			if is_temp {
+				std::fs::remove_file(&path).ok();
+			}
+		}
+	}
+}
+
+async fn download_to(
+	res: reqwest::Response,
+	url: &str,
+	temp: &Path,
+	path: &Path,
+) -> Result<(), AnyError> {
+	let mut file = tokio::fs::File::create(temp)
+		.await
+		.map_err(|e| wrap(e, "error creating extension cache file"))?;
+	let mut stream = res.bytes_stream();
+	while let Some(chunk) = stream
+		.try_next()
+		.await
+		.map_err(|e| wrap(e, format!("error downloading {}", url)))?
+	{
+		file.write_all(&chunk)
+			.await
+			.map_err(|e| wrap(e, "error writing extension cache file"))?;
+	}
+	drop(file);
+
+	tokio::fs::rename(temp, path)
+		.await
+		.map_err(|e| wrap(e, "error moving extension cache file"))?;
+	Ok(())
+}
+
+fn is_cdn_host(host: &str) -> bool {
+	host.ends_with(CDN_HOST_SUFFIX)
+		&& host
+			.chars()
+			.all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+}
+
+fn cache_key(url: &str) -> String {
+	let mut hash = Sha256::new();
+	hash.update(url.as_bytes());
+	format!("{:x}", hash.finalize())
+}
+
+fn rewrite_asset_urls(text: &str, origin: &str) -> String {
+	let proxied = format!("{}{}", origin, CDN_PREFIX);
+	text.replace(
+		"\"assetUri\":\"https://",
+		&format!("\"assetUri\":\"{}", proxied),
+	)
+	.replace(
+		"\"fallbackAssetUri\":\"https://",
+		&format!("\"fallbackAssetUri\":\"{}", proxied),
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_is_cdn_host() {
+		assert!(is_cdn_host("ms-python.gallerycdn.vsassets.io"));
+		assert!(!is_cdn_host("example.com"));
+		assert!(!is_cdn_host("example.com?.vsassets.io"));
+		assert!(!is_cdn_host("examplevsassets.io"));
+	}
+
+	#[test]
+	fn test_rewrite_asset_urls() {
+		let text = r#"{"versions":[{"assetUri":"https://a.gallerycdn.vsassets.io/x","fallbackAssetUri":"https://a.gallery.vsassets.io/y","other":"https://example.com"}]}"#;
+		assert_eq!(
+			rewrite_asset_urls(text, "http://127.0.0.1:1234"),
+			r#"{"versions":[{"assetUri":"http://127.0.0.1:1234/_cdn/a.gallerycdn.vsassets.io/x","fallbackAssetUri":"http://127.0.0.1:1234/_cdn/a.gallery.vsassets.io/y","other":"https://example.com"}]}"#
+		);
+	}
+}
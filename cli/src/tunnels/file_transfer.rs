@@ -0,0 +1,358 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	io::SeekFrom,
+	path::Path,
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+use sha2::{Digest, Sha256};
+use tokio::{
+	fs::{File, OpenOptions},
+	io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+
+use crate::util::{
+	errors::{wrap, AnyError, CodeError},
+	hash::hash_file,
+};
+
+use super::protocol::{
+	DownloadChunkParams, DownloadChunkResult, EmptyObject, FileChecksumParams, FileChecksumResult,
+	FsDirEntry, FsEntryKind, FsPathParams, FsReadDirResult, FsReadResult, FsStatResult,
+	FsWriteParams, UploadChunkParams, UploadChunkResult,
+};
+
+/// Largest chunk that's read or written in a single call.
+pub const MAX_CHUNK_SIZE: u32 = 1024 * 1024;
+/// Largest file `fsread` returns whole. It's meant for settings and logs;
+/// anything bigger should go through `downloadchunk`.
+const MAX_FS_READ_SIZE: u64 = 16 * 1024 * 1024;
+
+pub async fn handle_download_chunk(
+	params: DownloadChunkParams,
+) -> Result<DownloadChunkResult, AnyError> {
+	let mut file = File::open(&params.path)
+		.await
+		.map_err(|e| wrap(e, format!("error opening {}", params.path)))?;
+	let size = file
+		.metadata()
+		.await
+		.map_err(|e| wrap(e, format!("error reading {}", params.path)))?
+		.len();
+
+	file.seek(SeekFrom::Start(params.offset))
+		.await
+		.map_err(|e| wrap(e, format!("error seeking in {}", params.path)))?;
+
+	let mut data = Vec::with_capacity(params.length.min(MAX_CHUNK_SIZE) as usize);
+	(&mut file)
+		.take(params.length.min(MAX_CHUNK_SIZE) as u64)
+		.read_to_end(&mut data)
+		.await
+		.map_err(|e| wrap(e, format!("error reading {}", params.path)))?;
+
+	Ok(DownloadChunkResult {
+		sha256: sha256_hex(&data),
+		eof: params.offset + data.len() as u64 >= size,
+		size,
+		data,
+	})
+}
+
+pub async fn handle_upload_chunk(params: UploadChunkParams) -> Result<UploadChunkResult, AnyError> {
+	if let Some(expected) = &params.sha256 {
+		if *expected != sha256_hex(&params.data) {
+			return Err(CodeError::ChunkChecksumMismatch(params.offset).into());
+		}
+	}
+
+	let mut file = OpenOptions::new()
+		.write(true)
+		.create(true)
+		// chunks may arrive for an existing partial file, see `params.truncate`
+		.truncate(false)
+		.open(&params.path)
+		.await
+		.map_err(|e| wrap(e, format!("error opening {}", params.path)))?;
+
+	if params.truncate {
+		file.set_len(params.offset)
+			.await
+			.map_err(|e| wrap(e, format!("error truncating {}", params.path)))?;
+	}
+
+	file.seek(SeekFrom::Start(params.offset))
+		.await
+		.map_err(|e| wrap(e, format!("error seeking in {}", params.path)))?;
+	file.write_all(&params.data)
+		.await
+		.map_err(|e| wrap(e, format!("error writing {}", params.path)))?;
+	file.flush()
+		.await
+		.map_err(|e| wrap(e, format!("error writing {}", params.path)))?;
+
+	let size = file
+		.metadata()
+		.await
+		.map_err(|e| wrap(e, format!("error reading {}", params.path)))?
+		.len();
+
+	Ok(UploadChunkResult { size })
+}
+
+pub async fn handle_file_checksum(
+	params: FileChecksumParams,
+) -> Result<FileChecksumResult, AnyError> {
+	let hash = hash_file(Path::new(&params.path), params.length)
+		.await
+		.map_err(|e| wrap(e, format!("error reading {}", params.path)))?;
+
+	Ok(FileChecksumResult {
+		size: hash.size,
+		length: hash.length,
+		sha256: hash.sha256,
+	})
+}
+
+/// Stats the path without following it if it's a symlink, like `lstat`.
+pub async fn handle_fs_stat(params: FsPathParams) -> Result<FsStatResult, AnyError> {
+	let meta = tokio::fs::symlink_metadata(&params.path)
+		.await
+		.map_err(|e| wrap(e, format!("error reading {}", params.path)))?;
+
+	Ok(FsStatResult {
+		kind: FsEntryKind::from(meta.file_type()),
+		size: meta.len(),
+		mtime: meta
+			.modified()
+			.ok()
+			.and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+			.map(|d| d.as_secs()),
+		readonly: meta.permissions().readonly(),
+	})
+}
+
+pub async fn handle_fs_readdir(params: FsPathParams) -> Result<FsReadDirResult, AnyError> {
+	let mut dir = tokio::fs::read_dir(&params.path)
+		.await
+		.map_err(|e| wrap(e, format!("error reading {}", params.path)))?;
+
+	let mut entries = vec![];
+	while let Some(entry) = dir
+		.next_entry()
+		.await
+		.map_err(|e| wrap(e, format!("error reading {}", params.path)))?
+	{
+		let kind = match entry.file_type().await {
+			Ok(t) => FsEntryKind::from(t),
+			Err(_) => FsEntryKind::Other,
+		};
+		entries.push(FsDirEntry {
+			name: entry.file_name().to_string_lossy().to_string(),
+			kind,
+		});
+	}
+
+	entries.sort_by(|a, b| a.name.cmp(&b.name));
+	Ok(FsReadDirResult { entries })
+}
+
+pub async fn handle_fs_read(params: FsPathParams) -> Result<FsReadResult, AnyError> {
+	let file = File::open(&params.path)
+		.await
+		.map_err(|e| wrap(e, format!("error opening {}", params.path)))?;
+
+	let mut data = vec![];
+	file.take(MAX_FS_READ_SIZE + 1)
+		.read_to_end(&mut data)
+		.await
+		.map_err(|e| wrap(e, format!("error reading {}", params.path)))?;
+	if data.len() as u64 > MAX_FS_READ_SIZE {
+		return Err(CodeError::FileTooLarge(params.path, MAX_FS_READ_SIZE).into());
+	}
+
+	Ok(FsReadResult { data })
+}
+
+/// Replaces the file's contents. The data is written to a sibling file that's
+/// then renamed over the original, so a failed write never leaves it truncated.
+pub async fn handle_fs_write(params: FsWriteParams) -> Result<EmptyObject, AnyError> {
+	let path = Path::new(&params.path);
+	let temp = path.with_file_name(format!(
+		".{}.{}.tmp",
+		path.file_name()
+			.map(|n| n.to_string_lossy().to_string())
+			.unwrap_or_default(),
+		SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map(|d| d.as_nanos())
+			.unwrap_or_default()
+	));
+
+	if let Err(e) = tokio::fs::write(&temp, &params.data).await {
+		tokio::fs::remove_file(&temp).await.ok();
+		return Err(wrap(e, format!("error writing {}", params.path)).into());
+	}
+
+	// keep the permissions of the file being replaced
+	if let Ok(meta) = tokio::fs::metadata(path).await {
+		tokio::fs::set_permissions(&temp, meta.permissions())
+			.await
+			.ok();
+	}
+
+	if let Err(e) = tokio::fs::rename(&temp, path).await {
+		tokio::fs::remove_file(&temp).await.ok();
+		return Err(wrap(e, format!("error writing {}", params.path)).into());
+	}
+
+	Ok(EmptyObject {})
+}
+
+impl From<std::fs::FileType> for FsEntryKind {
+	fn from(t: std::fs::FileType) -> Self {
+		if t.is_symlink() {
+			FsEntryKind::Symlink
+		} else if t.is_dir() {
+			FsEntryKind::Directory
+		} else if t.is_file() {
+			FsEntryKind::File
+		} else {
+			FsEntryKind::Other
+		}
+	}
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+	format!("{:x}", Sha256::digest(data))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn test_chunked_roundtrip() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("file").to_string_lossy().to_string();
+
+		for (offset, data) in [(0, b"hello ".to_vec()), (6, b"world".to_vec())] {
+			handle_upload_chunk(UploadChunkParams {
+				path: path.clone(),
+				offset,
+				sha256: Some(sha256_hex(&data)),
+				data,
+				truncate: offset == 0,
+			})
+			.await
+			.unwrap();
+		}
+
+		let bad = handle_upload_chunk(UploadChunkParams {
+			path: path.clone(),
+			offset: 11,
+			data: b"!".to_vec(),
+			sha256: Some(sha256_hex(b"?")),
+			truncate: false,
+		})
+		.await;
+		assert!(bad.is_err());
+
+		let chunk = handle_download_chunk(DownloadChunkParams {
+			path: path.clone(),
+			offset: 6,
+			length: 100,
+		})
+		.await
+		.unwrap();
+		assert_eq!(chunk.data, b"world");
+		assert_eq!(chunk.size, 11);
+		assert!(chunk.eof);
+
+		let prefix = handle_file_checksum(FileChecksumParams {
+			path,
+			length: Some(5),
+		})
+		.await
+		.unwrap();
+		assert_eq!(prefix.size, 11);
+		assert_eq!(prefix.length, 5);
+		assert_eq!(prefix.sha256, sha256_hex(b"hello"));
+	}
+
+	#[tokio::test]
+	async fn test_fs_methods() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir
+			.path()
+			.join("settings.json")
+			.to_string_lossy()
+			.to_string();
+
+		for contents in [b"{ \"a\": 1 }".to_vec(), b"{}".to_vec()] {
+			handle_fs_write(FsWriteParams {
+				path: path.clone(),
+				data: contents,
+			})
+			.await
+			.unwrap();
+		}
+		std::fs::create_dir(dir.path().join("sub")).unwrap();
+
+		let read = handle_fs_read(FsPathParams { path: path.clone() })
+			.await
+			.unwrap();
+		assert_eq!(read.data, b"{}");
+
+		let stat = handle_fs_stat(FsPathParams { path }).await.unwrap();
+		assert_eq!(stat.kind, FsEntryKind::File);
+		assert_eq!(stat.size, 2);
+
+		// the temporary file from writing should be gone
+		let listing = handle_fs_readdir(FsPathParams {
+			path: dir.path().to_string_lossy().to_string(),
+		})
+		.await
+		.unwrap();
+		let names: Vec<_> = listing
+			.entries
+			.iter()
+			.map(|e| (e.name.as_str(), e.kind))
+			.collect();
+		assert_eq!(
+			names,
+			vec![
+				("settings.json", FsEntryKind::File),
+				("sub", FsEntryKind::Directory)
+			]
+		);
+	}
+
+	#[cfg(unix)]
+	#[tokio::test]
+	async fn test_fs_methods_symlink() {
+		let dir = tempfile::tempdir().unwrap();
+		std::fs::create_dir(dir.path().join("sub")).unwrap();
+		let link = dir.path().join("link");
+		std::os::unix::fs::symlink(dir.path().join("sub"), &link).unwrap();
+
+		let stat = handle_fs_stat(FsPathParams {
+			path: link.to_string_lossy().to_string(),
+		})
+		.await
+		.unwrap();
+		assert_eq!(stat.kind, FsEntryKind::Symlink);
+
+		let listing = handle_fs_readdir(FsPathParams {
+			path: dir.path().to_string_lossy().to_string(),
+		})
+		.await
+		.unwrap();
+		let kinds: Vec<_> = listing.entries.iter().map(|e| e.kind).collect();
+		assert_eq!(kinds, vec![FsEntryKind::Symlink, FsEntryKind::Directory]);
+	}
+}
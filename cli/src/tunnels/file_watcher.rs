@@ -0,0 +1,100 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::path::Path;
+
+use notify::{Config, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::{
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+	sync::mpsc,
+};
+
+use crate::{
+	log,
+	util::errors::{wrap, AnyError},
+};
+
+use super::protocol::{EmptyObject, FsChangeEvent, FsChangeKind, WatchParams};
+
+/// Watches the paths, writing a msgpack-encoded `FsChangeEvent` to `output`
+/// for each change. Runs until the client closes `input`.
+pub async fn handle_watch(
+	log: &log::Logger,
+	params: WatchParams,
+	mut input: impl AsyncRead + Unpin,
+	mut output: impl AsyncWrite + Unpin,
+) -> Result<EmptyObject, AnyError> {
+	let (tx, mut rx) = mpsc::unbounded_channel();
+	let mut watcher = RecommendedWatcher::new(
+		move |r: notify::Result<Event>| {
+			tx.send(r).ok();
+		},
+		Config::default(),
+	)
+	.map_err(|e| wrap(e, "error creating file watcher"))?;
+
+	let mode = if params.recursive {
+		RecursiveMode::Recursive
+	} else {
+		RecursiveMode::NonRecursive
+	};
+	for path in &params.paths {
+		watcher
+			.watch(Path::new(path), mode)
+			.map_err(|e| wrap(e, format!("error watching {}", path)))?;
+	}
+
+	debug!(log, "watching {:?}", params.paths);
+
+	let mut buf = [0u8; 64];
+	loop {
+		tokio::select! {
+			r = input.read(&mut buf) => match r {
+				Ok(0) | Err(_) => break,
+				Ok(_) => continue, // nothing is expected from the client
+			},
+			Some(r) = rx.recv() => {
+				let event = match r {
+					Ok(e) => e,
+					Err(e) => {
+						warning!(log, "error watching files: {}", e);
+						continue;
+					}
+				};
+
+				if let Some(change) = to_change_event(event) {
+					let msg = rmp_serde::to_vec_named(&change).expect("expected to serialize");
+					if output.write_all(&msg).await.is_err() {
+						break;
+					}
+				}
+			}
+		}
+	}
+
+	debug!(log, "stopped watching {:?}", params.paths);
+	Ok(EmptyObject {})
+}
+
+/// Converts the event to what's sent to clients. Access events aren't
+/// reported, since reads are frequent and rarely interesting.
+fn to_change_event(event: Event) -> Option<FsChangeEvent> {
+	let kind = match event.kind {
+		EventKind::Access(_) => return None,
+		EventKind::Create(_) => FsChangeKind::Create,
+		EventKind::Modify(_) => FsChangeKind::Modify,
+		EventKind::Remove(_) => FsChangeKind::Remove,
+		EventKind::Any | EventKind::Other => FsChangeKind::Other,
+	};
+
+	Some(FsChangeEvent {
+		kind,
+		paths: event
+			.paths
+			.iter()
+			.map(|p| p.to_string_lossy().to_string())
+			.collect(),
+	})
+}
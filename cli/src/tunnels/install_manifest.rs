@@ -0,0 +1,106 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Hashes of a server's key files, recorded when it's installed and checked
+//! before it's reused, so truncated or changed installs are reinstalled
+//! instead of failing to start in confusing ways.
+
+use std::{
+	collections::HashMap,
+	path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::util::{
+	errors::{wrap, WrappedError},
+	hash::hash_file,
+};
+
+const MANIFEST_FILE: &str = "install-manifest.json";
+
+#[derive(Serialize, Deserialize, Default)]
+struct InstallManifest {
+	/// SHA-256 hashes of files, by their path relative to the install
+	sha256: HashMap<String, String>,
+}
+
+/// Records the hashes of the files, which are relative to the install dir.
+pub async fn write_install_manifest(
+	install_dir: &Path,
+	files: &[PathBuf],
+) -> Result<(), WrappedError> {
+	let mut manifest = InstallManifest::default();
+	for file in files {
+		let hashes = hash_file(&install_dir.join(file), None)
+			.await
+			.map_err(|e| wrap(e, format!("error hashing {}", file.display())))?;
+		manifest
+			.sha256
+			.insert(file.to_string_lossy().to_string(), hashes.sha256);
+	}
+
+	let contents = serde_json::to_vec(&manifest).unwrap();
+	std::fs::write(install_dir.join(MANIFEST_FILE), contents)
+		.map_err(|e| wrap(e, "error writing install manifest"))
+}
+
+/// Checks that the files, which are relative to the install dir, exist and
+/// have the hashes in its manifest. Installs from before manifests were
+/// recorded only have their files' existence checked. Returns what's wrong
+/// with the install if it isn't intact.
+pub async fn verify_install_manifest(install_dir: &Path, files: &[PathBuf]) -> Result<(), String> {
+	let manifest = std::fs::read(install_dir.join(MANIFEST_FILE))
+		.ok()
+		.map(|c| serde_json::from_slice::<InstallManifest>(&c))
+		.transpose()
+		.map_err(|_| "its install manifest is corrupt".to_string())?;
+
+	for file in files {
+		let path = install_dir.join(file);
+		if !path.is_file() {
+			return Err(format!("missing {}", file.display()));
+		}
+
+		let expected = match manifest
+			.as_ref()
+			.and_then(|m| m.sha256.get(file.to_string_lossy().as_ref()))
+		{
+			Some(h) => h,
+			None => continue,
+		};
+		match hash_file(&path, None).await {
+			Ok(h) if &h.sha256 == expected => {}
+			_ => return Err(format!("{} was changed or truncated", file.display())),
+		}
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn test_verify_install_manifest() {
+		let dir = tempfile::tempdir().unwrap();
+		let files = vec![PathBuf::from("node"), PathBuf::from("server.sh")];
+		std::fs::write(dir.path().join("node"), "node binary").unwrap();
+		std::fs::write(dir.path().join("server.sh"), "entrypoint").unwrap();
+
+		// installs without manifests are only checked for their files
+		assert!(verify_install_manifest(dir.path(), &files).await.is_ok());
+
+		write_install_manifest(dir.path(), &files).await.unwrap();
+		assert!(verify_install_manifest(dir.path(), &files).await.is_ok());
+
+		std::fs::write(dir.path().join("node"), "node bin").unwrap();
+		assert!(verify_install_manifest(dir.path(), &files).await.is_err());
+
+		std::fs::remove_file(dir.path().join("node")).unwrap();
+		assert!(verify_install_manifest(dir.path(), &files).await.is_err());
+	}
+}
@@ -0,0 +1,142 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Finds how far apart pings to clients can be before a NAT or firewall
+//! between the host and the relay forgets the connection. Pings start out
+//! frequent, and are spaced further apart while they're answered. Once one
+//! goes unanswered, the spacing is binary searched between the longest one
+//! known to work and the shortest one that didn't. This saves battery and
+//! network on laptops, where idle timeouts are often several minutes.
+
+use std::{sync::Mutex, time::Duration};
+
+/// Spacing that pings start at, which is assumed to be under any timeout.
+const MIN_INTERVAL: Duration = Duration::from_secs(10);
+const MAX_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Pings that must be answered before trying a longer spacing.
+const PROBE_AFTER_ANSWERED: u32 = 3;
+/// The search stops once the bounds are this close.
+const RESOLUTION: Duration = Duration::from_secs(5);
+
+struct State {
+	interval: Duration,
+	/// Longest spacing that pings were answered at.
+	answered: Duration,
+	/// Shortest spacing that a ping went unanswered at.
+	unanswered: Option<Duration>,
+	/// Pings answered at the current spacing.
+	answered_count: u32,
+}
+
+/// Ping spacing shared by connections through the same relay, which are
+/// behind the same NATs.
+pub struct KeepAlive {
+	state: Mutex<State>,
+}
+
+impl Default for KeepAlive {
+	fn default() -> Self {
+		Self {
+			state: Mutex::new(State {
+				interval: MIN_INTERVAL,
+				answered: MIN_INTERVAL,
+				unanswered: None,
+				answered_count: 0,
+			}),
+		}
+	}
+}
+
+impl KeepAlive {
+	/// Gets how long to wait before the next ping.
+	pub fn interval(&self) -> Duration {
+		self.state.lock().unwrap().interval
+	}
+
+	/// Records that a ping sent after waiting `interval` was answered.
+	pub fn record_answered(&self, interval: Duration) {
+		let mut state = self.state.lock().unwrap();
+		state.answered = state.answered.max(interval);
+		if let Some(u) = state.unanswered {
+			if u <= interval {
+				// the timeout got longer, such as after changing networks
+				state.unanswered = None;
+			}
+		}
+
+		if interval != state.interval {
+			return;
+		}
+
+		state.answered_count += 1;
+		if state.answered_count >= PROBE_AFTER_ANSWERED {
+			state.answered_count = 0;
+			state.interval = match state.unanswered {
+				Some(u) if u - state.answered > RESOLUTION => midpoint(state.answered, u),
+				Some(_) => state.answered,
+				None => (state.answered * 2).min(MAX_INTERVAL),
+			};
+		}
+	}
+
+	/// Records that a ping sent after waiting `interval` went unanswered,
+	/// which is taken to mean the connection expired while idle.
+	pub fn record_unanswered(&self, interval: Duration) {
+		let mut state = self.state.lock().unwrap();
+		if interval <= state.answered {
+			// spacing that used to work doesn't anymore, such as after
+			// changing networks, so start the search over
+			state.answered = MIN_INTERVAL;
+		}
+
+		let unanswered = state.unanswered.map_or(interval, |u| u.min(interval));
+		state.unanswered = Some(unanswered);
+		state.answered_count = 0;
+		state.interval = if unanswered - state.answered > RESOLUTION {
+			midpoint(state.answered, unanswered)
+		} else {
+			state.answered
+		};
+	}
+}
+
+fn midpoint(a: Duration, b: Duration) -> Duration {
+	a + (b - a) / 2
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Pings with the keepalive against a connection that expires after
+	/// `timeout`, returning the spacing it settles on.
+	fn settle(keepalive: &KeepAlive, timeout: Duration) -> Duration {
+		for _ in 0..100 {
+			let interval = keepalive.interval();
+			if interval < timeout {
+				keepalive.record_answered(interval);
+			} else {
+				keepalive.record_unanswered(interval);
+			}
+		}
+		keepalive.interval()
+	}
+
+	#[test]
+	fn test_finds_timeout() {
+		let keepalive = KeepAlive::default();
+		let interval = settle(&keepalive, Duration::from_secs(60));
+		assert!(interval < Duration::from_secs(60));
+		assert!(interval >= Duration::from_secs(60) - RESOLUTION * 2);
+
+		// the timeout shrinks, such as after moving to a different network
+		let interval = settle(&keepalive, Duration::from_secs(30));
+		assert!(interval < Duration::from_secs(30));
+		assert!(interval >= Duration::from_secs(30) - RESOLUTION * 2);
+
+		let keepalive = KeepAlive::default();
+		assert_eq!(settle(&keepalive, Duration::MAX), MAX_INTERVAL);
+	}
+}
@@ -0,0 +1,211 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	collections::BTreeMap,
+	fmt::Write,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Mutex,
+	},
+};
+
+use crate::util::tags::Tags;
+
+const METRIC_PREFIX: &str = "code_tunnel_";
+
+/// Counters that are kept for the lifetime of the control server.
+#[derive(Default)]
+pub struct ServerMetrics {
+	/// Bytes sent and received on sockets that have since closed. Bytes for
+	/// live sockets are added in when rendering.
+	closed_bytes_tx: AtomicU64,
+	closed_bytes_rx: AtomicU64,
+	connections: AtomicU64,
+	rpc_calls: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+/// Point-in-time values reported alongside the server's counters.
+pub struct MetricsSnapshot {
+	pub live_bytes_tx: u64,
+	pub live_bytes_rx: u64,
+	pub active_sockets: usize,
+	pub attached_bridges: usize,
+	pub forwarded_ports: usize,
+	pub pending_http_requests: usize,
+}
+
+impl ServerMetrics {
+	pub fn record_connection(&self) {
+		self.connections.fetch_add(1, Ordering::Relaxed);
+	}
+
+	pub fn record_closed_socket(&self, tx: u64, rx: u64) {
+		self.closed_bytes_tx.fetch_add(tx, Ordering::Relaxed);
+		self.closed_bytes_rx.fetch_add(rx, Ordering::Relaxed);
+	}
+
+	pub fn record_rpc_call(&self, method: &'static str) {
+		*self.rpc_calls.lock().unwrap().entry(method).or_default() += 1;
+	}
+
+	/// Renders the metrics in the Prometheus text exposition format, with the
+	/// tags as labels on each sample.
+	pub fn render(&self, snapshot: &MetricsSnapshot, tags: &Tags) -> String {
+		let mut out = String::new();
+		let labels = format_labels(tags, None);
+		write_metric(
+			&mut out,
+			&labels,
+			"bytes_sent_total",
+			"counter",
+			"Bytes sent to clients of the control server.",
+			self.closed_bytes_tx.load(Ordering::Relaxed) + snapshot.live_bytes_tx,
+		);
+		write_metric(
+			&mut out,
+			&labels,
+			"bytes_received_total",
+			"counter",
+			"Bytes received from clients of the control server.",
+			self.closed_bytes_rx.load(Ordering::Relaxed) + snapshot.live_bytes_rx,
+		);
+		write_metric(
+			&mut out,
+			&labels,
+			"connections_total",
+			"counter",
+			"Connections made to the control server.",
+			self.connections.load(Ordering::Relaxed),
+		);
+
+		write_header(
+			&mut out,
+			"rpc_calls_total",
+			"counter",
+			"RPC calls received, by method.",
+		);
+		for (method, count) in self.rpc_calls.lock().unwrap().iter() {
+			writeln!(
+				out,
+				"{}rpc_calls_total{} {}",
+				METRIC_PREFIX,
+				format_labels(tags, Some(("method", method))),
+				count
+			)
+			.unwrap();
+		}
+
+		write_metric(
+			&mut out,
+			&labels,
+			"active_sockets",
+			"gauge",
+			"Sockets connected to the control server.",
+			snapshot.active_sockets as u64,
+		);
+		write_metric(
+			&mut out,
+			&labels,
+			"attached_bridges",
+			"gauge",
+			"Bridges attached to a running VS Code server.",
+			snapshot.attached_bridges as u64,
+		);
+		write_metric(
+			&mut out,
+			&labels,
+			"forwarded_ports",
+			"gauge",
+			"Ports forwarded through the tunnel.",
+			snapshot.forwarded_ports as u64,
+		);
+		write_metric(
+			&mut out,
+			&labels,
+			"pending_http_requests",
+			"gauge",
+			"HTTP requests delegated to clients that haven't completed.",
+			snapshot.pending_http_requests as u64,
+		);
+
+		out
+	}
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+	writeln!(out, "# HELP {}{} {}", METRIC_PREFIX, name, help).unwrap();
+	writeln!(out, "# TYPE {}{} {}", METRIC_PREFIX, name, kind).unwrap();
+}
+
+fn write_metric(out: &mut String, labels: &str, name: &str, kind: &str, help: &str, value: u64) {
+	write_header(out, name, kind, help);
+	writeln!(out, "{}{}{} {}", METRIC_PREFIX, name, labels, value).unwrap();
+}
+
+/// Formats the tags, and the extra label if any, as a label set like
+/// `{team="tools"}`. Returns an empty string if there are no labels.
+fn format_labels(tags: &Tags, extra: Option<(&str, &str)>) -> String {
+	let labels = tags
+		.iter()
+		.map(|(k, v)| (k.as_str(), v.as_str()))
+		.chain(extra)
+		.map(|(k, v)| {
+			let v = v
+				.replace('\\', "\\\\")
+				.replace('"', "\\\"")
+				.replace('\n', "\\n");
+			format!("{}=\"{}\"", k, v)
+		})
+		.collect::<Vec<_>>();
+
+	if labels.is_empty() {
+		String::new()
+	} else {
+		format!("{{{}}}", labels.join(","))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_render() {
+		let metrics = ServerMetrics::default();
+		metrics.record_connection();
+		metrics.record_closed_socket(100, 20);
+		metrics.record_rpc_call("ping");
+		metrics.record_rpc_call("ping");
+		metrics.record_rpc_call("forward");
+
+		let snapshot = MetricsSnapshot {
+			live_bytes_tx: 5,
+			live_bytes_rx: 1,
+			active_sockets: 1,
+			attached_bridges: 0,
+			forwarded_ports: 2,
+			pending_http_requests: 0,
+		};
+		let out = metrics.render(&snapshot, &Tags::new());
+
+		assert!(out.contains(
+			"# TYPE code_tunnel_bytes_sent_total counter\ncode_tunnel_bytes_sent_total 105\n"
+		));
+		assert!(out.contains("code_tunnel_bytes_received_total 21\n"));
+		assert!(out.contains(
+			"code_tunnel_rpc_calls_total{method=\"forward\"} 1\ncode_tunnel_rpc_calls_total{method=\"ping\"} 2\n"
+		));
+		assert!(out
+			.contains("# TYPE code_tunnel_forwarded_ports gauge\ncode_tunnel_forwarded_ports 2\n"));
+
+		let tags = Tags::from([("team".to_string(), "a \"b\"".to_string())]);
+		let out = metrics.render(&snapshot, &tags);
+		assert!(out.contains("code_tunnel_connections_total{team=\"a \\\"b\\\"\"} 1\n"));
+		assert!(
+			out.contains("code_tunnel_rpc_calls_total{team=\"a \\\"b\\\"\",method=\"ping\"} 2\n")
+		);
+	}
+}
@@ -0,0 +1,111 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	collections::HashMap,
+	sync::{Arc, Mutex},
+};
+
+use tokio::sync::mpsc;
+
+use super::{
+	protocol::{ClientRequestMethod, ToClientRequest},
+	socket_signal::SocketSignal,
+};
+
+/// Serializes a notification to a client of the control server.
+pub fn serialize_notification(params: ClientRequestMethod<'_>) -> Vec<u8> {
+	rmp_serde::to_vec_named(&ToClientRequest { id: None, params }).unwrap()
+}
+
+/// Sends notifications to clients connected to the control server, so that
+/// subsystems can publish events without holding on to sockets themselves.
+#[derive(Clone, Default)]
+pub struct Notifier {
+	sockets: Arc<Mutex<HashMap<u32, mpsc::Sender<SocketSignal>>>>,
+}
+
+impl Notifier {
+	/// Adds a socket, which is notified until it's removed.
+	pub fn add_socket(&self, id: u32, tx: mpsc::Sender<SocketSignal>) {
+		self.sockets.lock().unwrap().insert(id, tx);
+	}
+
+	pub fn remove_socket(&self, id: u32) {
+		self.sockets.lock().unwrap().remove(&id);
+	}
+
+	/// Sends a notification to the socket with the ID. Returns false if it's
+	/// no longer connected.
+	pub async fn notify(&self, id: u32, params: ClientRequestMethod<'_>) -> bool {
+		let tx = match self.sockets.lock().unwrap().get(&id) {
+			Some(tx) => tx.clone(),
+			None => return false,
+		};
+
+		tx.send(SocketSignal::Send(serialize_notification(params)))
+			.await
+			.is_ok()
+	}
+
+	/// Sends a notification to the socket with the ID without waiting, such as
+	/// from synchronous code. It's dropped if the socket's queue is full.
+	pub fn try_notify(&self, id: u32, params: ClientRequestMethod<'_>) {
+		if let Some(tx) = self.sockets.lock().unwrap().get(&id) {
+			tx.try_send(SocketSignal::Send(serialize_notification(params)))
+				.ok();
+		}
+	}
+
+	/// Sends a notification to every connected socket.
+	pub async fn notify_all(&self, params: ClientRequestMethod<'_>) {
+		let sockets: Vec<_> = self.sockets.lock().unwrap().values().cloned().collect();
+		let message = serialize_notification(params);
+		for tx in sockets {
+			tx.send(SocketSignal::Send(message.clone())).await.ok();
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::tunnels::protocol::{DrainingParams, ShutdownReason, ShuttingDownParams};
+
+	#[tokio::test]
+	async fn test_notify() {
+		let notifier = Notifier::default();
+		let (tx1, mut rx1) = mpsc::channel(4);
+		let (tx2, mut rx2) = mpsc::channel(4);
+		notifier.add_socket(1, tx1);
+		notifier.add_socket(2, tx2);
+
+		let shutting_down = || {
+			ClientRequestMethod::shuttingdown(ShuttingDownParams {
+				message: "bye".to_string(),
+				grace_period_ms: 0,
+			})
+		};
+		notifier.notify_all(shutting_down()).await;
+		let expected = serialize_notification(shutting_down());
+		for rx in [&mut rx1, &mut rx2] {
+			match rx.try_recv() {
+				Ok(SocketSignal::Send(m)) => assert_eq!(m, expected),
+				_ => panic!("expected a notification"),
+			}
+		}
+
+		notifier.remove_socket(1);
+		assert!(!notifier.notify(1, shutting_down()).await);
+		let draining = ClientRequestMethod::draining(DrainingParams {
+			reason: ShutdownReason::Disconnected,
+			message: String::new(),
+			restart_eta_ms: None,
+		});
+		assert!(notifier.notify(2, draining).await);
+		assert!(rx1.try_recv().is_err());
+		assert!(rx2.try_recv().is_ok());
+	}
+}
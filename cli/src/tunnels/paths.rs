@@ -5,7 +5,8 @@
 
 use std::{
 	fs::{read_dir, read_to_string, remove_dir_all, write},
-	path::PathBuf,
+	path::{Path, PathBuf},
+	time::Duration,
 };
 
 use serde::{Deserialize, Serialize};
@@ -20,6 +21,8 @@ use crate::{
 };
 
 pub const SERVER_FOLDER_NAME: &str = "server";
+/// How long isolated instances must go unused before their data is pruned.
+const STALE_INSTANCE_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
 
 pub struct ServerPaths {
 	// Directory into which the server is downloaded
@@ -30,20 +33,37 @@ pub struct ServerPaths {
 	pub logfile: PathBuf,
 	// File where the process ID for the server should be written.
 	pub pidfile: PathBuf,
+	// File where the settings the server was started with are written.
+	pub settingsfile: PathBuf,
+	// Data dir of the isolated instance the paths are for, if any. Instances
+	// share the executable, so servers are told apart by their data dir.
+	instance_dir: Option<PathBuf>,
 }
 
 impl ServerPaths {
 	// Queries the system to determine the process ID of the running server.
 	// Returns the process ID, if the server is running.
 	pub fn get_running_pid(&self) -> Option<u32> {
+		let executable = self.executable.display().to_string();
+		let data_dir_arg = self.instance_dir.as_deref().map(instance_arg);
+		let is_server = |cmd: &[String]| {
+			cmd.iter().any(|c| c.contains(&executable))
+				&& cmd.iter().find(|c| c.starts_with(INSTANCE_ARG)) == data_dir_arg.as_ref()
+		};
+
 		if let Some(pid) = self.read_pid() {
-			return match machine::process_at_path_exists(pid, &self.executable) {
+			return match machine::process_matches(pid, is_server) {
 				true => Some(pid),
 				false => None,
 			};
 		}
 
-		if let Some(pid) = machine::find_running_process(&self.executable) {
+		// instances always have a pidfile, since they're only started by the CLI
+		if self.instance_dir.is_some() {
+			return None;
+		}
+
+		if let Some(pid) = machine::find_running_process_matching(is_server) {
 			// attempt to backfill process ID:
 			self.write_pid(pid).ok();
 			return Some(pid);
@@ -52,6 +72,18 @@ impl ServerPaths {
 		None
 	}
 
+	/// Moves the logfile and pidfile into the directory of an isolated
+	/// instance, so its server is separate from others using the install.
+	pub fn for_instance(self, instance_dir: &Path) -> ServerPaths {
+		ServerPaths {
+			logfile: instance_dir.join("log.txt"),
+			pidfile: instance_dir.join("pid.txt"),
+			settingsfile: instance_dir.join("settings.json"),
+			instance_dir: Some(instance_dir.to_owned()),
+			..self
+		}
+	}
+
 	/// Delete the server directory
 	pub fn delete(&self) -> Result<(), WrappedError> {
 		remove_dir_all(&self.server_dir).map_err(|e| {
@@ -97,7 +129,9 @@ impl InstalledServer {
 				.join(self.quality.server_entrypoint()),
 			logfile: server_dir.join("log.txt"),
 			pidfile: server_dir.join("pid.txt"),
+			settingsfile: server_dir.join("settings.json"),
 			server_dir,
+			instance_dir: None,
 		}
 	}
 
@@ -110,12 +144,73 @@ impl InstalledServer {
 	}
 }
 
-/// Prunes servers not currently running, and returns the deleted servers.
-pub fn prune_stopped_servers(launcher_paths: &LauncherPaths) -> Result<Vec<ServerPaths>, AnyError> {
+/// Gets servers not currently running, which would be pruned. Servers of
+/// isolated instances run from the same install, so it's kept while any
+/// process runs its executable.
+pub fn get_stopped_servers(launcher_paths: &LauncherPaths) -> Vec<ServerPaths> {
 	get_all_servers(launcher_paths)
 		.into_iter()
 		.map(|s| s.server_paths(launcher_paths))
-		.filter(|s| s.get_running_pid().is_none())
+		.filter(|s| machine::find_running_process(&s.executable).is_none())
+		.collect()
+}
+
+/// Flag servers of isolated instances are given their data dir with.
+const INSTANCE_ARG: &str = "--server-data-dir=";
+
+pub fn instance_arg(instance_dir: &Path) -> String {
+	format!("{}{}", INSTANCE_ARG, instance_dir.display())
+}
+
+/// Gets data dirs of isolated instances whose servers aren't running and
+/// haven't been used for a while, which would be pruned.
+pub fn get_stale_instances(launcher_paths: &LauncherPaths) -> Vec<PathBuf> {
+	let entries = match read_dir(launcher_paths.server_instances_dir()) {
+		Ok(e) => e,
+		Err(_) => return vec![],
+	};
+
+	entries
+		.flatten()
+		.map(|e| e.path())
+		.filter(|dir| {
+			// servers write their logs while they run
+			let last_used = [dir.to_owned(), dir.join("log.txt")]
+				.iter()
+				.filter_map(|p| p.metadata().and_then(|m| m.modified()).ok())
+				.max();
+			let is_stale = last_used
+				.and_then(|t| t.elapsed().ok())
+				.map_or(true, |e| e > STALE_INSTANCE_AGE);
+			let arg = instance_arg(dir);
+			let is_running = read_to_string(dir.join("pid.txt"))
+				.ok()
+				.and_then(|s| s.parse::<u32>().ok())
+				.map_or(false, |pid| {
+					machine::process_matches(pid, |cmd| cmd.contains(&arg))
+				});
+			is_stale && !is_running
+		})
+		.collect()
+}
+
+/// Prunes data dirs of stale isolated instances, and returns the deleted dirs.
+pub fn prune_stale_instances(launcher_paths: &LauncherPaths) -> Result<Vec<PathBuf>, AnyError> {
+	get_stale_instances(launcher_paths)
+		.into_iter()
+		.map(|d| {
+			remove_dir_all(&d)
+				.map(|_| d.clone())
+				.map_err(|e| wrap(e, format!("error deleting instance dir {}", d.display())))
+		})
+		.collect::<Result<_, _>>()
+		.map_err(AnyError::from)
+}
+
+/// Prunes servers not currently running, and returns the deleted servers.
+pub fn prune_stopped_servers(launcher_paths: &LauncherPaths) -> Result<Vec<ServerPaths>, AnyError> {
+	get_stopped_servers(launcher_paths)
+		.into_iter()
 		.map(|s| s.delete().map(|_| s))
 		.collect::<Result<_, _>>()
 		.map_err(AnyError::from)
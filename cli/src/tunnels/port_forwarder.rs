@@ -3,19 +3,36 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
-use std::collections::HashSet;
+use std::{
+	collections::HashMap,
+	net::IpAddr,
+	path::{Component, Path, PathBuf},
+	sync::{Arc, Mutex},
+};
 
-use tokio::sync::{mpsc, oneshot};
+use tokio::{
+	io::{AsyncRead, AsyncWrite},
+	net::TcpStream,
+	sync::{mpsc, oneshot},
+};
 
 use crate::{
+	async_pipe::get_socket_rw_stream,
 	constants::CONTROL_PORT,
-	util::errors::{AnyError, CannotForwardControlPort, ServerHasClosed},
+	log,
+	util::errors::{wrap, AnyError, CannotForwardControlPort, CodeError, ServerHasClosed},
 };
 
-use super::dev_tunnels::ActiveTunnel;
+use tunnels::connections::ForwardedPortConnection;
+
+use super::{dev_tunnels::ActiveTunnel, protocol::ForwardTarget};
 
 pub enum PortForwardingRec {
-	Forward(u16, oneshot::Sender<Result<String, AnyError>>),
+	Forward(
+		u16,
+		Option<ForwardTarget>,
+		oneshot::Sender<Result<String, AnyError>>,
+	),
 	Unforward(u16, oneshot::Sender<Result<(), AnyError>>),
 }
 
@@ -23,18 +40,32 @@ pub enum PortForwardingRec {
 /// requests on it, which are (and *must be*) processed by calling the `.process()`
 /// method on the forwarder.
 pub struct PortForwardingProcessor {
+	log: log::Logger,
 	tx: mpsc::Sender<PortForwardingRec>,
 	rx: mpsc::Receiver<PortForwardingRec>,
-	forwarded: HashSet<u16>,
+	/// Forwarded ports, and where their connections are relayed if not to
+	/// the port on localhost.
+	forwarded: Arc<Mutex<HashMap<u16, Option<ForwardTarget>>>>,
+	/// Sockets, or directories of sockets, that ports may be forwarded to.
+	socket_allowlist: Vec<PathBuf>,
+	/// Hosts other than loopback that ports may be forwarded to.
+	host_allowlist: Vec<String>,
 }
 
 impl PortForwardingProcessor {
-	pub fn new() -> Self {
+	pub fn new(
+		log: log::Logger,
+		socket_allowlist: Vec<PathBuf>,
+		host_allowlist: Vec<String>,
+	) -> Self {
 		let (tx, rx) = mpsc::channel(8);
 		Self {
+			log,
+			socket_allowlist,
+			host_allowlist,
 			tx,
 			rx,
-			forwarded: HashSet::new(),
+			forwarded: Arc::new(Mutex::new(HashMap::new())),
 		}
 	}
 
@@ -42,6 +73,7 @@ impl PortForwardingProcessor {
 	pub fn handle(&self) -> PortForwarding {
 		PortForwarding {
 			tx: self.tx.clone(),
+			forwarded: self.forwarded.clone(),
 		}
 	}
 
@@ -54,8 +86,9 @@ impl PortForwardingProcessor {
 	/// Processes the incoming forwarding request.
 	pub async fn process(&mut self, req: PortForwardingRec, tunnel: &mut ActiveTunnel) {
 		match req {
-			PortForwardingRec::Forward(port, tx) => {
-				tx.send(self.process_forward(port, tunnel).await).ok();
+			PortForwardingRec::Forward(port, target, tx) => {
+				tx.send(self.process_forward(port, target, tunnel).await)
+					.ok();
 			}
 			PortForwardingRec::Unforward(port, tx) => {
 				tx.send(self.process_unforward(port, tunnel).await).ok();
@@ -63,6 +96,26 @@ impl PortForwardingProcessor {
 		}
 	}
 
+	/// Processes the request without a tunnel, such that forwarded ports are
+	/// only available on this machine.
+	pub fn process_locally(&mut self, req: PortForwardingRec) {
+		match req {
+			PortForwardingRec::Forward(port, _, tx) => {
+				let r = if port == CONTROL_PORT {
+					Err(CannotForwardControlPort().into())
+				} else {
+					self.forwarded.lock().unwrap().insert(port, None);
+					Ok(format!("http://127.0.0.1:{}", port))
+				};
+				tx.send(r).ok();
+			}
+			PortForwardingRec::Unforward(port, tx) => {
+				self.forwarded.lock().unwrap().remove(&port);
+				tx.send(Ok(())).ok();
+			}
+		}
+	}
+
 	async fn process_unforward(
 		&mut self,
 		port: u16,
@@ -73,37 +126,160 @@ impl PortForwardingProcessor {
 		}
 
 		tunnel.remove_port(port).await?;
-		self.forwarded.remove(&port);
+		self.forwarded.lock().unwrap().remove(&port);
 		Ok(())
 	}
 
 	async fn process_forward(
 		&mut self,
 		port: u16,
+		target: Option<ForwardTarget>,
 		tunnel: &mut ActiveTunnel,
 	) -> Result<String, AnyError> {
 		if port == CONTROL_PORT {
 			return Err(CannotForwardControlPort().into());
 		}
 
-		if !self.forwarded.contains(&port) {
-			tunnel.add_port_tcp(port).await?;
-			self.forwarded.insert(port);
+		match &target {
+			Some(ForwardTarget::Socket(path)) => {
+				if !is_socket_allowed(&self.socket_allowlist, Path::new(path)) {
+					return Err(CodeError::ForwardSocketNotAllowed(path.clone()).into());
+				}
+			}
+			Some(ForwardTarget::Host(host)) => {
+				if !is_host_allowed(&self.host_allowlist, host) {
+					return Err(CodeError::ForwardHostNotAllowed(host.clone()).into());
+				}
+			}
+			None => {}
+		}
+
+		let existing = self.forwarded.lock().unwrap().get(&port).cloned();
+		match existing {
+			Some(t) if t == target => return tunnel.get_port_uri(port).await,
+			Some(_) => {
+				// retargeting: the relay for the old target stops once its
+				// connection receiver is dropped by the removal.
+				tunnel.remove_port(port).await?;
+				self.forwarded.lock().unwrap().remove(&port);
+			}
+			None => {}
+		}
+
+		match &target {
+			None => tunnel.add_port_tcp(port).await?,
+			Some(t) => {
+				let connections = tunnel.add_port_direct(port).await?;
+				tokio::spawn(relay_connections(
+					self.log.clone(),
+					port,
+					t.clone(),
+					connections,
+				));
+			}
 		}
+		self.forwarded.lock().unwrap().insert(port, target);
 
 		tunnel.get_port_uri(port).await
 	}
 }
 
+/// Gets whether the socket is in, or is, one of the allowlisted paths. Paths
+/// are resolved first so that links and `..` can't escape the allowlist.
+fn is_socket_allowed(allowlist: &[PathBuf], path: &Path) -> bool {
+	let resolve = |p: &Path| std::fs::canonicalize(p).unwrap_or_else(|_| p.to_owned());
+	let path = resolve(path);
+	// named pipes and sockets yet to be created can't be canonicalized
+	if path.components().any(|c| c == Component::ParentDir) {
+		return false;
+	}
+
+	allowlist.iter().any(|a| path.starts_with(resolve(a)))
+}
+
+/// Gets whether the host is loopback or allowlisted, so clients can't use the
+/// tunnel to reach other machines on the host's network.
+fn is_host_allowed(allowlist: &[String], host: &str) -> bool {
+	let host = host.trim_start_matches('[').trim_end_matches(']');
+	host.eq_ignore_ascii_case("localhost")
+		|| host.parse::<IpAddr>().map_or(false, |ip| ip.is_loopback())
+		|| allowlist.iter().any(|a| a.eq_ignore_ascii_case(host))
+}
+
+/// Relays connections made to a forwarded port to its target.
+async fn relay_connections(
+	log: log::Logger,
+	port: u16,
+	target: ForwardTarget,
+	mut connections: mpsc::UnboundedReceiver<ForwardedPortConnection>,
+) {
+	while let Some(conn) = connections.recv().await {
+		let log = log.clone();
+		let target = target.clone();
+		tokio::spawn(async move {
+			if let Err(e) = relay_connection(port, &target, conn).await {
+				warning!(log, "error relaying forwarded port {}: {}", port, e);
+			}
+		});
+	}
+}
+
+async fn relay_connection(
+	port: u16,
+	target: &ForwardTarget,
+	conn: ForwardedPortConnection,
+) -> Result<(), AnyError> {
+	let (mut tunnel_write, mut tunnel_read) = conn.into_split();
+	match target {
+		ForwardTarget::Host(host) => {
+			let mut stream = TcpStream::connect((host.as_str(), port))
+				.await
+				.map_err(|e| wrap(e, format!("error connecting to {}:{}", host, port)))?;
+			let (mut read, mut write) = stream.split();
+			splice(&mut tunnel_read, &mut tunnel_write, &mut read, &mut write).await
+		}
+		ForwardTarget::Socket(path) => {
+			let stream = get_socket_rw_stream(Path::new(path)).await?;
+			let (mut read, mut write) = tokio::io::split(stream);
+			splice(&mut tunnel_read, &mut tunnel_write, &mut read, &mut write).await
+		}
+	}
+}
+
+/// Copies data in both directions until either side closes.
+async fn splice(
+	a_read: &mut (impl AsyncRead + Unpin),
+	a_write: &mut (impl AsyncWrite + Unpin),
+	b_read: &mut (impl AsyncRead + Unpin),
+	b_write: &mut (impl AsyncWrite + Unpin),
+) -> Result<(), AnyError> {
+	tokio::select! {
+		r = tokio::io::copy(a_read, b_write) => r,
+		r = tokio::io::copy(b_read, a_write) => r,
+	}
+	.map(|_| ())
+	.map_err(|e| wrap(e, "error copying data").into())
+}
+
 #[derive(Clone)]
 pub struct PortForwarding {
 	tx: mpsc::Sender<PortForwardingRec>,
+	forwarded: Arc<Mutex<HashMap<u16, Option<ForwardTarget>>>>,
 }
 
 impl PortForwarding {
-	pub async fn forward(&self, port: u16) -> Result<String, AnyError> {
+	/// Gets the ports that are currently forwarded.
+	pub fn forwarded_ports(&self) -> Vec<u16> {
+		self.forwarded.lock().unwrap().keys().copied().collect()
+	}
+
+	pub async fn forward(
+		&self,
+		port: u16,
+		target: Option<ForwardTarget>,
+	) -> Result<String, AnyError> {
 		let (tx, rx) = oneshot::channel();
-		let req = PortForwardingRec::Forward(port, tx);
+		let req = PortForwardingRec::Forward(port, target, tx);
 
 		if self.tx.send(req).await.is_err() {
 			return Err(ServerHasClosed().into());
@@ -129,3 +305,45 @@ impl PortForwarding {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_is_socket_allowed() {
+		let dir = tempfile::tempdir().unwrap();
+		let allowed = dir.path().join("allowed");
+		let other = dir.path().join("other");
+		std::fs::create_dir(&allowed).unwrap();
+		std::fs::create_dir(&other).unwrap();
+		std::fs::write(allowed.join("a.sock"), "").unwrap();
+		std::fs::write(other.join("b.sock"), "").unwrap();
+
+		let allowlist = vec![allowed.clone(), other.join("c.sock")];
+		assert!(is_socket_allowed(&allowlist, &allowed.join("a.sock")));
+		assert!(is_socket_allowed(&allowlist, &other.join("c.sock")));
+		assert!(!is_socket_allowed(&allowlist, &other.join("b.sock")));
+		assert!(!is_socket_allowed(
+			&allowlist,
+			&allowed.join("..").join("other").join("b.sock")
+		));
+		assert!(!is_socket_allowed(
+			&allowlist,
+			&allowed.join("..").join("other").join("c.sock")
+		));
+		assert!(!is_socket_allowed(&[], &allowed.join("a.sock")));
+	}
+
+	#[test]
+	fn test_is_host_allowed() {
+		let allowlist = vec!["db.internal".to_string()];
+		assert!(is_host_allowed(&allowlist, "localhost"));
+		assert!(is_host_allowed(&allowlist, "127.0.0.2"));
+		assert!(is_host_allowed(&allowlist, "[::1]"));
+		assert!(is_host_allowed(&allowlist, "DB.internal"));
+		assert!(!is_host_allowed(&allowlist, "10.0.0.1"));
+		assert!(!is_host_allowed(&allowlist, "example.com"));
+		assert!(!is_host_allowed(&[], "db.internal"));
+	}
+}
@@ -0,0 +1,292 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use crate::util::errors::AnyError;
+
+use super::protocol::{ListeningPort, PortProtocol};
+
+/// Lists sockets on the host that are listening for TCP connections or bound
+/// to a UDP port. The owning process is included where the CLI is allowed to
+/// see it, which usually excludes processes run by other users.
+pub async fn list_listening_ports() -> Result<Vec<ListeningPort>, AnyError> {
+	let mut ports = list_platform_ports().await?;
+	ports.sort_by(|a, b| (a.port, a.protocol, &a.address).cmp(&(b.port, b.protocol, &b.address)));
+	ports.dedup_by(|a, b| a.port == b.port && a.protocol == b.protocol && a.address == b.address);
+	Ok(ports)
+}
+
+#[cfg(target_os = "linux")]
+async fn list_platform_ports() -> Result<Vec<ListeningPort>, AnyError> {
+	use crate::util::errors::wrap;
+
+	tokio::task::spawn_blocking(linux::list_ports)
+		.await
+		.map_err(|e| wrap(e, "error listing ports"))?
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+	use std::{collections::HashMap, fs, net::Ipv6Addr};
+
+	use crate::util::errors::{wrap, AnyError};
+
+	use super::{ListeningPort, PortProtocol};
+
+	const TCP_LISTEN: &str = "0A";
+	/// Unconnected UDP sockets are reported as closed.
+	const UDP_UNCONNECTED: &str = "07";
+
+	pub fn list_ports() -> Result<Vec<ListeningPort>, AnyError> {
+		let owners = socket_owners();
+		let mut ports = vec![];
+		for (file, protocol) in [
+			("/proc/net/tcp", PortProtocol::Tcp),
+			("/proc/net/tcp6", PortProtocol::Tcp),
+			("/proc/net/udp", PortProtocol::Udp),
+			("/proc/net/udp6", PortProtocol::Udp),
+		] {
+			// the v6 tables are missing when IPv6 is disabled
+			let contents = match fs::read_to_string(file) {
+				Ok(c) => c,
+				Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+				Err(e) => return Err(wrap(e, format!("error reading {}", file)).into()),
+			};
+
+			for (address, port, inode) in parse_proc_net(&contents, protocol) {
+				let owner = owners.get(&inode);
+				ports.push(ListeningPort {
+					protocol,
+					address,
+					port,
+					pid: owner.map(|o| o.0),
+					process: owner.map(|o| o.1.clone()),
+				});
+			}
+		}
+
+		Ok(ports)
+	}
+
+	/// Parses a /proc/net table, returning the local address, port, and
+	/// socket inode of each listening entry.
+	pub fn parse_proc_net(contents: &str, protocol: PortProtocol) -> Vec<(String, u16, u64)> {
+		let state = match protocol {
+			PortProtocol::Tcp => TCP_LISTEN,
+			PortProtocol::Udp => UDP_UNCONNECTED,
+		};
+
+		contents
+			.lines()
+			.skip(1)
+			.filter_map(|line| {
+				let fields: Vec<&str> = line.split_whitespace().collect();
+				if fields.len() < 10 || fields[3] != state {
+					return None;
+				}
+
+				let (addr, port) = fields[1].split_once(':')?;
+				let port = u16::from_str_radix(port, 16).ok()?;
+				let inode = fields[9].parse().ok()?;
+				Some((parse_proc_addr(addr)?, port, inode))
+			})
+			.collect()
+	}
+
+	/// Addresses are in network byte order, printed as native-endian u32s.
+	fn parse_proc_addr(hex: &str) -> Option<String> {
+		let mut bytes = vec![];
+		for i in (0..hex.len()).step_by(8) {
+			let word = u32::from_str_radix(hex.get(i..i + 8)?, 16).ok()?;
+			bytes.extend_from_slice(&word.to_ne_bytes());
+		}
+
+		match bytes.len() {
+			4 => Some(format!(
+				"{}.{}.{}.{}",
+				bytes[0], bytes[1], bytes[2], bytes[3]
+			)),
+			16 => {
+				let octets: [u8; 16] = bytes.try_into().ok()?;
+				let addr = Ipv6Addr::from(octets);
+				Some(match addr.to_ipv4_mapped() {
+					Some(v4) => v4.to_string(),
+					None => addr.to_string(),
+				})
+			}
+			_ => None,
+		}
+	}
+
+	/// Maps socket inodes to the pid and name of the process holding them.
+	fn socket_owners() -> HashMap<u64, (u32, String)> {
+		let mut owners = HashMap::new();
+		let procs = match fs::read_dir("/proc") {
+			Ok(p) => p,
+			Err(_) => return owners,
+		};
+
+		for entry in procs.flatten() {
+			let pid: u32 = match entry.file_name().to_str().and_then(|s| s.parse().ok()) {
+				Some(p) => p,
+				None => continue,
+			};
+			// fails for processes of other users
+			let fds = match fs::read_dir(entry.path().join("fd")) {
+				Ok(f) => f,
+				Err(_) => continue,
+			};
+
+			let name = fs::read_to_string(entry.path().join("comm"))
+				.map(|s| s.trim_end().to_string())
+				.unwrap_or_default();
+			for fd in fds.flatten() {
+				let target = match fs::read_link(fd.path()) {
+					Ok(t) => t,
+					Err(_) => continue,
+				};
+				let inode = target
+					.to_str()
+					.and_then(|t| t.strip_prefix("socket:["))
+					.and_then(|t| t.strip_suffix(']'))
+					.and_then(|t| t.parse().ok());
+				if let Some(inode) = inode {
+					owners.insert(inode, (pid, name.clone()));
+				}
+			}
+		}
+
+		owners
+	}
+}
+
+#[cfg(target_os = "macos")]
+async fn list_platform_ports() -> Result<Vec<ListeningPort>, AnyError> {
+	use crate::util::command::capture_command;
+
+	let output =
+		capture_command("lsof", ["-nP", "-iTCP", "-sTCP:LISTEN", "-iUDP", "-FpcPn"]).await?;
+	Ok(parse_lsof(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Parses lsof's field output, where each line is a field identifier
+/// followed by its value, and process fields precede their files.
+#[cfg(target_os = "macos")]
+fn parse_lsof(output: &str) -> Vec<ListeningPort> {
+	let mut ports = vec![];
+	let mut pid = None;
+	let mut process = None;
+	let mut protocol = None;
+	for line in output.lines() {
+		let (field, value) = match line.char_indices().nth(1) {
+			Some((i, _)) => line.split_at(i),
+			None => continue,
+		};
+
+		match field {
+			"p" => pid = value.parse().ok(),
+			"c" => process = Some(value.to_string()),
+			"P" => {
+				protocol = match value {
+					"TCP" => Some(PortProtocol::Tcp),
+					"UDP" => Some(PortProtocol::Udp),
+					_ => None,
+				}
+			}
+			"n" => {
+				// connected UDP sockets show their peer after an arrow
+				if value.contains("->") {
+					continue;
+				}
+				let (address, port) = match value.rsplit_once(':') {
+					Some(p) => p,
+					None => continue,
+				};
+				if let (Some(protocol), Ok(port)) = (protocol, port.parse()) {
+					ports.push(ListeningPort {
+						protocol,
+						address: address.trim_matches(|c| c == '[' || c == ']').to_string(),
+						port,
+						pid,
+						process: process.clone(),
+					});
+				}
+			}
+			_ => {}
+		}
+	}
+
+	ports
+}
+
+#[cfg(target_os = "windows")]
+async fn list_platform_ports() -> Result<Vec<ListeningPort>, AnyError> {
+	use crate::util::command::capture_command;
+	use sysinfo::{Pid, PidExt, ProcessExt, ProcessRefreshKind, System, SystemExt};
+
+	let output = capture_command("netstat", ["-ano"]).await?;
+	let mut ports = parse_netstat(&String::from_utf8_lossy(&output.stdout));
+
+	let mut sys = System::new();
+	sys.refresh_processes_specifics(ProcessRefreshKind::new());
+	for port in ports.iter_mut() {
+		port.process = port
+			.pid
+			.and_then(|pid| sys.process(Pid::from_u32(pid)))
+			.map(|p| p.name().to_string());
+	}
+
+	Ok(ports)
+}
+
+/// Parses `netstat -ano` output. TCP rows have a state column before the
+/// pid, while UDP rows do not. The state is localized, so listening sockets
+/// are recognized by having no remote port instead.
+#[cfg(target_os = "windows")]
+fn parse_netstat(output: &str) -> Vec<ListeningPort> {
+	output
+		.lines()
+		.filter_map(|line| {
+			let fields: Vec<&str> = line.split_whitespace().collect();
+			let (protocol, pid) = match fields.as_slice() {
+				["TCP", _, remote, _, pid] if remote.ends_with(":0") => (PortProtocol::Tcp, pid),
+				["UDP", _, _, pid] => (PortProtocol::Udp, pid),
+				_ => return None,
+			};
+
+			let (address, port) = fields[1].rsplit_once(':')?;
+			Some(ListeningPort {
+				protocol,
+				address: address.trim_matches(|c| c == '[' || c == ']').to_string(),
+				port: port.parse().ok()?,
+				pid: pid.parse().ok(),
+				process: None,
+			})
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn test_parse_proc_net() {
+		use super::{linux::parse_proc_net, PortProtocol};
+
+		let tcp = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 0100007F:0CEA 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 12345 1 0000000000000000 100 0 0 10 0
+   1: 0100007F:9C40 0100007F:0CEA 01 00000000:00000000 00:00000000 00000000  1000        0 12346 1 0000000000000000 20 4 30 10 -1";
+		assert_eq!(
+			parse_proc_net(tcp, PortProtocol::Tcp),
+			vec![("127.0.0.1".to_string(), 3306, 12345)]
+		);
+
+		let tcp6 = "  sl  local_address                         remote_address                        st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 00000000000000000000000001000000:1F90 00000000000000000000000000000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 222 1 0000000000000000 100 0 0 10 0";
+		assert_eq!(
+			parse_proc_net(tcp6, PortProtocol::Tcp),
+			vec![("::1".to_string(), 8080, 222)]
+		);
+	}
+}
@@ -0,0 +1,345 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Server installs provided by the operator, such as on air-gapped hosts or
+//! in golden images, which are used instead of servers from the update
+//! service. The tunnel uses one with `--use-server-path`, and clients can
+//! request ones, or archives to install them from, in directories allowed
+//! with `--allow-server-path`, or connect to ones run by another supervisor on
+//! sockets allowed with `--allow-server-socket`.
+
+use std::{
+	fs::File,
+	io::{Read, Seek, SeekFrom},
+	path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::{
+	options::Quality,
+	update_service::Platform,
+	util::errors::{AnyError, CodeError},
+};
+
+/// A validated server install.
+#[derive(Clone, Debug)]
+pub struct PrebuiltServer {
+	/// The `server` folder extracted from a server archive.
+	pub dir: PathBuf,
+	pub commit: String,
+	pub quality: Quality,
+}
+
+#[derive(Deserialize)]
+struct ProductJson {
+	commit: Option<String>,
+	quality: Option<String>,
+}
+
+impl PrebuiltServer {
+	/// Reads the install in the directory, checking that it's complete and
+	/// built for the platform.
+	pub fn load(dir: &Path, platform: Platform) -> Result<Self, AnyError> {
+		let invalid = |reason: String| {
+			AnyError::from(CodeError::InvalidPrebuiltServer(
+				dir.display().to_string(),
+				reason,
+			))
+		};
+
+		let product: ProductJson = std::fs::read_to_string(dir.join("product.json"))
+			.ok()
+			.and_then(|s| serde_json::from_str(&s).ok())
+			.ok_or_else(|| invalid("product.json is missing or invalid".to_string()))?;
+		let commit = product
+			.commit
+			.ok_or_else(|| invalid("product.json doesn't have a commit".to_string()))?;
+		let quality = product
+			.quality
+			.as_deref()
+			.and_then(|q| Quality::try_from(q).ok())
+			.ok_or_else(|| invalid("product.json doesn't have a known quality".to_string()))?;
+
+		let entrypoint = dir.join("bin").join(quality.server_entrypoint());
+		if !entrypoint.is_file() {
+			return Err(invalid(format!("{} is missing", entrypoint.display())));
+		}
+
+		let node = dir.join(if cfg!(windows) { "node.exe" } else { "node" });
+		let expected = expected_binary(platform);
+		match read_binary_kind(&node) {
+			Some((format, arch))
+				if format == expected.0 && arch.map_or(true, |a| a == expected.1) => {}
+			Some(_) => {
+				return Err(invalid(format!(
+					"it's not built for {}",
+					platform.headless()
+				)))
+			}
+			None => {
+				return Err(invalid(format!(
+					"{} is missing or not an executable",
+					node.display()
+				)))
+			}
+		}
+
+		Ok(Self {
+			dir: dir.to_owned(),
+			commit,
+			quality,
+		})
+	}
+}
+
+/// Which operator-provided servers are used.
+#[derive(Clone, Debug, Default)]
+pub struct ServerPathPolicy {
+	/// Server used for clients that don't request one.
+	pub default: Option<PrebuiltServer>,
+	/// Directories that clients may request servers from.
+	pub allowed: Vec<PathBuf>,
+	/// Sockets of running servers that clients may connect to.
+	pub sockets: Vec<PathBuf>,
+}
+
+impl ServerPathPolicy {
+	/// Gets the prebuilt server to use for a serve request, which is the one
+	/// the client requested, if any, or the default.
+	pub fn resolve(
+		&self,
+		requested: Option<&str>,
+		platform: Platform,
+	) -> Result<Option<PrebuiltServer>, AnyError> {
+		let requested = match requested {
+			Some(r) => r,
+			None => return Ok(self.default.clone()),
+		};
+
+		let path = self.resolve_allowed(requested)?;
+		PrebuiltServer::load(&path, platform).map(Some)
+	}
+
+	/// Checks that clients may install a server from the archive.
+	pub fn resolve_archive(&self, requested: &str) -> Result<PathBuf, AnyError> {
+		let path = self.resolve_allowed(requested)?;
+		if path.is_file() {
+			Ok(path)
+		} else {
+			Err(CodeError::InvalidPrebuiltServer(
+				requested.to_string(),
+				"it's not an archive".to_string(),
+			)
+			.into())
+		}
+	}
+
+	fn resolve_allowed(&self, requested: &str) -> Result<PathBuf, AnyError> {
+		// resolved so that links and `..` can't escape the allowlist
+		let path = std::fs::canonicalize(requested)
+			.map_err(|_| CodeError::ServerPathNotAllowed(requested.to_string()))?;
+		let allowed = self
+			.allowed
+			.iter()
+			.filter_map(|a| std::fs::canonicalize(a).ok())
+			.any(|a| path.starts_with(a));
+		if allowed {
+			Ok(path)
+		} else {
+			Err(CodeError::ServerPathNotAllowed(requested.to_string()).into())
+		}
+	}
+
+	/// Checks that clients may connect to the server on the socket. Sockets
+	/// must be given exactly, since named pipes can't be resolved like paths.
+	pub fn resolve_socket(&self, requested: &str) -> Result<PathBuf, AnyError> {
+		let path = PathBuf::from(requested);
+		if self.sockets.contains(&path) {
+			Ok(path)
+		} else {
+			Err(CodeError::ServerSocketNotAllowed(requested.to_string()).into())
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BinaryFormat {
+	Elf,
+	MachO,
+	Pe,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Arch {
+	X64,
+	X86,
+	Arm64,
+	Arm32,
+	RiscV64,
+	Ppc64,
+}
+
+fn expected_binary(platform: Platform) -> (BinaryFormat, Arch) {
+	match platform {
+		Platform::LinuxAlpineX64 | Platform::LinuxX64 => (BinaryFormat::Elf, Arch::X64),
+		Platform::LinuxAlpineARM64 | Platform::LinuxARM64 => (BinaryFormat::Elf, Arch::Arm64),
+		Platform::LinuxARM32 => (BinaryFormat::Elf, Arch::Arm32),
+		Platform::LinuxRISCV64 => (BinaryFormat::Elf, Arch::RiscV64),
+		Platform::LinuxPPC64LE => (BinaryFormat::Elf, Arch::Ppc64),
+		Platform::DarwinX64 => (BinaryFormat::MachO, Arch::X64),
+		Platform::DarwinARM64 => (BinaryFormat::MachO, Arch::Arm64),
+		// arm64 Windows runs the x64 server, see `Platform::headless`
+		Platform::WindowsX64 | Platform::WindowsARM64 => (BinaryFormat::Pe, Arch::X64),
+		Platform::WindowsX86 => (BinaryFormat::Pe, Arch::X86),
+	}
+}
+
+/// Reads the format and architecture of an executable from its headers. The
+/// architecture is None for universal macOS binaries.
+fn read_binary_kind(path: &Path) -> Option<(BinaryFormat, Option<Arch>)> {
+	let mut file = File::open(path).ok()?;
+	let mut header = [0u8; 64];
+	file.read_exact(&mut header).ok()?;
+
+	let u16_at = |b: &[u8], i: usize| u16::from_le_bytes([b[i], b[i + 1]]);
+	let u32_at = |b: &[u8], i: usize| u32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]]);
+
+	match header {
+		[0x7f, b'E', b'L', b'F', ..] => {
+			let arch = match u16_at(&header, 18) {
+				0x3e => Arch::X64,
+				0x03 => Arch::X86,
+				0xb7 => Arch::Arm64,
+				0x28 => Arch::Arm32,
+				0xf3 => Arch::RiscV64,
+				0x15 => Arch::Ppc64,
+				_ => return None,
+			};
+			Some((BinaryFormat::Elf, Some(arch)))
+		}
+		[0xcf, 0xfa, 0xed, 0xfe, ..] => {
+			let arch = match u32_at(&header, 4) {
+				0x0100_0007 => Arch::X64,
+				0x0100_000c => Arch::Arm64,
+				_ => return None,
+			};
+			Some((BinaryFormat::MachO, Some(arch)))
+		}
+		[0xca, 0xfe, 0xba, 0xbe, ..] => Some((BinaryFormat::MachO, None)),
+		[b'M', b'Z', ..] => {
+			let mut pe = [0u8; 6];
+			file.seek(SeekFrom::Start(u32_at(&header, 0x3c) as u64))
+				.ok()?;
+			file.read_exact(&mut pe).ok()?;
+			if pe[..4] != *b"PE\0\0" {
+				return None;
+			}
+
+			let arch = match u16_at(&pe, 4) {
+				0x8664 => Arch::X64,
+				0x014c => Arch::X86,
+				0xaa64 => Arch::Arm64,
+				_ => return None,
+			};
+			Some((BinaryFormat::Pe, Some(arch)))
+		}
+		_ => None,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn write_server(dir: &Path, product: &str, node_header: &[u8]) {
+		std::fs::create_dir_all(dir.join("bin")).unwrap();
+		std::fs::write(dir.join("product.json"), product).unwrap();
+		std::fs::write(
+			dir.join("bin").join(Quality::Stable.server_entrypoint()),
+			"",
+		)
+		.unwrap();
+
+		let mut node = node_header.to_vec();
+		node.resize(64, 0);
+		std::fs::write(
+			dir.join(if cfg!(windows) { "node.exe" } else { "node" }),
+			node,
+		)
+		.unwrap();
+	}
+
+	fn elf_header(machine: u16) -> Vec<u8> {
+		let mut h = vec![0x7f, b'E', b'L', b'F'];
+		h.resize(18, 0);
+		h.extend_from_slice(&machine.to_le_bytes());
+		h
+	}
+
+	#[test]
+	#[cfg(target_os = "linux")]
+	fn test_load() {
+		let dir = tempfile::tempdir().unwrap();
+		let server = dir.path().join("server");
+		write_server(
+			&server,
+			r#"{"commit":"abc","quality":"stable"}"#,
+			&elf_header(0x3e),
+		);
+
+		let loaded = PrebuiltServer::load(&server, Platform::LinuxX64).unwrap();
+		assert_eq!(loaded.commit, "abc");
+		assert_eq!(loaded.quality, Quality::Stable);
+		assert!(PrebuiltServer::load(&server, Platform::LinuxARM64).is_err());
+		assert!(PrebuiltServer::load(&server, Platform::DarwinX64).is_err());
+
+		write_server(&server, r#"{"quality":"stable"}"#, &elf_header(0x3e));
+		assert!(PrebuiltServer::load(&server, Platform::LinuxX64).is_err());
+	}
+
+	#[test]
+	#[cfg(target_os = "linux")]
+	fn test_policy_resolve() {
+		let dir = tempfile::tempdir().unwrap();
+		let allowed = dir.path().join("allowed");
+		let other = dir.path().join("other");
+		for d in [&allowed, &other] {
+			write_server(
+				&d.join("server"),
+				r#"{"commit":"abc","quality":"stable"}"#,
+				&elf_header(0x3e),
+			);
+		}
+
+		let policy = ServerPathPolicy {
+			default: None,
+			allowed: vec![allowed.clone()],
+			sockets: vec![dir.path().join("server.sock")],
+		};
+		let resolve = |p: &Path| policy.resolve(Some(p.to_str().unwrap()), Platform::LinuxX64);
+		assert!(resolve(&allowed.join("server")).unwrap().is_some());
+		assert!(resolve(&other.join("server")).is_err());
+		assert!(resolve(&allowed.join("..").join("other").join("server")).is_err());
+		assert!(policy.resolve(None, Platform::LinuxX64).unwrap().is_none());
+
+		let archive = allowed.join("server.tar.gz");
+		std::fs::write(&archive, b"").unwrap();
+		std::fs::write(other.join("server.tar.gz"), b"").unwrap();
+		let resolve_archive = |p: &Path| policy.resolve_archive(p.to_str().unwrap());
+		assert!(resolve_archive(&archive).is_ok());
+		assert!(resolve_archive(&other.join("server.tar.gz")).is_err());
+		assert!(resolve_archive(&allowed.join("server")).is_err());
+
+		let socket = dir.path().join("server.sock");
+		assert_eq!(
+			policy.resolve_socket(socket.to_str().unwrap()).unwrap(),
+			socket
+		);
+		assert!(policy
+			.resolve_socket(dir.path().join("other.sock").to_str().unwrap())
+			.is_err());
+	}
+}
@@ -6,8 +6,8 @@ use std::collections::HashMap;
 
 use crate::{
 	constants::{PROTOCOL_VERSION, VSCODE_CLI_VERSION},
-	options::Quality,
-	update_service::Platform,
+	options::{Quality, TelemetryLevel},
+	update_service::{ListedRelease, Platform},
 };
 use serde::{Deserialize, Serialize};
 
@@ -19,6 +19,13 @@ pub enum ClientRequestMethod<'a> {
 	serverlog(ServerLog<'a>),
 	makehttpreq(HttpRequestParams<'a>),
 	version(VersionParams),
+	draining(DrainingParams),
+	restarting(RestartingParams),
+	updateavailable(UpdateAvailableParams),
+	shuttingdown(ShuttingDownParams),
+	spawnusage(SpawnUsageParams),
+	codeserverexited(CodeServerExitedParams),
+	downloadprogress(DownloadProgressParams),
 }
 
 #[derive(Deserialize, Debug)]
@@ -34,6 +41,9 @@ pub struct HttpRequestParams<'a> {
 	pub url: &'a str,
 	pub method: &'static str,
 	pub req_id: u32,
+	/// Body to send, for clients with `Capabilities::HTTP_BODIES`.
+	#[serde(with = "serde_bytes", skip_serializing_if = "Option::is_none")]
+	pub body: Option<&'a [u8]>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -43,17 +53,32 @@ pub struct HttpHeadersParams {
 	pub req_id: u32,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct ForwardParams {
 	pub port: u16,
+	/// Where connections to the port are relayed on this machine. Defaults to
+	/// the port on localhost.
+	#[serde(default)]
+	pub target: Option<ForwardTarget>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ForwardTarget {
+	/// Connect to the forwarded port on the given host or interface address.
+	/// Hosts other than loopback must be given with `--allow-forward-host`.
+	Host(String),
+	/// Connect to the unix socket (or named pipe, on Windows) at the path. The
+	/// tunnel must be started with `--allow-forward-socket` covering it.
+	Socket(String),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
 pub struct UnforwardParams {
 	pub port: u16,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct ForwardResult {
 	pub uri: String,
 }
@@ -66,11 +91,19 @@ pub struct InstallFromLocalFolderParams {
 	pub inner: ServeParams,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct ServeParams {
 	pub socket_id: u16,
 	pub commit_id: Option<String>,
+	/// Release version, like 1.86.2, to use if `commit_id` isn't given. The
+	/// update service resolves it to a commit.
+	#[serde(default)]
+	pub version: Option<String>,
 	pub quality: Quality,
+	/// Quality from the host's `custom_qualities`, such as an internal canary
+	/// channel, whose builds are used instead of ones of `quality`.
+	#[serde(default)]
+	pub custom_quality: Option<String>,
 	pub extensions: Vec<String>,
 	/// Optional preferred connection token.
 	#[serde(default)]
@@ -80,6 +113,39 @@ pub struct ServeParams {
 	/// If true, the client and server should gzip servermsg's sent in either direction.
 	#[serde(default)]
 	pub compress: bool,
+	/// Operator-provided server install to use instead of downloading one. It
+	/// must be in a directory allowed with `--allow-server-path`.
+	#[serde(default)]
+	pub server_path: Option<String>,
+	/// Operator-provided server archive to install from instead of
+	/// downloading one, for hosts without network access. It must be in a
+	/// directory allowed with `--allow-server-path`, and `commit_id` must be
+	/// given. Ignored if `server_path` is given.
+	#[serde(default)]
+	pub server_archive: Option<String>,
+	/// Runs an isolated server, with its own user data and extensions, for
+	/// clients that give the same instance, such as a workspace. Clients
+	/// that don't give one share a server.
+	#[serde(default)]
+	pub instance: Option<String>,
+	/// Socket of a server that's already running, such as one managed by
+	/// another supervisor, to connect to instead of starting one. It must be
+	/// allowed with `--allow-server-socket`, and `commit_id` must be given.
+	#[serde(default)]
+	pub server_socket: Option<String>,
+	/// Restarts the server if it crashes, see `CodeServerExitedParams`.
+	#[serde(default)]
+	pub restart_on_crash: bool,
+	/// Telemetry level of the client, which is given to the server when it's
+	/// started, unless the host's level is more private. It's an error if the
+	/// server is already running with another level, see
+	/// `ServerLaunchSettings`.
+	#[serde(default)]
+	pub telemetry_level: Option<TelemetryLevel>,
+	/// Extra flags for the server when it's started, such as `--locale=de`.
+	/// Only some flags are allowed, see `ALLOWED_EXTRA_ARGS` in code_server.rs.
+	#[serde(default)]
+	pub server_args: Vec<String>,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -88,9 +154,20 @@ pub struct EmptyObject {}
 #[derive(Serialize, Deserialize, Debug)]
 pub struct UpdateParams {
 	pub do_update: bool,
+	/// Returns the release that would be installed without installing it.
+	#[serde(default)]
+	pub dry_run: bool,
 }
 
-#[derive(Deserialize, Debug)]
+/// Params of methods that remove things, which, for dry runs, return what
+/// would be removed without removing it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DryRunParams {
+	#[serde(default)]
+	pub dry_run: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
 pub struct ServerMessageParams {
 	pub i: u16,
 	#[serde(with = "serde_bytes")]
@@ -104,10 +181,19 @@ pub struct RefServerMessageParams<'a> {
 	pub body: &'a [u8],
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct UpdateResult {
 	pub up_to_date: bool,
 	pub did_update: bool,
+	/// For dry runs, the release the CLI would be updated to.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub would_update_to: Option<UpdateRelease>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UpdateRelease {
+	pub name: String,
+	pub commit: String,
 }
 
 #[derive(Serialize, Debug)]
@@ -123,20 +209,354 @@ pub struct ServerLog<'a> {
 	pub level: u8,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct GetHostnameResponse {
 	pub value: String,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HealthResult {
+	/// How long the CLI process has been running, in seconds.
+	pub uptime_secs: u64,
+	pub active_sockets: usize,
+	pub attached_bridges: usize,
+	pub pending_http_requests: usize,
+	/// When the tunnel was last (re)connected, in seconds since the Unix epoch.
+	pub tunnel_connected_at: u64,
+}
+
+/// Result of the last check of a code server's health, see `serverhealth`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CodeServerHealth {
+	pub quality: Quality,
+	pub commit: String,
+	pub instance: Option<String>,
+	/// Whether the server answered the last check.
+	pub healthy: bool,
+	/// When the server was last checked, in seconds since the Unix epoch.
+	pub last_checked_at: u64,
+	/// When the server last answered a check, in seconds since the Unix
+	/// epoch. None if it never has.
+	pub last_healthy_at: Option<u64>,
+	/// Why the last check failed.
+	pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ServerHealthResult {
+	/// Servers the connection is using, which are checked periodically.
+	pub servers: Vec<CodeServerHealth>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MetricsResult {
+	/// Metrics in the Prometheus text exposition format.
+	pub text: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ConnectionStatsResult {
+	/// Round-trip time of pings to the client, in milliseconds.
+	pub rtt_ms: Option<f64>,
+	/// Fraction of pings to the client that went unanswered, from 0 to 1.
+	pub loss: Option<f64>,
+	pub tx_bytes_per_sec: Option<f64>,
+	pub rx_bytes_per_sec: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SysInfoResult {
+	pub os_name: Option<String>,
+	pub os_version: Option<String>,
+	pub kernel_version: Option<String>,
+	pub arch: String,
+	pub cpu_count: usize,
+	/// Total memory on the host, in bytes.
+	pub total_memory: u64,
+	/// Memory available for new processes, in bytes.
+	pub free_memory: u64,
+	/// Free space on the disk holding the CLI's data directory, in bytes, if
+	/// it could be determined.
+	pub data_dir_free_space: Option<u64>,
+	/// TLS backend the CLI was built with, see `util::http::TLS_BACKEND`.
+	pub tls_backend: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EnvProbeResult {
+	pub tools: Vec<ToolInfo>,
+	/// Shell that command lines are run through, see `SpawnParams::shell`.
+	pub default_shell: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ToolInfo {
+	/// Name of the toolchain, such as `git` or `python`.
+	pub name: String,
+	/// Command the tool was found as, or None if it isn't installed.
+	pub command: Option<String>,
+	/// Version number parsed from the tool's output, if any.
+	pub version: Option<String>,
+	/// First line the tool printed when asked for its version.
+	pub output: Option<String>,
+}
+
+/// Snapshot of the control server's internal state, for bug reports. Secrets
+/// are removed and paths under the home directory are abbreviated to `~`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DumpStateResult {
+	/// When the snapshot was taken, in seconds since the Unix epoch.
+	pub taken_at: u64,
+	pub cli_version: String,
+	/// When the tunnel was last (re)connected, in seconds since the Unix epoch.
+	pub tunnel_connected_at: u64,
+	pub forwarded_ports: Vec<u16>,
+	pub sockets: Vec<SocketStateDump>,
+	pub config: ConfigStateDump,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SocketStateDump {
+	pub id: u32,
+	/// Whether this is the connection that asked for the snapshot.
+	pub current: bool,
+	pub tx_bytes: u64,
+	pub rx_bytes: u64,
+	/// Messages waiting to be written to the connection.
+	pub outbound_queue: usize,
+	pub pending_http_requests: usize,
+	pub spawned_processes: usize,
+	pub bridges: Vec<BridgeStateDump>,
+	pub code_servers: Vec<CodeServerStateDump>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BridgeStateDump {
+	pub id: u16,
+	/// Messages waiting to be written to the VS Code server.
+	pub write_queue: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CodeServerStateDump {
+	pub commit_id: String,
+	pub socket: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ConfigStateDump {
+	pub platform: String,
+	pub data_dir: String,
+	/// Arguments the VS Code server is started with.
+	pub server_args: Vec<String>,
+	/// Names of environment variables set on the VS Code server. Values are
+	/// omitted since they may contain secrets.
+	pub server_env: Vec<String>,
+}
+
+/// Sent by clients to say who they are, so they can be told apart in
+/// `listsessions`. Identities are self-reported and not verified.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientIdentity {
+	/// Name of the client application, such as "vscode.dev".
+	pub name: String,
+	#[serde(default)]
+	pub version: Option<String>,
+	/// Account the client is signed in with, if any.
+	#[serde(default)]
+	pub user: Option<String>,
+}
+
+/// Gets a value the client set with `setclientstate`. Values are kept per
+/// `ClientIdentity`, so clients must call `identify` first.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetClientStateParams {
+	pub key: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetClientStateResult {
+	pub value: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SetClientStateParams {
+	pub key: String,
+	/// Value to keep, or None to remove it.
+	#[serde(default)]
+	pub value: Option<serde_json::Value>,
+}
+
+/// Server the CLI installed or started, see `ServerInventory`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InventoriedServer {
+	pub quality: Quality,
+	pub commit: String,
+	/// Data dir of the isolated instance the server was started for, if any.
+	pub instance_dir: Option<String>,
+	/// Process of the server, if it's running.
+	pub pid: Option<u32>,
+	/// Socket the server listens on, if it's running.
+	pub socket: Option<String>,
+	/// When the server was last installed or started, in seconds since the
+	/// Unix epoch.
+	pub last_used: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListServersResult {
+	pub servers: Vec<InventoriedServer>,
+}
+
+/// Kind of build that `listreleases` lists releases of.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReleaseTarget {
+	Cli,
+	Server,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListReleasesParams {
+	pub target: ReleaseTarget,
+	pub quality: Quality,
+	#[serde(default)]
+	pub custom_quality: Option<String>,
+	/// Platform of the builds. Defaults to the host's, or for servers to the
+	/// platform the tunnel runs servers for.
+	#[serde(default)]
+	pub platform: Option<Platform>,
+	/// Most releases to list. Defaults to 10.
+	#[serde(default)]
+	pub limit: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReleaseInfo {
+	/// Version of the release, such as 1.86.2.
+	pub version: String,
+	pub commit: String,
+	/// When the release was published, in milliseconds since the Unix epoch.
+	pub timestamp: Option<u64>,
+}
+
+impl From<ListedRelease> for ReleaseInfo {
+	fn from(r: ListedRelease) -> Self {
+		ReleaseInfo {
+			version: r.release.name,
+			commit: r.release.commit,
+			timestamp: r.timestamp,
+		}
+	}
+}
+
+/// Releases, newest first.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListReleasesResult {
+	pub releases: Vec<ReleaseInfo>,
+}
+
+/// What `gc` freed, or would free for dry runs.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GcResult {
+	/// Number of code server handles dropped from sessions with no attached
+	/// bridges. The servers keep running until they time out.
+	pub code_servers_released: usize,
+	/// Number of http requests removed whose requester stopped waiting.
+	pub http_requests_cleared: usize,
+	/// Download cache folders that were removed. Ones that running processes,
+	/// such as servers, use are kept.
+	pub cache_entries_removed: Vec<String>,
+	pub bytes_freed: u64,
+	/// Data dirs of isolated server instances that went unused for 30 days.
+	#[serde(default)]
+	pub server_instances_removed: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListSessionsResult {
+	pub sessions: Vec<SessionInfo>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SessionInfo {
+	pub id: u32,
+	/// Whether this is the connection that asked for the list.
+	pub current: bool,
+	/// When the client connected, in seconds since the Unix epoch.
+	pub connected_at: u64,
+	/// Identity the client gave with `identify`, if any.
+	pub client: Option<ClientIdentity>,
+	pub tx_bytes: u64,
+	pub rx_bytes: u64,
+	/// Commits of the VS Code servers the client is using.
+	pub code_servers: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DisconnectSessionParams {
+	pub id: u32,
+	/// Message shown to the disconnected client.
+	#[serde(default)]
+	pub reason: Option<String>,
+	/// Admin token the tunnel was started with. Only needed to disconnect
+	/// sessions other than the caller's own.
+	#[serde(default)]
+	pub token: Option<String>,
+}
+
+/// Sent from the server to ask the client to open a file in its editor, when
+/// `code <file>` is run in a process the client spawned.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OpenInEditorParams {
+	/// Absolute path of the file on the host.
+	pub path: String,
+	/// 1-based line to reveal.
+	#[serde(default)]
+	pub line: Option<u32>,
+	/// If true, the client responds only once the file is closed, such as
+	/// when it's used as git's commit message editor.
+	#[serde(default)]
+	pub wait: bool,
+}
+
+/// Sent from the server to ask the client to open a URL in the user's
+/// browser, when it's opened in a process the client spawned.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OpenExternalParams {
+	pub url: String,
+}
+
+/// Sent from the server to ask the client to prompt the user for a
+/// credential, when git or ssh needs one in a process the client spawned.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AskpassParams {
+	pub prompt: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AskpassResult {
+	/// What the user entered, or None if they cancelled the prompt.
+	pub response: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
 pub struct CallServerHttpParams {
 	pub path: String,
 	pub method: String,
 	pub headers: HashMap<String, String>,
 	pub body: Option<Vec<u8>>,
+	/// Commit of the server to call, needed if the client is using servers
+	/// for several commits.
+	#[serde(default)]
+	pub commit_id: Option<String>,
+	/// Instance of the server to call, needed if the client is using several
+	/// instances, see `ServeParams::instance`.
+	#[serde(default)]
+	pub instance: Option<String>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct CallServerHttpResult {
 	pub status: u16,
 	#[serde(with = "serde_bytes")]
@@ -144,30 +564,461 @@ pub struct CallServerHttpResult {
 	pub headers: HashMap<String, String>,
 }
 
-#[derive(Serialize, Debug)]
+/// A chunk of the response from `callserverhttpchunked`. The head is sent
+/// first, followed by the body as it's read.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum CallServerHttpChunk {
+	Head {
+		status: u16,
+		headers: HashMap<String, String>,
+	},
+	Body {
+		#[serde(with = "serde_bytes")]
+		data: Vec<u8>,
+	},
+}
+
+#[derive(Serialize, Deserialize, Debug)]
 pub struct VersionParams {
-	pub version: &'static str,
+	pub version: String,
 	pub protocol_version: u32,
+	/// Features the sender supports. Peers from before capabilities were
+	/// exchanged don't send this, and are assumed to support the legacy set.
+	#[serde(default = "Capabilities::legacy")]
+	pub capabilities: Capabilities,
 }
 
 impl Default for VersionParams {
 	fn default() -> Self {
 		Self {
-			version: VSCODE_CLI_VERSION.unwrap_or("dev"),
+			version: VSCODE_CLI_VERSION.unwrap_or("dev").to_owned(),
 			protocol_version: PROTOCOL_VERSION,
+			capabilities: Capabilities::local(),
 		}
 	}
 }
 
-#[derive(Deserialize)]
+/// Bitset of optional protocol features. Each side sends its set in the
+/// `version` message, and features are only used if both sides support them.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+	/// Deflate compression of server messages, requested with `serve.compress`.
+	pub const COMPRESS_DEFLATE: Capabilities = Capabilities(1 << 0);
+	/// Pseudoterminals for spawned processes.
+	pub const PTY: Capabilities = Capabilities(1 << 1);
+	/// HTTP requests the server streams through the client with `makehttpreq`.
+	pub const HTTP_STREAMING: Capabilities = Capabilities(1 << 2);
+	/// Requests from spawned processes to open files, URLs, and credential
+	/// prompts in the client.
+	pub const EDITOR_HOOK: Capabilities = Capabilities(1 << 3);
+	/// Calls sent together in a single `batch` message.
+	pub const BATCH: Capabilities = Capabilities(1 << 4);
+	/// Responses sent in chunks, such as from `callserverhttpchunked`.
+	pub const CHUNKED_RESPONSES: Capabilities = Capabilities(1 << 5);
+	/// Request bodies in `makehttpreq`, for POSTs and PUTs made through the
+	/// client. Redirects the client returns are followed by the CLI.
+	pub const HTTP_BODIES: Capabilities = Capabilities(1 << 6);
+
+	/// Features supported by this build of the CLI.
+	pub fn local() -> Self {
+		let caps = Self::COMPRESS_DEFLATE
+			| Self::HTTP_STREAMING
+			| Self::EDITOR_HOOK
+			| Self::BATCH
+			| Self::CHUNKED_RESPONSES
+			| Self::HTTP_BODIES;
+		if cfg!(feature = "pty") {
+			caps | Self::PTY
+		} else {
+			caps
+		}
+	}
+
+	/// Features assumed of peers that don't send their capabilities, which
+	/// is everything that existed before they were exchanged. The editor hook
+	/// changes the environment of spawned processes, so it's only used by
+	/// clients that ask for it.
+	pub fn legacy() -> Self {
+		Self::COMPRESS_DEFLATE | Self::PTY | Self::HTTP_STREAMING
+	}
+
+	pub fn from_bits(bits: u32) -> Self {
+		Self(bits)
+	}
+
+	pub fn bits(self) -> u32 {
+		self.0
+	}
+
+	pub fn contains(self, other: Capabilities) -> bool {
+		self.0 & other.0 == other.0
+	}
+
+	/// Gets the features supported by both sets.
+	pub fn negotiate(self, other: Capabilities) -> Self {
+		Self(self.0 & other.0)
+	}
+}
+
+impl std::ops::BitOr for Capabilities {
+	type Output = Self;
+
+	fn bitor(self, rhs: Self) -> Self {
+		Self(self.0 | rhs.0)
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ShutdownReason {
+	/// The CLI is restarting into a newly installed version.
+	UpdateRestart,
+	/// The tunnel is restarting, for example when its name changes.
+	Restart,
+	/// A user or service manager stopped the tunnel.
+	AdminStop,
+	/// The process that launched the tunnel exited.
+	ParentExited,
+	/// Another client disconnected this one using `disconnectsession`.
+	Disconnected,
+}
+
+/// Sent to clients right before the control server closes their connection.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DrainingParams {
+	pub reason: ShutdownReason,
+	pub message: String,
+	/// Estimated time until the tunnel is back, in milliseconds. None if the
+	/// tunnel isn't expected to come back on its own.
+	pub restart_eta_ms: Option<u64>,
+}
+
+/// Sent to all clients when the tunnel decides to restart, such as into an
+/// updated CLI, before it waits for work in progress and sends `draining`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RestartingParams {
+	pub reason: ShutdownReason,
+	pub message: String,
+	/// Estimated time until the tunnel is back once it closes connections, in
+	/// milliseconds.
+	pub restart_eta_ms: Option<u64>,
+}
+
+/// Sent to clients when a newer CLI is published than the one the tunnel
+/// runs, and to clients that connect while one is, if the tunnel checks for
+/// updates with `--notify-updates` or `--auto-update-interval`. It's
+/// installed with `update`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UpdateAvailableParams {
+	pub name: String,
+	pub commit: String,
+}
+
+/// Sent to clients when a server they started exits. Clients call `serve`
+/// again to reattach, which starts a new server if it's not restarting.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CodeServerExitedParams {
+	pub quality: Quality,
+	pub commit: String,
+	pub instance: Option<String>,
+	/// Whether the client was attached to servers when it exited. Servers
+	/// otherwise exit on their own after being idle.
+	pub crashed: bool,
+	pub restarting: bool,
+}
+
+/// Sent to clients about downloads they requested, such as CLI updates, at
+/// most once a second.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DownloadProgressParams {
+	pub name: String,
+	pub bytes_so_far: u64,
+	/// Zero if the size isn't known.
+	pub total_bytes: u64,
+	pub bytes_per_second: u64,
+	/// Estimated time remaining, if the size and speed are known.
+	pub eta_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RenameTunnelParams {
+	pub name: String,
+}
+
+/// Params of methods that only administrators may call.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AdminParams {
+	/// Admin token the tunnel was started with.
+	pub token: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ConnectionTokenResult {
+	/// Token that servers started from now on accept.
+	pub connection_token: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ShutdownParams {
+	/// Admin token the tunnel was started with.
+	pub token: String,
+	/// Message shown to connected clients.
+	#[serde(default)]
+	pub reason: Option<String>,
+	/// Time to give clients before shutting down. Defaults to 30 seconds.
+	#[serde(default)]
+	pub grace_period_ms: Option<u64>,
+}
+
+/// Sent to all clients when a shutdown is requested, ahead of the `draining`
+/// message sent once the grace period ends.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ShuttingDownParams {
+	pub message: String,
+	pub grace_period_ms: u64,
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct SpawnParams {
 	pub command: String,
 	pub args: Vec<String>,
 	#[serde(default)]
 	pub env: HashMap<String, String>,
+	/// If set, the command is run in a pseudoterminal of this size. Its output
+	/// is all written to the stdout stream, and the stderr stream instead
+	/// carries msgpack-encoded `PtySize` messages from the client to resize it.
+	#[serde(default)]
+	pub pty: Option<PtySize>,
+	/// Working directory for the process. Defaults to the CLI's.
+	#[serde(default)]
+	pub cwd: Option<String>,
+	/// If true, the command and args are joined with spaces, without
+	/// escaping, and run through the user's shell, so they can use aliases,
+	/// globs, pipes, and the shell's environment.
+	#[serde(default)]
+	pub shell: bool,
+	/// If true, the process is killed when the connection that spawned it
+	/// closes.
+	#[serde(default)]
+	pub kill_on_disconnect: bool,
+	/// If set, `spawnusage` notifications are sent to the client with the
+	/// process's resource usage at this interval.
+	#[serde(default)]
+	pub usage_interval_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PtySize {
+	pub cols: u16,
+	pub rows: u16,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SpawnedProcessInfo {
+	pub pid: u32,
+	pub command: String,
+	pub args: Vec<String>,
+	pub pty: bool,
+	pub kill_on_disconnect: bool,
+	/// When the process was started, in seconds since the Unix epoch.
+	pub started_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "camelCase")]
+pub enum PortProtocol {
+	Tcp,
+	Udp,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListeningPort {
+	pub protocol: PortProtocol,
+	/// Local address the socket is bound to, such as `0.0.0.0` or `::1`.
+	pub address: String,
+	pub port: u16,
+	pub pid: Option<u32>,
+	/// Name of the owning process, if it's visible to the CLI.
+	pub process: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListeningPortsResult {
+	pub ports: Vec<ListeningPort>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListSpawnedResult {
+	pub processes: Vec<SpawnedProcessInfo>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KillSpawnedParams {
+	pub pid: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum ProcessSignal {
+	Sighup,
+	Sigint,
+	Sigquit,
+	Sigkill,
+	Sigterm,
+	Sigusr1,
+	Sigusr2,
+	Sigstop,
+	Sigcont,
+	Sigwinch,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SignalSpawnedParams {
+	pub pid: u32,
+	pub signal: ProcessSignal,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DownloadChunkParams {
+	pub path: String,
+	pub offset: u64,
+	/// Maximum number of bytes to read. The server may return fewer.
+	pub length: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DownloadChunkResult {
+	#[serde(with = "serde_bytes")]
+	pub data: Vec<u8>,
+	/// Hex-encoded SHA-256 of `data`.
+	pub sha256: String,
+	/// Current size of the file.
+	pub size: u64,
+	/// Whether the chunk reaches the end of the file.
+	pub eof: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UploadChunkParams {
+	pub path: String,
+	pub offset: u64,
+	#[serde(with = "serde_bytes")]
+	pub data: Vec<u8>,
+	/// Hex-encoded SHA-256 of `data`, verified before it's written.
+	#[serde(default)]
+	pub sha256: Option<String>,
+	/// If true, the file is truncated to `offset` before writing.
+	#[serde(default)]
+	pub truncate: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UploadChunkResult {
+	/// Size of the file after the write.
+	pub size: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FileChecksumParams {
+	pub path: String,
+	/// Number of bytes from the start of the file to hash. Defaults to the
+	/// whole file.
+	#[serde(default)]
+	pub length: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FileChecksumResult {
+	pub size: u64,
+	/// Number of bytes that were hashed.
+	pub length: u64,
+	pub sha256: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FsPathParams {
+	pub path: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum FsEntryKind {
+	File,
+	Directory,
+	Symlink,
+	Other,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FsStatResult {
+	/// Kind of the path itself, so symlinks are not followed.
+	pub kind: FsEntryKind,
+	pub size: u64,
+	/// Modification time, in seconds since the Unix epoch.
+	pub mtime: Option<u64>,
+	pub readonly: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FsDirEntry {
+	pub name: String,
+	/// Kind of the entry itself, so symlinks are not followed.
+	pub kind: FsEntryKind,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FsReadDirResult {
+	pub entries: Vec<FsDirEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FsReadResult {
+	#[serde(with = "serde_bytes")]
+	pub data: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FsWriteParams {
+	pub path: String,
+	#[serde(with = "serde_bytes")]
+	pub data: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WatchParams {
+	pub paths: Vec<String>,
+	/// If true, changes in subdirectories of the paths are also reported.
+	#[serde(default)]
+	pub recursive: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum FsChangeKind {
+	Create,
+	Modify,
+	Remove,
+	/// A change the platform couldn't classify. Clients may want to rescan.
+	Other,
+}
+
+/// Sent on the stream of a `watch` call, msgpack-encoded, for each change.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FsChangeEvent {
+	pub kind: FsChangeKind,
+	pub paths: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClipboardParams {
+	pub text: String,
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct AcquireCliParams {
 	pub platform: Platform,
 	pub quality: Quality,
@@ -176,21 +1027,74 @@ pub struct AcquireCliParams {
 	pub spawn: SpawnParams,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct SpawnResult {
 	pub message: String,
 	pub exit_code: i32,
+	/// Resources used by the process, if it could be observed.
+	#[serde(default)]
+	pub usage: Option<ProcessUsage>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct ProcessUsage {
+	/// Largest resident set size that was observed, in bytes.
+	pub peak_rss: u64,
+	/// Approximate CPU time used, in milliseconds.
+	pub cpu_time_ms: u64,
+	pub wall_time_ms: u64,
+}
+
+/// A process that was spawned and has exited, kept in the host's history.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SpawnHistoryEntry {
+	pub pid: u32,
+	pub command: String,
+	pub args: Vec<String>,
+	/// Start time, in seconds since the Unix epoch.
+	pub started_at: u64,
+	pub usage: ProcessUsage,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SpawnHistoryParams {
+	/// Maximum number of entries to return, most recent first.
+	#[serde(default)]
+	pub limit: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SpawnHistoryResult {
+	pub entries: Vec<SpawnHistoryEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SpawnUsageParams {
+	pub pid: u32,
+	#[serde(flatten)]
+	pub usage: ProcessUsage,
 }
 
 pub mod singleton {
-	use crate::log;
+	use crate::{log, options::Quality};
 	use serde::{Deserialize, Serialize};
+	use std::path::PathBuf;
 
 	pub const METHOD_RESTART: &str = "restart";
 	pub const METHOD_SHUTDOWN: &str = "shutdown";
 	pub const METHOD_STATUS: &str = "status";
 	pub const METHOD_LOG: &str = "log";
 	pub const METHOD_LOG_REPLY_DONE: &str = "log_done";
+	/// Notifies clients that the account's quota is nearly used up. Sent when
+	/// the quota is read, and to clients that connect after that.
+	pub const METHOD_QUOTA_WARNING: &str = "quota_warning";
+	pub const METHOD_LATEST_CLI: &str = "latest_cli";
+	pub const METHOD_DOWNLOAD_CLI: &str = "download_cli";
+
+	/// Number of remaining tunnels at or below which users are warned.
+	const QUOTA_WARNING_REMAINING: usize = 2;
+	/// Percentage of the bandwidth quota at or above which users are warned.
+	const QUOTA_WARNING_BANDWIDTH_PERCENT: u64 = 90;
 
 	#[derive(Serialize)]
 	pub struct LogMessage<'a> {
@@ -209,11 +1113,71 @@ pub mod singleton {
 	#[derive(Serialize, Deserialize)]
 	pub struct Status {
 		pub tunnel: TunnelState,
+		/// Missing if the quota couldn't be read from the tunnel service.
+		#[serde(default)]
+		pub quota: Option<TunnelQuota>,
+	}
+
+	/// How much of the account's tunnel quota is used. Tunnels can't be
+	/// created once the limit is reached.
+	#[derive(Deserialize, Serialize, Debug, Clone)]
+	pub struct TunnelQuota {
+		pub tunnels: usize,
+		pub tunnel_limit: usize,
+		/// Bytes transferred by the account's tunnels, if the service reports it.
+		#[serde(default)]
+		pub bandwidth_bytes: Option<u64>,
+		/// Set if the account has a bandwidth quota.
+		#[serde(default)]
+		pub bandwidth_limit: Option<u64>,
+	}
+
+	impl TunnelQuota {
+		/// Gets whether few enough tunnels or little enough bandwidth remain
+		/// that the user should be warned before connections start failing.
+		pub fn is_nearly_exhausted(&self) -> bool {
+			self.tunnels + QUOTA_WARNING_REMAINING >= self.tunnel_limit
+				|| self.is_bandwidth_nearly_exhausted()
+		}
+
+		pub fn is_bandwidth_nearly_exhausted(&self) -> bool {
+			match (self.bandwidth_bytes, self.bandwidth_limit) {
+				(Some(used), Some(limit)) => used >= limit / 100 * QUOTA_WARNING_BANDWIDTH_PERCENT,
+				_ => false,
+			}
+		}
 	}
 
 	#[derive(Deserialize, Serialize, Debug)]
 	pub struct LogReplayFinished {}
 
+	/// Gets the latest CLI for the host through the tunnel, which caches
+	/// the result.
+	#[derive(Deserialize, Serialize, Debug)]
+	pub struct LatestCliParams {
+		pub quality: Quality,
+	}
+
+	#[derive(Deserialize, Serialize, Debug)]
+	pub struct CliRelease {
+		pub name: String,
+		pub commit: String,
+	}
+
+	/// Downloads a CLI for the host into the tunnel's download cache, so
+	/// that it's shared with other CLI invocations.
+	#[derive(Deserialize, Serialize, Debug)]
+	pub struct DownloadCliParams {
+		pub quality: Quality,
+		pub commit: String,
+	}
+
+	#[derive(Deserialize, Serialize, Debug)]
+	pub struct DownloadCliResult {
+		/// The CLI binary in the download cache.
+		pub path: PathBuf,
+	}
+
 	#[derive(Deserialize, Serialize, Debug)]
 	pub enum TunnelState {
 		Disconnected,
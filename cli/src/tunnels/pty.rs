@@ -0,0 +1,219 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	io::{self, Read, Write},
+	path::PathBuf,
+	time::Duration,
+};
+
+use futures::StreamExt;
+use portable_pty::{native_pty_system, CommandBuilder, MasterPty};
+use tokio::{
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+	sync::mpsc,
+};
+use tokio_util::codec::FramedRead;
+
+use crate::{
+	log,
+	msgpack_rpc::MsgPackCodec,
+	util::errors::{wrap, AnyError, CodeError},
+};
+
+use super::{
+	protocol::{PtySize, SpawnParams, SpawnResult},
+	spawned_processes::{command_line, SpawnedProcesses},
+};
+
+/// How long to keep forwarding output after the process exits. Descendants
+/// of the process may hold the terminal open indefinitely, so we can't wait
+/// for EOF.
+const OUTPUT_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+impl From<PtySize> for portable_pty::PtySize {
+	fn from(s: PtySize) -> Self {
+		portable_pty::PtySize {
+			rows: s.rows,
+			cols: s.cols,
+			pixel_width: 0,
+			pixel_height: 0,
+		}
+	}
+}
+
+/// Runs the command in a pseudoterminal (a ConPTY on Windows), bridging it
+/// to the given streams. `control` is read for resize messages.
+pub async fn spawn_pty<I, O, C>(
+	log: &log::Logger,
+	spawned: &SpawnedProcesses,
+	params: SpawnParams,
+	size: PtySize,
+	mut input: I,
+	mut output: O,
+	control: C,
+) -> Result<SpawnResult, AnyError>
+where
+	I: AsyncRead + Unpin + Send + 'static,
+	O: AsyncWrite + Unpin + Send + 'static,
+	C: AsyncRead + Unpin + Send + 'static,
+{
+	debug!(
+		log,
+		"requested to spawn {} with args {:?} in a {}x{} pty",
+		params.command,
+		params.args,
+		size.cols,
+		size.rows
+	);
+
+	let pair = native_pty_system()
+		.openpty(size.into())
+		.map_err(|e| wrap(e, "error opening pty"))?;
+
+	let (command, args) = command_line(&params);
+	let mut cmd = CommandBuilder::new(command);
+	cmd.args(args);
+	// portable-pty would otherwise start in the home directory, including
+	// when the given directory doesn't exist.
+	let cwd = match &params.cwd {
+		Some(cwd) => PathBuf::from(cwd),
+		None => std::env::current_dir().map_err(CodeError::ProcessSpawnFailed)?,
+	};
+	if !cwd.is_dir() {
+		return Err(CodeError::ProcessSpawnFailed(io::Error::new(
+			io::ErrorKind::NotFound,
+			format!("{} is not a directory", cwd.display()),
+		))
+		.into());
+	}
+	cmd.cwd(cwd);
+	for (k, v) in &params.env {
+		cmd.env(k, v);
+	}
+
+	let mut child = pair
+		.slave
+		.spawn_command(cmd)
+		.map_err(|e| wrap(e, format!("error spawning {}", params.command)))?;
+	// the child has its own handle now; ours must be closed for the reader to
+	// see EOF once it exits.
+	drop(pair.slave);
+	let tracked = child.process_id().map(|pid| spawned.track(pid, &params));
+
+	let mut reader = pair
+		.master
+		.try_clone_reader()
+		.map_err(|e| wrap(e, "error reading from pty"))?;
+	let mut writer = pair
+		.master
+		.take_writer()
+		.map_err(|e| wrap(e, "error writing to pty"))?;
+
+	// pty handles are blocking, so each direction gets its own thread.
+	let (out_tx, mut out_rx) = mpsc::channel::<Vec<u8>>(8);
+	std::thread::spawn(move || {
+		let mut buf = [0u8; 4096];
+		loop {
+			match reader.read(&mut buf) {
+				Ok(0) | Err(_) => return,
+				Ok(n) => {
+					if out_tx.blocking_send(buf[..n].to_vec()).is_err() {
+						return;
+					}
+				}
+			}
+		}
+	});
+
+	let (in_tx, in_rx) = std::sync::mpsc::channel::<Vec<u8>>();
+	std::thread::spawn(move || {
+		for chunk in in_rx {
+			if writer
+				.write_all(&chunk)
+				.and_then(|_| writer.flush())
+				.is_err()
+			{
+				return;
+			}
+		}
+	});
+
+	let output_task = tokio::spawn(async move {
+		while let Some(chunk) = out_rx.recv().await {
+			if output.write_all(&chunk).await.is_err() {
+				return;
+			}
+		}
+		output.shutdown().await.ok();
+	});
+
+	let input_task = tokio::spawn(async move {
+		let mut buf = vec![0u8; 4096];
+		loop {
+			match input.read(&mut buf).await {
+				Ok(0) | Err(_) => return,
+				Ok(n) => {
+					if in_tx.send(buf[..n].to_vec()).is_err() {
+						return;
+					}
+				}
+			}
+		}
+	});
+
+	let control_task = tokio::spawn(handle_resizes(log.clone(), pair.master, control));
+
+	let exit = tokio::task::spawn_blocking(move || child.wait()).await;
+	let usage = tracked.map(|t| t.finish());
+	let r = match exit {
+		Ok(Ok(e)) => SpawnResult {
+			message: e.to_string(),
+			exit_code: e.exit_code() as i32,
+			usage,
+		},
+		Ok(Err(e)) => SpawnResult {
+			message: e.to_string(),
+			exit_code: -1,
+			usage,
+		},
+		Err(e) => SpawnResult {
+			message: e.to_string(),
+			exit_code: -1,
+			usage,
+		},
+	};
+
+	input_task.abort();
+	control_task.abort();
+	tokio::time::timeout(OUTPUT_DRAIN_TIMEOUT, output_task)
+		.await
+		.ok();
+
+	debug!(
+		log,
+		"spawned command {} exited with code {}", params.command, r.exit_code
+	);
+
+	Ok(r)
+}
+
+async fn handle_resizes(
+	log: log::Logger,
+	master: Box<dyn MasterPty + Send>,
+	control: impl AsyncRead + Unpin,
+) {
+	let mut frames = FramedRead::new(control, MsgPackCodec {});
+	while let Some(Ok(frame)) = frames.next().await {
+		match rmp_serde::from_slice::<PtySize>(&frame) {
+			Ok(size) => {
+				if let Err(e) = master.resize(size.into()) {
+					warning!(log, "error resizing pty: {}", e);
+				}
+			}
+			Err(e) => warning!(log, "invalid pty resize message: {}", e),
+		}
+	}
+}
@@ -0,0 +1,338 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Caps the memory and CPU used by code servers, so a runaway extension host
+//! can't take down a shared machine. The limits are in place before servers
+//! run any code. On Linux, servers are put in cgroups next to the CLI's, so
+//! it needs cgroups v2 and to run in a leaf of a delegated cgroup, such as
+//! with systemd's `Delegate=yes` and `DelegateSubgroup=`. On Windows, they're
+//! put in a job object.
+
+use std::io;
+
+use tokio::process::{Child, Command};
+
+/// Limits given with `--server-memory-limit` and `--server-cpu-limit`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ResourceLimits {
+	pub memory_bytes: Option<u64>,
+	/// Percent of one CPU core, so 200 allows two cores.
+	pub cpu_percent: Option<u32>,
+}
+
+impl ResourceLimits {
+	pub fn is_empty(&self) -> bool {
+		self.memory_bytes.is_none() && self.cpu_percent.is_none()
+	}
+
+	/// Sets up the limits for the process the command spawns, and the
+	/// processes it starts. `PreparedLimits::started` must be called once
+	/// it's spawned.
+	pub fn prepare(&self, cmd: &mut Command) -> io::Result<PreparedLimits> {
+		if self.is_empty() {
+			return Ok(PreparedLimits(None));
+		}
+
+		prepare_limits(self, cmd).map(|p| PreparedLimits(Some(p)))
+	}
+}
+
+/// Limits set up for a process that's about to be spawned.
+pub struct PreparedLimits(Option<Prepared>);
+
+impl PreparedLimits {
+	/// Finishes applying the limits to the spawned process. If this fails,
+	/// the process must be killed.
+	pub fn started(self, child: &Child) -> io::Result<()> {
+		match self.0 {
+			Some(p) => p.started(child),
+			None => Ok(()),
+		}
+	}
+}
+
+#[cfg(target_os = "linux")]
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+/// Period that `cpu.max` quotas are given over, in microseconds.
+#[cfg(target_os = "linux")]
+const CPU_PERIOD_US: u64 = 100_000;
+#[cfg(target_os = "linux")]
+const GROUP_PREFIX: &str = "code-server-";
+/// How old empty groups must be to be removed, so ones set up for servers
+/// that are starting concurrently are kept.
+#[cfg(target_os = "linux")]
+const STALE_GROUP_AGE: std::time::Duration = std::time::Duration::from_secs(60);
+
+#[cfg(target_os = "linux")]
+struct Prepared {
+	/// `cgroup.procs` of the server's group, which the server writes itself
+	/// into before it runs.
+	_procs: std::fs::File,
+}
+
+#[cfg(target_os = "linux")]
+impl Prepared {
+	fn started(self, _child: &Child) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+#[cfg(target_os = "linux")]
+fn prepare_limits(limits: &ResourceLimits, cmd: &mut Command) -> io::Result<Prepared> {
+	use std::os::unix::io::AsRawFd;
+
+	let parent = servers_parent()?;
+	remove_stale_groups(&parent);
+
+	let group = parent.join(format!("{}{}", GROUP_PREFIX, uuid::Uuid::new_v4()));
+	std::fs::create_dir(&group)?;
+
+	if let Some(bytes) = limits.memory_bytes {
+		std::fs::write(group.join("memory.max"), bytes.to_string())?;
+	}
+	if let Some(percent) = limits.cpu_percent {
+		let quota = CPU_PERIOD_US * percent as u64 / 100;
+		std::fs::write(
+			group.join("cpu.max"),
+			format!("{} {}", quota.max(1000), CPU_PERIOD_US),
+		)?;
+	}
+
+	let procs = std::fs::OpenOptions::new()
+		.write(true)
+		.open(group.join("cgroup.procs"))?;
+	let fd = procs.as_raw_fd();
+	// runs in the forked process, where only async-signal-safe calls can be
+	// made. Writing 0 moves the writing process into the group.
+	unsafe {
+		cmd.pre_exec(move || {
+			if libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1) < 0 {
+				return Err(io::Error::last_os_error());
+			}
+			Ok(())
+		});
+	}
+
+	Ok(Prepared { _procs: procs })
+}
+
+/// Gets the group that servers' groups are made in, with the memory and cpu
+/// controllers enabled for them. Groups with processes can't enable
+/// controllers for their children, so this is the parent of the CLI's group,
+/// unless the CLI is in the root group.
+#[cfg(target_os = "linux")]
+fn servers_parent() -> io::Result<std::path::PathBuf> {
+	use std::path::Path;
+
+	let own = std::fs::read_to_string("/proc/self/cgroup")?;
+	let own = own
+		.lines()
+		.find_map(|l| l.strip_prefix("0::"))
+		.ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "cgroups v2 isn't in use"))?;
+	let own = Path::new(CGROUP_ROOT).join(own.trim_start_matches('/'));
+	let parent = match own.parent() {
+		Some(p) if own != Path::new(CGROUP_ROOT) => p.to_owned(),
+		_ => own,
+	};
+
+	match std::fs::write(parent.join("cgroup.subtree_control"), "+memory +cpu") {
+		Err(e) if e.raw_os_error() == Some(libc::EBUSY) => Err(io::Error::new(
+			io::ErrorKind::Other,
+			format!(
+				"{} has processes, so servers can't be put in groups in it. Run the CLI in a leaf of a delegated cgroup, such as with systemd's DelegateSubgroup=",
+				parent.display()
+			),
+		)),
+		r => r.map(|_| parent),
+	}
+}
+
+/// Removes groups of servers that exited, which the kernel keeps until
+/// they're removed. Groups with processes can't be removed, so removing
+/// them all only removes the empty ones.
+#[cfg(target_os = "linux")]
+fn remove_stale_groups(parent: &std::path::Path) {
+	if let Ok(entries) = std::fs::read_dir(parent) {
+		for entry in entries.flatten() {
+			let is_stale = entry
+				.metadata()
+				.and_then(|m| m.modified())
+				.map_or(false, |t| {
+					t.elapsed().map_or(false, |e| e > STALE_GROUP_AGE)
+				});
+			if is_stale
+				&& entry
+					.file_name()
+					.to_string_lossy()
+					.starts_with(GROUP_PREFIX)
+			{
+				std::fs::remove_dir(entry.path()).ok();
+			}
+		}
+	}
+}
+
+#[cfg(windows)]
+use winapi::um::winnt::HANDLE;
+
+#[cfg(windows)]
+struct Handle(HANDLE);
+
+#[cfg(windows)]
+impl Drop for Handle {
+	fn drop(&mut self) {
+		unsafe { winapi::um::handleapi::CloseHandle(self.0) };
+	}
+}
+
+// the job is only used by the task that starts the server
+#[cfg(windows)]
+unsafe impl Send for Handle {}
+
+#[cfg(windows)]
+fn check(ok: i32) -> io::Result<()> {
+	if ok == 0 {
+		Err(io::Error::last_os_error())
+	} else {
+		Ok(())
+	}
+}
+
+#[cfg(windows)]
+struct Prepared {
+	job: Handle,
+}
+
+#[cfg(windows)]
+impl Prepared {
+	fn started(self, child: &Child) -> io::Result<()> {
+		use winapi::um::jobapi2::AssignProcessToJobObject;
+
+		let (process, pid) = match (child.raw_handle(), child.id()) {
+			(Some(h), Some(pid)) => (h as HANDLE, pid),
+			_ => return Err(io::Error::new(io::ErrorKind::NotFound, "the server exited")),
+		};
+		// the job lives until its processes exit, so its handle can be closed
+		check(unsafe { AssignProcessToJobObject(self.job.0, process) })?;
+		resume_threads(pid)
+	}
+}
+
+/// Resumes the threads of a process started suspended.
+#[cfg(windows)]
+fn resume_threads(pid: u32) -> io::Result<()> {
+	use std::mem;
+	use winapi::{
+		shared::minwindef::FALSE,
+		um::{
+			handleapi::INVALID_HANDLE_VALUE,
+			processthreadsapi::{OpenThread, ResumeThread},
+			tlhelp32::{
+				CreateToolhelp32Snapshot, Thread32First, Thread32Next, TH32CS_SNAPTHREAD,
+				THREADENTRY32,
+			},
+			winnt::THREAD_SUSPEND_RESUME,
+		},
+	};
+
+	let snapshot = unsafe { CreateToolhelp32Snapshot(TH32CS_SNAPTHREAD, 0) };
+	if snapshot == INVALID_HANDLE_VALUE {
+		return Err(io::Error::last_os_error());
+	}
+	let snapshot = Handle(snapshot);
+
+	let mut entry: THREADENTRY32 = unsafe { mem::zeroed() };
+	entry.dwSize = mem::size_of::<THREADENTRY32>() as u32;
+	let mut ok = unsafe { Thread32First(snapshot.0, &mut entry) };
+	while ok != 0 {
+		if entry.th32OwnerProcessID == pid {
+			let thread = unsafe { OpenThread(THREAD_SUSPEND_RESUME, FALSE, entry.th32ThreadID) };
+			if thread.is_null() {
+				return Err(io::Error::last_os_error());
+			}
+			let thread = Handle(thread);
+			if unsafe { ResumeThread(thread.0) } == u32::MAX {
+				return Err(io::Error::last_os_error());
+			}
+		}
+		ok = unsafe { Thread32Next(snapshot.0, &mut entry) };
+	}
+
+	Ok(())
+}
+
+#[cfg(windows)]
+fn prepare_limits(limits: &ResourceLimits, cmd: &mut Command) -> io::Result<Prepared> {
+	use std::{mem, ptr};
+	use winapi::um::{
+		jobapi2::{CreateJobObjectW, SetInformationJobObject},
+		winbase::CREATE_SUSPENDED,
+		winnt::{
+			JobObjectCpuRateControlInformation, JobObjectExtendedLimitInformation,
+			JOBOBJECT_CPU_RATE_CONTROL_INFORMATION, JOBOBJECT_EXTENDED_LIMIT_INFORMATION,
+			JOB_OBJECT_CPU_RATE_CONTROL_ENABLE, JOB_OBJECT_CPU_RATE_CONTROL_HARD_CAP,
+			JOB_OBJECT_LIMIT_JOB_MEMORY,
+		},
+	};
+
+	let job = unsafe { CreateJobObjectW(ptr::null_mut(), ptr::null()) };
+	if job.is_null() {
+		return Err(io::Error::last_os_error());
+	}
+	let job = Handle(job);
+
+	if let Some(bytes) = limits.memory_bytes {
+		let mut info: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = unsafe { mem::zeroed() };
+		info.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_JOB_MEMORY;
+		info.JobMemoryLimit = usize::try_from(bytes).unwrap_or(usize::MAX);
+		check(unsafe {
+			SetInformationJobObject(
+				job.0,
+				JobObjectExtendedLimitInformation,
+				&mut info as *mut _ as *mut _,
+				mem::size_of_val(&info) as u32,
+			)
+		})?;
+	}
+
+	if let Some(percent) = limits.cpu_percent {
+		// rates are in hundredths of a percent of all cores
+		let cores = std::thread::available_parallelism().map_or(1, |n| n.get()) as u32;
+		let mut info: JOBOBJECT_CPU_RATE_CONTROL_INFORMATION = unsafe { mem::zeroed() };
+		info.ControlFlags =
+			JOB_OBJECT_CPU_RATE_CONTROL_ENABLE | JOB_OBJECT_CPU_RATE_CONTROL_HARD_CAP;
+		unsafe { *info.u.CpuRate_mut() = (percent.saturating_mul(100) / cores).clamp(1, 10_000) };
+		check(unsafe {
+			SetInformationJobObject(
+				job.0,
+				JobObjectCpuRateControlInformation,
+				&mut info as *mut _ as *mut _,
+				mem::size_of_val(&info) as u32,
+			)
+		})?;
+	}
+
+	// the server doesn't run until it's been put in the job
+	cmd.creation_flags(CREATE_SUSPENDED);
+	Ok(Prepared { job })
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+struct Prepared;
+
+#[cfg(not(any(target_os = "linux", windows)))]
+impl Prepared {
+	fn started(self, _child: &Child) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn prepare_limits(_limits: &ResourceLimits, _cmd: &mut Command) -> io::Result<Prepared> {
+	Err(io::Error::new(
+		io::ErrorKind::Unsupported,
+		"resource limits are only supported on Linux and Windows",
+	))
+}
@@ -4,7 +4,9 @@
  *--------------------------------------------------------------------------------------------*/
 use super::socket_signal::{ClientMessageDecoder, ServerMessageSink};
 use crate::{
-	async_pipe::{get_socket_rw_stream, socket_stream_split, AsyncPipeWriteHalf},
+	async_pipe::{
+		check_peer_is_current_user, get_socket_rw_stream, socket_stream_split, AsyncPipeWriteHalf,
+	},
 	util::errors::AnyError,
 };
 use std::path::Path;
@@ -18,12 +20,18 @@ pub struct ServerBridge {
 const BUFFER_SIZE: usize = 65536;
 
 impl ServerBridge {
+	/// Connects to the server on the socket. Servers the CLI started should
+	/// be checked to be run by the current user, with `check_peer`.
 	pub async fn new(
 		path: &Path,
 		mut target: ServerMessageSink,
 		decoder: ClientMessageDecoder,
+		check_peer: bool,
 	) -> Result<Self, AnyError> {
 		let stream = get_socket_rw_stream(path).await?;
+		if check_peer {
+			check_peer_is_current_user(&stream, path)?;
+		}
 		let (mut read, write) = socket_stream_split(stream);
 
 		tokio::spawn(async move {
@@ -0,0 +1,198 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Inventory of the servers the CLI installed and started, kept in the data
+//! dir so tools can see what's provisioned on the host without a tunnel
+//! running. See `listservers` and `code tunnel servers`.
+
+use std::{
+	path::{Path, PathBuf},
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::future::join_all;
+use tokio::time::timeout;
+
+use crate::{
+	async_pipe::get_socket_rw_stream, options::Quality, state::PersistedState,
+	util::errors::WrappedError,
+};
+
+use super::{paths::get_server_folder_name, protocol::InventoriedServer};
+
+/// How long a server's socket has to accept a connection to be listed as
+/// running.
+const SOCKET_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Clone)]
+pub struct ServerInventory {
+	state: PersistedState<Vec<InventoriedServer>>,
+	server_cache: PathBuf,
+}
+
+impl ServerInventory {
+	/// Creates an inventory kept in the file, of servers installed in the
+	/// cache dir. Use the one in `LauncherPaths`, which is shared.
+	pub fn new(file: PathBuf, server_cache: PathBuf) -> Self {
+		Self {
+			state: PersistedState::new(file),
+			server_cache,
+		}
+	}
+
+	/// Records that the server is installed, or was used again.
+	pub fn record_installed(&self, quality: Quality, commit: &str) -> Result<(), WrappedError> {
+		self.update(quality, commit, None, |_| {})
+	}
+
+	/// Records that the server is running with the process and socket.
+	pub fn record_started(
+		&self,
+		quality: Quality,
+		commit: &str,
+		instance_dir: Option<&Path>,
+		pid: Option<u32>,
+		socket: &Path,
+	) -> Result<(), WrappedError> {
+		let instance_dir = instance_dir.map(|d| d.to_string_lossy().to_string());
+		self.update(quality, commit, instance_dir, |s| {
+			s.pid = pid;
+			s.socket = Some(socket.to_string_lossy().to_string());
+		})
+	}
+
+	/// Gets the servers that are still installed. Processes and sockets are
+	/// left out for servers whose socket no longer accepts connections, since
+	/// their process IDs may have been reused.
+	pub async fn list(&self) -> Vec<InventoriedServer> {
+		let servers = self
+			.state
+			.load()
+			.into_iter()
+			.filter(|s| self.is_installed(s))
+			.map(|mut s| async move {
+				if !is_listening(s.socket.as_deref()).await {
+					s.pid = None;
+					s.socket = None;
+				}
+				s
+			});
+
+		join_all(servers).await
+	}
+
+	fn is_installed(&self, s: &InventoriedServer) -> bool {
+		self.server_cache
+			.join(get_server_folder_name(s.quality, &s.commit))
+			.exists()
+	}
+
+	fn update(
+		&self,
+		quality: Quality,
+		commit: &str,
+		instance_dir: Option<String>,
+		mutate: impl FnOnce(&mut InventoriedServer),
+	) -> Result<(), WrappedError> {
+		let now = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map_or(0, |d| d.as_secs());
+
+		self.state.update(|servers| {
+			servers.retain(|s| self.is_installed(s));
+			let index = servers.iter().position(|s| {
+				s.quality == quality && s.commit == commit && s.instance_dir == instance_dir
+			});
+			let server = match index {
+				Some(i) => &mut servers[i],
+				None => {
+					servers.push(InventoriedServer {
+						quality,
+						commit: commit.to_string(),
+						instance_dir,
+						pid: None,
+						socket: None,
+						last_used: now,
+					});
+					servers.last_mut().unwrap()
+				}
+			};
+
+			server.last_used = now;
+			mutate(server);
+		})
+	}
+}
+
+async fn is_listening(socket: Option<&str>) -> bool {
+	match socket {
+		Some(s) => matches!(
+			timeout(SOCKET_CHECK_TIMEOUT, get_socket_rw_stream(Path::new(s))).await,
+			Ok(Ok(_))
+		),
+		None => false,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		async_pipe::{get_socket_name, listen_socket_rw_stream},
+		state::LauncherPaths,
+	};
+
+	#[tokio::test]
+	async fn test_inventory() {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let inventory = paths.server_inventory.clone();
+
+		std::fs::create_dir_all(
+			paths
+				.server_cache
+				.path()
+				.join(get_server_folder_name(Quality::Stable, "abc")),
+		)
+		.unwrap();
+		inventory.record_installed(Quality::Stable, "abc").unwrap();
+		inventory.record_installed(Quality::Stable, "def").unwrap();
+
+		let socket = get_socket_name();
+		let _listener = listen_socket_rw_stream(&socket).await.unwrap();
+		inventory
+			.record_started(
+				Quality::Stable,
+				"abc",
+				None,
+				Some(std::process::id()),
+				&socket,
+			)
+			.unwrap();
+
+		let servers = inventory.list().await;
+		assert_eq!(servers.len(), 1);
+		assert_eq!(servers[0].commit, "abc");
+		assert_eq!(servers[0].pid, Some(std::process::id()));
+		assert_eq!(
+			servers[0].socket.as_deref(),
+			Some(socket.to_string_lossy().as_ref())
+		);
+
+		// a live process without a listening socket isn't a running server
+		inventory
+			.record_started(
+				Quality::Stable,
+				"abc",
+				None,
+				Some(std::process::id()),
+				&get_socket_name(),
+			)
+			.unwrap();
+		let servers = inventory.list().await;
+		assert_eq!(servers[0].pid, None);
+		assert_eq!(servers[0].socket, None);
+	}
+}
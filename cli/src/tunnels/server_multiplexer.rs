@@ -57,6 +57,18 @@ impl ServerMultiplexer {
 		}
 	}
 
+	/// Gets the number of bridges currently registered.
+	pub fn len(&self) -> usize {
+		self.inner.lock().unwrap().as_ref().map_or(0, |b| b.len())
+	}
+
+	/// Gets the ID of each bridge and the number of messages queued for it.
+	pub fn queue_depths(&self) -> Vec<(u16, usize)> {
+		self.inner.lock().unwrap().as_ref().map_or(vec![], |b| {
+			b.iter().map(|r| (r.id, r.write_queue.len())).collect()
+		})
+	}
+
 	/// Handle an incoming server message. This is synchronous and uses a 'write loop'
 	/// to ensure message order is preserved exactly, which is necessary for compression.
 	/// Returns false if there was no server with the given bridge_id.
@@ -0,0 +1,122 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Restricts which server versions clients may install, such as to builds
+//! approved by IT. The policy is read from the `server_versions` object in
+//! the data dir's `config.json`:
+//!
+//! ```json
+//! {
+//!   "server_versions": {
+//!     "allowed_qualities": ["stable"],
+//!     "allowed_commits": ["<commit>"],
+//!     "blocked_commits": ["<commit>"],
+//!     "pinned_version": "1.86.2"
+//!   }
+//! }
+//! ```
+//!
+//! Empty or missing lists don't restrict anything. Custom qualities are
+//! allowed by their name, or by the quality they're based on. If a version is
+//! pinned, it's the only one clients may install, and it's installed for
+//! clients that don't request a commit or version.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{options::Quality, update_service::Release, util::errors::CodeError};
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ServerVersionPolicy {
+	#[serde(default)]
+	allowed_qualities: Vec<String>,
+	#[serde(default)]
+	allowed_commits: Vec<String>,
+	#[serde(default)]
+	blocked_commits: Vec<String>,
+	#[serde(default)]
+	pinned_version: Option<String>,
+}
+
+impl ServerVersionPolicy {
+	/// Gets whether the policy restricts any versions.
+	pub fn is_restricted(&self) -> bool {
+		!self.allowed_qualities.is_empty()
+			|| !self.allowed_commits.is_empty()
+			|| !self.blocked_commits.is_empty()
+			|| self.pinned_version.is_some()
+	}
+
+	/// Gets the release version, like 1.86.2, that clients must install.
+	pub fn pinned_version(&self) -> Option<&str> {
+		self.pinned_version.as_deref()
+	}
+
+	/// Returns an error if the release may not be installed.
+	pub fn check(&self, release: &Release) -> Result<(), CodeError> {
+		let quality_allowed = self.allowed_qualities.is_empty()
+			|| self.allowed_qualities.iter().any(|q| {
+				Quality::try_from(q.to_lowercase().as_str()) == Ok(release.quality)
+					|| release.custom_quality.as_deref() == Some(q.as_str())
+			});
+		let commit_allowed = (self.allowed_commits.is_empty()
+			|| contains_commit(&self.allowed_commits, &release.commit))
+			&& !contains_commit(&self.blocked_commits, &release.commit);
+
+		if quality_allowed && commit_allowed {
+			Ok(())
+		} else {
+			Err(CodeError::ServerVersionNotAllowed(
+				release.quality,
+				release.commit.clone(),
+			))
+		}
+	}
+}
+
+/// Commits are hex, so they're compared case-insensitively.
+fn contains_commit(list: &[String], commit: &str) -> bool {
+	list.iter().any(|c| c.eq_ignore_ascii_case(commit))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::update_service::{Platform, TargetKind};
+
+	fn release(quality: Quality, commit: &str) -> Release {
+		Release {
+			name: String::new(),
+			platform: Platform::LinuxX64,
+			target: TargetKind::Server,
+			quality,
+			commit: commit.to_string(),
+			custom_quality: None,
+		}
+	}
+
+	#[test]
+	fn test_check() {
+		let policy = ServerVersionPolicy::default();
+		assert!(!policy.is_restricted());
+		assert!(policy.check(&release(Quality::Insiders, "a")).is_ok());
+
+		let policy: ServerVersionPolicy =
+			serde_json::from_str(r#"{"allowed_qualities":["Stable"],"blocked_commits":["ABC"]}"#)
+				.unwrap();
+		assert!(policy.check(&release(Quality::Stable, "def")).is_ok());
+		assert!(policy.check(&release(Quality::Stable, "abc")).is_err());
+		assert!(policy.check(&release(Quality::Insiders, "def")).is_err());
+
+		let policy: ServerVersionPolicy =
+			serde_json::from_str(r#"{"allowed_commits":["abc"]}"#).unwrap();
+		assert!(policy.check(&release(Quality::Insiders, "abc")).is_ok());
+		assert!(policy.check(&release(Quality::Stable, "def")).is_err());
+
+		let policy: ServerVersionPolicy =
+			serde_json::from_str(r#"{"pinned_version":"1.86.2"}"#).unwrap();
+		assert!(policy.is_restricted());
+		assert_eq!(policy.pinned_version(), Some("1.86.2"));
+	}
+}
@@ -20,7 +20,12 @@ use crate::{
 	util::errors::{wrap, wrapdbg, AnyError},
 };
 
-use super::service::{tail_log_file, ServiceContainer, ServiceManager as CliServiceManager};
+use super::{
+	event_log_windows::{
+		register_event_source, unregister_event_source, EventLogSink, ServiceEvent,
+	},
+	service::{tail_log_file, ServiceContainer, ServiceManager as CliServiceManager},
+};
 
 pub struct WindowsService {
 	log: log::Logger,
@@ -70,6 +75,14 @@ impl CliServiceManager for WindowsService {
 
 		info!(self.log, "Successfully registered service...");
 
+		if let Err(e) = register_event_source() {
+			warning!(
+				self.log,
+				"Could not register the event log source, run the install from an elevated prompt for events to be shown fully in the Event Viewer: {}",
+				e
+			);
+		}
+
 		cmd.stderr(Stdio::null());
 		cmd.stdout(Stdio::null());
 		cmd.stdin(Stdio::null());
@@ -89,7 +102,24 @@ impl CliServiceManager for WindowsService {
 		launcher_paths: LauncherPaths,
 		mut handle: impl 'static + ServiceContainer,
 	) -> Result<(), AnyError> {
-		handle.run_service(self.log, launcher_paths).await
+		let event_log = match EventLogSink::new() {
+			Ok(sink) => sink,
+			Err(e) => {
+				warning!(self.log, "Could not open the event log: {}", e);
+				return handle.run_service(self.log, launcher_paths).await;
+			}
+		};
+
+		event_log.report(ServiceEvent::Started);
+		let result = handle
+			.run_service(self.log.tee(event_log.clone()), launcher_paths)
+			.await;
+		match &result {
+			Ok(()) => event_log.report(ServiceEvent::Stopped),
+			Err(e) => event_log.report(ServiceEvent::Failed(&e.to_string())),
+		}
+
+		result
 	}
 
 	async fn unregister(&self) -> Result<(), AnyError> {
@@ -104,6 +134,10 @@ impl CliServiceManager for WindowsService {
 			.map_err(|e| AnyError::from(wrap(e, "error deleting registry key")))?;
 		info!(self.log, "Tunnel service uninstalled");
 
+		if let Err(e) = unregister_event_source() {
+			debug!(self.log, "Could not unregister the event log source: {}", e);
+		}
+
 		let mut sys = System::new();
 		sys.refresh_processes();
 
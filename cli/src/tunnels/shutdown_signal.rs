@@ -4,7 +4,7 @@
  *--------------------------------------------------------------------------------------------*/
 
 use futures::{stream::FuturesUnordered, StreamExt};
-use std::fmt;
+use std::{fmt, time::Duration};
 use sysinfo::Pid;
 
 use crate::util::{
@@ -12,6 +12,8 @@ use crate::util::{
 	sync::{new_barrier, Barrier, Receivable},
 };
 
+use super::protocol::{DrainingParams, RestartingParams, ShutdownReason};
+
 /// Describes the signal to manully stop the server
 #[derive(Copy, Clone)]
 pub enum ShutdownSignal {
@@ -20,6 +22,8 @@ pub enum ShutdownSignal {
 	ServiceStopped,
 	RpcShutdownRequested,
 	RpcRestartRequested,
+	/// The CLI updated itself and is respawning into the new version.
+	UpdateRestart,
 }
 
 impl fmt::Display for ShutdownSignal {
@@ -34,6 +38,51 @@ impl fmt::Display for ShutdownSignal {
 			ShutdownSignal::RpcRestartRequested => {
 				write!(f, "RPC client requested a tunnel restart")
 			}
+			ShutdownSignal::UpdateRestart => write!(f, "Restarting to apply an update"),
+		}
+	}
+}
+
+impl ShutdownSignal {
+	/// Gets the reason reported to clients for the shutdown.
+	pub fn reason(&self) -> ShutdownReason {
+		match self {
+			ShutdownSignal::CtrlC
+			| ShutdownSignal::ServiceStopped
+			| ShutdownSignal::RpcShutdownRequested => ShutdownReason::AdminStop,
+			ShutdownSignal::ParentProcessKilled(_) => ShutdownReason::ParentExited,
+			ShutdownSignal::RpcRestartRequested => ShutdownReason::Restart,
+			ShutdownSignal::UpdateRestart => ShutdownReason::UpdateRestart,
+		}
+	}
+
+	/// Gets roughly how long it'll be until the tunnel is available again,
+	/// or None if it won't come back without someone restarting it.
+	pub fn restart_eta(&self) -> Option<Duration> {
+		match self {
+			ShutdownSignal::RpcRestartRequested => Some(Duration::from_secs(5)),
+			ShutdownSignal::UpdateRestart => Some(Duration::from_secs(10)),
+			_ => None,
+		}
+	}
+}
+
+impl From<ShutdownSignal> for DrainingParams {
+	fn from(s: ShutdownSignal) -> Self {
+		DrainingParams {
+			reason: s.reason(),
+			message: s.to_string(),
+			restart_eta_ms: s.restart_eta().map(|d| d.as_millis() as u64),
+		}
+	}
+}
+
+impl From<ShutdownSignal> for RestartingParams {
+	fn from(s: ShutdownSignal) -> Self {
+		RestartingParams {
+			reason: s.reason(),
+			message: s.to_string(),
+			restart_eta_ms: s.restart_eta().map(|d| d.as_millis() as u64),
 		}
 	}
 }
@@ -4,28 +4,38 @@
  *--------------------------------------------------------------------------------------------*/
 
 use std::{
+	collections::HashMap,
+	path::{Path, PathBuf},
 	pin::Pin,
 	sync::{Arc, Mutex},
+	time::{Duration, Instant},
 };
 
 use super::{
-	code_server::CodeServerArgs,
+	auto_update::AutoUpdateSchedule,
+	code_server::{download_cli_into_cache, CodeServerArgs},
 	control_server::ServerTermination,
 	dev_tunnels::ActiveTunnel,
+	prebuilt_server::ServerPathPolicy,
 	protocol,
 	shutdown_signal::{ShutdownRequest, ShutdownSignal},
 };
 use crate::{
 	async_pipe::socket_stream_split,
+	constants::{APPLICATION_NAME, PRODUCT_NAME_LONG},
 	json_rpc::{new_json_rpc, start_json_rpc, JsonRpcSerializer},
 	log,
+	options::Quality,
 	rpc::{RpcCaller, RpcDispatcher},
 	singleton::SingletonServer,
 	state::LauncherPaths,
 	tunnels::code_server::print_listening,
-	update_service::Platform,
+	update_service::{Platform, Release, TargetKind, UpdateService},
 	util::{
-		errors::{AnyError, CodeError},
+		errors::{AnyError, CodeError, UpdatesNotConfigured},
+		http::{BoxedHttp, ReqwestSimpleHttp},
+		io::SilentCopyProgress,
+		rate_limit::RateLimit,
 		ring_buffer::RingBuffer,
 		sync::{Barrier, ConcatReceivable},
 	},
@@ -44,8 +54,26 @@ pub struct SingletonServerArgs<'a> {
 	pub paths: &'a LauncherPaths,
 	pub code_server_args: &'a CodeServerArgs,
 	pub platform: Platform,
+	/// Client for the requests the tunnel makes in the background.
+	pub http: BoxedHttp,
 	pub shutdown: Barrier<ShutdownSignal>,
 	pub log_broadcast: &'a BroadcastLogSink,
+	pub forward_socket_allowlist: &'a [PathBuf],
+	pub forward_host_allowlist: &'a [String],
+	pub admin_token: Option<&'a str>,
+	pub rate_limits: &'a [(String, RateLimit)],
+	pub metrics_socket: Option<&'a Path>,
+	pub metrics_socket_mode: Option<u32>,
+	pub control_socket: Option<&'a Path>,
+	pub server_path_policy: &'a ServerPathPolicy,
+	pub max_frame_size: usize,
+	pub slow_rpc_threshold: Duration,
+	pub strict_protocol: bool,
+	/// Quality of servers that are set up ahead of clients connecting.
+	pub prefetch_quality: Option<Quality>,
+	/// When the CLI is checked for updates in the background, if it is.
+	pub auto_update: Option<AutoUpdateSchedule>,
+	pub quota: Option<protocol::singleton::TunnelQuota>,
 }
 
 #[derive(Clone)]
@@ -54,17 +82,29 @@ struct SingletonServerContext {
 	shutdown_tx: broadcast::Sender<ShutdownSignal>,
 	broadcast_tx: broadcast::Sender<Vec<u8>>,
 	current_name: Arc<Mutex<Option<String>>>,
+	quota: Arc<Mutex<Option<protocol::singleton::TunnelQuota>>>,
+	paths: LauncherPaths,
+	/// Shared with other CLI invocations on the host, so they reuse the
+	/// tunnel's connections.
+	update_service: Arc<UpdateService>,
+	/// Latest CLI of each quality, and when it was looked up.
+	latest_cli: Arc<Mutex<HashMap<Quality, (Instant, Release)>>>,
 }
 
+/// How long the latest CLI is cached for other CLI invocations.
+const LATEST_CLI_CACHE_DURATION: Duration = Duration::from_secs(10 * 60);
+
 pub struct RpcServer {
 	fut: JoinHandle<Result<(), CodeError>>,
 	shutdown_broadcast: broadcast::Sender<ShutdownSignal>,
 	current_name: Arc<Mutex<Option<String>>>,
+	quota: Arc<Mutex<Option<protocol::singleton::TunnelQuota>>>,
 }
 
 pub fn make_singleton_server(
 	log_broadcast: BroadcastLogSink,
 	log: log::Logger,
+	paths: &LauncherPaths,
 	server: SingletonServer,
 	shutdown_rx: Barrier<ShutdownSignal>,
 ) -> RpcServer {
@@ -72,11 +112,19 @@ pub fn make_singleton_server(
 	let rpc = new_json_rpc();
 
 	let current_name = Arc::new(Mutex::new(None));
+	let quota = Arc::new(Mutex::new(None));
 	let mut rpc = rpc.methods(SingletonServerContext {
 		log: log.clone(),
 		shutdown_tx: shutdown_broadcast.clone(),
 		broadcast_tx: log_broadcast.get_brocaster(),
 		current_name: current_name.clone(),
+		quota: quota.clone(),
+		paths: paths.clone(),
+		update_service: Arc::new(UpdateService::new(
+			log.clone(),
+			Arc::new(ReqwestSimpleHttp::new()),
+		)),
+		latest_cli: Arc::new(Mutex::new(HashMap::new())),
 	});
 
 	rpc.register_sync(
@@ -96,10 +144,44 @@ pub fn make_singleton_server(
 					Some(name) => protocol::singleton::TunnelState::Connected { name },
 					None => protocol::singleton::TunnelState::Disconnected,
 				},
+				quota: c.quota.lock().unwrap().clone(),
 			})
 		},
 	);
 
+	rpc.register_async(
+		protocol::singleton::METHOD_LATEST_CLI,
+		|p: protocol::singleton::LatestCliParams, c| async move {
+			let release = get_latest_cli(&c, p.quality).await?;
+			Ok(protocol::singleton::CliRelease {
+				name: release.name,
+				commit: release.commit,
+			})
+		},
+	);
+
+	rpc.register_async(
+		protocol::singleton::METHOD_DOWNLOAD_CLI,
+		|p: protocol::singleton::DownloadCliParams, c| async move {
+			let release = Release {
+				name: format!("{} CLI", PRODUCT_NAME_LONG),
+				commit: p.commit,
+				platform: host_platform()?,
+				quality: p.quality,
+				target: TargetKind::Cli,
+				custom_quality: None,
+			};
+			let path = download_cli_into_cache(
+				&c.paths.cli_cache,
+				&release,
+				&c.update_service,
+				SilentCopyProgress(),
+			)
+			.await?;
+			Ok(protocol::singleton::DownloadCliResult { path })
+		},
+	);
+
 	rpc.register_sync(
 		protocol::singleton::METHOD_SHUTDOWN,
 		|_: protocol::EmptyObject, ctx| {
@@ -119,12 +201,21 @@ pub fn make_singleton_server(
 
 	// we tokio spawn instead of keeping a future, since we want it to progress
 	// even outside of the start_singleton_server loop (i.e. while the tunnel restarts)
+	let connect_quota = quota.clone();
 	let fut = tokio::spawn(async move {
-		serve_singleton_rpc(log_broadcast, server, rpc.build(log), shutdown_rx).await
+		serve_singleton_rpc(
+			log_broadcast,
+			server,
+			rpc.build(log),
+			connect_quota,
+			shutdown_rx,
+		)
+		.await
 	});
 	RpcServer {
 		shutdown_broadcast,
 		current_name,
+		quota,
 		fut,
 	}
 }
@@ -143,12 +234,50 @@ pub async fn start_singleton_server<'a>(
 		*name = Some(args.tunnel.name.clone())
 	}
 
+	*args.server.quota.lock().unwrap() = args.quota.clone();
+	if let Some(quota) = &args.quota {
+		if quota.is_bandwidth_nearly_exhausted() {
+			warning!(
+				args.log,
+				"Your account's tunnels have transferred {} of the {} bytes they're allowed. Once the limit is reached, connections through them will fail.",
+				quota.bandwidth_bytes.unwrap_or_default(),
+				quota.bandwidth_limit.unwrap_or_default()
+			);
+		} else if quota.is_nearly_exhausted() {
+			warning!(
+				args.log,
+				"{} of the {} tunnels allowed on your account are in use. Once the limit is reached, new tunnels can't be created until you unregister unused ones with `{} tunnel unregister`.",
+				quota.tunnels,
+				quota.tunnel_limit,
+				APPLICATION_NAME
+			);
+		}
+		// clients that connect later are sent the warning when they do
+		if let Some(warning) = quota_warning(quota) {
+			let _ = args.log_broadcast.get_brocaster().send(warning);
+		}
+	}
+
 	let serve_fut = super::serve(
 		&args.log,
 		args.tunnel,
 		args.paths,
 		args.code_server_args,
 		args.platform,
+		args.http,
+		args.forward_socket_allowlist,
+		args.forward_host_allowlist,
+		args.admin_token,
+		args.rate_limits,
+		args.metrics_socket,
+		args.metrics_socket_mode,
+		args.control_socket,
+		args.server_path_policy,
+		args.max_frame_size,
+		args.slow_rpc_threshold,
+		args.strict_protocol,
+		args.prefetch_quality,
+		args.auto_update,
 		shutdown_rx,
 	);
 
@@ -166,10 +295,34 @@ pub async fn start_singleton_server<'a>(
 	}
 }
 
+fn host_platform() -> Result<Platform, AnyError> {
+	Platform::env_default()
+		.ok_or_else(|| UpdatesNotConfigured("unknown platform".to_string()).into())
+}
+
+async fn get_latest_cli(c: &SingletonServerContext, quality: Quality) -> Result<Release, AnyError> {
+	if let Some((at, release)) = c.latest_cli.lock().unwrap().get(&quality) {
+		if at.elapsed() < LATEST_CLI_CACHE_DURATION {
+			return Ok(release.clone());
+		}
+	}
+
+	let release = c
+		.update_service
+		.get_latest_commit(host_platform()?, TargetKind::Cli, quality, None)
+		.await?;
+	c.latest_cli
+		.lock()
+		.unwrap()
+		.insert(quality, (Instant::now(), release.clone()));
+	Ok(release)
+}
+
 async fn serve_singleton_rpc<C: Clone + Send + Sync + 'static>(
 	log_broadcast: BroadcastLogSink,
 	mut server: SingletonServer,
 	dispatcher: RpcDispatcher<JsonRpcSerializer, C>,
+	quota: Arc<Mutex<Option<protocol::singleton::TunnelQuota>>>,
 	shutdown_rx: Barrier<ShutdownSignal>,
 ) -> Result<(), CodeError> {
 	let mut own_shutdown = shutdown_rx.clone();
@@ -184,7 +337,8 @@ async fn serve_singleton_rpc<C: Clone + Send + Sync + 'static>(
 
 		let (read, write) = socket_stream_split(cnx);
 		let dispatcher = dispatcher.clone();
-		let msg_rx = log_broadcast.replay_and_subscribe();
+		let warning = quota.lock().unwrap().as_ref().and_then(quota_warning);
+		let msg_rx = log_broadcast.replay_and_subscribe(warning);
 		let shutdown_rx = shutdown_rx.clone();
 		tokio::spawn(async move {
 			let _ = start_json_rpc(dispatcher.clone(), read, write, msg_rx, shutdown_rx).await;
@@ -192,6 +346,18 @@ async fn serve_singleton_rpc<C: Clone + Send + Sync + 'static>(
 	}
 }
 
+/// Gets the notification that warns clients about the quota, if it's nearly
+/// used up.
+fn quota_warning(quota: &protocol::singleton::TunnelQuota) -> Option<Vec<u8>> {
+	quota.is_nearly_exhausted().then(|| {
+		RpcCaller::serialize_notify(
+			&JsonRpcSerializer {},
+			protocol::singleton::METHOD_QUOTA_WARNING,
+			quota,
+		)
+	})
+}
+
 /// Log sink that can broadcast and replay log events. Used for transmitting
 /// logs from the singleton to all clients. This should be created and injected
 /// into other services, like the tunnel, before `start_singleton_server`
@@ -221,14 +387,21 @@ impl BroadcastLogSink {
 		self.tx.clone()
 	}
 
+	/// Subscribes to messages, after replaying recent logs and then the
+	/// `pending` message, if any.
 	fn replay_and_subscribe(
 		&self,
-	) -> ConcatReceivable<Vec<u8>, mpsc::UnboundedReceiver<Vec<u8>>, broadcast::Receiver<Vec<u8>>> {
+		pending: Option<Vec<u8>>,
+	) -> ConcatReceivable<Vec<u8>, mpsc::UnboundedReceiver<Vec<u8>>, broadcast::Receiver<Vec<u8>>>
+	{
 		let (log_replay_tx, log_replay_rx) = mpsc::unbounded_channel();
 
 		for log in self.recent.lock().unwrap().iter() {
 			let _ = log_replay_tx.send(log.clone());
 		}
+		if let Some(msg) = pending {
+			let _ = log_replay_tx.send(msg);
+		}
 
 		let _ = log_replay_tx.send(RpcCaller::serialize_notify(
 			&JsonRpcSerializer {},
@@ -3,13 +3,13 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
-use serde::Serialize;
 use tokio::sync::mpsc;
 
 use crate::msgpack_rpc::MsgPackCaller;
 
 use super::{
-	protocol::{ClientRequestMethod, RefServerMessageParams, ToClientRequest},
+	notifier::serialize_notification,
+	protocol::{ClientRequestMethod, RefServerMessageParams},
 	server_multiplexer::ServerMultiplexer,
 };
 
@@ -29,11 +29,9 @@ impl From<Vec<u8>> for SocketSignal {
 }
 
 impl SocketSignal {
-	pub fn from_message<T>(msg: &T) -> Self
-	where
-		T: Serialize + ?Sized,
-	{
-		SocketSignal::Send(rmp_serde::to_vec_named(msg).unwrap())
+	/// Creates a signal that sends the notification to the client.
+	pub fn notification(params: ClientRequestMethod<'_>) -> Self {
+		SocketSignal::Send(serialize_notification(params))
 	}
 }
 
@@ -91,10 +89,9 @@ impl ServerMessageSink {
 
 		let r = match &mut tx {
 			ServerMessageDestination::Channel(tx) => {
-				tx.send(SocketSignal::from_message(&ToClientRequest {
-					id: None,
-					params: ClientRequestMethod::servermsg(msg),
-				}))
+				tx.send(SocketSignal::notification(ClientRequestMethod::servermsg(
+					msg,
+				)))
 				.await
 			}
 			ServerMessageDestination::Rpc(caller) => {
@@ -0,0 +1,355 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	collections::HashMap,
+	sync::{Arc, Mutex},
+	time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use sysinfo::{Pid, PidExt, ProcessExt, Signal, System, SystemExt};
+use tokio::{sync::mpsc, task::JoinHandle};
+
+use crate::util::{
+	command::kill_tree,
+	errors::{AnyError, CodeError},
+	event_store::EventStore,
+};
+
+use super::{
+	protocol::{
+		ClientRequestMethod, ProcessSignal, ProcessUsage, SpawnHistoryEntry, SpawnParams,
+		SpawnUsageParams, SpawnedProcessInfo,
+	},
+	socket_signal::SocketSignal,
+};
+
+/// How often the resource usage of spawned processes is sampled.
+const USAGE_SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+/// Lower bound for how often clients can ask for usage notifications.
+const MIN_USAGE_REPORT_INTERVAL: Duration = Duration::from_millis(100);
+
+pub type SpawnHistory = EventStore<SpawnHistoryEntry>;
+
+/// Processes a client started with the `spawn` method, which it's allowed to
+/// list and control. Processes are removed once they exit.
+#[derive(Clone)]
+pub struct SpawnedProcesses {
+	processes: Arc<Mutex<HashMap<u32, SpawnedProcessInfo>>>,
+	/// Used to send usage notifications to the client.
+	socket_tx: mpsc::Sender<SocketSignal>,
+	/// Where the usage of exited processes is recorded, if available.
+	history: Option<Arc<SpawnHistory>>,
+}
+
+/// Removes the process from its `SpawnedProcesses` when dropped.
+pub struct SpawnedProcessGuard {
+	processes: SpawnedProcesses,
+	pid: u32,
+	started: Instant,
+	usage: Arc<Mutex<ProcessUsage>>,
+	monitor: JoinHandle<()>,
+}
+
+impl SpawnedProcessGuard {
+	/// Stops tracking the process, returning its resource usage.
+	pub fn finish(self) -> ProcessUsage {
+		let mut usage = self.usage.lock().unwrap().clone();
+		usage.wall_time_ms = self.started.elapsed().as_millis() as u64;
+
+		if let Some(history) = &self.processes.history {
+			let info = self
+				.processes
+				.processes
+				.lock()
+				.unwrap()
+				.get(&self.pid)
+				.cloned();
+			if let Some(info) = info {
+				// history is best-effort, and not worth failing the spawn for
+				history
+					.append(&SpawnHistoryEntry {
+						pid: info.pid,
+						command: info.command,
+						args: info.args,
+						started_at: info.started_at,
+						usage: usage.clone(),
+					})
+					.ok();
+			}
+		}
+
+		usage
+	}
+}
+
+impl Drop for SpawnedProcessGuard {
+	fn drop(&mut self) {
+		self.monitor.abort();
+		self.processes.processes.lock().unwrap().remove(&self.pid);
+	}
+}
+
+impl SpawnedProcesses {
+	pub fn new(socket_tx: mpsc::Sender<SocketSignal>, history: Option<Arc<SpawnHistory>>) -> Self {
+		SpawnedProcesses {
+			processes: Arc::new(Mutex::new(HashMap::new())),
+			socket_tx,
+			history,
+		}
+	}
+
+	/// Tracks the process until the returned guard is dropped, sampling its
+	/// resource usage in the meantime.
+	pub fn track(&self, pid: u32, params: &SpawnParams) -> SpawnedProcessGuard {
+		let info = SpawnedProcessInfo {
+			pid,
+			command: params.command.clone(),
+			args: params.args.clone(),
+			pty: params.pty.is_some(),
+			kill_on_disconnect: params.kill_on_disconnect,
+			started_at: SystemTime::now()
+				.duration_since(UNIX_EPOCH)
+				.map(|d| d.as_secs())
+				.unwrap_or_default(),
+		};
+
+		self.processes.lock().unwrap().insert(pid, info);
+
+		let started = Instant::now();
+		let usage = Arc::new(Mutex::new(ProcessUsage::default()));
+		let report = params.usage_interval_ms.map(|ms| {
+			(
+				Duration::from_millis(ms).max(MIN_USAGE_REPORT_INTERVAL),
+				self.socket_tx.clone(),
+			)
+		});
+
+		SpawnedProcessGuard {
+			processes: self.clone(),
+			pid,
+			started,
+			monitor: tokio::spawn(monitor_usage(pid, started, usage.clone(), report)),
+			usage,
+		}
+	}
+
+	pub fn list(&self) -> Vec<SpawnedProcessInfo> {
+		let mut list: Vec<_> = self.processes.lock().unwrap().values().cloned().collect();
+		list.sort_by_key(|p| p.started_at);
+		list
+	}
+
+	/// Kills the process and any of its children.
+	pub async fn kill(&self, pid: u32) -> Result<(), AnyError> {
+		self.ensure_tracked(pid)?;
+		kill_tree(pid).await?;
+		Ok(())
+	}
+
+	pub fn signal(&self, pid: u32, signal: ProcessSignal) -> Result<(), AnyError> {
+		self.ensure_tracked(pid)?;
+
+		let pid = Pid::from_u32(pid);
+		let mut sys = System::new();
+		sys.refresh_process(pid);
+		let process = match sys.process(pid) {
+			Some(p) => p,
+			None => return Err(CodeError::NoSuchSpawnedProcess(pid.as_u32()).into()),
+		};
+
+		match process.kill_with(signal.into()) {
+			Some(true) => Ok(()),
+			Some(false) => Err(CodeError::ProcessSignalFailed(signal).into()),
+			None => Err(CodeError::UnsupportedSignal(signal).into()),
+		}
+	}
+
+	/// Kills processes that were spawned with `kill_on_disconnect`. Called
+	/// when the connection that spawned them closes.
+	pub async fn kill_on_disconnect(&self) {
+		let pids: Vec<u32> = self
+			.processes
+			.lock()
+			.unwrap()
+			.values()
+			.filter(|p| p.kill_on_disconnect)
+			.map(|p| p.pid)
+			.collect();
+
+		futures::future::join_all(pids.into_iter().map(kill_tree)).await;
+	}
+
+	fn ensure_tracked(&self, pid: u32) -> Result<(), CodeError> {
+		if self.processes.lock().unwrap().contains_key(&pid) {
+			Ok(())
+		} else {
+			Err(CodeError::NoSuchSpawnedProcess(pid))
+		}
+	}
+}
+
+/// Samples the process's usage until it exits. CPU time is estimated from
+/// its utilization between samples, so very short-lived processes may not
+/// report any.
+async fn monitor_usage(
+	pid: u32,
+	started: Instant,
+	usage: Arc<Mutex<ProcessUsage>>,
+	report: Option<(Duration, mpsc::Sender<SocketSignal>)>,
+) {
+	let sample_interval = match &report {
+		Some((every, _)) => USAGE_SAMPLE_INTERVAL.min(*every),
+		None => USAGE_SAMPLE_INTERVAL,
+	};
+
+	let sys_pid = Pid::from_u32(pid);
+	let mut sys = System::new();
+	let mut interval = tokio::time::interval(sample_interval);
+	let mut last_sample = Instant::now();
+	let mut last_report = Instant::now();
+	loop {
+		interval.tick().await;
+		if !sys.refresh_process(sys_pid) {
+			return;
+		}
+		let process = match sys.process(sys_pid) {
+			Some(p) => p,
+			None => return,
+		};
+
+		let now = Instant::now();
+		let snapshot = {
+			let mut u = usage.lock().unwrap();
+			u.peak_rss = u.peak_rss.max(process.memory());
+			u.cpu_time_ms += (process.cpu_usage() as f64 / 100.0
+				* now.duration_since(last_sample).as_millis() as f64) as u64;
+			u.wall_time_ms = now.duration_since(started).as_millis() as u64;
+			u.clone()
+		};
+		last_sample = now;
+
+		if let Some((every, tx)) = &report {
+			if now.duration_since(last_report) >= *every {
+				last_report = now;
+				let msg =
+					SocketSignal::notification(ClientRequestMethod::spawnusage(SpawnUsageParams {
+						pid,
+						usage: snapshot,
+					}));
+				if tx.send(msg).await.is_err() {
+					return;
+				}
+			}
+		}
+	}
+}
+
+/// Gets the program and arguments to run for the spawn request, wrapping the
+/// command line in the user's shell if requested.
+pub fn command_line(params: &SpawnParams) -> (String, Vec<String>) {
+	if !params.shell {
+		return (params.command.clone(), params.args.clone());
+	}
+
+	// passed through as-is, so the shell expands variables, globs, and pipes
+	let line = std::iter::once(&params.command)
+		.chain(params.args.iter())
+		.map(String::as_str)
+		.collect::<Vec<_>>()
+		.join(" ");
+
+	#[cfg(windows)]
+	let r = (default_shell(), vec!["/C".to_string(), line]);
+	#[cfg(not(windows))]
+	let r = (default_shell(), vec!["-c".to_string(), line]);
+
+	r
+}
+
+/// Gets the user's shell, which command lines are run through.
+pub fn default_shell() -> String {
+	#[cfg(windows)]
+	let shell = std::env::var("COMSPEC").unwrap_or_else(|_| "cmd.exe".to_string());
+	#[cfg(not(windows))]
+	let shell = std::env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_string());
+
+	shell
+}
+
+impl From<ProcessSignal> for Signal {
+	fn from(s: ProcessSignal) -> Self {
+		match s {
+			ProcessSignal::Sighup => Signal::Hangup,
+			ProcessSignal::Sigint => Signal::Interrupt,
+			ProcessSignal::Sigquit => Signal::Quit,
+			ProcessSignal::Sigkill => Signal::Kill,
+			ProcessSignal::Sigterm => Signal::Term,
+			ProcessSignal::Sigusr1 => Signal::User1,
+			ProcessSignal::Sigusr2 => Signal::User2,
+			ProcessSignal::Sigstop => Signal::Stop,
+			ProcessSignal::Sigcont => Signal::Continue,
+			ProcessSignal::Sigwinch => Signal::Winch,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn test_tracks_until_dropped() {
+		let (tx, _rx) = mpsc::channel(1);
+		let processes = SpawnedProcesses::new(tx, None);
+		let params = SpawnParams {
+			command: "sleep".to_string(),
+			args: vec!["10".to_string()],
+			env: Default::default(),
+			pty: None,
+			cwd: None,
+			shell: false,
+			kill_on_disconnect: false,
+			usage_interval_ms: None,
+		};
+
+		let guard = processes.track(1234, &params);
+		let list = processes.list();
+		assert_eq!(list.len(), 1);
+		assert_eq!(list[0].pid, 1234);
+		assert_eq!(list[0].command, "sleep");
+		assert!(processes.ensure_tracked(1234).is_ok());
+
+		drop(guard);
+		assert!(processes.list().is_empty());
+		assert!(matches!(
+			processes.signal(1234, ProcessSignal::Sigterm),
+			Err(AnyError::CodeError(CodeError::NoSuchSpawnedProcess(1234)))
+		));
+	}
+
+	#[test]
+	#[cfg(not(windows))]
+	fn test_command_line() {
+		let mut params = SpawnParams {
+			command: "echo".to_string(),
+			args: vec!["a b".to_string(), "$HOME".to_string()],
+			env: Default::default(),
+			pty: None,
+			cwd: None,
+			shell: false,
+			kill_on_disconnect: false,
+			usage_interval_ms: None,
+		};
+		assert_eq!(
+			command_line(&params),
+			("echo".to_string(), params.args.clone())
+		);
+
+		params.shell = true;
+		let (_, args) = command_line(&params);
+		assert_eq!(args, vec!["-c", "echo a b $HOME"]);
+	}
+}
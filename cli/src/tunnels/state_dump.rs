@@ -0,0 +1,159 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{fmt, path::Path};
+
+use super::protocol::DumpStateResult;
+
+const REDACTED: &str = "<redacted>";
+
+/// Server arguments whose values are secret.
+const SECRET_ARGS: &[&str] = &["--connection-token="];
+
+/// Abbreviates the home directory in the path to `~`, so that the user's
+/// name isn't included in the dump.
+pub fn redact_path(path: &Path) -> String {
+	match dirs::home_dir() {
+		Some(home) if !home.as_os_str().is_empty() => match path.strip_prefix(&home) {
+			Ok(rest) => Path::new("~").join(rest).display().to_string(),
+			Err(_) => path.display().to_string(),
+		},
+		_ => path.display().to_string(),
+	}
+}
+
+/// Removes the values of secret arguments from a server command line.
+pub fn redact_args(args: Vec<String>) -> Vec<String> {
+	args.into_iter()
+		.map(|a| match SECRET_ARGS.iter().find(|s| a.starts_with(*s)) {
+			Some(prefix) => format!("{}{}", prefix, REDACTED),
+			None => a,
+		})
+		.collect()
+}
+
+/// Human-readable rendering of the dump, for reading it in a terminal.
+impl fmt::Display for DumpStateResult {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, "CLI version:       {}", self.cli_version)?;
+		writeln!(f, "Taken at:          {}", self.taken_at)?;
+		writeln!(f, "Tunnel connected:  {}", self.tunnel_connected_at)?;
+		writeln!(f, "Forwarded ports:   {:?}", self.forwarded_ports)?;
+		writeln!(f)?;
+
+		writeln!(f, "Config:")?;
+		writeln!(f, "  platform:        {}", self.config.platform)?;
+		writeln!(f, "  data dir:        {}", self.config.data_dir)?;
+		writeln!(
+			f,
+			"  server args:     {}",
+			self.config.server_args.join(" ")
+		)?;
+		writeln!(
+			f,
+			"  server env:      {}",
+			self.config.server_env.join(", ")
+		)?;
+
+		for socket in &self.sockets {
+			writeln!(f)?;
+			writeln!(
+				f,
+				"Socket {}{}:",
+				socket.id,
+				if socket.current { " (current)" } else { "" }
+			)?;
+			writeln!(
+				f,
+				"  tx/rx bytes:     {}/{}",
+				socket.tx_bytes, socket.rx_bytes
+			)?;
+			writeln!(f, "  outbound queue:  {}", socket.outbound_queue)?;
+			writeln!(f, "  http requests:   {}", socket.pending_http_requests)?;
+			writeln!(f, "  spawned:         {}", socket.spawned_processes)?;
+			if socket.code_servers.is_empty() {
+				writeln!(f, "  code server:     none")?;
+			}
+			for s in &socket.code_servers {
+				writeln!(f, "  code server:     {} at {}", s.commit_id, s.socket)?;
+			}
+			for bridge in &socket.bridges {
+				writeln!(
+					f,
+					"  bridge {}:        {} queued",
+					bridge.id, bridge.write_queue
+				)?;
+			}
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::tunnels::protocol::{ConfigStateDump, SocketStateDump};
+
+	#[test]
+	fn test_redact_args() {
+		assert_eq!(
+			redact_args(vec![
+				"--socket-path=/tmp/a.sock".to_string(),
+				"--connection-token=abc123".to_string(),
+			]),
+			vec![
+				"--socket-path=/tmp/a.sock".to_string(),
+				"--connection-token=<redacted>".to_string(),
+			]
+		);
+	}
+
+	#[test]
+	fn test_redact_path() {
+		if let Some(home) = dirs::home_dir() {
+			assert_eq!(
+				redact_path(&home.join("code").join("file")),
+				Path::new("~")
+					.join("code")
+					.join("file")
+					.display()
+					.to_string()
+			);
+		}
+	}
+
+	#[test]
+	fn test_display() {
+		let dump = DumpStateResult {
+			taken_at: 2,
+			cli_version: "dev".to_string(),
+			tunnel_connected_at: 1,
+			forwarded_ports: vec![8080],
+			sockets: vec![SocketStateDump {
+				id: 4,
+				current: true,
+				tx_bytes: 10,
+				rx_bytes: 20,
+				outbound_queue: 0,
+				pending_http_requests: 0,
+				spawned_processes: 1,
+				bridges: vec![],
+				code_servers: vec![],
+			}],
+			config: ConfigStateDump {
+				platform: "LinuxX64".to_string(),
+				data_dir: "~/.vscode-cli".to_string(),
+				server_args: vec![],
+				server_env: vec![],
+			},
+		};
+
+		let out = dump.to_string();
+		assert!(out.contains("Forwarded ports:   [8080]\n"));
+		assert!(out.contains("Socket 4 (current):\n  tx/rx bytes:     10/20\n"));
+		assert!(out.contains("  code server:     none\n"));
+	}
+}
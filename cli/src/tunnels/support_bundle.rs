@@ -0,0 +1,190 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Archive of diagnostics that users can attach to issues. Everything added
+//! to the bundle is redacted, and a manifest lists each file along with the
+//! redactions that were applied, so users can check what they're sharing.
+
+use std::{fmt, fs::File, io::Write, path::Path};
+
+use flate2::{write::GzEncoder, Compression};
+use serde::Serialize;
+
+use crate::{
+	constants::VSCODE_CLI_VERSION,
+	util::{
+		errors::{wrap, WrappedError},
+		permissions::restrict_file,
+		redact::{RedactionRule, Redactor},
+		tags::{tags, Tags},
+	},
+};
+
+use super::file_transfer::sha256_hex;
+
+const MANIFEST_NAME: &str = "manifest.json";
+
+pub struct SupportBundle {
+	redactor: Redactor,
+	files: Vec<(String, Vec<u8>)>,
+	omitted: Vec<OmittedEntry>,
+}
+
+#[derive(Serialize)]
+struct Manifest<'a> {
+	created_at: String,
+	cli_version: &'a str,
+	os: &'a str,
+	arch: &'a str,
+	/// Tags the machine was configured with, see `util::tags`.
+	tags: Tags,
+	redactions: &'a [RedactionRule],
+	/// Number of additional strings given to be redacted.
+	custom_redactions: usize,
+	files: Vec<ManifestFile<'a>>,
+	omitted: &'a [OmittedEntry],
+}
+
+#[derive(Serialize)]
+struct ManifestFile<'a> {
+	name: &'a str,
+	size: usize,
+	sha256: String,
+}
+
+/// A file that couldn't be collected, and why.
+#[derive(Serialize)]
+struct OmittedEntry {
+	name: String,
+	reason: String,
+}
+
+impl SupportBundle {
+	pub fn new(redactor: Redactor) -> Self {
+		Self {
+			redactor,
+			files: vec![],
+			omitted: vec![],
+		}
+	}
+
+	/// Adds the text to the bundle at the given path, after redacting it.
+	pub fn add_text(&mut self, name: impl Into<String>, text: &str) {
+		self.files
+			.push((name.into(), self.redactor.redact(text).into_bytes()));
+	}
+
+	/// Adds the value to the bundle as pretty-printed JSON.
+	pub fn add_json(&mut self, name: impl Into<String>, value: &impl Serialize) {
+		match serde_json::to_string_pretty(value) {
+			Ok(text) => self.add_text(name, &text),
+			Err(e) => self.omit(name, e),
+		}
+	}
+
+	/// Notes in the manifest that a file couldn't be collected.
+	pub fn omit(&mut self, name: impl Into<String>, reason: impl fmt::Display) {
+		self.omitted.push(OmittedEntry {
+			name: name.into(),
+			reason: self.redactor.redact(&reason.to_string()),
+		});
+	}
+
+	/// Writes the bundle as a gzipped tarball, with the manifest first.
+	pub fn write(&self, path: &Path) -> Result<(), WrappedError> {
+		let manifest = Manifest {
+			created_at: chrono::Local::now().to_rfc3339(),
+			cli_version: VSCODE_CLI_VERSION.unwrap_or("dev"),
+			os: std::env::consts::OS,
+			arch: std::env::consts::ARCH,
+			tags: tags()
+				.into_iter()
+				.map(|(k, v)| (k, self.redactor.redact(&v)))
+				.collect(),
+			redactions: self.redactor.rules(),
+			custom_redactions: self.redactor.custom_count(),
+			files: self
+				.files
+				.iter()
+				.map(|(name, contents)| ManifestFile {
+					name,
+					size: contents.len(),
+					sha256: sha256_hex(contents),
+				})
+				.collect(),
+			omitted: &self.omitted,
+		};
+		let manifest = serde_json::to_vec_pretty(&manifest).unwrap();
+
+		let file = File::create(path)
+			.map_err(|e| wrap(e, format!("error creating {}", path.display())))?;
+		restrict_file(path).map_err(|e| wrap(e, "error setting bundle permissions"))?;
+
+		let mut archive = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+		let mtime = chrono::Utc::now().timestamp() as u64;
+		let entries = std::iter::once((MANIFEST_NAME, manifest.as_slice()))
+			.chain(self.files.iter().map(|(n, c)| (n.as_str(), c.as_slice())));
+		for (name, contents) in entries {
+			let mut header = tar::Header::new_gnu();
+			header.set_size(contents.len() as u64);
+			header.set_mode(0o600);
+			header.set_mtime(mtime);
+			archive
+				.append_data(&mut header, name, contents)
+				.map_err(|e| wrap(e, format!("error adding {} to the bundle", name)))?;
+		}
+
+		archive
+			.into_inner()
+			.and_then(|gz| gz.finish())
+			.and_then(|mut f| f.flush())
+			.map_err(|e| wrap(e, "error writing the bundle"))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use flate2::read::GzDecoder;
+	use std::io::Read;
+
+	#[test]
+	fn test_write_bundle() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("bundle.tar.gz");
+
+		let mut bundle = SupportBundle::new(Redactor::new(
+			&[RedactionRule::Tokens],
+			&["contoso".to_string()],
+		));
+		bundle.add_text("logs/a.log", "token=abc for contoso\n");
+		bundle.omit("status.json", "no tunnel is running");
+		bundle.write(&path).unwrap();
+
+		let mut archive = tar::Archive::new(GzDecoder::new(File::open(&path).unwrap()));
+		let mut contents = vec![];
+		for entry in archive.entries().unwrap() {
+			let mut entry = entry.unwrap();
+			let mut text = String::new();
+			entry.read_to_string(&mut text).unwrap();
+			contents.push((entry.path().unwrap().display().to_string(), text));
+		}
+
+		assert_eq!(contents.len(), 2);
+		assert_eq!(contents[0].0, MANIFEST_NAME);
+		assert!(contents[0].1.contains("\"name\": \"logs/a.log\""));
+		assert!(contents[0]
+			.1
+			.contains("\"reason\": \"no tunnel is running\""));
+		assert!(contents[0].1.contains("\"custom_redactions\": 1"));
+		assert_eq!(
+			contents[1],
+			(
+				"logs/a.log".to_string(),
+				"token=<redacted> for <redacted>\n".to_string()
+			)
+		);
+	}
+}
@@ -121,11 +121,14 @@ async fn handle_serve(
 	c: &HandlerContext,
 	params: InstallFromLocalFolderParams,
 ) -> Result<EmptyObject, AnyError> {
-	// fill params.extensions into code_server_args.install_extensions
-	let mut csa = c.code_server_args.clone();
-	csa.connection_token = params.inner.connection_token.or(csa.connection_token);
-	csa.install_extensions
-		.extend(params.inner.extensions.into_iter());
+	let mut csa = c
+		.code_server_args
+		.to_builder()
+		.install_extensions(params.inner.extensions);
+	if let Some(token) = params.inner.connection_token {
+		csa = csa.connection_token(token);
+	}
+	let csa = csa.build()?;
 
 	let resolved = ResolvedServerParams {
 		code_server_args: csa,
@@ -138,7 +141,10 @@ async fn handle_serve(
 			platform: c.platform,
 			target: TargetKind::Server,
 			quality: params.inner.quality,
+			custom_quality: None,
 		},
+		prebuilt_dir: None,
+		archive: None,
 	};
 
 	let sb = ServerBuilder::new(
@@ -164,6 +170,7 @@ async fn handle_serve(
 			ServerMessageDestination::Rpc(c.caller.clone()),
 		),
 		ClientMessageDecoder::new_plain(),
+		true,
 	)
 	.await?;
 
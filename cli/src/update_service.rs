@@ -3,19 +3,36 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
-use std::{ffi::OsStr, fmt, path::Path};
+use std::{
+	collections::HashMap,
+	ffi::OsStr,
+	fmt,
+	path::Path,
+	sync::{
+		atomic::{AtomicUsize, Ordering},
+		RwLock,
+	},
+};
 
+use hyper::StatusCode;
+use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
+use tokio::io::AsyncReadExt;
 
 use crate::{
 	constants::VSCODE_CLI_UPDATE_ENDPOINT,
-	debug, log, options, spanf,
+	debug, log, options,
+	product::product_config,
+	spanf,
+	state::config::CliConfig,
 	util::{
-		errors::{AnyError, CodeError, UpdatesNotConfigured, WrappedError},
-		http::{BoxedHttp, SimpleResponse},
+		errors::{wrap, AnyError, CodeError, UpdatesNotConfigured, WrappedError},
+		hash::verify_file_hash,
+		http::{download_into_file_chunked, BoxedHttp, SimpleResponse},
 		io::ReportCopyProgress,
 		tar, zipper,
 	},
+	warning,
 };
 
 /// Implementation of the VS Code Update service for use in the CLI.
@@ -32,6 +49,8 @@ pub struct Release {
 	pub target: TargetKind,
 	pub quality: options::Quality,
 	pub commit: String,
+	/// Custom quality the release is from, see `CustomQuality`.
+	pub custom_quality: Option<String>,
 }
 
 impl std::fmt::Display for Release {
@@ -44,6 +63,182 @@ impl std::fmt::Display for Release {
 struct UpdateServerVersion {
 	pub version: String,
 	pub name: String,
+	/// When the build was published, in milliseconds since the Unix epoch.
+	#[serde(default)]
+	pub timestamp: Option<u64>,
+}
+
+/// Releases `list_releases` returns if it's not given a limit.
+pub const DEFAULT_LISTED_RELEASES: usize = 10;
+/// Most releases `list_releases` returns, since each is looked up separately.
+const MAX_LISTED_RELEASES: usize = 50;
+/// Largest file `get_small_file` reads into memory.
+const MAX_SMALL_FILE_SIZE: u64 = 64 * 1024;
+
+/// Release returned from `list_releases`, with when it was published.
+pub struct ListedRelease {
+	pub release: Release,
+	/// Milliseconds since the Unix epoch, if the update service gives it.
+	pub timestamp: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct UpdateServerDownload {
+	#[serde(default)]
+	pub sha256hash: Option<String>,
+}
+
+/// Delta between the CLI binaries of two releases.
+#[derive(Deserialize)]
+struct UpdateServerDelta {
+	pub url: String,
+	/// SHA-256 of the patched binary.
+	pub sha256hash: String,
+}
+
+lazy_static! {
+	static ref DOWNLOAD_MIRROR: RwLock<Option<String>> = RwLock::new(None);
+	static ref CUSTOM_QUALITIES: RwLock<HashMap<String, CustomQuality>> =
+		RwLock::new(HashMap::new());
+}
+
+/// Quality served by an organization's own update service, such as an
+/// internal canary channel. They're read from `custom_qualities` in the data
+/// dir's config.json, keyed by the name the update service knows them by.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CustomQuality {
+	/// Quality whose product names the builds use.
+	pub quality: options::Quality,
+	/// Update service the builds are downloaded from. Defaults to the mirror,
+	/// if any, or the built-in update service.
+	#[serde(default)]
+	pub update_endpoint: Option<String>,
+}
+
+/// Gets the mirror given as an argument, or else the one in the data dir's
+/// config.json. It's checked when builds are fetched, see
+/// `check_download_mirror`, so commands that don't fetch any still work.
+pub fn load_download_mirror(config: &CliConfig, arg: Option<&str>) -> Option<String> {
+	arg.map(|s| s.to_string())
+		.or_else(|| config.download_mirror.clone())
+		.or_else(|| config.update_endpoint.clone())
+		.map(|s| s.trim_end_matches('/').to_string())
+		.filter(|s| !s.is_empty())
+}
+
+/// Mirrors must be http(s) URLs, so misconfigured hosts, such as air-gapped
+/// ones that can't reach the update service, fail clearly.
+fn check_download_mirror(mirror: &str) -> Result<(), CodeError> {
+	match url::Url::parse(mirror) {
+		Ok(u) if matches!(u.scheme(), "http" | "https") => Ok(()),
+		_ => Err(CodeError::InvalidDownloadMirror(mirror.to_string())),
+	}
+}
+
+/// Sets a mirror that builds are fetched from instead of the update service.
+/// Mirrors must serve the same paths as the update service. Should be called
+/// at startup.
+pub fn install_download_mirror(mirror: Option<String>) {
+	*DOWNLOAD_MIRROR.write().unwrap() = mirror;
+}
+
+/// Reads custom qualities from the config file. Ones whose names can't be
+/// used in URLs, or that are the names of built-in qualities, are ignored.
+pub fn load_custom_qualities(config: &CliConfig) -> HashMap<String, CustomQuality> {
+	config
+		.custom_qualities
+		.clone()
+		.into_iter()
+		.filter(|(name, _)| {
+			!name.is_empty()
+				&& name
+					.chars()
+					.all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+				&& options::Quality::try_from(name.as_str()).is_err()
+		})
+		.map(|(name, mut q)| {
+			q.update_endpoint = q
+				.update_endpoint
+				.map(|e| e.trim_end_matches('/').to_string());
+			(name, q)
+		})
+		.collect()
+}
+
+/// Sets the custom qualities clients may request. Should be called at startup.
+pub fn install_custom_qualities(qualities: HashMap<String, CustomQuality>) {
+	*CUSTOM_QUALITIES.write().unwrap() = qualities;
+}
+
+/// Gets the installed custom quality with the name.
+pub fn custom_quality(name: &str) -> Result<CustomQuality, CodeError> {
+	CUSTOM_QUALITIES
+		.read()
+		.unwrap()
+		.get(name)
+		.cloned()
+		.ok_or_else(|| CodeError::UnknownCustomQuality(name.to_string()))
+}
+
+/// Most connections a build can be downloaded over.
+pub const MAX_DOWNLOAD_CONNECTIONS: usize = 16;
+
+static DOWNLOAD_CONNECTIONS: AtomicUsize = AtomicUsize::new(1);
+
+/// Sets how many concurrent connections builds are downloaded over. Should
+/// be called at startup.
+pub fn install_download_connections(connections: usize) {
+	DOWNLOAD_CONNECTIONS.store(connections, Ordering::Relaxed);
+}
+
+/// Parses a number of download connections given on the command line.
+pub fn parse_download_connections(s: &str) -> Result<usize, String> {
+	match s.parse::<usize>() {
+		Ok(n) if (1..=MAX_DOWNLOAD_CONNECTIONS).contains(&n) => Ok(n),
+		_ => Err(format!(
+			"expected a number of connections from 1 to {}",
+			MAX_DOWNLOAD_CONNECTIONS
+		)),
+	}
+}
+
+/// Parses a platform by the name it's displayed with, ignoring case.
+pub fn parse_platform(s: &str) -> Result<Platform, String> {
+	ALL_PLATFORMS
+		.iter()
+		.find(|p| p.to_string().eq_ignore_ascii_case(s))
+		.copied()
+		.ok_or_else(|| {
+			let names: Vec<String> = ALL_PLATFORMS.iter().map(|p| p.to_string()).collect();
+			format!("expected one of {}", names.join(", "))
+		})
+}
+
+/// Gets the mirror if one's installed, or else the product config's update
+/// endpoint, or else the built-in one.
+fn update_endpoint() -> Result<String, AnyError> {
+	if let Some(mirror) = DOWNLOAD_MIRROR.read().unwrap().as_ref() {
+		check_download_mirror(mirror)?;
+		return Ok(mirror.clone());
+	}
+
+	if let Some(url) = product_config().and_then(|p| p.update_url.as_deref()) {
+		return Ok(url.trim_end_matches('/').to_string());
+	}
+
+	Ok(VSCODE_CLI_UPDATE_ENDPOINT
+		.ok_or_else(UpdatesNotConfigured::no_url)?
+		.to_string())
+}
+
+/// Gets whether the string is a release version like 1.86 or 1.86.2, which
+/// are the versions the update service resolves.
+fn is_release_version(s: &str) -> bool {
+	let parts: Vec<_> = s.split('.').collect();
+	(2..=3).contains(&parts.len())
+		&& parts
+			.iter()
+			.all(|p| !p.is_empty() && p.chars().all(|c| c.is_ascii_digit()))
 }
 
 fn quality_download_segment(quality: options::Quality) -> &'static str {
@@ -54,6 +249,29 @@ fn quality_download_segment(quality: options::Quality) -> &'static str {
 	}
 }
 
+/// Gets the update endpoint and URL segment that builds of the quality, or
+/// of the custom quality if one's given, are fetched with.
+fn quality_endpoint(
+	quality: options::Quality,
+	custom: Option<&str>,
+) -> Result<(String, String), AnyError> {
+	let name = match custom {
+		Some(name) => name,
+		None => {
+			return Ok((
+				update_endpoint()?,
+				quality_download_segment(quality).to_string(),
+			))
+		}
+	};
+
+	let endpoint = match custom_quality(name)?.update_endpoint {
+		Some(endpoint) => endpoint,
+		None => update_endpoint()?,
+	};
+	Ok((endpoint, name.to_string()))
+}
+
 impl UpdateService {
 	pub fn new(log: log::Logger, http: BoxedHttp) -> Self {
 		UpdateService { client: http, log }
@@ -64,19 +282,43 @@ impl UpdateService {
 		platform: Platform,
 		target: TargetKind,
 		quality: options::Quality,
+		custom_quality: Option<&str>,
 		version: &str,
 	) -> Result<Release, AnyError> {
-		let update_endpoint =
-			VSCODE_CLI_UPDATE_ENDPOINT.ok_or_else(UpdatesNotConfigured::no_url)?;
+		if !is_release_version(version) {
+			return Err(CodeError::InvalidReleaseVersion(version.to_string()).into());
+		}
+
+		let res = self
+			.get_version(platform, target, quality, custom_quality, version)
+			.await?;
+		debug!(self.log, "Resolved version {} to {}", version, res.version);
+
+		Ok(Release {
+			target,
+			platform,
+			quality,
+			name: res.name,
+			commit: res.version,
+			custom_quality: custom_quality.map(|q| q.to_string()),
+		})
+	}
+
+	async fn get_version(
+		&self,
+		platform: Platform,
+		target: TargetKind,
+		quality: options::Quality,
+		custom_quality: Option<&str>,
+		version: &str,
+	) -> Result<UpdateServerVersion, AnyError> {
+		let (update_endpoint, quality_segment) = quality_endpoint(quality, custom_quality)?;
 		let download_segment = target
 			.download_segment(platform)
 			.ok_or_else(|| CodeError::UnsupportedPlatform(platform.to_string()))?;
 		let download_url = format!(
 			"{}/api/versions/{}/{}/{}",
-			update_endpoint,
-			version,
-			download_segment,
-			quality_download_segment(quality),
+			update_endpoint, version, download_segment, quality_segment,
 		);
 
 		let mut response = spanf!(
@@ -86,38 +328,77 @@ impl UpdateService {
 		)?;
 
 		if !response.status_code.is_success() {
-			return Err(response.into_err().await.into());
+			return Err(platform_err(platform, response).await);
 		}
 
-		let res = response.json::<UpdateServerVersion>().await?;
-		debug!(self.log, "Resolved version {} to {}", version, res.version);
+		response.json::<UpdateServerVersion>().await
+	}
 
-		Ok(Release {
-			target,
-			platform,
-			quality,
-			name: res.name,
-			commit: res.version,
-		})
+	/// Gets up to `limit` of the most recent releases of the target, newest
+	/// first, so an exact build can be chosen to pin or roll back to. Releases
+	/// without builds of the target for the platform are left out.
+	pub async fn list_releases(
+		&self,
+		platform: Platform,
+		target: TargetKind,
+		quality: options::Quality,
+		custom_quality: Option<&str>,
+		limit: usize,
+	) -> Result<Vec<ListedRelease>, AnyError> {
+		let (update_endpoint, quality_segment) = quality_endpoint(quality, custom_quality)?;
+		let list_url = format!("{}/api/releases/{}", update_endpoint, quality_segment);
+
+		let mut response = self.client.make_request("GET", list_url).await?;
+		if !response.status_code.is_success() {
+			return Err(response.into_err().await.into());
+		}
+		let versions = response.json::<Vec<String>>().await?;
+
+		let resolved = futures::future::join_all(
+			versions
+				.iter()
+				.take(limit.min(MAX_LISTED_RELEASES))
+				.map(|v| self.get_version(platform, target, quality, custom_quality, v)),
+		)
+		.await;
+
+		let mut releases = vec![];
+		for (version, r) in versions.iter().zip(resolved) {
+			match r {
+				Ok(res) => releases.push(ListedRelease {
+					release: Release {
+						target,
+						platform,
+						quality,
+						name: res.name,
+						commit: res.version,
+						custom_quality: custom_quality.map(|q| q.to_string()),
+					},
+					timestamp: res.timestamp,
+				}),
+				Err(e) => debug!(self.log, "Not listing release {}: {}", version, e),
+			}
+		}
+
+		Ok(releases)
 	}
 
-	/// Gets the latest commit for the target of the given quality.
+	/// Gets the latest commit for the target of the given quality, or of the
+	/// custom quality if one's given.
 	pub async fn get_latest_commit(
 		&self,
 		platform: Platform,
 		target: TargetKind,
 		quality: options::Quality,
+		custom_quality: Option<&str>,
 	) -> Result<Release, AnyError> {
-		let update_endpoint =
-			VSCODE_CLI_UPDATE_ENDPOINT.ok_or_else(UpdatesNotConfigured::no_url)?;
+		let (update_endpoint, quality_segment) = quality_endpoint(quality, custom_quality)?;
 		let download_segment = target
 			.download_segment(platform)
 			.ok_or_else(|| CodeError::UnsupportedPlatform(platform.to_string()))?;
 		let download_url = format!(
 			"{}/api/latest/{}/{}",
-			update_endpoint,
-			download_segment,
-			quality_download_segment(quality),
+			update_endpoint, download_segment, quality_segment,
 		);
 
 		let mut response = spanf!(
@@ -127,7 +408,7 @@ impl UpdateService {
 		)?;
 
 		if !response.status_code.is_success() {
-			return Err(response.into_err().await.into());
+			return Err(platform_err(platform, response).await);
 		}
 
 		let res = response.json::<UpdateServerVersion>().await?;
@@ -139,13 +420,153 @@ impl UpdateService {
 			quality,
 			name: res.name,
 			commit: res.version,
+			custom_quality: custom_quality.map(|q| q.to_string()),
 		})
 	}
 
+	/// Checks the downloaded archive of the release against the hash the
+	/// update service gives for it. Should be called before it's unpacked.
+	pub async fn verify_download(&self, release: &Release, archive: &Path) -> Result<(), AnyError> {
+		let (update_endpoint, quality_segment) =
+			quality_endpoint(release.quality, release.custom_quality.as_deref())?;
+		let download_segment = release
+			.target
+			.download_segment(release.platform)
+			.ok_or_else(|| CodeError::UnsupportedPlatform(release.platform.to_string()))?;
+		let info_url = format!(
+			"{}/api/versions/commit:{}/{}/{}",
+			update_endpoint, release.commit, download_segment, quality_segment,
+		);
+
+		let mut response = self.client.make_request("GET", info_url).await?;
+		if !response.status_code.is_success() {
+			return Err(response.into_err().await.into());
+		}
+
+		let expected = response
+			.json::<UpdateServerDownload>()
+			.await?
+			.sha256hash
+			.ok_or_else(|| CodeError::DownloadHashMissing(release.to_string()))?;
+		verify_file_hash(archive, &expected, &release.to_string()).await?;
+
+		debug!(self.log, "Verified download of {}", release);
+		Ok(())
+	}
+
+	/// Patches `from`, the CLI binary of `from_commit`, into the release's CLI
+	/// at `target` with a zstd delta from the update service, which is much
+	/// smaller than the full download. Returns false if there's no delta
+	/// between them or it couldn't be applied, and the release should be
+	/// downloaded in full instead.
+	pub async fn patch_cli_with_delta(
+		&self,
+		from: &Path,
+		from_commit: &str,
+		release: &Release,
+		target: &Path,
+		progress: impl ReportCopyProgress + Send,
+	) -> bool {
+		match self
+			.try_patch_cli_with_delta(from, from_commit, release, target, progress)
+			.await
+		{
+			Ok(patched) => patched,
+			Err(e) => {
+				warning!(
+					self.log,
+					"Error applying delta update, downloading the full release: {}",
+					e
+				);
+				false
+			}
+		}
+	}
+
+	async fn try_patch_cli_with_delta(
+		&self,
+		from: &Path,
+		from_commit: &str,
+		release: &Release,
+		target: &Path,
+		progress: impl ReportCopyProgress + Send,
+	) -> Result<bool, AnyError> {
+		let (update_endpoint, quality_segment) =
+			quality_endpoint(release.quality, release.custom_quality.as_deref())?;
+		let download_segment = release
+			.target
+			.download_segment(release.platform)
+			.ok_or_else(|| CodeError::UnsupportedPlatform(release.platform.to_string()))?;
+		let info_url = format!(
+			"{}/api/versions/commit:{}/{}/{}/delta:{}",
+			update_endpoint, release.commit, download_segment, quality_segment, from_commit,
+		);
+
+		let mut response = self.client.make_request("GET", info_url).await?;
+		if response.status_code == StatusCode::NOT_FOUND {
+			debug!(self.log, "No delta from {} to {}", from_commit, release);
+			return Ok(false);
+		}
+		if !response.status_code.is_success() {
+			return Err(response.into_err().await.into());
+		}
+		let delta = response.json::<UpdateServerDelta>().await?;
+
+		let stream = self.client.make_request("GET", delta.url).await?;
+		if !stream.status_code.is_success() {
+			return Err(stream.into_err().await.into());
+		}
+		let delta_path = target.with_extension("delta");
+		self.download_into_file(&delta_path, progress, stream)
+			.await?;
+		apply_zstd_delta(from, &delta_path, target)?;
+		let _ = std::fs::remove_file(&delta_path);
+
+		verify_file_hash(target, &delta.sha256hash, &format!("delta to {}", release)).await?;
+
+		debug!(self.log, "Patched CLI to {} with a delta", release);
+		Ok(true)
+	}
+
+	/// Gets a file from the update service that's small enough to keep in
+	/// memory, such as a detached signature.
+	pub async fn get_small_file(&self, url: String) -> Result<Vec<u8>, AnyError> {
+		let response = self.client.make_request("GET", url.clone()).await?;
+		if !response.status_code.is_success() {
+			return Err(response.into_err().await.into());
+		}
+
+		let mut contents = vec![];
+		response
+			.read
+			.take(MAX_SMALL_FILE_SIZE + 1)
+			.read_to_end(&mut contents)
+			.await
+			.map_err(|e| wrap(e, "error reading response"))?;
+		if contents.len() as u64 > MAX_SMALL_FILE_SIZE {
+			return Err(CodeError::ResponseTooLarge(url, MAX_SMALL_FILE_SIZE).into());
+		}
+
+		Ok(contents)
+	}
+
+	/// Downloads a stream from `get_download_stream` into the file, resuming
+	/// it if it's interrupted. It's split over the installed number of
+	/// connections if the server supports ranges.
+	pub async fn download_into_file(
+		&self,
+		path: &Path,
+		progress: impl ReportCopyProgress + Send,
+		stream: SimpleResponse,
+	) -> Result<(), AnyError> {
+		let connections = DOWNLOAD_CONNECTIONS.load(Ordering::Relaxed);
+		download_into_file_chunked(self.client.as_ref(), path, progress, stream, connections).await
+	}
+
 	/// Gets the download stream for the release.
 	pub async fn get_download_stream(&self, release: &Release) -> Result<SimpleResponse, AnyError> {
-		let update_endpoint =
-			VSCODE_CLI_UPDATE_ENDPOINT.ok_or_else(UpdatesNotConfigured::no_url)?;
+		let (update_endpoint, quality_segment) =
+			quality_endpoint(release.quality, release.custom_quality.as_deref())?;
 		let download_segment = release
 			.target
 			.download_segment(release.platform)
@@ -153,21 +574,47 @@ impl UpdateService {
 
 		let download_url = format!(
 			"{}/commit:{}/{}/{}",
-			update_endpoint,
-			release.commit,
-			download_segment,
-			quality_download_segment(release.quality),
+			update_endpoint, release.commit, download_segment, quality_segment,
 		);
 
 		let response = self.client.make_request("GET", download_url).await?;
 		if !response.status_code.is_success() {
-			return Err(response.into_err().await.into());
+			return Err(platform_err(release.platform, response).await);
 		}
 
 		Ok(response)
 	}
 }
 
+/// Gets the error for an unsuccessful response about builds for the platform,
+/// with guidance if it's one there are no published builds for.
+async fn platform_err(platform: Platform, response: SimpleResponse) -> AnyError {
+	if response.status_code == StatusCode::NOT_FOUND && !platform.is_published() {
+		return CodeError::NoPublishedBuilds(platform.to_string()).into();
+	}
+
+	response.into_err().await.into()
+}
+
+/// Writes `target` by decompressing the zstd delta, which was made with
+/// `zstd --patch-from` against `base`.
+fn apply_zstd_delta(base: &Path, delta: &Path, target: &Path) -> Result<(), WrappedError> {
+	let base = std::fs::read(base).map_err(|e| wrap(e, "error reading the CLI to patch"))?;
+	let delta = std::fs::File::open(delta).map_err(|e| wrap(e, "error opening delta"))?;
+	let mut decoder =
+		zstd::stream::read::Decoder::with_dictionary(std::io::BufReader::new(delta), &base)
+			.map_err(|e| wrap(e, "error reading delta"))?;
+	// deltas of large binaries need windows larger than the default limit
+	decoder
+		.window_log_max(31)
+		.map_err(|e| wrap(e, "error reading delta"))?;
+
+	let mut out =
+		std::fs::File::create(target).map_err(|e| wrap(e, "error creating patched CLI"))?;
+	std::io::copy(&mut decoder, &mut out).map_err(|e| wrap(e, "error applying delta"))?;
+	Ok(())
+}
+
 pub fn unzip_downloaded_release<T>(
 	compressed_file: &Path,
 	target_dir: &Path,
@@ -214,8 +661,25 @@ pub enum Platform {
 	WindowsX64,
 	WindowsX86,
 	WindowsARM64,
+	LinuxRISCV64,
+	LinuxPPC64LE,
 }
 
+const ALL_PLATFORMS: [Platform; 12] = [
+	Platform::LinuxAlpineX64,
+	Platform::LinuxAlpineARM64,
+	Platform::LinuxX64,
+	Platform::LinuxARM64,
+	Platform::LinuxARM32,
+	Platform::DarwinX64,
+	Platform::DarwinARM64,
+	Platform::WindowsX64,
+	Platform::WindowsX86,
+	Platform::WindowsARM64,
+	Platform::LinuxRISCV64,
+	Platform::LinuxPPC64LE,
+];
+
 impl Platform {
 	pub fn archive(&self) -> Option<String> {
 		match self {
@@ -242,6 +706,8 @@ impl Platform {
 			Platform::WindowsX64 => "server-win32-x64",
 			Platform::WindowsX86 => "server-win32",
 			Platform::WindowsARM64 => "server-win32-x64", // we don't publish an arm64 server build yet
+			Platform::LinuxRISCV64 => "server-linux-riscv64",
+			Platform::LinuxPPC64LE => "server-linux-ppc64le",
 		}
 		.to_owned()
 	}
@@ -258,10 +724,18 @@ impl Platform {
 			Platform::WindowsARM64 => "cli-win32-arm64",
 			Platform::WindowsX64 => "cli-win32-x64",
 			Platform::WindowsX86 => "cli-win32",
+			Platform::LinuxRISCV64 => "cli-linux-riscv64",
+			Platform::LinuxPPC64LE => "cli-linux-ppc64le",
 		}
 		.to_owned()
 	}
 
+	/// Gets whether builds for the platform are published. Others can only be
+	/// downloaded from mirrors or custom qualities that serve them.
+	pub fn is_published(&self) -> bool {
+		!matches!(self, Platform::LinuxRISCV64 | Platform::LinuxPPC64LE)
+	}
+
 	pub fn web(&self) -> String {
 		format!("{}-web", self.headless())
 	}
@@ -285,6 +759,14 @@ impl Platform {
 			Some(Platform::LinuxARM32)
 		} else if cfg!(all(target_os = "linux", target_arch = "aarch64")) {
 			Some(Platform::LinuxARM64)
+		} else if cfg!(all(target_os = "linux", target_arch = "riscv64")) {
+			Some(Platform::LinuxRISCV64)
+		} else if cfg!(all(
+			target_os = "linux",
+			target_arch = "powerpc64",
+			target_endian = "little"
+		)) {
+			Some(Platform::LinuxPPC64LE)
 		} else if cfg!(all(target_os = "macos", target_arch = "x86_64")) {
 			Some(Platform::DarwinX64)
 		} else if cfg!(all(target_os = "macos", target_arch = "aarch64")) {
@@ -314,6 +796,122 @@ impl fmt::Display for Platform {
 			Platform::WindowsX64 => "WindowsX64",
 			Platform::WindowsX86 => "WindowsX86",
 			Platform::WindowsARM64 => "WindowsARM64",
+			Platform::LinuxRISCV64 => "LinuxRISCV64",
+			Platform::LinuxPPC64LE => "LinuxPPC64LE",
 		})
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::state::LauncherPaths;
+
+	#[test]
+	fn test_apply_zstd_delta() {
+		let dir = tempfile::tempdir().unwrap();
+		let base: Vec<u8> = (0..100_000u32).flat_map(|i| i.to_le_bytes()).collect();
+		let mut updated = base.clone();
+		updated[1234..1240].copy_from_slice(b"update");
+
+		let mut encoder =
+			zstd::stream::write::Encoder::with_dictionary(Vec::new(), 19, &base).unwrap();
+		std::io::Write::write_all(&mut encoder, &updated).unwrap();
+		let delta = encoder.finish().unwrap();
+		assert!(delta.len() < 1000);
+
+		std::fs::write(dir.path().join("base"), &base).unwrap();
+		std::fs::write(dir.path().join("delta"), &delta).unwrap();
+		apply_zstd_delta(
+			&dir.path().join("base"),
+			&dir.path().join("delta"),
+			&dir.path().join("updated"),
+		)
+		.unwrap();
+		assert_eq!(std::fs::read(dir.path().join("updated")).unwrap(), updated);
+	}
+
+	#[test]
+	fn test_parse_platform() {
+		assert_eq!(parse_platform("DarwinX64"), Ok(Platform::DarwinX64));
+		assert_eq!(parse_platform("linuxx64"), Ok(Platform::LinuxX64));
+		assert!(parse_platform("linux-x64").is_err());
+	}
+
+	#[test]
+	fn test_is_release_version() {
+		assert!(is_release_version("1.86.2"));
+		assert!(is_release_version("1.86"));
+		assert!(!is_release_version("1"));
+		assert!(!is_release_version("1.86.2.1"));
+		assert!(!is_release_version("1..2"));
+		assert!(!is_release_version("1.86/../x"));
+		assert!(!is_release_version("latest"));
+	}
+
+	#[test]
+	fn test_load_custom_qualities() {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		std::fs::write(
+			paths.config_file(),
+			r#"{"custom_qualities":{
+				"canary":{"quality":"insiders","update_endpoint":"https://updates.example.com/"},
+				"stable":{"quality":"stable"},
+				"a/b":{"quality":"stable"}
+			}}"#,
+		)
+		.unwrap();
+
+		let qualities = load_custom_qualities(&CliConfig::load(&paths).unwrap());
+		assert_eq!(qualities.len(), 1);
+		let canary = &qualities["canary"];
+		assert_eq!(canary.quality, options::Quality::Insiders);
+		assert_eq!(
+			canary.update_endpoint.as_deref(),
+			Some("https://updates.example.com")
+		);
+	}
+
+	#[test]
+	fn test_load_download_mirror() {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let load = |arg: Option<&str>| load_download_mirror(&CliConfig::load(&paths).unwrap(), arg);
+		assert_eq!(load(None), None);
+
+		std::fs::write(
+			paths.config_file(),
+			r#"{"download_mirror":"https://mirror.example.com/vscode/"}"#,
+		)
+		.unwrap();
+		assert_eq!(
+			load(None).as_deref(),
+			Some("https://mirror.example.com/vscode")
+		);
+		assert_eq!(
+			load(Some("https://other.example.com")).as_deref(),
+			Some("https://other.example.com")
+		);
+		assert!(check_download_mirror("https://mirror.example.com/vscode").is_ok());
+		assert!(check_download_mirror("mirror.example.com").is_err());
+
+		std::fs::write(
+			paths.config_file(),
+			r#"{"update_endpoint":"http://artifacts.internal/vscode"}"#,
+		)
+		.unwrap();
+		assert_eq!(
+			load(None).as_deref(),
+			Some("http://artifacts.internal/vscode")
+		);
+
+		// the old name doesn't clash with the new one, which is preferred
+		std::fs::write(
+			paths.config_file(),
+			r#"{"download_mirror":"https://mirror.example.com","update_endpoint":"http://artifacts.internal"}"#,
+		)
+		.unwrap();
+		assert_eq!(load(None).as_deref(), Some("https://mirror.example.com"));
+	}
+}
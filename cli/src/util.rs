@@ -7,13 +7,20 @@ mod is_integrated;
 
 pub mod command;
 pub mod errors;
+pub mod event_store;
+pub mod hash;
 pub mod http;
 pub mod input;
 pub mod io;
 pub mod machine;
+pub mod permissions;
 pub mod prereqs;
+pub mod rate_limit;
+pub mod redact;
 pub mod ring_buffer;
+pub mod runtime_dir;
 pub mod sync;
+pub mod tags;
 pub use is_integrated::*;
 pub mod app_lock;
 pub mod file_lock;
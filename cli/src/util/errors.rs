@@ -4,8 +4,11 @@
  *--------------------------------------------------------------------------------------------*/
 use crate::{
 	constants::{APPLICATION_NAME, CONTROL_PORT, DOCUMENTATION_URL, QUALITYLESS_PRODUCT_NAME},
+	options::Quality,
 	rpc::ResponseError,
+	tunnels::protocol::ProcessSignal,
 };
+use serde::{Deserialize, Serialize};
 use std::fmt::Display;
 use thiserror::Error;
 
@@ -14,6 +17,8 @@ use thiserror::Error;
 pub struct WrappedError {
 	message: String,
 	original: String,
+	/// Whether the original error came from a network request.
+	network: bool,
 }
 
 impl std::fmt::Display for WrappedError {
@@ -42,6 +47,7 @@ impl From<reqwest::Error> for WrappedError {
 				e.url().map_or("<unknown>", |u| u.as_str())
 			),
 			original: format!("{}", e),
+			network: true,
 		}
 	}
 }
@@ -54,6 +60,7 @@ where
 	WrappedError {
 		message: message.into(),
 		original: format!("{:?}", original),
+		network: false,
 	}
 }
 
@@ -65,6 +72,7 @@ where
 	WrappedError {
 		message: message.into(),
 		original: format!("{}", original),
+		network: false,
 	}
 }
 
@@ -450,6 +458,8 @@ pub enum CodeError {
 	AsyncPipeFailed(std::io::Error),
 	#[error("could not listen on socket/pipe: {0:?}")]
 	AsyncPipeListenerFailed(std::io::Error),
+	#[error("permission denied using socket {path}: {hint}")]
+	SocketAccessDenied { path: String, hint: String },
 	#[error("could not create singleton lock file: {0:?}")]
 	SingletonLockfileOpenFailed(std::io::Error),
 	#[error("could not read singleton lock file: {0:?}")]
@@ -482,6 +492,108 @@ pub enum CodeError {
 
 	#[error("download appears corrupted, please retry ({0})")]
 	CorruptDownload(&'static str),
+
+	#[error("forwarding to {0} is not allowed, the tunnel must be started with --allow-forward-socket covering it")]
+	ForwardSocketNotAllowed(String),
+
+	#[error("no process with pid {0} was spawned on this connection")]
+	NoSuchSpawnedProcess(u32),
+	#[error("failed to send {0:?} to the process")]
+	ProcessSignalFailed(ProcessSignal),
+	#[error("{0:?} is not supported on this platform")]
+	UnsupportedSignal(ProcessSignal),
+	#[error("checksum mismatch in file chunk at offset {0}")]
+	ChunkChecksumMismatch(u64),
+	#[error("checksum of {0} did not match after transfer")]
+	TransferChecksumMismatch(String),
+	#[error("{0} is larger than {1} bytes, download it in chunks instead")]
+	FileTooLarge(String, u64),
+	#[error("this build of the CLI does not support pseudoterminals")]
+	PtyNotSupported,
+	#[error("no clipboard is available; the host must have a graphical session")]
+	ClipboardUnavailable,
+	#[error("invalid VS Code server arguments: {0}")]
+	InvalidServerArgs(String),
+	#[error("no session with id {0} is connected")]
+	NoSuchSession(u32),
+	#[error("remote shutdown is disabled, the tunnel must be started with --admin-token")]
+	RemoteShutdownDisabled,
+	#[error("the admin token is incorrect")]
+	InvalidAdminToken,
+	#[error("this command can only be run in a process started by a tunnel client")]
+	EditorHookUnavailable,
+	#[error("the request was cancelled")]
+	RequestCancelled,
+	#[error("too many calls to {0}, try again later")]
+	RateLimited(String),
+	#[error("the server at {0} can't be used: {1}")]
+	InvalidPrebuiltServer(String, String),
+	#[error("using the server at {0} is not allowed, the tunnel must be started with --allow-server-path covering it")]
+	ServerPathNotAllowed(String),
+	#[error("{0} server commit {1} is not allowed by this host's policy")]
+	ServerVersionNotAllowed(Quality, String),
+	#[error("message of {0} bytes is larger than the limit of {1} bytes")]
+	FrameTooLarge(usize, usize),
+	#[error(
+		"the connection is using several servers, give the commit_id or instance of the one to use"
+	)]
+	AmbiguousCodeServer,
+	#[error("the client broke the protocol: {0}")]
+	ProtocolViolation(String),
+	#[error("connecting to the server at {0} is not allowed, the tunnel must be started with --allow-server-socket giving it")]
+	ServerSocketNotAllowed(String),
+	#[error("a commit_id must be given to use a server that's already running")]
+	ExternalServerCommitRequired,
+	#[error("the client must call identify before using client state")]
+	ClientNotIdentified,
+	#[error("client state would be {0} bytes, which is larger than the limit of {1} bytes")]
+	ClientStateTooLarge(usize, usize),
+	#[error("request body of {0} bytes is larger than the limit of {1} bytes for requests made through the client")]
+	DelegatedBodyTooLarge(usize, usize),
+	#[error("too many redirects, the last was to {0}")]
+	TooManyRedirects(String),
+	#[error("the update service didn't give a hash for {0}, so the download can't be verified")]
+	DownloadHashMissing(String),
+	#[error("download of {name} has SHA-256 hash {actual}, but {expected} was expected")]
+	DownloadHashMismatch {
+		name: String,
+		expected: String,
+		actual: String,
+	},
+	#[error("a commit_id must be given to install a server from an archive")]
+	ServerArchiveCommitRequired,
+	#[error("'{0}' is not a release version, such as 1.86.2")]
+	InvalidReleaseVersion(String),
+	#[error("quality '{0}' isn't in the custom_qualities of this host's config")]
+	UnknownCustomQuality(String),
+	#[error("{0} is disabled, the tunnel must be started with --admin-token")]
+	AdminTokenRequired(&'static str),
+	#[error("servers don't use a connection token file, the tunnel must be started with --use-connection-token-file")]
+	ConnectionTokenFileNotUsed,
+	#[error("no builds are published for {0}. Use --download-mirror with a mirror that serves them, or --use-server-path with a server built for this machine")]
+	NoPublishedBuilds(String),
+	#[error("the server on {path} is run by another user (uid {uid}), refusing to connect")]
+	SocketPeerNotTrusted { path: String, uid: u32 },
+	#[error("there's no previous version of the CLI to roll back to. It's kept when the CLI updates itself")]
+	NoPreviousCli,
+	#[error("the signature of the downloaded CLI couldn't be verified: {0}. Use --allow-unsigned-updates to install it anyway")]
+	UpdateSignatureNotVerified(String),
+	#[error("download mirror '{0}' isn't an http or https URL")]
+	InvalidDownloadMirror(String),
+	#[error("response from {0} is larger than the limit of {1} bytes")]
+	ResponseTooLarge(String, u64),
+	#[error("forwarding to host {0} is not allowed, the tunnel must be started with --allow-forward-host giving it")]
+	ForwardHostNotAllowed(String),
+	#[error("the CLI is already being updated")]
+	UpdateInProgress,
+	#[error("config file {0} is invalid: {1}")]
+	InvalidConfigFile(String, String),
+	#[error("this host's policy only allows version {0} of the server")]
+	ServerVersionPinned(String),
+	#[error("{0} server {1} is already running with other settings, start an isolated instance to use different ones")]
+	ServerSettingsMismatch(Quality, String),
+	#[error("the tunnel is restarting and isn't setting up servers, try again once it's back")]
+	InstallsDraining,
 }
 
 makeAnyError!(
@@ -521,3 +633,192 @@ impl From<reqwest::Error> for AnyError {
 		AnyError::WrappedError(WrappedError::from(e))
 	}
 }
+
+/// Broad classes of errors, which clients can use to decide how to handle a
+/// failed call.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorCategory {
+	/// The request was malformed or can't be made in the current state.
+	InvalidRequest,
+	/// Something the call refers to, like a server or process, doesn't exist.
+	NotFound,
+	/// The caller isn't allowed to make the call, or needs to sign in.
+	PermissionDenied,
+	/// The host or this build of the CLI doesn't support the call.
+	Unsupported,
+	/// A network request or download failed.
+	Network,
+	/// The tunnel or the port forwarding service failed.
+	Tunnel,
+	/// Running a process or accessing files on the host failed.
+	Host,
+	Cancelled,
+	Internal,
+}
+
+/// Machine-readable description of an error, sent with RPC error responses.
+/// Codes are stable across versions, and new errors are given new codes.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErrorDetails {
+	pub code: u32,
+	pub category: ErrorCategory,
+	/// Whether the same call may succeed if it's made again.
+	pub retryable: bool,
+}
+
+impl ErrorDetails {
+	pub const INVALID_PARAMS: ErrorDetails = details(1, ErrorCategory::InvalidRequest, false);
+	pub const METHOD_NOT_FOUND: ErrorDetails = details(2, ErrorCategory::InvalidRequest, false);
+	pub const INTERNAL: ErrorDetails = details(3, ErrorCategory::Internal, false);
+	pub const NETWORK: ErrorDetails = details(4, ErrorCategory::Network, true);
+}
+
+const fn details(code: u32, category: ErrorCategory, retryable: bool) -> ErrorDetails {
+	ErrorDetails {
+		code,
+		category,
+		retryable,
+	}
+}
+
+impl AnyError {
+	pub fn details(&self) -> ErrorDetails {
+		use ErrorCategory::*;
+		match self {
+			AnyError::WrappedError(e) if e.network => ErrorDetails::NETWORK,
+			AnyError::WrappedError(_) => ErrorDetails::INTERNAL,
+			AnyError::MissingLegalConsent(_) => details(100, PermissionDenied, false),
+			AnyError::MismatchConnectionToken(_) => details(101, PermissionDenied, false),
+			AnyError::DevTunnelError(_) => details(102, Tunnel, true),
+			AnyError::StatusError(e) => {
+				details(103, Network, e.status_code >= 500 || e.status_code == 429)
+			}
+			AnyError::InvalidServerExtensionError(_) => details(104, InvalidRequest, false),
+			AnyError::MissingEntrypointError(_) => details(105, Host, false),
+			AnyError::SetupError(_) => details(106, Host, false),
+			AnyError::NoHomeForLauncherError(_) => details(107, Host, false),
+			AnyError::TunnelCreationFailed(_) => details(108, Tunnel, true),
+			AnyError::TunnelHostFailed(_) => details(109, Tunnel, true),
+			AnyError::InvalidTunnelName(_) => details(110, InvalidRequest, false),
+			AnyError::ExtensionInstallFailed(_) => details(111, Host, false),
+			AnyError::MismatchedLaunchModeError(_) => details(112, InvalidRequest, false),
+			AnyError::NoAttachedServerError(_) => details(113, NotFound, false),
+			AnyError::RefreshTokenNotAvailableError(_) => details(114, PermissionDenied, false),
+			AnyError::NoInstallInUserProvidedPath(_) => details(115, NotFound, false),
+			AnyError::UserCancelledInstallation(_) => details(116, Cancelled, false),
+			AnyError::InvalidRequestedVersion(_) => details(117, InvalidRequest, false),
+			AnyError::CannotForwardControlPort(_) => details(118, InvalidRequest, false),
+			AnyError::ServerHasClosed(_) => details(119, Host, true),
+			AnyError::ServiceAlreadyRegistered(_) => details(120, InvalidRequest, false),
+			AnyError::WindowsNeedsElevation(_) => details(121, PermissionDenied, false),
+			AnyError::UpdatesNotConfigured(_) => details(122, Unsupported, false),
+			AnyError::CorruptDownload(_) => details(123, Network, true),
+			AnyError::MissingHomeDirectory(_) => details(124, Host, false),
+			AnyError::OAuthError(_) => details(125, PermissionDenied, false),
+			AnyError::InvalidRpcDataError(_) => details(126, InvalidRequest, false),
+			AnyError::CodeError(e) => e.details(),
+		}
+	}
+}
+
+impl CodeError {
+	pub fn details(&self) -> ErrorDetails {
+		use ErrorCategory::*;
+		match self {
+			CodeError::AsyncPipeFailed(_) => details(200, Host, true),
+			CodeError::AsyncPipeListenerFailed(_) => details(201, Host, false),
+			CodeError::SocketAccessDenied { .. } => details(202, PermissionDenied, false),
+			CodeError::SingletonLockfileOpenFailed(_) => details(203, Host, false),
+			CodeError::SingletonLockfileReadFailed(_) => details(204, Host, false),
+			CodeError::SingletonLockedProcessExited(_) => details(205, NotFound, true),
+			CodeError::NoRunningTunnel => details(206, NotFound, false),
+			// keep the details the remote gave, so they pass through proxies
+			CodeError::TunnelRpcCallFailed(e) => e.data.unwrap_or(ErrorDetails::INTERNAL),
+			#[cfg(windows)]
+			CodeError::AppAlreadyLocked(_) => details(207, Host, false),
+			#[cfg(windows)]
+			CodeError::AppLockFailed(_) => details(208, Host, false),
+			CodeError::CommandFailed { .. } => details(209, Host, false),
+			CodeError::UnsupportedPlatform(_) => details(210, Unsupported, false),
+			CodeError::PrerequisitesFailed { .. } => details(211, Unsupported, false),
+			CodeError::ProcessSpawnFailed(_) => details(212, Host, false),
+			CodeError::CorruptDownload(_) => details(213, Network, true),
+			CodeError::ForwardSocketNotAllowed(_) => details(214, PermissionDenied, false),
+			CodeError::NoSuchSpawnedProcess(_) => details(215, NotFound, false),
+			CodeError::ProcessSignalFailed(_) => details(216, Host, false),
+			CodeError::UnsupportedSignal(_) => details(217, Unsupported, false),
+			CodeError::ChunkChecksumMismatch(_) => details(218, Network, true),
+			CodeError::TransferChecksumMismatch(_) => details(219, Network, true),
+			CodeError::FileTooLarge(..) => details(220, InvalidRequest, false),
+			CodeError::PtyNotSupported => details(221, Unsupported, false),
+			CodeError::ClipboardUnavailable => details(222, Unsupported, false),
+			CodeError::InvalidServerArgs(_) => details(223, InvalidRequest, false),
+			CodeError::NoSuchSession(_) => details(224, NotFound, false),
+			CodeError::RemoteShutdownDisabled => details(225, Unsupported, false),
+			CodeError::InvalidAdminToken => details(226, PermissionDenied, false),
+			CodeError::EditorHookUnavailable => details(227, Unsupported, false),
+			CodeError::RequestCancelled => details(228, Cancelled, false),
+			CodeError::RateLimited(_) => details(229, InvalidRequest, true),
+			CodeError::InvalidPrebuiltServer(_, _) => details(230, InvalidRequest, false),
+			CodeError::ServerPathNotAllowed(_) => details(231, PermissionDenied, false),
+			CodeError::ServerVersionNotAllowed(_, _) => details(232, PermissionDenied, false),
+			CodeError::FrameTooLarge(_, _) => details(233, InvalidRequest, false),
+			CodeError::AmbiguousCodeServer => details(234, InvalidRequest, false),
+			CodeError::ProtocolViolation(_) => details(235, InvalidRequest, false),
+			CodeError::ServerSocketNotAllowed(_) => details(236, PermissionDenied, false),
+			CodeError::ExternalServerCommitRequired => details(237, InvalidRequest, false),
+			CodeError::ClientNotIdentified => details(238, InvalidRequest, false),
+			CodeError::ClientStateTooLarge(_, _) => details(239, InvalidRequest, false),
+			CodeError::DelegatedBodyTooLarge(_, _) => details(240, InvalidRequest, false),
+			CodeError::TooManyRedirects(_) => details(241, Network, false),
+			CodeError::DownloadHashMissing(_) => details(242, Network, false),
+			CodeError::DownloadHashMismatch { .. } => details(243, Network, true),
+			CodeError::ServerArchiveCommitRequired => details(244, InvalidRequest, false),
+			CodeError::InvalidReleaseVersion(_) => details(245, InvalidRequest, false),
+			CodeError::UnknownCustomQuality(_) => details(246, NotFound, false),
+			CodeError::AdminTokenRequired(_) => details(247, Unsupported, false),
+			CodeError::ConnectionTokenFileNotUsed => details(248, Unsupported, false),
+			CodeError::NoPublishedBuilds(_) => details(249, Unsupported, false),
+			CodeError::SocketPeerNotTrusted { .. } => details(250, PermissionDenied, false),
+			CodeError::NoPreviousCli => details(251, NotFound, false),
+			CodeError::UpdateSignatureNotVerified(_) => details(252, PermissionDenied, false),
+			CodeError::InvalidDownloadMirror(_) => details(253, InvalidRequest, false),
+			CodeError::ResponseTooLarge(_, _) => details(254, Network, false),
+			CodeError::ForwardHostNotAllowed(_) => details(255, PermissionDenied, false),
+			CodeError::UpdateInProgress => details(256, InvalidRequest, true),
+			CodeError::InvalidConfigFile(_, _) => details(257, Host, false),
+			CodeError::ServerVersionPinned(_) => details(258, PermissionDenied, false),
+			CodeError::ServerSettingsMismatch(_, _) => details(259, InvalidRequest, false),
+			CodeError::InstallsDraining => details(260, InvalidRequest, true),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_error_details() {
+		let no_server: AnyError = NoAttachedServerError().into();
+		assert_eq!(no_server.details().category, ErrorCategory::NotFound);
+
+		let unavailable: AnyError = StatusError {
+			url: "https://update.code.visualstudio.com".to_string(),
+			status_code: 503,
+			body: String::new(),
+		}
+		.into();
+		assert_eq!(unavailable.details().category, ErrorCategory::Network);
+		assert!(unavailable.details().retryable);
+
+		let remote: AnyError = CodeError::TunnelRpcCallFailed(ResponseError {
+			code: -1,
+			message: "no server".to_string(),
+			data: Some(no_server.details()),
+		})
+		.into();
+		assert_eq!(remote.details(), no_server.details());
+	}
+}
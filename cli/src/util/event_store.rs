@@ -0,0 +1,279 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! An append-only store for records that accumulate over the life of a
+//! long-running process, such as usage history. Records are written to
+//! segment files that are rolled once they reach a size limit, and the
+//! oldest segments are removed so the store stays bounded. Each record is
+//! checksummed, so a torn write from a crash is detected and discarded
+//! when the store is next opened.
+
+use std::{
+	fs::{self, File, OpenOptions},
+	io::{BufReader, Read, Write},
+	marker::PhantomData,
+	path::{Path, PathBuf},
+	sync::Mutex,
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{
+	errors::{wrap, WrappedError},
+	permissions::create_private_dir_all,
+};
+
+const SEGMENT_EXTENSION: &str = "seg";
+/// Length and CRC32, both little-endian u32s.
+const RECORD_HEADER_LEN: usize = 8;
+
+pub struct EventStore<T> {
+	dir: PathBuf,
+	max_segment_size: u64,
+	max_segments: usize,
+	active: Mutex<ActiveSegment>,
+	_record: PhantomData<T>,
+}
+
+struct ActiveSegment {
+	id: u64,
+	file: File,
+	len: u64,
+}
+
+impl<T> EventStore<T>
+where
+	T: Serialize + DeserializeOwned,
+{
+	/// Opens or creates a store in the directory. It keeps up to
+	/// `max_segments` segments of around `max_segment_size` bytes each.
+	pub fn open(
+		dir: PathBuf,
+		max_segment_size: u64,
+		max_segments: usize,
+	) -> Result<Self, WrappedError> {
+		create_private_dir_all(&dir)
+			.map_err(|e| wrap(e, format!("error creating {}", dir.display())))?;
+
+		let id = list_segments(&dir)?.last().copied().unwrap_or(0);
+		let path = segment_path(&dir, id);
+		let file = OpenOptions::new()
+			.read(true)
+			.append(true)
+			.create(true)
+			.open(&path)
+			.map_err(|e| wrap(e, format!("error opening {}", path.display())))?;
+
+		// drop anything after the last intact record, such as a write that
+		// was interrupted by a crash, so new records aren't appended after it.
+		let valid_len = read_segment::<T>(&path)?.1;
+		let len = file
+			.metadata()
+			.map_err(|e| wrap(e, format!("error reading {}", path.display())))?
+			.len();
+		if valid_len < len {
+			file.set_len(valid_len)
+				.map_err(|e| wrap(e, format!("error truncating {}", path.display())))?;
+		}
+
+		Ok(EventStore {
+			dir,
+			max_segment_size,
+			max_segments: max_segments.max(1),
+			active: Mutex::new(ActiveSegment {
+				id,
+				file,
+				len: valid_len,
+			}),
+			_record: PhantomData,
+		})
+	}
+
+	pub fn append(&self, record: &T) -> Result<(), WrappedError> {
+		let payload = rmp_serde::to_vec_named(record)
+			.map_err(|e| wrap(e, "error serializing event record"))?;
+
+		let mut buf = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+		buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+		buf.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+		buf.extend_from_slice(&payload);
+
+		let mut active = self.active.lock().unwrap();
+		if active.len > 0 && active.len + buf.len() as u64 > self.max_segment_size {
+			self.roll(&mut active)?;
+		}
+
+		// written in a single call so a crash leaves at most one torn record
+		active
+			.file
+			.write_all(&buf)
+			.map_err(|e| wrap(e, "error writing event record"))?;
+		active.len += buf.len() as u64;
+		Ok(())
+	}
+
+	/// Reads every record in the store, oldest first.
+	pub fn read_all(&self) -> Result<Vec<T>, WrappedError> {
+		self.read_last(usize::MAX)
+	}
+
+	/// Reads up to the `n` most recent records, oldest first. Only as many
+	/// segments as are needed are read.
+	pub fn read_last(&self, n: usize) -> Result<Vec<T>, WrappedError> {
+		// holding the lock keeps segments from being removed while reading
+		let _active = self.active.lock().unwrap();
+
+		let mut out: Vec<T> = vec![];
+		for id in list_segments(&self.dir)?.into_iter().rev() {
+			if out.len() >= n {
+				break;
+			}
+
+			let (mut records, _) = read_segment::<T>(&segment_path(&self.dir, id))?;
+			let skip = records.len().saturating_sub(n - out.len());
+			records.drain(..skip);
+			records.append(&mut out);
+			out = records;
+		}
+
+		Ok(out)
+	}
+
+	/// Starts a new segment, removing the oldest ones beyond the limit.
+	fn roll(&self, active: &mut ActiveSegment) -> Result<(), WrappedError> {
+		let id = active.id + 1;
+		let path = segment_path(&self.dir, id);
+		active.file = OpenOptions::new()
+			.read(true)
+			.append(true)
+			.create(true)
+			.open(&path)
+			.map_err(|e| wrap(e, format!("error opening {}", path.display())))?;
+		active.id = id;
+		active.len = 0;
+
+		let segments = list_segments(&self.dir)?;
+		let excess = segments.len().saturating_sub(self.max_segments);
+		for id in &segments[..excess] {
+			let path = segment_path(&self.dir, *id);
+			fs::remove_file(&path)
+				.map_err(|e| wrap(e, format!("error removing {}", path.display())))?;
+		}
+
+		Ok(())
+	}
+}
+
+/// Reads the intact records in the segment, returning them along with the
+/// length of the segment they occupy. Reading stops at the first record
+/// that's incomplete or fails its checksum.
+fn read_segment<T: DeserializeOwned>(path: &Path) -> Result<(Vec<T>, u64), WrappedError> {
+	let file = match File::open(path) {
+		Ok(f) => f,
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((vec![], 0)),
+		Err(e) => return Err(wrap(e, format!("error opening {}", path.display()))),
+	};
+
+	let segment_len = file
+		.metadata()
+		.map_err(|e| wrap(e, format!("error reading {}", path.display())))?
+		.len();
+	if segment_len == 0 {
+		return Ok((vec![], 0));
+	}
+
+	// records are checksummed, so concurrent appends are at worst seen as an
+	// incomplete trailing record
+	let mut reader = BufReader::new(file);
+	let mut records = vec![];
+	let mut offset = 0;
+	let mut header = [0u8; RECORD_HEADER_LEN];
+	let mut payload = vec![];
+	loop {
+		if reader.read_exact(&mut header).is_err() {
+			break;
+		}
+		let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as u64;
+		let crc = u32::from_le_bytes(header[4..8].try_into().unwrap());
+		let end = offset + RECORD_HEADER_LEN as u64 + len;
+		// a torn length could otherwise ask for a huge buffer
+		if end > segment_len {
+			break;
+		}
+
+		payload.resize(len as usize, 0);
+		if reader.read_exact(&mut payload).is_err() || crc32fast::hash(&payload) != crc {
+			break;
+		}
+
+		// records that are intact but fail to parse are from an incompatible
+		// version, and are skipped
+		if let Ok(r) = rmp_serde::from_slice(&payload) {
+			records.push(r);
+		}
+		offset = end;
+	}
+
+	Ok((records, offset))
+}
+
+fn list_segments(dir: &Path) -> Result<Vec<u64>, WrappedError> {
+	let mut ids: Vec<u64> = fs::read_dir(dir)
+		.map_err(|e| wrap(e, format!("error reading {}", dir.display())))?
+		.filter_map(|e| e.ok())
+		.filter_map(|e| {
+			let path = e.path();
+			if path.extension()? != SEGMENT_EXTENSION {
+				return None;
+			}
+			u64::from_str_radix(path.file_stem()?.to_str()?, 16).ok()
+		})
+		.collect();
+	ids.sort_unstable();
+	Ok(ids)
+}
+
+fn segment_path(dir: &Path, id: u64) -> PathBuf {
+	dir.join(format!("{:016x}.{}", id, SEGMENT_EXTENSION))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_rolls_and_compacts() {
+		let dir = tempfile::tempdir().unwrap();
+		let store = EventStore::<u32>::open(dir.path().to_owned(), 20, 2).unwrap();
+		for i in 0..10 {
+			store.append(&i).unwrap();
+		}
+
+		// each record is 9 bytes, so segments hold two records and only the
+		// last two segments are kept
+		assert_eq!(list_segments(dir.path()).unwrap().len(), 2);
+		assert_eq!(store.read_all().unwrap(), vec![6, 7, 8, 9]);
+		assert_eq!(store.read_last(3).unwrap(), vec![7, 8, 9]);
+	}
+
+	#[test]
+	fn test_recovers_from_torn_write() {
+		let dir = tempfile::tempdir().unwrap();
+		{
+			let store = EventStore::<String>::open(dir.path().to_owned(), 1024, 2).unwrap();
+			store.append(&"a".to_string()).unwrap();
+			store.append(&"b".to_string()).unwrap();
+		}
+
+		let path = segment_path(dir.path(), 0);
+		let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+		file.write_all(&[5, 0, 0, 0, 1, 2]).unwrap();
+		drop(file);
+
+		let store = EventStore::<String>::open(dir.path().to_owned(), 1024, 2).unwrap();
+		store.append(&"c".to_string()).unwrap();
+		assert_eq!(store.read_all().unwrap(), vec!["a", "b", "c"]);
+	}
+}
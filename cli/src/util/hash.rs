@@ -0,0 +1,92 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+use tokio::{fs::File, io::AsyncReadExt};
+
+use super::errors::{wrap, AnyError, CodeError};
+
+/// SHA-256 hash of the start of a file.
+pub struct FileHash {
+	/// Size of the whole file.
+	pub size: u64,
+	/// Number of bytes that were hashed.
+	pub length: u64,
+	/// Lowercase hex of the hash.
+	pub sha256: String,
+}
+
+/// Hashes the first `length` bytes of the file, or all of it if None.
+pub async fn hash_file(path: &Path, length: Option<u64>) -> std::io::Result<FileHash> {
+	let file = File::open(path).await?;
+	let size = file.metadata().await?.len();
+
+	let mut reader = file.take(length.unwrap_or(u64::MAX));
+	let mut hash = Sha256::new();
+	let mut buf = vec![0; 64 * 1024];
+	let mut hashed = 0;
+	loop {
+		let n = reader.read(&mut buf).await?;
+		if n == 0 {
+			break;
+		}
+		hash.update(&buf[..n]);
+		hashed += n as u64;
+	}
+
+	Ok(FileHash {
+		size,
+		length: hashed,
+		sha256: format!("{:x}", hash.finalize()),
+	})
+}
+
+/// Checks that the file's SHA-256 hash is the `expected` hex, failing with a
+/// `DownloadHashMismatch` that names the file as `name` if it isn't.
+pub async fn verify_file_hash(path: &Path, expected: &str, name: &str) -> Result<(), AnyError> {
+	let actual = hash_file(path, None)
+		.await
+		.map_err(|e| wrap(e, format!("error hashing {}", name)))?
+		.sha256;
+
+	if !actual.eq_ignore_ascii_case(expected) {
+		return Err(CodeError::DownloadHashMismatch {
+			name: name.to_string(),
+			expected: expected.to_string(),
+			actual,
+		}
+		.into());
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn test_verify_file_hash() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("archive");
+		std::fs::write(&path, b"hello").unwrap();
+
+		let hello = "2CF24DBA5FB0A30E26E83B2AC5B9E29E1B161E5C1FA7425E73043362938B9824";
+		verify_file_hash(&path, hello, "archive").await.unwrap();
+
+		let res = verify_file_hash(&path, &"0".repeat(64), "archive").await;
+		assert!(matches!(
+			res,
+			Err(AnyError::CodeError(CodeError::DownloadHashMismatch { actual, .. }))
+				if actual == hello.to_lowercase()
+		));
+
+		let prefix = hash_file(&path, Some(2)).await.unwrap();
+		assert_eq!(prefix.size, 5);
+		assert_eq!(prefix.length, 2);
+	}
+}
@@ -9,26 +9,45 @@ use crate::{
 };
 use async_trait::async_trait;
 use core::panic;
-use futures::stream::TryStreamExt;
+use futures::stream::{self, StreamExt, TryStreamExt};
 use hyper::{
-	header::{HeaderName, CONTENT_LENGTH},
+	header::{HeaderName, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, LOCATION, RANGE},
 	http::HeaderValue,
 	HeaderMap, StatusCode,
 };
 use serde::de::DeserializeOwned;
-use std::{io, pin::Pin, str::FromStr, sync::Arc, task::Poll};
+use std::{
+	io,
+	path::Path,
+	pin::Pin,
+	str::FromStr,
+	sync::{Arc, Mutex},
+	task::Poll,
+	time::Duration,
+};
 use tokio::{
 	fs,
-	io::{AsyncRead, AsyncReadExt},
+	io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
 	sync::mpsc,
 };
 use tokio_util::compat::FuturesAsyncReadCompatExt;
 
 use super::{
-	errors::{wrap, AnyError, StatusError},
+	errors::{wrap, AnyError, CodeError, StatusError},
 	io::{copy_async_progress, ReadBuffer, ReportCopyProgress},
 };
 
+#[cfg(all(feature = "native-tls", feature = "rustls"))]
+compile_error!("only one of the `native-tls` and `rustls` features can be enabled");
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+compile_error!("one of the `native-tls` or `rustls` features must be enabled");
+
+/// Name of the TLS backend this build makes HTTP requests with.
+#[cfg(feature = "native-tls")]
+pub const TLS_BACKEND: &str = "native-tls";
+#[cfg(feature = "rustls")]
+pub const TLS_BACKEND: &str = "rustls";
+
 pub async fn download_into_file<T>(
 	filename: &std::path::Path,
 	progress: T,
@@ -41,18 +60,277 @@ where
 		.await
 		.map_err(|e| errors::wrap(e, "failed to create file"))?;
 
-	let content_length = res
+	let content_length = content_length(&res.headers);
+	copy_async_progress(progress, &mut res.read, &mut file, content_length)
+		.await
+		.map_err(|e| errors::wrap(e, "failed to download file"))?;
+
+	Ok(file)
+}
+
+/// Attempts made to download a file, each resuming where the last stopped.
+const DOWNLOAD_ATTEMPTS: u32 = 5;
+/// Delay before resuming a download, multiplied by the attempt number.
+const DOWNLOAD_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Downloads the response into the file like `download_into_file`, but if
+/// the download is interrupted, it's resumed from the partial file using
+/// Range requests to the response's URL instead of starting over.
+pub async fn download_into_file_resumable<T>(
+	http: &(dyn SimpleHttp + Send + Sync),
+	filename: &Path,
+	mut progress: T,
+	mut res: SimpleResponse,
+) -> Result<(), AnyError>
+where
+	T: ReportCopyProgress,
+{
+	let url = res.url.as_ref().map(|u| u.to_string());
+	let mut file = fs::File::create(filename)
+		.await
+		.map_err(|e| errors::wrap(e, "failed to create file"))?;
+	let mut written = 0;
+	let mut attempt = 1;
+
+	loop {
+		let reporter = OffsetProgress {
+			inner: &mut progress,
+			offset: written,
+		};
+		let total = written + content_length(&res.headers);
+		let mut err: AnyError =
+			match copy_async_progress(reporter, &mut res.read, &mut file, total).await {
+				Ok(_) => {
+					// the last write may still be running, and the file is read next
+					file.flush()
+						.await
+						.map_err(|e| errors::wrap(e, "failed to write file"))?;
+					return Ok(());
+				}
+				Err(e) => errors::wrap(e, "failed to download file").into(),
+			};
+
+		res = loop {
+			let url = match &url {
+				Some(url) if attempt < DOWNLOAD_ATTEMPTS => url.clone(),
+				_ => return Err(err),
+			};
+
+			attempt += 1;
+			tokio::time::sleep(DOWNLOAD_RETRY_DELAY * attempt).await;
+			file.flush()
+				.await
+				.map_err(|e| errors::wrap(e, "failed to write file"))?;
+			written = file
+				.metadata()
+				.await
+				.map_err(|e| errors::wrap(e, "failed to read file"))?
+				.len();
+
+			match http.make_range_request(url, written, None).await {
+				Ok(r) => break r,
+				Err(e) => err = e,
+			}
+		};
+
+		match res.status_code {
+			StatusCode::PARTIAL_CONTENT if resumes_at(&res.headers, written) => {}
+			// the server doesn't support ranges and sent the whole file again
+			StatusCode::OK => {
+				file.set_len(0)
+					.await
+					.map_err(|e| errors::wrap(e, "failed to truncate file"))?;
+				file.seek(io::SeekFrom::Start(0))
+					.await
+					.map_err(|e| errors::wrap(e, "failed to truncate file"))?;
+				written = 0;
+			}
+			_ => return Err(res.into_err().await.into()),
+		}
+	}
+}
+
+/// Size of each chunk of a download made over several connections.
+const DOWNLOAD_CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Downloads the response into the file in chunks, using up to `connections`
+/// concurrent Range requests to make better use of high-latency links. Falls
+/// back to a single resumable stream if the server or client doesn't support
+/// ranges, or the file fits in one chunk.
+pub async fn download_into_file_chunked<T>(
+	http: &(dyn SimpleHttp + Send + Sync),
+	filename: &Path,
+	progress: T,
+	res: SimpleResponse,
+	connections: usize,
+) -> Result<(), AnyError>
+where
+	T: ReportCopyProgress + Send,
+{
+	let total = content_length(&res.headers);
+	let accepts_ranges = res
 		.headers
+		.get(ACCEPT_RANGES)
+		.map_or(false, |v| v.as_bytes() == b"bytes");
+	let url = match &res.url {
+		Some(url) if connections > 1 && accepts_ranges && total > DOWNLOAD_CHUNK_SIZE => {
+			url.to_string()
+		}
+		_ => return download_into_file_resumable(http, filename, progress, res).await,
+	};
+
+	// clients such as delegated ones may get the whole file for any request,
+	// even if the server advertises ranges
+	let ranges_work = match http.make_range_request(url.clone(), 0, Some(0)).await {
+		Ok(r) => r.status_code == StatusCode::PARTIAL_CONTENT && resumes_at(&r.headers, 0),
+		Err(_) => false,
+	};
+	if !ranges_work {
+		return download_into_file_resumable(http, filename, progress, res).await;
+	}
+	drop(res); // each chunk is requested on its own
+
+	let file = fs::File::create(filename)
+		.await
+		.map_err(|e| errors::wrap(e, "failed to create file"))?;
+	file.set_len(total)
+		.await
+		.map_err(|e| errors::wrap(e, "failed to create file"))?;
+
+	let progress = Mutex::new(SharedProgress {
+		inner: progress,
+		bytes_so_far: 0,
+	});
+	stream::iter((0..total).step_by(DOWNLOAD_CHUNK_SIZE as usize))
+		.map(|start| {
+			let end = (start + DOWNLOAD_CHUNK_SIZE).min(total);
+			download_chunk(http, &url, filename, start..end, total, &progress)
+		})
+		.buffer_unordered(connections)
+		.try_collect::<Vec<()>>()
+		.await?;
+
+	Ok(())
+}
+
+/// Downloads the range of the file, retrying it if it's interrupted.
+async fn download_chunk<T: ReportCopyProgress>(
+	http: &(dyn SimpleHttp + Send + Sync),
+	url: &str,
+	filename: &Path,
+	range: std::ops::Range<u64>,
+	total: u64,
+	progress: &Mutex<SharedProgress<T>>,
+) -> Result<(), AnyError> {
+	let mut file = fs::OpenOptions::new()
+		.write(true)
+		.open(filename)
+		.await
+		.map_err(|e| errors::wrap(e, "failed to open file"))?;
+	let len = range.end - range.start;
+
+	let mut attempt = 1;
+	loop {
+		let mut reporter = ChunkProgress {
+			shared: progress,
+			total,
+			reported: 0,
+		};
+		let result: Result<(), AnyError> = async {
+			let mut res = http
+				.make_range_request(url.to_string(), range.start, Some(range.end - 1))
+				.await?;
+			if res.status_code != StatusCode::PARTIAL_CONTENT
+				|| !resumes_at(&res.headers, range.start)
+			{
+				return Err(
+					CodeError::CorruptDownload("server didn't return the requested range").into(),
+				);
+			}
+
+			file.seek(io::SeekFrom::Start(range.start))
+				.await
+				.map_err(|e| errors::wrap(e, "failed to write file"))?;
+			let copied = copy_async_progress(&mut reporter, &mut res.read, &mut file, len)
+				.await
+				.map_err(|e| errors::wrap(e, "failed to download file"))?;
+			if copied != len {
+				return Err(CodeError::CorruptDownload("download chunk was cut short").into());
+			}
+
+			file.flush()
+				.await
+				.map_err(|e| errors::wrap(e, "failed to write file"))?;
+			Ok(())
+		}
+		.await;
+
+		match result {
+			Ok(()) => return Ok(()),
+			Err(e) if attempt >= DOWNLOAD_ATTEMPTS => return Err(e),
+			Err(_) => {
+				// the chunk is downloaded again, so don't count it twice
+				progress.lock().unwrap().bytes_so_far -= reporter.reported;
+				attempt += 1;
+				tokio::time::sleep(DOWNLOAD_RETRY_DELAY * attempt).await;
+			}
+		}
+	}
+}
+
+struct SharedProgress<T> {
+	inner: T,
+	bytes_so_far: u64,
+}
+
+/// Reports progress of a chunk as part of the progress of the whole file.
+struct ChunkProgress<'a, T> {
+	shared: &'a Mutex<SharedProgress<T>>,
+	total: u64,
+	reported: u64,
+}
+
+impl<'a, T: ReportCopyProgress> ReportCopyProgress for ChunkProgress<'a, T> {
+	fn report_progress(&mut self, bytes_so_far: u64, _total_bytes: u64) {
+		let mut shared = self.shared.lock().unwrap();
+		shared.bytes_so_far += bytes_so_far - self.reported;
+		self.reported = bytes_so_far;
+		let bytes_so_far = shared.bytes_so_far;
+		shared.inner.report_progress(bytes_so_far, self.total);
+	}
+}
+
+fn content_length(headers: &HeaderMap) -> u64 {
+	headers
 		.get(CONTENT_LENGTH)
 		.and_then(|h| h.to_str().ok())
 		.and_then(|s| s.parse::<u64>().ok())
-		.unwrap_or(0);
+		.unwrap_or(0)
+}
 
-	copy_async_progress(progress, &mut res.read, &mut file, content_length)
-		.await
-		.map_err(|e| errors::wrap(e, "failed to download file"))?;
+/// Gets whether the Content-Range of a partial response starts at the offset.
+fn resumes_at(headers: &HeaderMap, offset: u64) -> bool {
+	headers
+		.get(CONTENT_RANGE)
+		.and_then(|h| h.to_str().ok())
+		.and_then(|s| s.strip_prefix("bytes "))
+		.and_then(|s| s.split_once('-'))
+		.and_then(|(start, _)| start.parse::<u64>().ok())
+		== Some(offset)
+}
 
-	Ok(file)
+/// Reports progress of a resumed download, counting what was downloaded
+/// before it was resumed.
+struct OffsetProgress<'a, T> {
+	inner: &'a mut T,
+	offset: u64,
+}
+
+impl<'a, T: ReportCopyProgress> ReportCopyProgress for OffsetProgress<'a, T> {
+	fn report_progress(&mut self, bytes_so_far: u64, total_bytes: u64) {
+		self.inner
+			.report_progress(self.offset + bytes_so_far, total_bytes)
+	}
 }
 
 pub struct SimpleResponse {
@@ -126,6 +404,27 @@ pub trait SimpleHttp {
 		method: &'static str,
 		url: String,
 	) -> Result<SimpleResponse, AnyError>;
+
+	/// Makes a request with a body, such as a POST or PUT.
+	async fn make_request_with_body(
+		&self,
+		method: &'static str,
+		url: String,
+		body: Vec<u8>,
+	) -> Result<SimpleResponse, AnyError>;
+
+	/// Makes a GET request for the bytes of the resource from `start` to the
+	/// inclusive `end`, or to the end of the resource if None. If ranges aren't
+	/// supported, the whole resource is requested, and the response has a 200
+	/// status rather than 206.
+	async fn make_range_request(
+		&self,
+		url: String,
+		_start: u64,
+		_end: Option<u64>,
+	) -> Result<SimpleResponse, AnyError> {
+		self.make_request("GET", url).await
+	}
 }
 
 pub type BoxedHttp = Arc<dyn SimpleHttp + Send + Sync + 'static>;
@@ -164,13 +463,43 @@ impl SimpleHttp for ReqwestSimpleHttp {
 		method: &'static str,
 		url: String,
 	) -> Result<SimpleResponse, AnyError> {
-		let res = self
+		let req = self
+			.client
+			.request(reqwest::Method::try_from(method).unwrap(), &url);
+		Ok(Self::to_response(req.send().await?))
+	}
+
+	async fn make_request_with_body(
+		&self,
+		method: &'static str,
+		url: String,
+		body: Vec<u8>,
+	) -> Result<SimpleResponse, AnyError> {
+		let req = self
 			.client
 			.request(reqwest::Method::try_from(method).unwrap(), &url)
-			.send()
-			.await?;
+			.body(body);
+		Ok(Self::to_response(req.send().await?))
+	}
 
-		Ok(SimpleResponse {
+	async fn make_range_request(
+		&self,
+		url: String,
+		start: u64,
+		end: Option<u64>,
+	) -> Result<SimpleResponse, AnyError> {
+		let range = match end {
+			Some(end) => format!("bytes={}-{}", start, end),
+			None => format!("bytes={}-", start),
+		};
+		let req = self.client.get(&url).header(RANGE, range);
+		Ok(Self::to_response(req.send().await?))
+	}
+}
+
+impl ReqwestSimpleHttp {
+	fn to_response(res: reqwest::Response) -> SimpleResponse {
+		SimpleResponse {
 			status_code: res.status(),
 			headers: res.headers().clone(),
 			url: Some(res.url().clone()),
@@ -180,7 +509,7 @@ impl SimpleHttp for ReqwestSimpleHttp {
 					.into_async_read()
 					.compat(),
 			),
-		})
+		}
 	}
 }
 
@@ -197,6 +526,7 @@ enum DelegatedHttpEvent {
 pub struct DelegatedHttpRequest {
 	pub method: &'static str,
 	pub url: String,
+	pub body: Option<Vec<u8>>,
 	ch: mpsc::UnboundedSender<DelegatedHttpEvent>,
 }
 
@@ -215,6 +545,11 @@ impl DelegatedHttpRequest {
 	}
 
 	pub fn end(self) {}
+
+	/// Gets whether the requester stopped waiting for the response.
+	pub fn is_closed(&self) -> bool {
+		self.ch.is_closed()
+	}
 }
 
 impl Drop for DelegatedHttpRequest {
@@ -223,11 +558,18 @@ impl Drop for DelegatedHttpRequest {
 	}
 }
 
+/// Default largest request body that's sent through the client.
+const DEFAULT_MAX_DELEGATED_BODY_SIZE: usize = 16 * 1024 * 1024;
+/// Redirects followed for a delegated request, for clients that don't follow
+/// them themselves.
+const MAX_DELEGATED_REDIRECTS: usize = 5;
+
 /// Implementation of SimpleHttp that allows manually controlling responses.
 #[derive(Clone)]
 pub struct DelegatedSimpleHttp {
 	start_request: mpsc::Sender<DelegatedHttpRequest>,
 	log: log::Logger,
+	max_body_size: usize,
 }
 
 impl DelegatedSimpleHttp {
@@ -237,18 +579,67 @@ impl DelegatedSimpleHttp {
 			DelegatedSimpleHttp {
 				log,
 				start_request: tx,
+				max_body_size: DEFAULT_MAX_DELEGATED_BODY_SIZE,
 			},
 			rx,
 		)
 	}
-}
 
-#[async_trait]
-impl SimpleHttp for DelegatedSimpleHttp {
-	async fn make_request(
+	/// Sets the largest request body that's sent through the client. Requests
+	/// with larger bodies fail without being sent.
+	pub fn with_max_body_size(mut self, max_body_size: usize) -> Self {
+		self.max_body_size = max_body_size;
+		self
+	}
+
+	/// Makes the request, following redirects the client returns.
+	async fn make_delegated_request(
+		&self,
+		mut method: &'static str,
+		mut url: String,
+		mut body: Option<Vec<u8>>,
+	) -> Result<SimpleResponse, AnyError> {
+		if let Some(b) = &body {
+			if b.len() > self.max_body_size {
+				return Err(CodeError::DelegatedBodyTooLarge(b.len(), self.max_body_size).into());
+			}
+		}
+
+		for _ in 0..MAX_DELEGATED_REDIRECTS {
+			let res = self
+				.make_single_request(method, url.clone(), body.clone())
+				.await?;
+			let location = res
+				.headers
+				.get(LOCATION)
+				.and_then(|l| l.to_str().ok())
+				.and_then(|l| res.url.as_ref()?.join(l).ok());
+			let location = match location {
+				Some(l) if res.status_code.is_redirection() => l,
+				_ => return Ok(res),
+			};
+
+			trace!(
+				self.log,
+				"delegated request to {} redirected to {}",
+				url,
+				location
+			);
+			if res.status_code == StatusCode::SEE_OTHER {
+				method = "GET";
+				body = None;
+			}
+			url = location.to_string();
+		}
+
+		Err(CodeError::TooManyRedirects(url).into())
+	}
+
+	async fn make_single_request(
 		&self,
 		method: &'static str,
 		url: String,
+		body: Option<Vec<u8>>,
 	) -> Result<SimpleResponse, AnyError> {
 		trace!(self.log, "making delegated request to {}", url);
 		let (tx, mut rx) = mpsc::unbounded_channel();
@@ -257,6 +648,7 @@ impl SimpleHttp for DelegatedSimpleHttp {
 			.send(DelegatedHttpRequest {
 				method,
 				url: url.clone(),
+				body,
 				ch: tx,
 			})
 			.await;
@@ -301,6 +693,26 @@ impl SimpleHttp for DelegatedSimpleHttp {
 	}
 }
 
+#[async_trait]
+impl SimpleHttp for DelegatedSimpleHttp {
+	async fn make_request(
+		&self,
+		method: &'static str,
+		url: String,
+	) -> Result<SimpleResponse, AnyError> {
+		self.make_delegated_request(method, url, None).await
+	}
+
+	async fn make_request_with_body(
+		&self,
+		method: &'static str,
+		url: String,
+		body: Vec<u8>,
+	) -> Result<SimpleResponse, AnyError> {
+		self.make_delegated_request(method, url, Some(body)).await
+	}
+}
+
 struct DelegatedReader {
 	receiver: mpsc::UnboundedReceiver<DelegatedHttpEvent>,
 	readbuf: ReadBuffer,
@@ -373,4 +785,106 @@ impl SimpleHttp for FallbackSimpleHttp {
 
 		self.delegated.make_request(method, url).await
 	}
+
+	async fn make_request_with_body(
+		&self,
+		method: &'static str,
+		url: String,
+		body: Vec<u8>,
+	) -> Result<SimpleResponse, AnyError> {
+		let r1 = self
+			.native
+			.make_request_with_body(method, url.clone(), body.clone())
+			.await;
+		if let Ok(res) = r1 {
+			if !res.status_code.is_server_error() {
+				return Ok(res);
+			}
+		}
+
+		self.delegated
+			.make_request_with_body(method, url, body)
+			.await
+	}
+
+	async fn make_range_request(
+		&self,
+		url: String,
+		start: u64,
+		end: Option<u64>,
+	) -> Result<SimpleResponse, AnyError> {
+		let r1 = self
+			.native
+			.make_range_request(url.clone(), start, end)
+			.await;
+		if let Ok(res) = r1 {
+			if !res.status_code.is_server_error() {
+				return Ok(res);
+			}
+		}
+
+		self.delegated.make_range_request(url, start, end).await
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn test_delegated_request_with_body() {
+		let (http, mut rx) = DelegatedSimpleHttp::new(log::Logger::test());
+		let responder = tokio::spawn(async move {
+			let req = rx.recv().await.unwrap();
+			assert_eq!(req.method, "POST");
+			assert_eq!(req.body.as_deref(), Some(&b"hello"[..]));
+			req.initial_response(200, vec![]);
+			req.body(b"ok".to_vec());
+		});
+
+		let mut res = http
+			.make_request_with_body("POST", "http://localhost/a".to_string(), b"hello".to_vec())
+			.await
+			.unwrap();
+		let mut body = String::new();
+		res.read.read_to_string(&mut body).await.unwrap();
+		assert_eq!(res.status_code, StatusCode::OK);
+		assert_eq!(body, "ok");
+		responder.await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_delegated_body_too_large() {
+		let (http, mut rx) = DelegatedSimpleHttp::new(log::Logger::test());
+		let http = http.with_max_body_size(4);
+
+		let res = http
+			.make_request_with_body("PUT", "http://localhost/a".to_string(), vec![0; 5])
+			.await;
+		assert!(res.is_err());
+		assert!(rx.try_recv().is_err());
+	}
+
+	#[tokio::test]
+	async fn test_delegated_see_other_redirect() {
+		let (http, mut rx) = DelegatedSimpleHttp::new(log::Logger::test());
+		let responder = tokio::spawn(async move {
+			let req = rx.recv().await.unwrap();
+			req.initial_response(303, vec![("Location".to_string(), "/b".to_string())]);
+			drop(req);
+
+			let req = rx.recv().await.unwrap();
+			assert_eq!(req.method, "GET");
+			assert_eq!(req.url, "http://localhost/b");
+			assert!(req.body.is_none());
+			req.initial_response(204, vec![]);
+		});
+
+		let res = http
+			.make_request_with_body("POST", "http://localhost/a".to_string(), b"x".to_vec())
+			.await
+			.unwrap();
+		assert_eq!(res.status_code, StatusCode::NO_CONTENT);
+		responder.await.unwrap();
+	}
 }
@@ -21,6 +21,12 @@ pub trait ReportCopyProgress {
 	fn report_progress(&mut self, bytes_so_far: u64, total_bytes: u64);
 }
 
+impl<T: ReportCopyProgress + ?Sized> ReportCopyProgress for &mut T {
+	fn report_progress(&mut self, bytes_so_far: u64, total_bytes: u64) {
+		(**self).report_progress(bytes_so_far, total_bytes)
+	}
+}
+
 /// Type that doesn't emit anything for download progress.
 pub struct SilentCopyProgress();
 
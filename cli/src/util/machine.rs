@@ -7,23 +7,21 @@ use std::{path::Path, time::Duration};
 use sysinfo::{Pid, PidExt, ProcessExt, System, SystemExt};
 
 pub fn process_at_path_exists(pid: u32, name: &Path) -> bool {
+	let name_str = format!("{}", name.display());
+	process_matches(pid, |cmd| cmd.iter().any(|c| c.contains(&name_str)))
+}
+
+/// Gets whether the process is running with a command line that matches.
+pub fn process_matches(pid: u32, matches: impl Fn(&[String]) -> bool) -> bool {
 	let mut sys = System::new();
 	let pid = Pid::from_u32(pid);
 	if !sys.refresh_process(pid) {
 		return false;
 	}
 
-	let name_str = format!("{}", name.display());
-	if let Some(process) = sys.process(pid) {
-		for cmd in process.cmd() {
-			if cmd.contains(&name_str) {
-				return true;
-			}
-		}
-	}
-
-	false
+	sys.process(pid).map_or(false, |p| matches(p.cmd()))
 }
+
 pub fn process_exists(pid: u32) -> bool {
 	let mut sys = System::new();
 	sys.refresh_process(Pid::from_u32(pid))
@@ -38,17 +36,17 @@ pub async fn wait_until_process_exits(pid: Pid, poll_ms: u64) {
 }
 
 pub fn find_running_process(name: &Path) -> Option<u32> {
+	let name_str = format!("{}", name.display());
+	find_running_process_matching(|cmd| cmd.iter().any(|c| c.contains(&name_str)))
+}
+
+/// Finds a process running with a command line that matches.
+pub fn find_running_process_matching(matches: impl Fn(&[String]) -> bool) -> Option<u32> {
 	let mut sys = System::new();
 	sys.refresh_processes();
 
-	let name_str = format!("{}", name.display());
-
-	for (pid, process) in sys.processes() {
-		for cmd in process.cmd() {
-			if cmd.contains(&name_str) {
-				return Some(pid.as_u32());
-			}
-		}
-	}
-	None
+	sys.processes()
+		.iter()
+		.find(|(_, process)| matches(process.cmd()))
+		.map(|(pid, _)| pid.as_u32())
 }
@@ -0,0 +1,285 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Permissions given to files and directories the CLI creates. On multi-user
+//! hosts these hold credentials and sockets that other users must not reach,
+//! so they're made private regardless of the process umask. On Windows, files
+//! inherit the ACL of the user's profile and the policy is not applied.
+
+use std::{
+	io,
+	path::{Path, PathBuf},
+	sync::RwLock,
+};
+
+use lazy_static::lazy_static;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PermissionPolicy {
+	/// Mode for regular files, such as credentials and logs.
+	pub file_mode: u32,
+	/// Mode for directories and unix sockets. Also used for executables,
+	/// which need the execute bit.
+	pub dir_mode: u32,
+}
+
+impl Default for PermissionPolicy {
+	fn default() -> Self {
+		Self {
+			file_mode: 0o600,
+			dir_mode: 0o700,
+		}
+	}
+}
+
+lazy_static! {
+	static ref POLICY: RwLock<PermissionPolicy> = RwLock::new(PermissionPolicy::default());
+}
+
+/// Sets the policy used for files created by the CLI. Should be called at
+/// startup, before any files are created.
+pub fn install_policy(policy: PermissionPolicy) {
+	*POLICY.write().unwrap() = policy;
+}
+
+pub fn policy() -> PermissionPolicy {
+	*POLICY.read().unwrap()
+}
+
+/// Parses an octal mode given on the command line, such as `600` or `0o600`.
+pub fn parse_mode(s: &str) -> Result<u32, String> {
+	let digits = s.trim_start_matches("0o");
+	match u32::from_str_radix(digits, 8) {
+		Ok(m) if m <= 0o777 => Ok(m),
+		_ => Err(format!("'{}' is not an octal permission mode", s)),
+	}
+}
+
+/// Applies the policy's file mode to the file.
+pub fn restrict_file(path: &Path) -> io::Result<()> {
+	set_mode(path, policy().file_mode)
+}
+
+/// Applies the policy's directory mode to the directory or socket.
+pub fn restrict_dir(path: &Path) -> io::Result<()> {
+	set_mode(path, policy().dir_mode)
+}
+
+/// Writes the file, creating it with the policy's file mode so that its
+/// contents are never readable by others.
+pub fn write_private(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+	use std::io::Write;
+
+	let mut options = std::fs::OpenOptions::new();
+	options.write(true).create(true).truncate(true);
+	#[cfg(unix)]
+	{
+		use std::os::unix::fs::OpenOptionsExt;
+		options.mode(policy().file_mode);
+	}
+
+	let mut file = options.open(path)?;
+	// the mode only applies to new files
+	restrict_file(path)?;
+	file.write_all(contents.as_ref())
+}
+
+/// Creates the directory and any missing parents, giving the ones it creates
+/// the policy's directory mode.
+pub fn create_private_dir_all(path: &Path) -> io::Result<()> {
+	// relative paths end with an empty parent, which is the working dir
+	if path.as_os_str().is_empty() || path.is_dir() {
+		return Ok(());
+	}
+
+	if let Some(parent) = path.parent() {
+		create_private_dir_all(parent)?;
+	}
+
+	match std::fs::create_dir(path) {
+		Ok(()) => restrict_dir(path),
+		Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+		Err(e) => Err(e),
+	}
+}
+
+/// Creates a private directory in a location other users can write to, such
+/// as the temp dir, where one of them may have created it first. An existing
+/// directory is only used if it's owned by the current user and others can't
+/// access it.
+#[cfg(unix)]
+pub fn create_owned_private_dir(path: &Path) -> io::Result<()> {
+	use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+
+	match std::fs::DirBuilder::new().mode(0o700).create(path) {
+		Ok(()) => return Ok(()),
+		Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+		Err(e) => return Err(e),
+	}
+
+	let metadata = std::fs::symlink_metadata(path)?;
+	let uid = unsafe { libc::getuid() };
+	if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+		return Err(io::Error::new(
+			io::ErrorKind::PermissionDenied,
+			format!(
+				"{} is not a private directory owned by the current user",
+				path.display()
+			),
+		));
+	}
+
+	Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn create_owned_private_dir(path: &Path) -> io::Result<()> {
+	create_private_dir_all(path)
+}
+
+/// Removes permissions that the policy doesn't allow from the directory and
+/// its direct children, returning the paths that were changed. Deeper entries
+/// aren't checked, since they're unreachable once their parent is private.
+pub fn fix_permissions(dir: &Path) -> io::Result<Vec<PathBuf>> {
+	let mut fixed = vec![];
+	if fix_entry(dir)? {
+		fixed.push(dir.to_path_buf());
+	}
+
+	for entry in std::fs::read_dir(dir)? {
+		let path = entry?.path();
+		if fix_entry(&path)? {
+			fixed.push(path);
+		}
+	}
+
+	Ok(fixed)
+}
+
+#[cfg(unix)]
+fn fix_entry(path: &Path) -> io::Result<bool> {
+	use std::os::unix::fs::PermissionsExt;
+
+	let metadata = std::fs::symlink_metadata(path)?;
+	if metadata.file_type().is_symlink() {
+		return Ok(false);
+	}
+
+	let mode = metadata.permissions().mode() & 0o777;
+	let policy = policy();
+	let allowed = if metadata.is_dir() || mode & 0o100 != 0 || !metadata.is_file() {
+		policy.dir_mode
+	} else {
+		policy.file_mode
+	};
+
+	if mode & !allowed == 0 {
+		return Ok(false);
+	}
+
+	set_mode(path, mode & allowed)?;
+	Ok(true)
+}
+
+#[cfg(not(unix))]
+fn fix_entry(_path: &Path) -> io::Result<bool> {
+	Ok(false)
+}
+
+/// Sets the mode of the file or directory. Does nothing on Windows.
+#[cfg(unix)]
+pub fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+	use std::os::unix::fs::PermissionsExt;
+	std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+pub fn set_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse_mode() {
+		assert_eq!(parse_mode("600"), Ok(0o600));
+		assert_eq!(parse_mode("0o750"), Ok(0o750));
+		assert!(parse_mode("800").is_err());
+		assert!(parse_mode("1777").is_err());
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn test_create_private_dir_all_relative() {
+		let dir = tempfile::tempdir().unwrap();
+		// relative to the working dir, which can't be changed while other
+		// tests run
+		let cwd = std::env::current_dir().unwrap();
+		let relative = cwd
+			.components()
+			.skip(1)
+			.map(|_| Path::new(".."))
+			.fold(PathBuf::new(), |p, c| p.join(c))
+			.join(dir.path().strip_prefix("/").unwrap())
+			.join("a")
+			.join("b");
+		assert!(relative.is_relative());
+
+		create_private_dir_all(&relative).unwrap();
+		assert!(dir.path().join("a").join("b").is_dir());
+		create_private_dir_all(&relative).unwrap();
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn test_create_owned_private_dir() {
+		let dir = tempfile::tempdir().unwrap();
+		let private = dir.path().join("private");
+		create_owned_private_dir(&private).unwrap();
+		create_owned_private_dir(&private).unwrap();
+
+		let shared = dir.path().join("shared");
+		std::fs::create_dir(&shared).unwrap();
+		set_mode(&shared, 0o777).unwrap();
+		assert!(create_owned_private_dir(&shared).is_err());
+
+		let link = dir.path().join("link");
+		std::os::unix::fs::symlink(&private, &link).unwrap();
+		assert!(create_owned_private_dir(&link).is_err());
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn test_fix_permissions() {
+		use std::os::unix::fs::PermissionsExt;
+
+		let dir = tempfile::tempdir().unwrap();
+		let mode = |p: &Path| std::fs::metadata(p).unwrap().permissions().mode() & 0o777;
+
+		let secret = dir.path().join("token.json");
+		let exe = dir.path().join("code");
+		let private = dir.path().join("private.log");
+		std::fs::write(&secret, "").unwrap();
+		std::fs::write(&exe, "").unwrap();
+		std::fs::write(&private, "").unwrap();
+		set_mode(dir.path(), 0o755).unwrap();
+		set_mode(&secret, 0o644).unwrap();
+		set_mode(&exe, 0o755).unwrap();
+		set_mode(&private, 0o600).unwrap();
+
+		let mut fixed = fix_permissions(dir.path()).unwrap();
+		fixed.sort();
+		let mut expected = vec![dir.path().to_path_buf(), exe.clone(), secret.clone()];
+		expected.sort();
+		assert_eq!(fixed, expected);
+
+		assert_eq!(mode(dir.path()), 0o700);
+		assert_eq!(mode(&secret), 0o600);
+		assert_eq!(mode(&exe), 0o700);
+		assert_eq!(mode(&private), 0o600);
+	}
+}
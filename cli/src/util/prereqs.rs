@@ -2,7 +2,7 @@
  *  Copyright (c) Microsoft Corporation. All rights reserved.
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
-use std::cmp::Ordering;
+use std::{cmp::Ordering, sync::RwLock};
 
 use super::command::capture_command;
 use crate::constants::QUALITYLESS_SERVER_NAME;
@@ -22,6 +22,13 @@ lazy_static! {
 		BinRegex::new(r"GLIBCXX_([0-9]+)\.([0-9]+)(?:\.([0-9]+))?").unwrap();
 	static ref MIN_CXX_VERSION: SimpleSemver = SimpleSemver::new(3, 4, 18);
 	static ref MIN_LDD_VERSION: SimpleSemver = SimpleSemver::new(2, 17, 0);
+	static ref PLATFORM_OVERRIDE: RwLock<Option<Platform>> = RwLock::new(None);
+}
+
+/// Installs a platform that's used instead of the detected one, from
+/// `--platform-override`.
+pub fn install_platform_override(platform: Option<Platform>) {
+	*PLATFORM_OVERRIDE.write().unwrap() = platform;
 }
 
 const NIXOS_TEST_PATH: &str = "/etc/NIXOS";
@@ -39,8 +46,18 @@ impl PreReqChecker {
 		PreReqChecker {}
 	}
 
-	#[cfg(not(target_os = "linux"))]
+	/// Gets the platform to use builds for, which is the installed override
+	/// if there is one.
 	pub async fn verify(&self) -> Result<Platform, CodeError> {
+		if let Some(platform) = *PLATFORM_OVERRIDE.read().unwrap() {
+			return Ok(platform);
+		}
+
+		self.detect().await
+	}
+
+	#[cfg(not(target_os = "linux"))]
+	async fn detect(&self) -> Result<Platform, CodeError> {
 		Platform::env_default().ok_or_else(|| {
 			CodeError::UnsupportedPlatform(format!(
 				"{} {}",
@@ -51,24 +68,38 @@ impl PreReqChecker {
 	}
 
 	#[cfg(target_os = "linux")]
-	pub async fn verify(&self) -> Result<Platform, CodeError> {
-		let (is_nixos, gnu_a, gnu_b, or_musl) = tokio::join!(
+	async fn detect(&self) -> Result<Platform, CodeError> {
+		let (is_nixos, is_musl, gnu_a, gnu_b, or_musl) = tokio::join!(
 			check_is_nixos(),
+			check_is_musl(),
 			check_glibc_version(),
 			check_glibcxx_version(),
 			check_musl_interpreter()
 		);
 
-		if (gnu_a.is_ok() && gnu_b.is_ok()) || is_nixos {
+		// musl hosts can pass the glibc checks, such as Alpine with gcompat and
+		// libstdc++ installed, but glibc builds fail to start on them
+		let is_gnu = gnu_a.is_ok() && gnu_b.is_ok() && !(is_musl && or_musl.is_ok());
+		if is_gnu || is_nixos {
 			return Ok(if cfg!(target_arch = "x86_64") {
 				Platform::LinuxX64
 			} else if cfg!(target_arch = "arm") {
 				Platform::LinuxARM32
-			} else {
+			} else if cfg!(target_arch = "riscv64") {
+				Platform::LinuxRISCV64
+			} else if cfg!(all(target_arch = "powerpc64", target_endian = "little")) {
+				Platform::LinuxPPC64LE
+			} else if cfg!(target_arch = "aarch64") {
 				Platform::LinuxARM64
+			} else {
+				return Err(CodeError::UnsupportedPlatform(format!(
+					"linux {}",
+					std::env::consts::ARCH
+				)));
 			});
 		}
 
+		// there are no musl builds for other architectures, whose check fails
 		if or_musl.is_ok() {
 			return Ok(if cfg!(target_arch = "x86_64") {
 				Platform::LinuxAlpineX64
@@ -150,6 +181,22 @@ async fn check_glibc_version() -> Result<(), String> {
 	Ok(())
 }
 
+/// Checks whether the system's libc is musl, which its `ldd` says when asked
+/// for its version.
+#[allow(dead_code)]
+async fn check_is_musl() -> bool {
+	capture_command("ldd", ["--version"])
+		.await
+		.map_or(false, |o| {
+			is_musl_ldd_output(&o.stdout) || is_musl_ldd_output(&o.stderr)
+		})
+}
+
+#[allow(dead_code)]
+fn is_musl_ldd_output(output: &[u8]) -> bool {
+	output.windows(4).any(|w| w.eq_ignore_ascii_case(b"musl"))
+}
+
 /// Check for nixos to avoid mandating glibc versions. See:
 /// https://github.com/microsoft/vscode-remote-release/issues/7129
 #[allow(dead_code)]
@@ -320,6 +367,19 @@ mod tests {
 		);
 	}
 
+	#[test]
+	fn test_is_musl_ldd_output() {
+		let musl = "musl libc (x86_64)
+Version 1.2.4
+Dynamic Program Loader
+Usage: ldd [options] [--] pathname";
+		assert!(is_musl_ldd_output(musl.as_bytes()));
+
+		let glibc = "ldd (Ubuntu GLIBC 2.31-0ubuntu9.7) 2.31
+Copyright (C) 2020 Free Software Foundation, Inc.";
+		assert!(!is_musl_ldd_output(glibc.as_bytes()));
+	}
+
 	#[test]
 	fn test_gte() {
 		assert!(SimpleSemver::new(1, 2, 3) >= SimpleSemver::new(1, 2, 3));
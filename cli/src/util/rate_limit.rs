@@ -0,0 +1,124 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::time::{Duration, Instant};
+
+/// Allows `calls` calls in each `per` period, on average.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+	pub calls: u32,
+	pub per: Duration,
+}
+
+impl RateLimit {
+	pub const fn per_minute(calls: u32) -> Self {
+		Self {
+			calls,
+			per: Duration::from_secs(60),
+		}
+	}
+}
+
+/// Token bucket that holds up to the limit's number of calls, so bursts of
+/// that size are allowed, and refills continuously over the period.
+pub struct TokenBucket {
+	limit: RateLimit,
+	tokens: f64,
+	updated: Instant,
+}
+
+impl TokenBucket {
+	pub fn new(limit: RateLimit) -> Self {
+		Self {
+			limit,
+			tokens: limit.calls as f64,
+			updated: Instant::now(),
+		}
+	}
+
+	/// Takes a token, returning false if the bucket is empty.
+	pub fn try_take(&mut self) -> bool {
+		self.try_take_at(Instant::now())
+	}
+
+	fn try_take_at(&mut self, now: Instant) -> bool {
+		let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+		let rate = self.limit.calls as f64 / self.limit.per.as_secs_f64().max(f64::EPSILON);
+		self.tokens = (self.tokens + elapsed * rate).min(self.limit.calls as f64);
+		self.updated = now;
+
+		if self.tokens >= 1.0 {
+			self.tokens -= 1.0;
+			true
+		} else {
+			false
+		}
+	}
+}
+
+/// Parses a limit for an RPC method given on the command line, in the form
+/// `method=calls/seconds`, such as `spawn=30/60`.
+pub fn parse_method_rate_limit(s: &str) -> Result<(String, RateLimit), String> {
+	let invalid = || {
+		format!(
+			"expected a rate limit in the form method=calls/seconds, got '{}'",
+			s
+		)
+	};
+
+	let (method, limit) = s.split_once('=').ok_or_else(invalid)?;
+	let (calls, secs) = limit.split_once('/').ok_or_else(invalid)?;
+	match (calls.parse(), secs.parse::<u64>()) {
+		(Ok(calls), Ok(secs)) if !method.is_empty() && secs > 0 => Ok((
+			method.to_string(),
+			RateLimit {
+				calls,
+				per: Duration::from_secs(secs),
+			},
+		)),
+		_ => Err(invalid()),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_token_bucket() {
+		let mut bucket = TokenBucket::new(RateLimit {
+			calls: 2,
+			per: Duration::from_secs(10),
+		});
+		let start = bucket.updated;
+
+		assert!(bucket.try_take_at(start));
+		assert!(bucket.try_take_at(start));
+		assert!(!bucket.try_take_at(start));
+		assert!(!bucket.try_take_at(start + Duration::from_secs(4)));
+		assert!(bucket.try_take_at(start + Duration::from_secs(5)));
+		// refills only up to the limit
+		assert!(bucket.try_take_at(start + Duration::from_secs(100)));
+		assert!(bucket.try_take_at(start + Duration::from_secs(100)));
+		assert!(!bucket.try_take_at(start + Duration::from_secs(100)));
+	}
+
+	#[test]
+	fn test_parse_method_rate_limit() {
+		assert_eq!(
+			parse_method_rate_limit("spawn=30/60"),
+			Ok((
+				"spawn".to_string(),
+				RateLimit {
+					calls: 30,
+					per: Duration::from_secs(60)
+				}
+			))
+		);
+		assert!(parse_method_rate_limit("spawn=30").is_err());
+		assert!(parse_method_rate_limit("spawn=30/0").is_err());
+		assert!(parse_method_rate_limit("=1/1").is_err());
+	}
+}
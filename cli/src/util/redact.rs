@@ -0,0 +1,227 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Removes personal and secret information from text, such as logs, so it
+//! can be shared for diagnostics.
+
+use lazy_static::lazy_static;
+use regex::{Regex, RegexBuilder};
+use serde::Serialize;
+
+/// Categories of information that can be redacted.
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum RedactionRule {
+	/// The current user's name, in paths and `user@host` addresses.
+	Usernames,
+	/// The machine's hostname, other than `localhost`.
+	Hostnames,
+	/// Access tokens, passwords, and other secrets.
+	Tokens,
+	/// The user's home directory, which is replaced with `~`.
+	Paths,
+}
+
+impl RedactionRule {
+	pub fn all() -> Vec<Self> {
+		vec![Self::Usernames, Self::Hostnames, Self::Tokens, Self::Paths]
+	}
+}
+
+lazy_static! {
+	static ref TOKEN_PATTERNS: Vec<(Regex, &'static str)> = vec![
+		// JWTs, used by the dev tunnels service and for Microsoft accounts
+		(
+			Regex::new(r"eyJ[A-Za-z0-9_-]+\.[A-Za-z0-9_-]+\.[A-Za-z0-9_-]*").unwrap(),
+			"<token>"
+		),
+		// GitHub access tokens
+		(
+			Regex::new(r"\bgh[pousr]_[A-Za-z0-9]{20,}\b").unwrap(),
+			"<token>"
+		),
+		(
+			Regex::new(r"(?i)\b(bearer)\s+[A-Za-z0-9._~+/=-]+").unwrap(),
+			"$1 <token>"
+		),
+		// key = value pairs, in arguments, JSON, and query strings
+		(
+			Regex::new(
+				r#"(?i)((?:token|secret|password|passwd|api[_-]?key)[a-z_-]*["']?\s*[:=]\s*["']?)[^\s"'&,;]+"#
+			)
+			.unwrap(),
+			"$1<redacted>"
+		),
+	];
+}
+
+/// Replaces information matching each rule, and additional literal strings,
+/// with placeholders.
+pub struct Redactor {
+	rules: Vec<RedactionRule>,
+	custom_count: usize,
+	/// Literal patterns and their replacements, applied in order.
+	literals: Vec<(Regex, String)>,
+}
+
+impl Redactor {
+	/// Creates a redactor for the current user and machine. `extra` are
+	/// strings to be removed in addition to the rules, such as project names.
+	pub fn new(rules: &[RedactionRule], extra: &[String]) -> Self {
+		let mut literals = vec![];
+		for text in extra {
+			add_literal(&mut literals, text, "", "<redacted>", "");
+		}
+
+		// the home dir usually includes the username, so replace it first
+		if rules.contains(&RedactionRule::Paths) {
+			if let Some(home) = dirs::home_dir() {
+				add_literal(&mut literals, &home.to_string_lossy(), "", "~", "");
+			}
+		}
+
+		if rules.contains(&RedactionRule::Usernames) {
+			if let Some(user) = current_username() {
+				add_username(&mut literals, &user);
+			}
+		}
+
+		if rules.contains(&RedactionRule::Hostnames) {
+			let hostname = gethostname::gethostname().to_string_lossy().to_string();
+			let short = hostname.split_once('.').map(|(s, _)| s.to_string());
+			for name in std::iter::once(hostname).chain(short) {
+				// also names the loopback address, which isn't identifying
+				if !name.eq_ignore_ascii_case("localhost") {
+					add_literal(&mut literals, &name, r"\b", "<host>", r"\b");
+				}
+			}
+		}
+
+		Self {
+			rules: rules.to_vec(),
+			custom_count: extra.len(),
+			literals,
+		}
+	}
+
+	pub fn rules(&self) -> &[RedactionRule] {
+		&self.rules
+	}
+
+	/// Gets the number of additional strings the redactor removes.
+	pub fn custom_count(&self) -> usize {
+		self.custom_count
+	}
+
+	pub fn redact(&self, text: &str) -> String {
+		let tokens: &[(Regex, &str)] = if self.rules.contains(&RedactionRule::Tokens) {
+			&TOKEN_PATTERNS
+		} else {
+			&[]
+		};
+
+		let mut text = text.to_string();
+		for (re, replacement) in tokens {
+			text = re.replace_all(&text, *replacement).into_owned();
+		}
+		for (re, replacement) in &self.literals {
+			text = re.replace_all(&text, replacement.as_str()).into_owned();
+		}
+
+		text
+	}
+}
+
+/// Adds a pattern replacing the text, between the `before` and `after`
+/// patterns, which aren't replaced.
+fn add_literal(
+	literals: &mut Vec<(Regex, String)>,
+	text: &str,
+	before: &str,
+	replacement: &str,
+	after: &str,
+) {
+	if text.len() < 2 {
+		return; // replacing single characters would mangle the text
+	}
+
+	let pattern = format!("({}){}({})", before, regex::escape(text), after);
+	if let Ok(re) = RegexBuilder::new(&pattern)
+		.case_insensitive(cfg!(windows))
+		.build()
+	{
+		literals.push((re, format!("${{1}}{}${{2}}", replacement)));
+	}
+}
+
+/// Adds patterns for the username where it identifies the user, in paths and
+/// addresses. Elsewhere it's left alone, since usernames such as `code` or
+/// `admin` are often ordinary words.
+fn add_username(literals: &mut Vec<(Regex, String)>, user: &str) {
+	add_literal(literals, user, r"[/\\]", "<user>", r"\b");
+	add_literal(literals, user, r"\b", "<user>", "@");
+}
+
+fn current_username() -> Option<String> {
+	std::env::var("USER")
+		.or_else(|_| std::env::var("USERNAME"))
+		.ok()
+		.filter(|u| !u.is_empty())
+		.or_else(|| {
+			dirs::home_dir().and_then(|h| h.file_name().map(|n| n.to_string_lossy().to_string()))
+		})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_redacts_tokens() {
+		let r = Redactor::new(&[RedactionRule::Tokens], &[]);
+		assert_eq!(
+			r.redact("Authorization: Bearer abc.def-123"),
+			"Authorization: Bearer <token>"
+		);
+		assert_eq!(
+			r.redact("--connection-token=s3cret --port 80"),
+			"--connection-token=<redacted> --port 80"
+		);
+		assert_eq!(
+			r.redact(r#"{"access_token":"xyz","expires":3}"#),
+			r#"{"access_token":"<redacted>","expires":3}"#
+		);
+		assert_eq!(
+			r.redact("got eyJhbGciOi.eyJzdWIiOi.c2lnbmF0dXJl"),
+			"got <token>"
+		);
+		assert_eq!(r.redact("nothing secret"), "nothing secret");
+	}
+
+	#[test]
+	fn test_redacts_extra_strings() {
+		let r = Redactor::new(&[], &["contoso".to_string()]);
+		assert_eq!(
+			r.redact("cloning contoso/app into contoso-app"),
+			"cloning <redacted>/app into <redacted>-app"
+		);
+	}
+
+	#[test]
+	fn test_redacts_usernames_in_context() {
+		let mut literals = vec![];
+		add_username(&mut literals, "code");
+		let r = Redactor {
+			rules: vec![RedactionRule::Usernames],
+			custom_count: 0,
+			literals,
+		};
+		assert_eq!(
+			r.redact("started code server in /tmp/code/x for code@box"),
+			"started code server in /tmp/<user>/x for <user>@box"
+		);
+		assert_eq!(r.redact(r"C:\Users\code"), r"C:\Users\<user>");
+	}
+}
@@ -0,0 +1,128 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Placement of sockets and other files that only live as long as the
+//! processes using them. On hardened distros, mandatory access control (MAC)
+//! policies such as SELinux or AppArmor may deny access to sockets in some
+//! directories, so the location is configurable and denials come with hints
+//! on how to resolve them.
+
+use std::{
+	path::{Path, PathBuf},
+	sync::RwLock,
+};
+
+use lazy_static::lazy_static;
+
+lazy_static! {
+	static ref RUNTIME_DIR: RwLock<Option<PathBuf>> = RwLock::new(None);
+}
+
+/// Sets the directory that runtime files are placed in. Should be called at
+/// startup, before any sockets are created.
+pub fn install_runtime_dir(dir: Option<PathBuf>) {
+	*RUNTIME_DIR.write().unwrap() = dir;
+}
+
+/// Gets the directory runtime files are placed in. This is the configured
+/// directory if any, then `$XDG_RUNTIME_DIR`, and finally the temp dir.
+pub fn runtime_dir() -> PathBuf {
+	if let Some(dir) = RUNTIME_DIR.read().unwrap().as_ref() {
+		return dir.clone();
+	}
+
+	match std::env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from) {
+		Some(dir) if dir.is_absolute() && dir.is_dir() => dir,
+		_ => std::env::temp_dir(),
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MacPolicy {
+	SELinux,
+	AppArmor,
+}
+
+/// Gets the MAC policy enforced on the host, if any.
+pub fn active_mac_policy() -> Option<MacPolicy> {
+	let read = |p: &str| std::fs::read_to_string(p).unwrap_or_default();
+	if read("/sys/fs/selinux/enforce").trim() == "1" {
+		Some(MacPolicy::SELinux)
+	} else if read("/sys/module/apparmor/parameters/enabled").trim() == "Y" {
+		Some(MacPolicy::AppArmor)
+	} else {
+		None
+	}
+}
+
+/// Gets the SELinux context the file or directory is labeled with.
+#[cfg(target_os = "linux")]
+pub fn selinux_context(path: &Path) -> Option<String> {
+	use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+	let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+	let name = CString::new("security.selinux").unwrap();
+	let mut buf = [0u8; 256];
+	let len = unsafe {
+		libc::getxattr(
+			path.as_ptr(),
+			name.as_ptr(),
+			buf.as_mut_ptr() as *mut libc::c_void,
+			buf.len(),
+		)
+	};
+	if len <= 0 {
+		return None;
+	}
+
+	let value = &buf[..len as usize];
+	let value = value.strip_suffix(&[0]).unwrap_or(value);
+	Some(String::from_utf8_lossy(value).to_string())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn selinux_context(_path: &Path) -> Option<String> {
+	None
+}
+
+/// Gets a suggestion for resolving a denied access to the path. MAC denials
+/// surface as ordinary permission errors, so the hint covers file modes too.
+pub fn denial_hint(path: &Path) -> String {
+	let dir = path.parent().unwrap_or(path);
+	let relocate = "or use --runtime-dir to place sockets in a directory the policy allows, such as $XDG_RUNTIME_DIR";
+
+	match active_mac_policy() {
+		Some(MacPolicy::SELinux) => format!(
+			"SELinux may have denied this; {} is labeled {}. Check `ausearch -m avc -ts recent`, relabel the directory with `semanage fcontext -a -t user_tmp_t '{}(/.*)?' && restorecon -R {}`, {}",
+			dir.display(),
+			selinux_context(dir).unwrap_or_else(|| "with an unknown context".to_string()),
+			dir.display(),
+			dir.display(),
+			relocate,
+		),
+		Some(MacPolicy::AppArmor) => format!(
+			"AppArmor may have denied this. Check `journalctl -k | grep apparmor=\"DENIED\"`, allow `{}/** rw,` in the profile confining the CLI, {}",
+			dir.display(),
+			relocate,
+		),
+		None => format!(
+			"check the permissions of {}, {}",
+			dir.display(),
+			relocate
+		),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_denial_hint() {
+		let hint = denial_hint(Path::new("/run/user/1000/code-1234"));
+		assert!(hint.contains("/run/user/1000"));
+		assert!(hint.contains("--runtime-dir"));
+	}
+}
@@ -0,0 +1,104 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+//! Key/value tags, such as the team or datacenter a machine belongs to, that
+//! are added to spans, metrics, log events, and support bundles so data from
+//! a fleet of machines can be filtered. Tags are read from the `tags` object
+//! in the data dir's `config.json`, and from `--tag` arguments, which take
+//! precedence.
+
+use std::{collections::BTreeMap, sync::RwLock};
+
+use lazy_static::lazy_static;
+
+use crate::state::config::CliConfig;
+
+pub type Tags = BTreeMap<String, String>;
+
+lazy_static! {
+	static ref TAGS: RwLock<Tags> = RwLock::new(Tags::new());
+}
+
+/// Sets the tags to add to telemetry. Should be called at startup, before
+/// logging is set up.
+pub fn install_tags(tags: Tags) {
+	*TAGS.write().unwrap() = tags;
+}
+
+pub fn tags() -> Tags {
+	TAGS.read().unwrap().clone()
+}
+
+/// Reads tags from the config file and adds the ones given as arguments.
+/// Returns the tags along with keys from the config file that were ignored
+/// because they're invalid.
+pub fn load_tags(config: &CliConfig, args: &[(String, String)]) -> (Tags, Vec<String>) {
+	let (mut tags, invalid): (Tags, Tags) = config
+		.tags
+		.clone()
+		.into_iter()
+		.partition(|(k, _)| is_valid_key(k));
+
+	tags.extend(args.iter().cloned());
+	(tags, invalid.into_keys().collect())
+}
+
+/// Parses a `key=value` tag given on the command line.
+pub fn parse_tag(s: &str) -> Result<(String, String), String> {
+	match s.split_once('=') {
+		Some((key, value)) if is_valid_key(key) => Ok((key.to_string(), value.to_string())),
+		Some((key, _)) => Err(format!(
+			"'{}' is not a valid tag name, names may only contain letters, digits, and underscores",
+			key
+		)),
+		None => Err(format!("expected a tag in the form key=value, got '{}'", s)),
+	}
+}
+
+/// Gets whether the key can be used as a tag. Keys are limited to what
+/// Prometheus allows in label names, so tags can be added to metrics.
+fn is_valid_key(key: &str) -> bool {
+	let mut chars = key.chars();
+	matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+		&& chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::state::LauncherPaths;
+
+	#[test]
+	fn test_parse_tag() {
+		assert_eq!(
+			parse_tag("team=tools"),
+			Ok(("team".to_string(), "tools".to_string()))
+		);
+		assert_eq!(
+			parse_tag("cost_center=a=b"),
+			Ok(("cost_center".to_string(), "a=b".to_string()))
+		);
+		assert!(parse_tag("team").is_err());
+		assert!(parse_tag("data-center=west").is_err());
+		assert!(parse_tag("1dc=west").is_err());
+	}
+
+	#[test]
+	fn test_load_tags() {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		std::fs::write(
+			paths.config_file(),
+			r#"{"tags":{"team":"tools","dc":"west","bad-key":"x"}}"#,
+		)
+		.unwrap();
+
+		let config = CliConfig::load(&paths).unwrap();
+		let (tags, invalid) = load_tags(&config, &[("dc".to_string(), "east".to_string())]);
+		assert_eq!(tags.get("team").map(String::as_str), Some("tools"));
+		assert_eq!(tags.get("dc").map(String::as_str), Some("east"));
+		assert_eq!(invalid, vec!["bad-key".to_string()]);
+	}
+}
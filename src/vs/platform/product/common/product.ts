@@ -37,6 +37,18 @@ else if (globalThis._VSCODE_PRODUCT_JSON && globalThis._VSCODE_PACKAGE_JSON) {
 		});
 	}
 
+	// Extension gallery overridden by whoever launched us, e.g. the CLI's
+	// caching proxy for extension downloads
+	if (env['EXTENSIONS_GALLERY']) {
+		try {
+			Object.assign(product, {
+				extensionsGallery: JSON.parse(env['EXTENSIONS_GALLERY'])
+			});
+		} catch (error) {
+			console.error(`Ignoring invalid EXTENSIONS_GALLERY: ${error}`);
+		}
+	}
+
 	// Version is added during built time, but we still
 	// want to have it running out of sources so we
 	// read it from package.json only when we need it.